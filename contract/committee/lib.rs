@@ -0,0 +1,311 @@
+#![cfg_attr(not(feature = "std"), no_std, no_main)]
+
+use ink::prelude::vec::Vec;
+use ink::primitives::AccountId;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[ink::scale_derive(Encode, Decode, TypeInfo)]
+#[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+pub struct Member {
+    /// the account that registered (and staked for) the node
+    pub operator: AccountId,
+    /// the committee index of the node
+    pub index: u32,
+    /// the iroh node id (ed25519 public key)
+    pub node_id: [u8; 32],
+    /// the compressed STE public key (hint), empty if not known yet
+    pub ste_public_key: Vec<u8>,
+    /// the RPC endpoint of the node (e.g. `http://127.0.0.1:30332`)
+    pub rpc_endpoint: Vec<u8>,
+    /// the stake locked by the operator
+    pub stake: u128,
+}
+
+#[ink::contract]
+pub mod committee_registry {
+    use super::*;
+    use ink::prelude::vec;
+    use ink::storage::Mapping;
+
+    #[ink(storage)]
+    pub struct Contract {
+        /// Map committee index to member
+        members: Mapping<u32, Member>,
+        /// Map operator to committee index
+        operators: Mapping<AccountId, u32>,
+        /// List of all taken indices
+        indices: Vec<u32>,
+        /// The number of seats in the committee
+        committee_size: u32,
+        /// The minimum stake to register
+        min_stake: Balance,
+        /// The account that instantiated the registry
+        owner: AccountId,
+        /// Committee epochs before this one should be retired
+        retire_before: u64,
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub enum Error {
+        IndexOutOfRange,
+        IndexTaken,
+        AlreadyRegistered,
+        NotRegistered,
+        InsufficientStake,
+        TransferFailed,
+        NotOwner,
+    }
+
+    impl Contract {
+        #[ink(constructor)]
+        pub fn new(committee_size: u32, min_stake: Balance) -> Self {
+            Self {
+                members: Mapping::default(),
+                operators: Mapping::default(),
+                indices: vec![],
+                committee_size,
+                min_stake,
+                owner: Self::env().caller(),
+                retire_before: 0,
+            }
+        }
+
+        /// Register the caller's node as the committee member at `index`
+        /// The transferred value is locked as stake until the member deregisters
+        ///
+        /// * `index`: the committee index of the node
+        /// * `node_id`: the iroh node id, announcements must be signed by it
+        /// * `ste_public_key`: the compressed STE public key (may be empty)
+        /// * `rpc_endpoint`: where clients reach the node
+        #[ink(message, payable)]
+        pub fn register_member(
+            &mut self,
+            index: u32,
+            node_id: [u8; 32],
+            ste_public_key: Vec<u8>,
+            rpc_endpoint: Vec<u8>,
+        ) -> Result<(), Error> {
+            let operator = self.env().caller();
+            let stake = self.env().transferred_value();
+
+            if index >= self.committee_size {
+                return Err(Error::IndexOutOfRange);
+            }
+            if self.members.contains(index) {
+                return Err(Error::IndexTaken);
+            }
+            if self.operators.contains(operator) {
+                return Err(Error::AlreadyRegistered);
+            }
+            if stake < self.min_stake {
+                return Err(Error::InsufficientStake);
+            }
+
+            let member = Member {
+                operator,
+                index,
+                node_id,
+                ste_public_key,
+                rpc_endpoint,
+                stake,
+            };
+            self.members.insert(index, &member);
+            self.operators.insert(operator, &index);
+            self.indices.push(index);
+
+            Ok(())
+        }
+
+        /// Update the STE public key and RPC endpoint of the caller's node
+        #[ink(message)]
+        pub fn update_member(
+            &mut self,
+            ste_public_key: Vec<u8>,
+            rpc_endpoint: Vec<u8>,
+        ) -> Result<(), Error> {
+            let index = self
+                .operators
+                .get(self.env().caller())
+                .ok_or(Error::NotRegistered)?;
+            let mut member = self.members.get(index).ok_or(Error::NotRegistered)?;
+            member.ste_public_key = ste_public_key;
+            member.rpc_endpoint = rpc_endpoint;
+            self.members.insert(index, &member);
+            Ok(())
+        }
+
+        /// Leave the committee, returning the stake to the caller
+        #[ink(message)]
+        pub fn deregister_member(&mut self) -> Result<Member, Error> {
+            let operator = self.env().caller();
+            let index = self.operators.take(operator).ok_or(Error::NotRegistered)?;
+            let member = self.members.take(index).ok_or(Error::NotRegistered)?;
+
+            if let Some(pos) = self.indices.iter().position(|i| *i == index) {
+                self.indices.swap_remove(pos);
+            }
+
+            if member.stake > 0 {
+                self.env()
+                    .transfer(operator, member.stake)
+                    .map_err(|_| Error::TransferFailed)?;
+            }
+
+            Ok(member)
+        }
+
+        /// Read the member at a committee index
+        #[ink(message)]
+        pub fn member(&self, index: u32) -> Option<Member> {
+            self.members.get(index)
+        }
+
+        /// List all registered members
+        #[ink(message)]
+        pub fn members(&self) -> Vec<Member> {
+            self.indices
+                .iter()
+                .filter_map(|index| self.members.get(index))
+                .collect()
+        }
+
+        /// The number of seats in the committee
+        #[ink(message)]
+        pub fn committee_size(&self) -> u32 {
+            self.committee_size
+        }
+
+        /// Ask the committee to retire the epochs before `epoch` (owner only)
+        /// Ciphertexts encrypted under retired epochs can no longer be decrypted
+        #[ink(message)]
+        pub fn set_retire_before(&mut self, epoch: u64) -> Result<(), Error> {
+            if self.env().caller() != self.owner {
+                return Err(Error::NotOwner);
+            }
+            self.retire_before = epoch;
+            Ok(())
+        }
+
+        /// Committee epochs before this one should be retired
+        #[ink(message)]
+        pub fn retire_before(&self) -> u64 {
+            self.retire_before
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ink::env::{test, DefaultEnvironment};
+
+        fn accounts() -> test::DefaultAccounts<DefaultEnvironment> {
+            test::default_accounts::<DefaultEnvironment>()
+        }
+
+        /// Register a node as `caller`, staking `stake`
+        fn register(
+            contract: &mut Contract,
+            caller: AccountId,
+            index: u32,
+            stake: Balance,
+        ) -> Result<(), Error> {
+            test::set_caller::<DefaultEnvironment>(caller);
+            test::set_value_transferred::<DefaultEnvironment>(stake);
+            contract.register_member(index, [index as u8; 32], vec![], b"http://node".to_vec())
+        }
+
+        #[ink::test]
+        fn members_register_once_per_seat() {
+            let accounts = accounts();
+            let mut contract = Contract::new(2, 10);
+
+            assert_eq!(
+                register(&mut contract, accounts.bob, 2, 10),
+                Err(Error::IndexOutOfRange)
+            );
+            assert_eq!(
+                register(&mut contract, accounts.bob, 0, 9),
+                Err(Error::InsufficientStake)
+            );
+            register(&mut contract, accounts.bob, 0, 10).unwrap();
+            assert_eq!(
+                register(&mut contract, accounts.charlie, 0, 10),
+                Err(Error::IndexTaken)
+            );
+            assert_eq!(
+                register(&mut contract, accounts.bob, 1, 10),
+                Err(Error::AlreadyRegistered)
+            );
+            register(&mut contract, accounts.charlie, 1, 20).unwrap();
+
+            let members = contract.members();
+            assert_eq!(members.len(), 2);
+            assert_eq!(members[1].operator, accounts.charlie);
+            assert_eq!(members[1].stake, 20);
+            assert_eq!(contract.member(0).unwrap().node_id, [0u8; 32]);
+        }
+
+        #[ink::test]
+        fn only_registered_operators_update_their_member() {
+            let accounts = accounts();
+            let mut contract = Contract::new(2, 0);
+            register(&mut contract, accounts.bob, 1, 0).unwrap();
+
+            test::set_caller::<DefaultEnvironment>(accounts.charlie);
+            assert_eq!(
+                contract.update_member(vec![1], b"http://other".to_vec()),
+                Err(Error::NotRegistered)
+            );
+
+            test::set_caller::<DefaultEnvironment>(accounts.bob);
+            contract
+                .update_member(vec![1, 2], b"http://moved".to_vec())
+                .unwrap();
+            let member = contract.member(1).unwrap();
+            assert_eq!(member.ste_public_key, vec![1, 2]);
+            assert_eq!(member.rpc_endpoint, b"http://moved".to_vec());
+        }
+
+        #[ink::test]
+        fn deregistering_frees_the_seat_and_returns_the_stake() {
+            let accounts = accounts();
+            let mut contract = Contract::new(2, 10);
+            register(&mut contract, accounts.bob, 0, 100).unwrap();
+            let contract_id = test::callee::<DefaultEnvironment>();
+            test::set_account_balance::<DefaultEnvironment>(contract_id, 1_000_000);
+            let balance = test::get_account_balance::<DefaultEnvironment>(accounts.bob).unwrap();
+
+            test::set_caller::<DefaultEnvironment>(accounts.charlie);
+            assert_eq!(contract.deregister_member(), Err(Error::NotRegistered));
+
+            test::set_caller::<DefaultEnvironment>(accounts.bob);
+            let member = contract.deregister_member().unwrap();
+            assert_eq!(member.stake, 100);
+            assert_eq!(
+                test::get_account_balance::<DefaultEnvironment>(accounts.bob).unwrap(),
+                balance + 100
+            );
+            assert!(contract.members().is_empty());
+            assert_eq!(contract.member(0), None);
+
+            // the seat can be taken again
+            register(&mut contract, accounts.charlie, 0, 10).unwrap();
+        }
+
+        #[ink::test]
+        fn only_the_owner_retires_epochs() {
+            let accounts = accounts();
+            test::set_caller::<DefaultEnvironment>(accounts.alice);
+            let mut contract = Contract::new(2, 0);
+
+            test::set_caller::<DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.set_retire_before(3), Err(Error::NotOwner));
+            assert_eq!(contract.retire_before(), 0);
+
+            test::set_caller::<DefaultEnvironment>(accounts.alice);
+            contract.set_retire_before(3).unwrap();
+            assert_eq!(contract.retire_before(), 3);
+        }
+    }
+}
@@ -23,20 +23,47 @@ pub struct Intent(pub Vec<u8>);
 pub struct Entry {
     pub cid: CID,
     pub intent: Vec<u8>,
+    /// the account that registered the entry, the only one allowed to update it
+    /// None for entries registered before owners were recorded
+    pub owner: Option<ink::primitives::AccountId>,
+}
+
+/// An entry as stored before owners were recorded
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[ink::scale_derive(Encode, Decode, TypeInfo)]
+#[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+pub struct LegacyEntry {
+    pub cid: CID,
+    pub intent: Vec<u8>,
+}
+
+impl From<LegacyEntry> for Entry {
+    fn from(legacy: LegacyEntry) -> Self {
+        Self {
+            cid: legacy.cid,
+            intent: legacy.intent,
+            owner: None,
+        }
+    }
 }
 
 #[ink::contract]
 pub mod fangorn_registry {
     use super::*;
     use ink::prelude::vec;
-    use ink::storage::Mapping;
+    use ink::storage::{Lazy, Mapping};
 
     #[ink(storage)]
     pub struct Contract {
-        /// Map filename to entry
-        registry: Mapping<Filename, Entry>,
+        /// Map filename to entries registered before owners were recorded (read only)
+        registry: Mapping<Filename, LegacyEntry>,
         /// List of all registered filenames
         filenames: Vec<Filename>,
+        /// Map filename to entry
+        entries: Mapping<Filename, Entry>,
+        /// The account allowed to assign owners to legacy entries
+        /// Lazy, so the storage root keeps the layout of older versions
+        admin: Lazy<AccountId>,
     }
 
     #[derive(Debug, PartialEq, Eq)]
@@ -45,14 +72,19 @@ pub mod fangorn_registry {
         FilenameAlreadyExists,
         FilenameNotFound,
         Unauthorized,
+        CidMismatch,
     }
 
     impl Contract {
         #[ink(constructor)]
         pub fn new() -> Self {
+            let mut admin = Lazy::new();
+            admin.set(&Self::env().caller());
             Self {
                 registry: Mapping::default(),
                 filenames: vec![],
+                entries: Mapping::default(),
+                admin,
             }
         }
 
@@ -69,13 +101,17 @@ pub mod fangorn_registry {
             intent: Vec<u8>,
         ) -> Result<(), Error> {
             // check duplicate filenames
-            if self.registry.contains(&filename) {
+            if self.entries.contains(&filename) || self.registry.contains(&filename) {
                 return Err(Error::FilenameAlreadyExists);
             }
 
-            let entry = Entry { cid, intent };
+            let entry = Entry {
+                cid,
+                intent,
+                owner: Some(self.env().caller()),
+            };
 
-            self.registry.insert(&filename, &entry);
+            self.entries.insert(&filename, &entry);
             self.filenames.push(filename);
 
             Ok(())
@@ -84,7 +120,9 @@ pub mod fangorn_registry {
         /// Read entry by filename
         #[ink(message)]
         pub fn read(&self, filename: Filename) -> Option<Entry> {
-            self.registry.get(&filename)
+            self.entries
+                .get(&filename)
+                .or_else(|| self.registry.get(&filename).map(Entry::from))
         }
 
         /// List all registered filenames (todo: pagination)
@@ -101,12 +139,59 @@ pub mod fangorn_registry {
             // self.filenames[start..end].to_vec()
         }
 
+        /// Point an entry at a new cid (e.g. a document migrated to a new committee epoch)
+        /// Only the owner can update the entry, and only if it still points at `old_cid`
+        /// Legacy entries have no owner until the admin assigns one
+        #[ink(message)]
+        pub fn update_cid(
+            &mut self,
+            filename: Filename,
+            old_cid: CID,
+            new_cid: CID,
+        ) -> Result<(), Error> {
+            let mut entry = self.read(filename.clone()).ok_or(Error::FilenameNotFound)?;
+
+            if entry.owner != Some(self.env().caller()) {
+                return Err(Error::Unauthorized);
+            }
+            if entry.cid != old_cid {
+                return Err(Error::CidMismatch);
+            }
+
+            entry.cid = new_cid;
+            self.entries.insert(&filename, &entry);
+
+            Ok(())
+        }
+
+        /// Assign an owner to an entry registered before owners were recorded
+        /// Only the admin can migrate legacy entries, entries that already have an owner are left as is
+        #[ink(message)]
+        pub fn assign_owner(&mut self, filename: Filename, owner: AccountId) -> Result<(), Error> {
+            if self.admin.get() != Some(self.env().caller()) {
+                return Err(Error::Unauthorized);
+            }
+            let legacy = self
+                .registry
+                .take(&filename)
+                .ok_or(Error::FilenameNotFound)?;
+
+            let entry = Entry {
+                owner: Some(owner),
+                ..Entry::from(legacy)
+            };
+            self.entries.insert(&filename, &entry);
+
+            Ok(())
+        }
+
         /// Remove an entry
         #[ink(message)]
         pub fn remove(&mut self, filename: Filename) -> Result<Entry, Error> {
             let entry = self
-                .registry
+                .entries
                 .take(&filename)
+                .or_else(|| self.registry.take(&filename).map(Entry::from))
                 .ok_or(Error::FilenameNotFound)?;
 
             if let Some(pos) = self.filenames.iter().position(|f| f == &filename) {
@@ -116,4 +201,95 @@ pub mod fangorn_registry {
             Ok(entry)
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ink::env::{test, DefaultEnvironment};
+
+        fn accounts() -> test::DefaultAccounts<DefaultEnvironment> {
+            test::default_accounts::<DefaultEnvironment>()
+        }
+
+        fn filename() -> Filename {
+            Filename(b"doc".to_vec())
+        }
+
+        #[ink::test]
+        fn only_the_owner_updates_the_cid() {
+            let accounts = accounts();
+            test::set_caller::<DefaultEnvironment>(accounts.alice);
+            let mut contract = Contract::new();
+
+            test::set_caller::<DefaultEnvironment>(accounts.bob);
+            contract
+                .register(filename(), CID(vec![1]), vec![9])
+                .unwrap();
+            assert_eq!(contract.read(filename()).unwrap().owner, Some(accounts.bob));
+
+            test::set_caller::<DefaultEnvironment>(accounts.charlie);
+            assert_eq!(
+                contract.update_cid(filename(), CID(vec![1]), CID(vec![2])),
+                Err(Error::Unauthorized)
+            );
+
+            test::set_caller::<DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                contract.update_cid(filename(), CID(vec![3]), CID(vec![2])),
+                Err(Error::CidMismatch)
+            );
+            contract
+                .update_cid(filename(), CID(vec![1]), CID(vec![2]))
+                .unwrap();
+            assert_eq!(contract.read(filename()).unwrap().cid, CID(vec![2]));
+            assert_eq!(
+                contract.update_cid(Filename(b"other".to_vec()), CID(vec![1]), CID(vec![2])),
+                Err(Error::FilenameNotFound)
+            );
+        }
+
+        #[ink::test]
+        fn legacy_entries_are_migrated_by_the_admin() {
+            let accounts = accounts();
+            test::set_caller::<DefaultEnvironment>(accounts.alice);
+            let mut contract = Contract::new();
+            let legacy = LegacyEntry {
+                cid: CID(vec![1]),
+                intent: vec![9],
+            };
+            contract.registry.insert(&filename(), &legacy);
+            contract.filenames.push(filename());
+
+            // readable, but nobody can update it yet
+            assert_eq!(contract.read(filename()), Some(legacy.into()));
+            test::set_caller::<DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                contract.register(filename(), CID(vec![2]), vec![]),
+                Err(Error::FilenameAlreadyExists)
+            );
+            assert_eq!(
+                contract.update_cid(filename(), CID(vec![1]), CID(vec![2])),
+                Err(Error::Unauthorized)
+            );
+            assert_eq!(
+                contract.assign_owner(filename(), accounts.bob),
+                Err(Error::Unauthorized)
+            );
+
+            test::set_caller::<DefaultEnvironment>(accounts.alice);
+            contract.assign_owner(filename(), accounts.bob).unwrap();
+            assert_eq!(
+                contract.assign_owner(filename(), accounts.charlie),
+                Err(Error::FilenameNotFound)
+            );
+
+            test::set_caller::<DefaultEnvironment>(accounts.bob);
+            contract
+                .update_cid(filename(), CID(vec![1]), CID(vec![2]))
+                .unwrap();
+            let entry = contract.read(filename()).unwrap();
+            assert_eq!(entry.cid, CID(vec![2]));
+            assert_eq!(entry.owner, Some(accounts.bob));
+        }
+    }
 }
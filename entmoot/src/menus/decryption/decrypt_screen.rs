@@ -4,6 +4,7 @@ use fangorn::backend::BlockchainBackend;
 use anyhow::Result;
 use fangorn::crypto::cipher::handle_decrypt;
 use fangorn::crypto::keystore::KeystoreError;
+use fangorn::node::NetworkConfig;
 use fangorn::utils::{decode_public_key, load_mnemonic};
 use ratatui::crossterm::event::{KeyCode, KeyEvent};
 use ratatui::layout::{Alignment, Rect};
@@ -54,7 +55,7 @@ pub async fn handle_input(app: &mut App, key: KeyEvent) {
                     let witness_string = &prepare_witness_string(app, password).await.expect("Something went wrong when creating witness string");
                     let contract_addr = String::from("5Ccuf8QBBoqZtUPFTxwixMd9mfHLUmXhRvNfBdEU7uL1ApR7");
                     
-                    handle_decrypt(&config_path, &filename, witness_string, &filename, &contract_addr).await;
+                    handle_decrypt(&config_path, &filename, witness_string, &filename, &contract_addr, &NetworkConfig::default()).await;
                     cleanup(app);
                 }
             } else {
@@ -65,7 +66,7 @@ pub async fn handle_input(app: &mut App, key: KeyEvent) {
                     let witness_string = &prepare_witness_string(app, String::from("")).await.expect("Something went wrong when creating witness string");
                     let contract_addr = String::from("5Ccuf8QBBoqZtUPFTxwixMd9mfHLUmXhRvNfBdEU7uL1ApR7");
                     
-                    handle_decrypt(&config_path, &filename, witness_string, &filename, &contract_addr).await;
+                    handle_decrypt(&config_path, &filename, witness_string, &filename, &contract_addr, &NetworkConfig::default()).await;
                     cleanup(app);
                 }
             } 
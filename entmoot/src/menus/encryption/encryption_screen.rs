@@ -1,6 +1,7 @@
 use std::path::Path;
 
-use fangorn::crypto::cipher::handle_encrypt;
+use fangorn::crypto::{cipher::handle_encrypt, encrypt::DEFAULT_THRESHOLD};
+use fangorn::node::NetworkConfig;
 use ratatui::crossterm::event::{KeyCode, KeyEvent};
 use ratatui::Frame;
 use ratatui::layout::{Alignment, Constraint, Layout, Rect};
@@ -257,7 +258,7 @@ async fn handle_encrypt_all(app: &mut App, password: String, contract_address: S
         intent_str = String::from(format!("Password({}) && Psp22({}, {}) && Sr25519()", password, contract_address, token_count));
     }
 
-    handle_encrypt(&filename, &filename, &config_path, &keystore_path, &intent_str, &intent_store_address).await;
+    handle_encrypt(&filename, &filename, &config_path, &keystore_path, &intent_str, &intent_store_address, DEFAULT_THRESHOLD, &NetworkConfig::default()).await;
 
 }
 
@@ -279,7 +280,7 @@ async fn handle_encrypt_psp22(app: &mut App, contract_address: String, token_cou
         intent_str = String::from(format!("Psp22({}, {}) && Sr25519()", contract_address, token_count));
     }
 
-    handle_encrypt(&filename, &filename, &config_path, &keystore_path, &intent_str, &intent_store_address).await;
+    handle_encrypt(&filename, &filename, &config_path, &keystore_path, &intent_str, &intent_store_address, DEFAULT_THRESHOLD, &NetworkConfig::default()).await;
 
 }
 
@@ -297,6 +298,6 @@ async fn handle_encrypt_password(app: &mut App, password: String) {
         intent_str = String::from(format!("Password({}) && Sr25519()", password));
     }
     let intent_store_address = String::from("5Ccuf8QBBoqZtUPFTxwixMd9mfHLUmXhRvNfBdEU7uL1ApR7");
-    handle_encrypt(&filename, &filename, &config_path, &keystore_path, &intent_str, &intent_store_address).await;
+    handle_encrypt(&filename, &filename, &config_path, &keystore_path, &intent_str, &intent_store_address, DEFAULT_THRESHOLD, &NetworkConfig::default()).await;
 
 }
\ No newline at end of file
@@ -1,6 +1,7 @@
 use std::path::Path;
 
-use fangorn::crypto::cipher::handle_encrypt;
+use fangorn::crypto::{cipher::handle_encrypt, encrypt::DEFAULT_THRESHOLD};
+use fangorn::node::NetworkConfig;
 use ratatui::crossterm::event::KeyCode;
 use ratatui::layout::Rect;
 use ratatui::style::Stylize;
@@ -47,7 +48,7 @@ pub async fn handle_input(app: &mut App, key_code: KeyCode) {
                         let keystore_path = String::from("tmp/keystore");
                         let intent_str = String::from("Sr25519()");
                         let contract_addr = String::from("5Ccuf8QBBoqZtUPFTxwixMd9mfHLUmXhRvNfBdEU7uL1ApR7");
-                        handle_encrypt(&filename, &filename, &config_path, &keystore_path, &intent_str, &contract_addr).await;
+                        handle_encrypt(&filename, &filename, &config_path, &keystore_path, &intent_str, &contract_addr, DEFAULT_THRESHOLD, &NetworkConfig::default()).await;
                         app.current_screen = CurrentScreen::Main;
                         app.generated_pubkey = None;
                         app.reset_intent_list();
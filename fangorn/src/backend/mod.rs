@@ -29,6 +29,15 @@ pub trait BlockchainBackend: Send + Sync + std::fmt::Debug {
         data: Vec<u8>,
     ) -> Result<Vec<u8>>;
 
+    /// Call a payable contract message, transferring `value` to the contract
+    async fn call_contract_with_value(
+        &self,
+        contract_address: [u8; 32],
+        method_selector: [u8; 4],
+        data: Vec<u8>,
+        value: u128,
+    ) -> Result<Vec<u8>>;
+
     /// Create method selector from method name
     fn selector(&self, name: &str) -> [u8; 4] {
         use sp_core_hashing::blake2_256;
@@ -97,6 +97,17 @@ impl BlockchainBackend for SubstrateBackend {
         contract_address: [u8; 32],
         method_selector: [u8; 4],
         data: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        self.call_contract_with_value(contract_address, method_selector, data, 0)
+            .await
+    }
+
+    async fn call_contract_with_value(
+        &self,
+        contract_address: [u8; 32],
+        method_selector: [u8; 4],
+        data: Vec<u8>,
+        value: u128,
     ) -> Result<Vec<u8>> {
         // call_data = selector || data
         let mut call_data = method_selector.to_vec();
@@ -104,7 +115,7 @@ impl BlockchainBackend for SubstrateBackend {
 
         let call = runtime::tx().contracts().call(
             MultiAddress::Id(AccountId32(contract_address)),
-            0u128,
+            value,
             runtime::runtime_types::sp_weights::weight_v2::Weight {
                 ref_time: 10_000_000_000,
                 proof_size: 500_000,
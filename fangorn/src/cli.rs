@@ -1,4 +1,5 @@
 use clap::{Parser, Subcommand};
+use std::str::FromStr;
 
 #[derive(Parser, Debug)]
 #[command(name = "fangorn", version = "1.0")]
@@ -10,10 +11,23 @@ pub struct FangornNodeCli {
 /// Define available subcommands
 #[derive(Subcommand, Debug)]
 pub enum FangornNodeCommands {
+    /// Generate the node keys (in a new password protected keystore) and the CRS
     Setup {
         /// The output directory (relative path)
         #[arg(long)]
         out_dir: String,
+        /// The index of the node
+        #[arg(long)]
+        index: usize,
+        /// The maximum committee size the CRS supports (committees can change up to this size)
+        #[arg(long, default_value_t = 2)]
+        committee_size: usize,
+        /// Import the CRS from a config or powers-of-tau file instead of generating it
+        #[arg(long, default_value = None)]
+        import_crs: Option<String>,
+        /// Reuse the node id of the keystore in this directory (to join another universe as the same node)
+        #[arg(long, default_value = None)]
+        node_identity: Option<String>,
     },
     Run {
         /// Port to bind for incoming connections
@@ -22,9 +36,15 @@ pub enum FangornNodeCommands {
         /// Port for the RPC interface
         #[arg(long)]
         rpc_port: u16,
-        /// The index of a node
+        /// The address the RPC interface binds to
+        #[arg(long, default_value = "127.0.0.1")]
+        rpc_host: String,
+        /// An RPC endpoint clients reach the node at, published with its hint (repeatable) [default: http://<rpc-host>:<rpc-port>]
+        #[arg(long = "rpc-endpoint")]
+        rpc_endpoints: Vec<String>,
+        /// The node directory with the keystore and config (created by `fangorn setup`)
         #[arg(long)]
-        index: usize,
+        keystore_dir: String,
         /// Determine it the node should act as a bootstrap node
         #[arg(long)]
         is_bootstrap: bool,
@@ -40,5 +60,152 @@ pub enum FangornNodeCommands {
         /// The bootstrap node ip
         #[arg(long, default_value=None)]
         bootstrap_ip: Option<String>,
+        /// A peer to connect to, as `<node id>@<ip:port>` (repeatable)
+        #[arg(long = "peer")]
+        peers: Vec<String>,
+        /// Turn off n0 discovery and relays: find peers on the local network, from `--peer` or through `--relay-url`
+        #[arg(long)]
+        offline: bool,
+        /// A self-hosted relay to use instead of the n0 relays
+        #[arg(long, default_value = None)]
+        relay_url: Option<String>,
+        /// Generate the CRS with a ceremony between this many nodes (bootstrap only)
+        #[arg(long, default_value=None)]
+        ceremony_participants: Option<u32>,
+        /// The maximum committee size the ceremony CRS supports [default: the number of participants]
+        #[arg(long, default_value=None)]
+        ceremony_size: Option<usize>,
+        /// The committee members, one `<index> <node id>` per line, polled for committee changes [default: <keystore-dir>/members.txt]
+        #[arg(long, default_value = None)]
+        members: Option<String>,
+        /// Read the admitted members from the committee registry contract instead
+        #[arg(long, default_value = None)]
+        registry_addr: Option<String>,
+        /// Persist docs, blobs and state snapshots to this directory, to resume from after a restart [default: in memory]
+        #[arg(long, default_value = None)]
+        data_dir: Option<String>,
+        /// Also take part in another universe (repeatable):
+        /// `<keystore dir>,(ticket=<ticket>|bootstrap),contract=<addr>[,members=<path>][,registry=<addr>]`
+        /// With `bootstrap`, the node bootstraps the universe with the config in the keystore dir
+        #[arg(long = "universe")]
+        universes: Vec<UniverseArg>,
     },
+    /// Register the node in the on-chain committee registry
+    Register {
+        /// The node directory with the keystore and config (created by `fangorn setup`)
+        #[arg(long)]
+        keystore_dir: String,
+        /// The committee registry contract address
+        #[arg(long)]
+        registry_addr: String,
+        /// The RPC endpoint clients reach the node at (e.g. http://127.0.0.1:30332)
+        #[arg(long)]
+        rpc_endpoint: String,
+        /// The stake to lock
+        #[arg(long, default_value_t = 0)]
+        stake: u128,
+        /// Also register the STE public key computed against the node's config (not for ceremonies)
+        #[arg(long)]
+        with_hint: bool,
+        /// The operator keystore (with the mnemonic of the account registering the node)
+        #[arg(long, default_value = None)]
+        operator_keystore: Option<String>,
+    },
+}
+
+/// Another universe a node takes part in, next to the one of `--keystore-dir`
+#[derive(Clone, Debug, PartialEq)]
+pub struct UniverseArg {
+    /// the node directory with the keystore and config of the universe
+    pub keystore_dir: String,
+    /// the ticket of the universe doc, None when bootstrapping the universe
+    pub ticket: Option<String>,
+    /// bootstrap the universe instead of joining it
+    pub bootstrap: bool,
+    /// the intent registry contract of the universe (filenames are unique per universe)
+    pub contract_addr: String,
+    /// the committee members [default: <keystore dir>/members.txt]
+    pub members: Option<String>,
+    /// the committee registry contract of the universe
+    pub registry_addr: Option<String>,
+}
+
+impl FromStr for UniverseArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let keystore_dir = parts
+            .next()
+            .filter(|dir| !dir.is_empty())
+            .ok_or("missing the keystore dir of the universe")?;
+        let mut arg = Self {
+            keystore_dir: keystore_dir.to_string(),
+            ticket: None,
+            bootstrap: false,
+            contract_addr: String::new(),
+            members: None,
+            registry_addr: None,
+        };
+        for part in parts {
+            match part.split_once('=') {
+                Some(("ticket", ticket)) => arg.ticket = Some(ticket.to_string()),
+                Some(("contract", contract)) => arg.contract_addr = contract.to_string(),
+                Some(("members", members)) => arg.members = Some(members.to_string()),
+                Some(("registry", registry)) => arg.registry_addr = Some(registry.to_string()),
+                None if part == "bootstrap" => arg.bootstrap = true,
+                _ => return Err(format!("unknown universe option: {}", part)),
+            }
+        }
+
+        // a typo in the ticket must not bootstrap a new universe by accident
+        match (&arg.ticket, arg.bootstrap) {
+            (Some(_), true) => return Err("a universe is either joined or bootstrapped".into()),
+            (None, false) => return Err("missing ticket=<ticket> (or bootstrap)".into()),
+            _ => {}
+        }
+        if arg.contract_addr.is_empty() {
+            return Err("missing the intent registry contract of the universe".into());
+        }
+        Ok(arg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_universe_args() {
+        let arg: UniverseArg = "tmp/staging,ticket=docabc,contract=5Fabc,registry=5Fxyz"
+            .parse()
+            .unwrap();
+        assert_eq!(arg.keystore_dir, "tmp/staging");
+        assert_eq!(arg.ticket.as_deref(), Some("docabc"));
+        assert!(!arg.bootstrap);
+        assert_eq!(arg.contract_addr, "5Fabc");
+        assert_eq!(arg.members, None);
+        assert_eq!(arg.registry_addr.as_deref(), Some("5Fxyz"));
+
+        let arg: UniverseArg = "tmp/staging,bootstrap,contract=5Fabc".parse().unwrap();
+        assert!(arg.bootstrap);
+        assert_eq!(arg.ticket, None);
+
+        assert!("".parse::<UniverseArg>().is_err());
+        let unknown = "tmp/staging,bootstrap,contract=5Fabc,port=1".parse::<UniverseArg>();
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn universes_are_bootstrapped_only_on_request() {
+        // no ticket and no bootstrap flag, or both
+        assert!("tmp/staging,contract=5Fabc".parse::<UniverseArg>().is_err());
+        assert!(
+            "tmp/staging,ticket=docabc,bootstrap,contract=5Fabc"
+                .parse::<UniverseArg>()
+                .is_err()
+        );
+        // filenames of different universes never share a contract by default
+        assert!("tmp/staging,ticket=docabc".parse::<UniverseArg>().is_err());
+    }
 }
@@ -0,0 +1,525 @@
+//! Resolving committee members to their RPC endpoints
+//!
+//! Every node publishes the endpoint(s) it can be reached at alongside its hint, and operators
+//! may register one in the committee registry. Clients learn them from the synced state of any
+//! node (the `Committee` RPC) or directly from the registry, so committees of any size can run
+//! on any hosts.
+//!
+//! An endpoint is either a gRPC URL (`http(s)://...`) or `iroh:<node id>`, which is dialed
+//! over the fangorn ALPN of the node's iroh router. Nodes also report which members they heard
+//! a heartbeat from recently, so clients ask live members first.
+use crate::crypto::transport::{PUBLIC_KEY_LEN, decode_public_key};
+use crate::gadget::{
+    IntentError,
+    zk_password::{OprfEvaluation, OprfEvaluator},
+};
+use crate::membership::{Members, verify_identity};
+use crate::registry::CommitteeRegistry;
+use crate::rpc::{
+    client::NodeRpc,
+    protocol::IrohRpcClient,
+    server::{IdentityResponse, RpcClient},
+};
+use crate::universe::UniverseId;
+use async_trait::async_trait;
+use iroh::{Endpoint, NodeId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::Mutex,
+};
+use thiserror::Error;
+
+/// The scheme of endpoints dialed by iroh node id
+pub const IROH_SCHEME: &str = "iroh:";
+
+#[derive(Error, Debug)]
+pub enum CommitteeError {
+    #[error("No endpoint is known for committee member {0}")]
+    UnknownMember(usize),
+    #[error("Invalid RPC endpoint: {0}")]
+    InvalidEndpoint(String),
+    #[error("Failed to reach committee member {index}: {reason}")]
+    Unreachable { index: usize, reason: String },
+    #[error("RPC communication error: {0}")]
+    RpcError(String),
+    #[error("Failed to read the committee registry: {0}")]
+    RegistryError(String),
+    #[error("The identity of committee member {index} cannot be trusted: {reason}")]
+    UnverifiedIdentity { index: usize, reason: String },
+}
+
+/// The keys a member signed with its node id
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemberIdentity {
+    /// the key witnesses are sealed to
+    pub transport_key: [u8; PUBLIC_KEY_LEN],
+    /// the public key of the member's password OPRF
+    pub oprf_key: Vec<u8>,
+}
+
+/// The endpoint of a node reachable over iroh
+pub fn iroh_endpoint(node_id: &NodeId) -> String {
+    format!("{}{}", IROH_SCHEME, node_id)
+}
+
+/// Check that an endpoint is an absolute http(s) URL or an iroh node id
+pub fn check_endpoint(endpoint: &str) -> Result<(), CommitteeError> {
+    if let Some(node_id) = endpoint.strip_prefix(IROH_SCHEME) {
+        NodeId::from_str(node_id).map_err(|_| CommitteeError::InvalidEndpoint(endpoint.into()))?;
+        return Ok(());
+    }
+    let host = endpoint
+        .strip_prefix("http://")
+        .or_else(|| endpoint.strip_prefix("https://"))
+        .ok_or_else(|| CommitteeError::InvalidEndpoint(endpoint.into()))?;
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(CommitteeError::InvalidEndpoint(endpoint.into()));
+    }
+    Ok(())
+}
+
+/// Connect to the RPC of a node at an http(s) endpoint, or over iroh by its node id
+pub async fn connect_endpoint(
+    endpoint: &str,
+    iroh: Option<&Endpoint>,
+) -> Result<Box<dyn NodeRpc>, String> {
+    match (endpoint.strip_prefix(IROH_SCHEME), iroh) {
+        (Some(_), None) => Err(format!("{} needs an iroh endpoint", endpoint)),
+        (Some(node_id), Some(iroh)) => {
+            let node_id = NodeId::from_str(node_id).map_err(|e| e.to_string())?;
+            IrohRpcClient::connect(iroh, node_id)
+                .await
+                .map(|client| Box::new(client) as Box<dyn NodeRpc>)
+                .map_err(|e| e.to_string())
+        }
+        (None, _) => RpcClient::connect(endpoint.to_string())
+            .await
+            .map(|client| Box::new(client) as Box<dyn NodeRpc>)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// What a node reported about the liveness of a member
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemberStatus {
+    pub live: bool,
+    /// the partial decryptions the member served between its last two heartbeats
+    pub load: u32,
+}
+
+/// The RPC endpoints of the committee members, by committee index
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Committee {
+    endpoints: BTreeMap<u32, Vec<String>>,
+    /// the liveness of the members, if the committee was read from a node
+    status: BTreeMap<u32, MemberStatus>,
+    /// the node ids of the members, which sign their transport keys
+    identities: BTreeMap<u32, NodeId>,
+}
+
+impl Committee {
+    /// Set the endpoints of a member, replacing any previously known ones
+    pub fn insert(&mut self, index: u32, endpoints: Vec<String>) -> Result<(), CommitteeError> {
+        for endpoint in &endpoints {
+            check_endpoint(endpoint)?;
+        }
+        if !endpoints.is_empty() {
+            self.endpoints.insert(index, endpoints);
+        }
+        Ok(())
+    }
+
+    /// The endpoints of a member, in order of preference
+    pub fn endpoints(&self, index: usize) -> Result<&[String], CommitteeError> {
+        u32::try_from(index)
+            .ok()
+            .and_then(|index| self.endpoints.get(&index))
+            .map(|endpoints| endpoints.as_slice())
+            .ok_or(CommitteeError::UnknownMember(index))
+    }
+
+    /// Set the node id of a member
+    pub fn set_node_id(&mut self, index: u32, node_id: NodeId) {
+        self.identities.insert(index, node_id);
+    }
+
+    /// The node id of a member, if known (or dialed over iroh)
+    pub fn node_id(&self, index: usize) -> Option<NodeId> {
+        let index = u32::try_from(index).ok()?;
+        self.identities.get(&index).copied().or_else(|| {
+            self.endpoints
+                .get(&index)?
+                .iter()
+                .filter_map(|endpoint| endpoint.strip_prefix(IROH_SCHEME))
+                .find_map(|node_id| NodeId::from_str(node_id).ok())
+        })
+    }
+
+    /// Record what a node reported about the liveness of a member
+    pub fn set_status(&mut self, index: u32, status: MemberStatus) {
+        self.status.insert(index, status);
+    }
+
+    pub fn status(&self, index: usize) -> Option<&MemberStatus> {
+        u32::try_from(index)
+            .ok()
+            .and_then(|index| self.status.get(&index))
+    }
+
+    /// Split members, given as `(key, committee index)`, into the live ones (least loaded
+    /// first) and the others (not live, or of unknown liveness)
+    pub fn live_first<K>(&self, members: impl IntoIterator<Item = (K, usize)>) -> (Vec<K>, Vec<K>) {
+        let mut live = Vec::new();
+        let mut others = Vec::new();
+        for (key, index) in members {
+            match self.status(index) {
+                Some(status) if status.live => live.push((status.load, key)),
+                _ => others.push(key),
+            }
+        }
+        live.sort_by_key(|(load, _)| *load);
+        (live.into_iter().map(|(_, key)| key).collect(), others)
+    }
+
+    /// All members with known endpoints, ordered by committee index
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &[String])> {
+        self.endpoints
+            .iter()
+            .map(|(index, endpoints)| (*index, endpoints.as_slice()))
+    }
+
+    /// The node ids of the members reachable over iroh
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.endpoints
+            .values()
+            .flatten()
+            .filter_map(|endpoint| endpoint.strip_prefix(IROH_SCHEME))
+            .filter_map(|node_id| NodeId::from_str(node_id).ok())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.is_empty()
+    }
+
+    /// The endpoints registered for the admitted members
+    pub fn from_members(members: &Members) -> Self {
+        let mut committee = Self::default();
+        for (index, member) in members.iter() {
+            committee.set_node_id(index, member.node_id);
+            if let Some(endpoint) = &member.rpc_endpoint {
+                // a bad registration must not stop the node from starting
+                if let Err(e) = committee.insert(index, vec![endpoint.clone()]) {
+                    println!(
+                        "Ignored the endpoint registered for member {}: {}",
+                        index, e
+                    );
+                }
+            }
+        }
+        committee
+    }
+
+    /// Read the committee from the on-chain committee registry
+    pub async fn from_registry(registry: &dyn CommitteeRegistry) -> Result<Self, CommitteeError> {
+        let registered = registry
+            .members()
+            .await
+            .map_err(|e| CommitteeError::RegistryError(e.to_string()))?;
+        let members = Members::from_registry(&registered)
+            .map_err(|e| CommitteeError::RegistryError(e.to_string()))?;
+        Ok(Self::from_members(&members))
+    }
+
+    /// Read the committee of an epoch of a universe (the current one if `None`)
+    /// from the synced state of a node
+    pub async fn from_node(
+        client: &mut dyn NodeRpc,
+        universe_id: UniverseId,
+        epoch: Option<u64>,
+    ) -> Result<Self, CommitteeError> {
+        let response = client
+            .committee(universe_id, epoch)
+            .await
+            .map_err(|e| CommitteeError::RpcError(e.to_string()))?;
+
+        let mut committee = Self::default();
+        for member in response.members {
+            committee.set_status(
+                member.index,
+                MemberStatus {
+                    live: member.live,
+                    load: member.load,
+                },
+            );
+            if let Ok(node_id) = NodeId::from_str(&member.node_id) {
+                committee.set_node_id(member.index, node_id);
+            }
+            committee.insert(member.index, member.rpc_endpoints)?;
+        }
+        Ok(committee)
+    }
+
+    /// Check that the keys a member reported were signed by its node id, so they were not
+    /// swapped on the way
+    pub fn check_identity(
+        &self,
+        index: usize,
+        identity: &IdentityResponse,
+    ) -> Result<MemberIdentity, CommitteeError> {
+        let unverified = |reason: String| CommitteeError::UnverifiedIdentity { index, reason };
+        let node_id = self
+            .node_id(index)
+            .ok_or_else(|| unverified("its node id is unknown".into()))?;
+        let transport_key = decode_public_key(&identity.transport_pubkey_hex)
+            .map_err(|e| unverified(e.to_string()))?;
+        let oprf_key =
+            hex::decode(&identity.oprf_pubkey_hex).map_err(|e| unverified(e.to_string()))?;
+        let signature =
+            hex::decode(&identity.signature_hex).map_err(|e| unverified(e.to_string()))?;
+        verify_identity(&node_id, transport_key.as_bytes(), &oprf_key, &signature)
+            .map_err(|_| unverified(format!("its keys are not signed by {}", node_id)))?;
+        Ok(MemberIdentity {
+            transport_key: *transport_key.as_bytes(),
+            oprf_key,
+        })
+    }
+
+    /// Connect to a member, trying each of its endpoints in turn
+    /// iroh endpoints are skipped unless the client has an iroh endpoint to dial them with
+    pub async fn connect(
+        &self,
+        index: usize,
+        iroh: Option<&Endpoint>,
+    ) -> Result<Box<dyn NodeRpc>, CommitteeError> {
+        let mut reason = String::from("no usable endpoint");
+        for endpoint in self.endpoints(index)? {
+            if endpoint.starts_with(IROH_SCHEME) && iroh.is_none() {
+                continue;
+            }
+            match connect_endpoint(endpoint, iroh).await {
+                Ok(client) => return Ok(client),
+                Err(e) => reason = format!("{}: {}", endpoint, e),
+            }
+        }
+        Err(CommitteeError::Unreachable { index, reason })
+    }
+}
+
+/// Evaluates the password OPRF with the members of a committee, whose keys are checked
+/// against their node ids first
+pub struct CommitteeOprf {
+    committee: Committee,
+    universe_id: UniverseId,
+    iroh: Option<Endpoint>,
+    /// the committee index and OPRF key of each checked transport key
+    known: Mutex<HashMap<[u8; PUBLIC_KEY_LEN], (usize, Vec<u8>)>>,
+}
+
+impl CommitteeOprf {
+    pub fn new(committee: Committee, universe_id: UniverseId, iroh: Option<Endpoint>) -> Self {
+        Self {
+            committee,
+            universe_id,
+            iroh,
+            known: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn identity(&self, index: usize) -> Result<MemberIdentity, CommitteeError> {
+        let mut client = self.committee.connect(index, self.iroh.as_ref()).await?;
+        let identity = client
+            .identity(self.universe_id)
+            .await
+            .map_err(|e| CommitteeError::RpcError(e.to_string()))?;
+        self.committee.check_identity(index, &identity)
+    }
+
+    fn lookup(&self, node: &[u8; PUBLIC_KEY_LEN]) -> Option<(usize, Vec<u8>)> {
+        self.known.lock().unwrap().get(node).cloned()
+    }
+}
+
+#[async_trait]
+impl OprfEvaluator for CommitteeOprf {
+    /// The members whose keys could be checked (unreachable ones are skipped)
+    async fn nodes(&self) -> Result<Vec<[u8; PUBLIC_KEY_LEN]>, IntentError> {
+        let mut nodes = Vec::new();
+        for (index, _) in self.committee.iter() {
+            match self.identity(index as usize).await {
+                Ok(identity) => {
+                    self.known
+                        .lock()
+                        .unwrap()
+                        .insert(identity.transport_key, (index as usize, identity.oprf_key));
+                    nodes.push(identity.transport_key);
+                }
+                Err(e) => println!("Skipped member {} for the password OPRF: {}", index, e),
+            }
+        }
+        Ok(nodes)
+    }
+
+    async fn evaluate(
+        &self,
+        node: &[u8; PUBLIC_KEY_LEN],
+        blinded: &[u8],
+    ) -> Result<OprfEvaluation, IntentError> {
+        if self.lookup(node).is_none() {
+            self.nodes().await?;
+        }
+        let (index, public_key) = self.lookup(node).ok_or_else(|| {
+            IntentError::VerificationError(format!(
+                "{} is not the transport key of a committee member",
+                hex::encode(node)
+            ))
+        })?;
+
+        let response = self
+            .committee
+            .connect(index, self.iroh.as_ref())
+            .await
+            .map_err(|e| IntentError::VerificationError(e.to_string()))?
+            .oprf(self.universe_id, blinded)
+            .await
+            .map_err(|e| IntentError::VerificationError(e.to_string()))?;
+
+        Ok(OprfEvaluation {
+            evaluated: hex::decode(&response.evaluated_hex)
+                .map_err(|e| IntentError::VerificationError(e.to_string()))?,
+            proof: hex::decode(&response.proof_hex)
+                .map_err(|e| IntentError::VerificationError(e.to_string()))?,
+            public_key,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::transport::TransportKeypair;
+    use crate::gadget::zk_password::oprf_public_key;
+    use crate::membership::AnnouncementSigner;
+    use crate::registry::RegisteredMember;
+    use ark_std::rand::rngs::OsRng;
+    use iroh::SecretKey as IrohSecretKey;
+
+    #[test]
+    fn resolves_published_and_registered_endpoints() {
+        let registered = |index: u32, endpoint: &str| RegisteredMember {
+            operator: [index as u8; 32],
+            index,
+            node_id: *IrohSecretKey::generate(OsRng).public().as_bytes(),
+            ste_public_key: vec![],
+            rpc_endpoint: endpoint.as_bytes().to_vec(),
+            stake: 0,
+        };
+        let members = Members::from_registry(&[
+            registered(0, "http://10.0.0.1:30332"),
+            registered(1, ""),
+            registered(7, "not a url"),
+        ])
+        .unwrap();
+
+        let mut committee = Committee::from_members(&members);
+        assert_eq!(committee.len(), 1);
+        assert_eq!(committee.endpoints(0).unwrap(), ["http://10.0.0.1:30332"]);
+        assert!(matches!(
+            committee.endpoints(1),
+            Err(CommitteeError::UnknownMember(1))
+        ));
+
+        // published endpoints replace the registered ones
+        committee
+            .insert(
+                0,
+                vec![
+                    "https://node0.example.org".into(),
+                    "http://10.0.0.1:30332".into(),
+                ],
+            )
+            .unwrap();
+        committee
+            .insert(12, vec!["http://10.0.0.12:30332".into()])
+            .unwrap();
+        assert_eq!(committee.endpoints(0).unwrap().len(), 2);
+        assert_eq!(
+            committee.iter().map(|(index, _)| index).collect::<Vec<_>>(),
+            vec![0, 12]
+        );
+
+        // members reachable over iroh are dialed by node id
+        let node_id = IrohSecretKey::generate(OsRng).public();
+        committee.insert(3, vec![iroh_endpoint(&node_id)]).unwrap();
+        assert!(committee.endpoints(3).is_ok());
+
+        // the node ids that sign transport keys are known for the admitted members
+        assert_eq!(committee.node_id(1), Some(members.get(1).unwrap().node_id));
+        assert_eq!(committee.node_id(3), Some(node_id));
+        assert_eq!(committee.node_id(12), None);
+
+        assert!(matches!(
+            committee.insert(1, vec!["ftp://10.0.0.1".into()]),
+            Err(CommitteeError::InvalidEndpoint(_))
+        ));
+        assert!(matches!(
+            committee.insert(1, vec!["iroh:not-a-node-id".into()]),
+            Err(CommitteeError::InvalidEndpoint(_))
+        ));
+        assert!(committee.endpoints(1).is_err());
+    }
+
+    #[test]
+    fn orders_live_members_first() {
+        let mut committee = Committee::default();
+        let status = |live, load| MemberStatus { live, load };
+        committee.set_status(0, status(false, 0));
+        committee.set_status(1, status(true, 7));
+        committee.set_status(2, status(true, 2));
+
+        // positions 10.. of members 0..4, member 3 was never heard from
+        let (live, others) = committee.live_first((0..4).map(|index| (index + 10, index)));
+        assert_eq!(live, vec![12, 11]);
+        assert_eq!(others, vec![10, 13]);
+    }
+
+    #[test]
+    fn identities_are_checked_against_node_ids() {
+        let signer = AnnouncementSigner::new(0, IrohSecretKey::generate(OsRng));
+        let mut committee = Committee::default();
+        committee.set_node_id(0, signer.node_id());
+
+        let transport = TransportKeypair::generate();
+        let oprf = oprf_public_key(&transport);
+        let identity = IdentityResponse {
+            transport_pubkey_hex: transport.public_hex(),
+            node_id: signer.node_id().to_string(),
+            signature_hex: hex::encode(signer.sign_identity(transport.public().as_bytes(), &oprf)),
+            oprf_pubkey_hex: hex::encode(&oprf),
+        };
+        let checked = committee.check_identity(0, &identity).unwrap();
+        assert_eq!(checked.transport_key, *transport.public().as_bytes());
+        assert_eq!(checked.oprf_key, oprf);
+
+        // swapped keys, or a member whose node id is unknown
+        let swapped = IdentityResponse {
+            transport_pubkey_hex: TransportKeypair::generate().public_hex(),
+            ..identity.clone()
+        };
+        assert!(matches!(
+            committee.check_identity(0, &swapped),
+            Err(CommitteeError::UnverifiedIdentity { index: 0, .. })
+        ));
+        let swapped = IdentityResponse {
+            oprf_pubkey_hex: hex::encode(oprf_public_key(&TransportKeypair::generate())),
+            ..identity.clone()
+        };
+        assert!(committee.check_identity(0, &swapped).is_err());
+        assert!(committee.check_identity(1, &identity).is_err());
+    }
+}
@@ -0,0 +1,360 @@
+//! A multi-party powers-of-tau ceremony
+//!
+//! Committee members contribute in turn, by index. Each contributor samples a secret `s`
+//! and raises the current powers to `tau' = s * tau`, publishing `s*h` and a Schnorr proof
+//! of knowledge of `s` bound to the powers it built on. A contribution is accepted when the
+//! new powers are consistent, `e(tau' g, h) == e(tau g, s h)`, and the proof verifies, so
+//! no contributor can erase the randomness of the previous ones. The resulting CRS is
+//! secure as long as a single contributor was honest and forgot its secret.
+use crate::crypto::crs::{CrsError, PowersOfTau};
+use crate::types::Config;
+use ark_ec::{AffineRepr, CurveGroup, pairing::Pairing};
+use ark_ff::{Field, PrimeField};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_std::{UniformRand, rand::rngs::OsRng};
+use codec::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// The doc key of the ceremony parameters (contributions use `ceremony-{index}`)
+pub const CEREMONY_KEY: &str = "ceremony";
+
+/// Domain separator for the proof of knowledge
+const POK_DST: &[u8] = b"fangorn-ceremony-pok";
+
+#[derive(Error, Debug)]
+pub enum CeremonyError {
+    #[error("Unexpected contribution from {found}, expected {expected}")]
+    UnexpectedContributor { expected: u32, found: u32 },
+    #[error("The ceremony is already complete")]
+    Complete,
+    #[error("The ceremony is not complete")]
+    Incomplete,
+    #[error("Invalid contribution from {0}: {1}")]
+    InvalidContribution(u32, String),
+    #[error("Serialization failed: {0}")]
+    SerializationError(String),
+    #[error("{0}")]
+    CrsError(#[from] CrsError),
+}
+
+/// Announced by the node starting the ceremony
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct CeremonyParams {
+    /// the committee size of the resulting config
+    pub size: u32,
+    /// the number of contributors (indices 0..participants)
+    pub participants: u32,
+}
+
+impl CeremonyParams {
+    /// the number of powers in each group
+    pub fn num_powers(&self) -> usize {
+        self.size as usize + 1
+    }
+}
+
+/// A contribution to the ceremony
+#[derive(Clone, Debug, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Contribution<C: Pairing> {
+    /// the index of the contributor
+    pub index: u32,
+    /// the updated powers
+    pub powers: PowersOfTau<C>,
+    /// s*h
+    pub public: C::G2Affine,
+    /// the Schnorr proof of knowledge of s: (r*h, r + c*s)
+    pub commitment: C::G2Affine,
+    pub response: C::ScalarField,
+}
+
+impl<C: Pairing> Contribution<C> {
+    /// Contribute fresh randomness on top of the previous powers
+    pub fn new(index: u32, previous: &PowersOfTau<C>) -> Result<Self, CeremonyError> {
+        let secret = C::ScalarField::rand(&mut OsRng);
+
+        let mut power = C::ScalarField::ONE;
+        let mut powers_of_g = Vec::with_capacity(previous.powers_of_g.len());
+        let mut powers_of_h = Vec::with_capacity(previous.powers_of_h.len());
+        for i in 0..previous.powers_of_g.len().max(previous.powers_of_h.len()) {
+            if let Some(g) = previous.powers_of_g.get(i) {
+                powers_of_g.push((*g * power).into_affine());
+            }
+            if let Some(h) = previous.powers_of_h.get(i) {
+                powers_of_h.push((*h * power).into_affine());
+            }
+            power *= secret;
+        }
+
+        let public = (C::G2Affine::generator() * secret).into_affine();
+        let nonce = C::ScalarField::rand(&mut OsRng);
+        let commitment = (C::G2Affine::generator() * nonce).into_affine();
+        let challenge = challenge::<C>(index, previous, &public, &commitment)?;
+
+        Ok(Self {
+            index,
+            powers: PowersOfTau {
+                powers_of_g,
+                powers_of_h,
+            },
+            public,
+            commitment,
+            response: nonce + challenge * secret,
+        })
+    }
+
+    /// Check that this contribution correctly updates the previous powers
+    pub fn verify(&self, previous: &PowersOfTau<C>) -> Result<(), CeremonyError> {
+        let invalid = |reason: &str| CeremonyError::InvalidContribution(self.index, reason.into());
+
+        if self.powers.powers_of_g.len() != previous.powers_of_g.len()
+            || self.powers.powers_of_h.len() != previous.powers_of_h.len()
+        {
+            return Err(invalid("the number of powers changed"));
+        }
+        if self.public.is_zero() {
+            return Err(invalid("zero contribution"));
+        }
+        self.powers.verify().map_err(|e| invalid(&e.to_string()))?;
+
+        // tau' = s * tau
+        let h = C::G2Affine::generator();
+        if C::pairing(self.powers.powers_of_g[1], h)
+            != C::pairing(previous.powers_of_g[1], self.public)
+        {
+            return Err(invalid(
+                "the powers were not updated with the published secret",
+            ));
+        }
+
+        // knowledge of s: response*h == commitment + c*public
+        let challenge = challenge::<C>(self.index, previous, &self.public, &self.commitment)?;
+        if h * self.response != self.commitment.into_group() + self.public * challenge {
+            return Err(invalid("invalid proof of knowledge"));
+        }
+
+        Ok(())
+    }
+}
+
+/// The ceremony state: the verified transcript so far, and contributions received early
+#[derive(Clone)]
+pub struct Ceremony<C: Pairing> {
+    pub params: CeremonyParams,
+    transcript: Vec<Contribution<C>>,
+    pending: BTreeMap<u32, Contribution<C>>,
+}
+
+impl<C: Pairing> Ceremony<C> {
+    pub fn new(params: CeremonyParams) -> Self {
+        Self {
+            params,
+            transcript: Vec::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// The powers the next contributor builds on
+    pub fn current(&self) -> PowersOfTau<C> {
+        self.transcript
+            .last()
+            .map(|c| c.powers.clone())
+            .unwrap_or_else(|| initial_powers(self.params.num_powers()))
+    }
+
+    /// The index of the next contributor, if any
+    pub fn next_contributor(&self) -> Option<u32> {
+        let next = self.transcript.len() as u32;
+        (next < self.params.participants).then_some(next)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.next_contributor().is_none()
+    }
+
+    pub fn transcript(&self) -> &[Contribution<C>] {
+        &self.transcript
+    }
+
+    /// Add a contribution, applying any buffered contributions that follow it
+    /// Contributions may arrive out of order, but are only accepted in order
+    pub fn add(&mut self, contribution: Contribution<C>) -> Result<(), CeremonyError> {
+        let Some(next) = self.next_contributor() else {
+            return Err(CeremonyError::Complete);
+        };
+        if contribution.index < next || contribution.index >= self.params.participants {
+            return Err(CeremonyError::UnexpectedContributor {
+                expected: next,
+                found: contribution.index,
+            });
+        }
+        self.pending.insert(contribution.index, contribution);
+
+        while let Some(next) = self.next_contributor() {
+            let Some(contribution) = self.pending.remove(&next) else {
+                break;
+            };
+            contribution.verify(&self.current())?;
+            self.transcript.push(contribution);
+        }
+        Ok(())
+    }
+
+    /// Verify the whole transcript and build the final config
+    pub fn finalize(&self) -> Result<Config<C>, CeremonyError> {
+        if !self.is_complete() {
+            return Err(CeremonyError::Incomplete);
+        }
+        let powers = verify_transcript(&self.params, &self.transcript)?;
+        Ok(powers.into_config(self.params.size as usize)?)
+    }
+}
+
+/// Verify a full transcript from the trivial powers, returning the final powers
+pub fn verify_transcript<C: Pairing>(
+    params: &CeremonyParams,
+    transcript: &[Contribution<C>],
+) -> Result<PowersOfTau<C>, CeremonyError> {
+    if transcript.len() != params.participants as usize {
+        return Err(CeremonyError::Incomplete);
+    }
+    let mut powers = initial_powers(params.num_powers());
+    for (index, contribution) in transcript.iter().enumerate() {
+        if contribution.index != index as u32 {
+            return Err(CeremonyError::UnexpectedContributor {
+                expected: index as u32,
+                found: contribution.index,
+            });
+        }
+        contribution.verify(&powers)?;
+        powers = contribution.powers.clone();
+    }
+    Ok(powers)
+}
+
+/// The powers of tau = 1, where every ceremony starts
+fn initial_powers<C: Pairing>(num_powers: usize) -> PowersOfTau<C> {
+    PowersOfTau {
+        powers_of_g: vec![C::G1Affine::generator(); num_powers],
+        powers_of_h: vec![C::G2Affine::generator(); num_powers],
+    }
+}
+
+/// c = H(index, previous powers, s*h, r*h)
+fn challenge<C: Pairing>(
+    index: u32,
+    previous: &PowersOfTau<C>,
+    public: &C::G2Affine,
+    commitment: &C::G2Affine,
+) -> Result<C::ScalarField, CeremonyError> {
+    let mut bytes = index.to_le_bytes().to_vec();
+    previous
+        .serialize_compressed(&mut bytes)
+        .map_err(|e| CeremonyError::SerializationError(e.to_string()))?;
+    public
+        .serialize_compressed(&mut bytes)
+        .map_err(|e| CeremonyError::SerializationError(e.to_string()))?;
+    commitment
+        .serialize_compressed(&mut bytes)
+        .map_err(|e| CeremonyError::SerializationError(e.to_string()))?;
+
+    let digest = Sha256::new()
+        .chain_update(POK_DST)
+        .chain_update(&bytes)
+        .finalize();
+    Ok(C::ScalarField::from_be_bytes_mod_order(&digest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::membership::{AnnouncementSigner, Members, member_entry};
+    use crate::types::{Announcement, E, GENESIS_EPOCH, NodeKeys, State, Tag};
+    use iroh::SecretKey as IrohSecretKey;
+
+    fn params() -> CeremonyParams {
+        CeremonyParams {
+            size: 2,
+            participants: 3,
+        }
+    }
+
+    #[test]
+    fn ceremony_in_any_arrival_order_works() {
+        let mut ceremony = Ceremony::<E>::new(params());
+        let first = Contribution::new(0, &ceremony.current()).unwrap();
+        let second = Contribution::new(1, &first.powers).unwrap();
+        let third = Contribution::new(2, &second.powers).unwrap();
+
+        // buffered until the previous contributions arrive
+        ceremony.add(third).unwrap();
+        ceremony.add(first).unwrap();
+        assert_eq!(ceremony.next_contributor(), Some(1));
+        ceremony.add(second).unwrap();
+
+        assert!(ceremony.is_complete());
+        assert!(verify_transcript(&params(), ceremony.transcript()).is_ok());
+    }
+
+    #[test]
+    fn invalid_contributions_are_rejected() {
+        let ceremony = Ceremony::<E>::new(params());
+        let first = Contribution::new(0, &ceremony.current()).unwrap();
+
+        // built on the wrong powers (erasing the previous contribution)
+        let mut ceremony = Ceremony::<E>::new(params());
+        ceremony.add(first.clone()).unwrap();
+        let rogue = Contribution::new(1, &Ceremony::<E>::new(params()).current()).unwrap();
+        assert!(ceremony.add(rogue).is_err());
+
+        // a replayed proof for another index
+        let mut replayed = Contribution::new(1, &first.powers).unwrap();
+        replayed.index = 2;
+        replayed.powers = first.powers.clone();
+        assert!(replayed.verify(&first.powers).is_err());
+    }
+
+    #[test]
+    fn configs_are_only_accepted_once_and_outside_ceremonies() {
+        let keys = NodeKeys::<E>::generate(0);
+        let alice = AnnouncementSigner::new(0, keys.iroh_secret_key.clone());
+        let bob = AnnouncementSigner::new(1, IrohSecretKey::generate(OsRng));
+        let entries = member_entry(0, &alice.node_id()) + &member_entry(1, &bob.node_id());
+        // alice bootstrapped the universe
+        let state = || {
+            let members = Members::parse(&entries).unwrap();
+            let (sk, transport_key) = (keys.secret_key.clone(), keys.transport_key.clone());
+            State::<E>::empty(&alice, sk, transport_key, members).with_bootstrap(alice.node_id())
+        };
+        let config = |signer: &AnnouncementSigner, config: &Config<E>| {
+            let mut data = Vec::new();
+            config.serialize_compressed(&mut data).unwrap();
+            let announcement = Announcement {
+                tag: Tag::Config,
+                data,
+            };
+            signer.sign(announcement, GENESIS_EPOCH)
+        };
+        let (first, second) = (Config::<E>::rand(2), Config::<E>::rand(2));
+
+        // only the bootstrap node publishes the config, and only once
+        let mut bootstrapped = state();
+        bootstrapped.receive(config(&bob, &first));
+        assert!(bootstrapped.config.is_none());
+        bootstrapped.receive(config(&alice, &first));
+        bootstrapped.receive(config(&alice, &second));
+        assert_eq!(bootstrapped.universe_id(), Some(first.universe_id()));
+
+        // a ceremony config is only accepted once its transcript verifies
+        let mut ceremony = state();
+        let start = Announcement {
+            tag: Tag::CeremonyStart,
+            data: params().encode(),
+        };
+        ceremony.receive(alice.sign(start, GENESIS_EPOCH));
+        ceremony.receive(config(&alice, &first));
+        assert!(ceremony.config.is_none());
+        assert!(ceremony.ceremony.is_some());
+    }
+}
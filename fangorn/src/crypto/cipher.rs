@@ -1,22 +1,30 @@
-use crate::rpc::server::*;
+use crate::rpc::client::NodeRpc;
 use crate::storage::{
+    blob_store::IrohDocStore,
     contract_store::ContractIntentStore,
-    local_store::{LocalDocStore, LocalPlaintextStore},
-    AppStore,
+    local_store::{LocalDocStore, LocalPlaintextStore, LEGACY_DOCS_DIR},
+    AppStore, IntentStore,
 };
 use crate::types::*;
 use crate::{
     backend::SubstrateBackend,
-    crypto::{decrypt::DecryptionClient, encrypt::EncryptionClient},
-    gadget::{GadgetRegistry, PasswordGadget, Psp22Gadget, Sr25519Gadget},
-    storage::PlaintextStore,
+    committee::{connect_endpoint, Committee, CommitteeOprf},
+    crypto::{decrypt::DecryptionClient, encrypt::EncryptionClient, migration::MigrationAuth},
+    gadget::{GadgetRegistry, PasswordGadget, Psp22Gadget, Sr25519Gadget, ZkPasswordGadget},
+    node::{client_blobs, NetworkConfig},
+    universe::UniverseId,
     utils::load_mnemonic,
 };
 use ark_serialize::CanonicalDeserialize;
 use silent_threshold_encryption::aggregate::SystemPublicKeys;
+use iroh::Endpoint;
+use sp_core::{sr25519, Pair};
 use std::sync::Arc;
 
-/// encrypt the message located at message_path
+/// encrypt the message located at message_path, so that `threshold` members can decrypt it
+/// the document is served from our blobs until the entry node has downloaded it,
+/// the other committee members download it from the entry node
+/// if the entry node cannot download it, the registration is withdrawn, as no node holds it
 pub async fn handle_encrypt(
     message_path: &String,
     filename: &String,
@@ -24,22 +32,42 @@ pub async fn handle_encrypt(
     keystore_path: &String,
     intent_str: &String,
     contract_addr: &String,
+    threshold: u8,
+    network: &NetworkConfig,
 ) {
     let seed = load_mnemonic(keystore_path);
-    let (sys_keys, gadget_registry, app_store) =
-        testnet_setup(contract_addr, Some(&seed)).await;
+    let (router, blobs) = client_blobs(network).await.unwrap();
+    let (universe_id, epoch, sys_keys, gadget_registry, app_store, _) = testnet_setup(
+        config_path,
+        contract_addr,
+        Some(&seed),
+        IrohDocStore::new(blobs, router.endpoint().clone()),
+        network,
+        router.endpoint(),
+    )
+    .await;
 
-    let message = app_store
-        .pt_store
-        .read_plaintext(message_path)
-        .await
-        .expect("Something went wrong while reading PT");
-
-    let client = EncryptionClient::new(config_path, sys_keys, app_store, gadget_registry);
+    // documents are encrypted under the current committee epoch
+    let client = EncryptionClient::new(config_path, epoch, sys_keys, app_store, gadget_registry)
+        .with_threshold(threshold);
     client
-        .encrypt(&message, filename.as_bytes(), &intent_str)
+        .encrypt_file(message_path, filename.as_bytes(), &intent_str)
         .await
         .unwrap();
+
+    if let Err(e) = publish(universe_id, filename, network, router.endpoint()).await {
+        println!("Failed to publish {}: {}", filename, e);
+        match client
+            .app_store()
+            .intent_store
+            .remove_intent(filename.as_bytes())
+            .await
+        {
+            Ok(()) => println!("> Withdrew the registration of {}", filename),
+            Err(e) => println!("Failed to withdraw the registration of {}: {}", filename, e),
+        }
+    }
+    router.shutdown().await.unwrap();
 }
 
 pub async fn handle_decrypt(
@@ -48,29 +76,153 @@ pub async fn handle_decrypt(
     witness_string: &String,
     pt_filename: &String,
     contract_addr: &String,
+    network: &NetworkConfig,
 ) {
-    let (sys_keys, _registry, app_store) = testnet_setup(contract_addr, None).await;
+    // members are dialed by node id when possible, so they need not expose their RPC port
+    let (router, blobs) = client_blobs(network).await.unwrap();
+    let doc_store = IrohDocStore::new(blobs, router.endpoint().clone())
+        .with_legacy(LocalDocStore::new(LEGACY_DOCS_DIR));
+    let (_, epoch, sys_keys, registry, app_store, committee) = testnet_setup(
+        config_path,
+        contract_addr,
+        None,
+        doc_store.clone(),
+        network,
+        router.endpoint(),
+    )
+    .await;
 
     // Parse witnesses
     let witnesses: Vec<&str> = witness_string.trim().split(',').map(|s| s.trim()).collect();
 
+    // the document is downloaded from the committee members
+    doc_store.add_providers(committee.node_ids());
+
     // Decrypt
-    let client = DecryptionClient::new(config_path, sys_keys, app_store).unwrap();
-    client
+    let client =
+        DecryptionClient::new(config_path, epoch, sys_keys, app_store, registry, committee)
+            .unwrap()
+            .with_iroh_endpoint(router.endpoint().clone());
+    let report = client
         .decrypt(filename, &witnesses, pt_filename)
         .await
         .unwrap();
+    println!("> Decrypted with {}", report);
+    router.shutdown().await.unwrap();
 }
 
-/// an app store configured for all nodes running on the same machine,
+/// re-encrypt a document of an older epoch to the current committee epoch
+/// the old committee releases the key to witnesses satisfying the intents if given,
+/// or else to the owner of the document (the account of the keystore)
+pub async fn handle_migrate(
+    config_path: &String,
+    filename: &String,
+    keystore_path: &String,
+    witness_string: Option<&String>,
+    contract_addr: &String,
+    threshold: u8,
+    network: &NetworkConfig,
+) {
+    // the registry entry can only be updated by its owner
+    let seed = load_mnemonic(keystore_path);
+    let (router, blobs) = client_blobs(network).await.unwrap();
+    let doc_store = IrohDocStore::new(blobs, router.endpoint().clone())
+        .with_legacy(LocalDocStore::new(LEGACY_DOCS_DIR));
+    let (universe_id, epoch, sys_keys, registry, app_store, committee) = testnet_setup(
+        config_path,
+        contract_addr,
+        Some(&seed),
+        doc_store.clone(),
+        network,
+        router.endpoint(),
+    )
+    .await;
+    doc_store.add_providers(committee.node_ids());
+
+    let client =
+        DecryptionClient::new(config_path, epoch, sys_keys, app_store, registry, committee)
+            .unwrap()
+            .with_iroh_endpoint(router.endpoint().clone())
+            .with_threshold(threshold);
+
+    let witnesses: Vec<&str> = witness_string
+        .map(|w| w.trim().split(',').map(|s| s.trim()).collect())
+        .unwrap_or_default();
+    let (owner, _) = sr25519::Pair::from_phrase(&seed, None).expect("Invalid mnemonic");
+    let auth = match witness_string {
+        Some(_) => MigrationAuth::Witnesses(&witnesses),
+        None => MigrationAuth::Owner(&owner),
+    };
+
+    let intent_store = &client.app_store().intent_store;
+    let (old_cid, _) = intent_store
+        .get_intent(filename.as_bytes())
+        .await
+        .unwrap()
+        .expect("the document is not registered");
+    let (cid, report) = client.migrate(filename, auth).await.unwrap();
+    println!("> Migrated to epoch {} as {} with {}", epoch, cid, report);
+
+    // the re-encrypted document is served from our blobs,
+    // the registry points back at the old one if no node could download it
+    if let Err(e) = publish(universe_id, filename, network, router.endpoint()).await {
+        println!("Failed to publish {}: {}", filename, e);
+        match intent_store
+            .replace_cid(filename.as_bytes(), &cid, &old_cid)
+            .await
+        {
+            Ok(()) => println!("> Restored {} to {}", filename, old_cid),
+            Err(e) => println!("Failed to restore {} to {}: {}", filename, old_cid, e),
+        }
+    }
+    router.shutdown().await.unwrap();
+}
+
+/// have the entry node download a registered document from our blobs
+async fn publish(
+    universe_id: UniverseId,
+    filename: &str,
+    network: &NetworkConfig,
+    endpoint: &Endpoint,
+) -> anyhow::Result<()> {
+    let mut entry_node = connect_entry_node(network, endpoint).await;
+    let response = entry_node
+        .publish(universe_id, filename, endpoint.node_id())
+        .await?;
+    println!("> Published {} ({} bytes)", response.cid, response.size);
+    Ok(())
+}
+
+/// an app store keeping documents in the client's blobs,
 /// against a smart contract deployed on the configured substrate backend
-type TestnetAppStore = AppStore<LocalDocStore, ContractIntentStore, LocalPlaintextStore>;
+type TestnetAppStore = AppStore<IrohDocStore, ContractIntentStore, LocalPlaintextStore>;
 
+/// clients take part in the universe of their config
+/// the committee members are resolved from the synced state of the entry node,
+/// they also evaluate the password OPRF for ZkPassword intents
 async fn testnet_setup(
+    config_path: &String,
     contract_addr: &String,
     seed: Option<&str>,
-) -> (SystemPublicKeys<E>, GadgetRegistry, TestnetAppStore) {
-    let sys_keys = get_system_keys().await;
+    doc_store: IrohDocStore,
+    network: &NetworkConfig,
+    endpoint: &Endpoint,
+) -> (
+    UniverseId,
+    u64,
+    SystemPublicKeys<E>,
+    GadgetRegistry,
+    TestnetAppStore,
+    Committee,
+) {
+    let universe_id = Config::<E>::load(config_path)
+        .expect("Failed to load config file")
+        .universe_id();
+    let mut entry_node = connect_entry_node(network, endpoint).await;
+    let (epoch, sys_keys) = get_system_keys(entry_node.as_mut(), universe_id).await;
+    let committee = Committee::from_node(entry_node.as_mut(), universe_id, Some(epoch))
+        .await
+        .unwrap();
 
     // build the backend
     let backend = Arc::new(
@@ -83,20 +235,43 @@ async fn testnet_setup(
     gadget_registry.register(PasswordGadget {});
     gadget_registry.register(Psp22Gadget::new(backend.clone()));
     gadget_registry.register(Sr25519Gadget::new(backend.clone()));
+    gadget_registry.register(ZkPasswordGadget::with_oprf(Arc::new(CommitteeOprf::new(
+        committee.clone(),
+        universe_id,
+        Some(endpoint.clone()),
+    ))));
 
     let app_store = AppStore::new(
-        LocalDocStore::new("tmp/docs/"),
+        doc_store,
         ContractIntentStore::new(contract_addr.to_string(), backend),
         LocalPlaintextStore::new("tmp/plaintexts/"),
     );
 
-    (sys_keys, gadget_registry, app_store)
+    (
+        universe_id,
+        epoch,
+        sys_keys,
+        gadget_registry,
+        app_store,
+        committee,
+    )
+}
+
+/// The entry node of the network, dialed over iroh if it is given by node id
+async fn connect_entry_node(network: &NetworkConfig, endpoint: &Endpoint) -> Box<dyn NodeRpc> {
+    let entry_node = network.entry_node();
+    connect_endpoint(entry_node, Some(endpoint))
+        .await
+        .unwrap_or_else(|e| panic!("Failed to reach the entry node {}: {}", entry_node, e))
 }
 
-async fn get_system_keys() -> SystemPublicKeys<E> {
-    let mut client = RpcClient::connect("http://127.0.0.1:30332").await.unwrap();
-    let response = client.preprocess(PreprocessRequest {}).await.unwrap();
-    let hex = response.into_inner().hex_serialized_sys_key;
-    let bytes = hex::decode(&hex).unwrap();
-    SystemPublicKeys::<E>::deserialize_compressed(&bytes[..]).unwrap()
+/// The system keys of the current committee epoch of a universe
+async fn get_system_keys(
+    entry_node: &mut dyn NodeRpc,
+    universe_id: UniverseId,
+) -> (u64, SystemPublicKeys<E>) {
+    let response = entry_node.preprocess(universe_id, None).await.unwrap();
+    let bytes = hex::decode(&response.hex_serialized_sys_key).unwrap();
+    let sys_keys = SystemPublicKeys::<E>::deserialize_compressed(&bytes[..]).unwrap();
+    (response.epoch, sys_keys)
 }
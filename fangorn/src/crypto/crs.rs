@@ -0,0 +1,298 @@
+//! Loading, importing and verifying the CRS (the powers of tau behind a `Config`)
+//!
+//! Configs are stored as hex text: `hex(SCALE(magic, version, size, config, checksum))`,
+//! where the checksum is the SHA-256 hash of everything before it. A config is only
+//! accepted if its powers of tau are consistent (checked with batched pairings) and its
+//! Lagrange polynomials match its size.
+use crate::types::Config;
+use ark_ec::{AffineRepr, CurveGroup, VariableBaseMSM, pairing::Pairing};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_std::{UniformRand, rand::rngs::OsRng};
+use codec::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use silent_threshold_encryption::{crs::CRS, setup::LagPolys};
+use std::{fs, path::Path};
+use thiserror::Error;
+
+/// Identifies fangorn config files
+pub const CONFIG_FILE_MAGIC: [u8; 4] = *b"FGCF";
+/// The current config file format version
+pub const CONFIG_FILE_VERSION: u16 = 1;
+/// The name of the config file in a node directory
+pub const CONFIG_FILE: &str = "config.txt";
+
+#[derive(Error, Debug)]
+pub enum CrsError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The config file is malformed: {0}")]
+    Malformed(String),
+    #[error("Unsupported config file version {0} (supported: {CONFIG_FILE_VERSION})")]
+    UnsupportedVersion(u16),
+    #[error("The config file checksum does not match")]
+    ChecksumMismatch,
+    #[error("Invalid CRS: {0}")]
+    InvalidCrs(String),
+    #[error("Serialization failed: {0}")]
+    SerializationError(String),
+}
+
+#[derive(Encode, Decode)]
+struct ConfigFile {
+    magic: [u8; 4],
+    version: u16,
+    size: u32,
+    config: Vec<u8>,
+    checksum: [u8; 32],
+}
+
+impl ConfigFile {
+    fn checksum(&self) -> [u8; 32] {
+        let body = (self.magic, self.version, self.size, &self.config).encode();
+        Sha256::digest(body).into()
+    }
+}
+
+/// Powers of tau in both groups: `[g, tau*g, tau^2*g, ...]` and `[h, tau*h, ...]`
+/// This is what a powers-of-tau ceremony produces, and all the CRS is made of
+#[derive(Clone, Debug, PartialEq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct PowersOfTau<C: Pairing> {
+    pub powers_of_g: Vec<C::G1Affine>,
+    pub powers_of_h: Vec<C::G2Affine>,
+}
+
+impl<C: Pairing> PowersOfTau<C> {
+    /// Check that both vectors are powers of the same non-trivial tau
+    /// e(tau^(i+1) g, h) == e(tau^i g, tau h) and e(g, tau^(j+1) h) == e(tau g, tau^j h),
+    /// each batched with a random linear combination
+    pub fn verify(&self) -> Result<(), CrsError> {
+        let g = &self.powers_of_g;
+        let h = &self.powers_of_h;
+        if g.len() < 2 || h.len() < 2 {
+            return Err(CrsError::InvalidCrs("too few powers".into()));
+        }
+        if g[0] != C::G1Affine::generator() || h[0] != C::G2Affine::generator() {
+            return Err(CrsError::InvalidCrs(
+                "powers must start at the generators".into(),
+            ));
+        }
+        if g[1].is_zero() || g[1] == g[0] {
+            return Err(CrsError::InvalidCrs("tau is trivial".into()));
+        }
+
+        let r: Vec<C::ScalarField> = (0..g.len() - 1)
+            .map(|_| UniformRand::rand(&mut OsRng))
+            .collect();
+        let shifted = C::G1::msm(&g[1..], &r).map_err(|_| CrsError::InvalidCrs("msm".into()))?;
+        let base =
+            C::G1::msm(&g[..g.len() - 1], &r).map_err(|_| CrsError::InvalidCrs("msm".into()))?;
+        if C::pairing(shifted, h[0]) != C::pairing(base, h[1]) {
+            return Err(CrsError::InvalidCrs("inconsistent G1 powers".into()));
+        }
+
+        let s: Vec<C::ScalarField> = (0..h.len() - 1)
+            .map(|_| UniformRand::rand(&mut OsRng))
+            .collect();
+        let shifted = C::G2::msm(&h[1..], &s).map_err(|_| CrsError::InvalidCrs("msm".into()))?;
+        let base =
+            C::G2::msm(&h[..h.len() - 1], &s).map_err(|_| CrsError::InvalidCrs("msm".into()))?;
+        if C::pairing(g[0], shifted) != C::pairing(g[1], base) {
+            return Err(CrsError::InvalidCrs("inconsistent G2 powers".into()));
+        }
+
+        Ok(())
+    }
+
+    /// Build a config for a committee of the given size
+    pub fn into_config(self, size: usize) -> Result<Config<C>, CrsError> {
+        self.verify()?;
+        if self.powers_of_g.len() < size {
+            return Err(CrsError::InvalidCrs(format!(
+                "{} powers cannot support a committee of size {}",
+                self.powers_of_g.len(),
+                size
+            )));
+        }
+        let lag_polys = LagPolys::<C::ScalarField>::new(size)
+            .map_err(|e| CrsError::InvalidCrs(format!("{:?}", e)))?;
+        Ok(Config {
+            crs: CRS {
+                powers_of_g: self.powers_of_g,
+                powers_of_h: self.powers_of_h,
+            },
+            lag_polys,
+            size,
+        })
+    }
+}
+
+impl<C: Pairing> From<&CRS<C>> for PowersOfTau<C> {
+    fn from(crs: &CRS<C>) -> Self {
+        Self {
+            powers_of_g: crs.powers_of_g.clone(),
+            powers_of_h: crs.powers_of_h.clone(),
+        }
+    }
+}
+
+impl<C: Pairing> Config<C> {
+    /// Check that the CRS is well-formed and the Lagrange polynomials match the size
+    pub fn verify(&self) -> Result<(), CrsError> {
+        let powers = PowersOfTau::from(&self.crs);
+        powers.verify()?;
+        if powers.powers_of_g.len() < self.size {
+            return Err(CrsError::InvalidCrs(
+                "the CRS is smaller than the committee".into(),
+            ));
+        }
+
+        let expected = LagPolys::<C::ScalarField>::new(self.size)
+            .map_err(|e| CrsError::InvalidCrs(format!("{:?}", e)))?;
+        if serialize(&expected)? != serialize(&self.lag_polys)? {
+            return Err(CrsError::InvalidCrs(
+                "unexpected Lagrange polynomials".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Encode the config in the versioned, checksummed file format
+    pub fn to_file_string(&self) -> Result<String, CrsError> {
+        let mut file = ConfigFile {
+            magic: CONFIG_FILE_MAGIC,
+            version: CONFIG_FILE_VERSION,
+            size: self.size as u32,
+            config: serialize(self)?,
+            checksum: [0u8; 32],
+        };
+        file.checksum = file.checksum();
+        Ok(hex::encode(file.encode()))
+    }
+
+    /// Decode and verify a config from the file format
+    pub fn from_file_string(contents: &str) -> Result<Self, CrsError> {
+        let bytes = hex::decode(contents.trim()).map_err(|e| CrsError::Malformed(e.to_string()))?;
+        Self::from_file_bytes(&bytes)
+    }
+
+    fn from_file_bytes(bytes: &[u8]) -> Result<Self, CrsError> {
+        if bytes.len() < 6 || bytes[..4] != CONFIG_FILE_MAGIC {
+            return Err(CrsError::Malformed("not a fangorn config file".into()));
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != CONFIG_FILE_VERSION {
+            return Err(CrsError::UnsupportedVersion(version));
+        }
+
+        let file =
+            ConfigFile::decode(&mut &bytes[..]).map_err(|e| CrsError::Malformed(e.to_string()))?;
+        if file.checksum != file.checksum() {
+            return Err(CrsError::ChecksumMismatch);
+        }
+
+        let config = Config::<C>::deserialize_compressed(&file.config[..])
+            .map_err(|e| CrsError::Malformed(e.to_string()))?;
+        if config.size != file.size as usize {
+            return Err(CrsError::Malformed("size mismatch".into()));
+        }
+        config.verify()?;
+        Ok(config)
+    }
+
+    /// Load and verify a config file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CrsError> {
+        Self::from_file_string(&fs::read_to_string(path)?)
+    }
+
+    /// Write the config file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), CrsError> {
+        fs::write(path, self.to_file_string()?)?;
+        Ok(())
+    }
+}
+
+/// Import a CRS for a committee of the given size from a file
+/// The file may be a fangorn config file, or powers of tau (compressed, raw or hex encoded)
+pub fn import_crs<C: Pairing>(path: impl AsRef<Path>, size: usize) -> Result<Config<C>, CrsError> {
+    let bytes = fs::read(path)?;
+    // config files are hex, powers of tau may be either hex or raw
+    let raw = std::str::from_utf8(&bytes)
+        .ok()
+        .and_then(|s| hex::decode(s.trim()).ok())
+        .unwrap_or(bytes);
+
+    if raw.starts_with(&CONFIG_FILE_MAGIC) {
+        let config = Config::<C>::from_file_bytes(&raw)?;
+        if config.size != size {
+            return Err(CrsError::InvalidCrs(format!(
+                "the config is for a committee of size {}, expected {}",
+                config.size, size
+            )));
+        }
+        return Ok(config);
+    }
+
+    let powers = PowersOfTau::<C>::deserialize_compressed(&raw[..])
+        .map_err(|e| CrsError::Malformed(e.to_string()))?;
+    powers.into_config(size)
+}
+
+fn serialize<T: CanonicalSerialize>(value: &T) -> Result<Vec<u8>, CrsError> {
+    let mut bytes = Vec::new();
+    value
+        .serialize_compressed(&mut bytes)
+        .map_err(|e| CrsError::SerializationError(e.to_string()))?;
+    Ok(bytes)
+}
+
+/// Powers of tau for a known tau (tests only, tau must stay secret in practice)
+#[cfg(test)]
+fn powers_for<C: Pairing>(tau: C::ScalarField, len: usize) -> PowersOfTau<C> {
+    use ark_ff::Field;
+    let powers: Vec<C::ScalarField> = (0..len as u64).map(|i| tau.pow([i])).collect();
+    PowersOfTau {
+        powers_of_g: powers
+            .iter()
+            .map(|p| (C::G1Affine::generator() * p).into_affine())
+            .collect(),
+        powers_of_h: powers
+            .iter()
+            .map(|p| (C::G2Affine::generator() * p).into_affine())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::E;
+    use ark_bls12_381::Fr;
+
+    #[test]
+    fn powers_of_tau_verification_works() {
+        let powers = powers_for::<E>(Fr::from(42u64), 4);
+        assert!(powers.verify().is_ok());
+
+        let mut bad = powers.clone();
+        bad.powers_of_g[2] = bad.powers_of_g[3];
+        assert!(bad.verify().is_err());
+
+        let mut bad = powers;
+        bad.powers_of_h[1] = (bad.powers_of_h[1] * Fr::from(2u64)).into_affine();
+        assert!(bad.verify().is_err());
+    }
+
+    #[test]
+    fn config_file_roundtrip_works() {
+        let config = Config::<E>::rand(2);
+        let contents = config.to_file_string().unwrap();
+        let loaded = Config::<E>::from_file_string(&contents).unwrap();
+        assert_eq!(loaded.universe_id(), config.universe_id());
+
+        // flip a byte of the payload
+        let mut bytes = hex::decode(&contents).unwrap();
+        let last = bytes.len() - 40;
+        bytes[last] ^= 1;
+        assert!(Config::<E>::from_file_string(&hex::encode(bytes)).is_err());
+    }
+}
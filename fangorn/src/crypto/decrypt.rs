@@ -1,21 +1,37 @@
 use crate::{
-    rpc::server::{PartDecRequest, RpcClient},
+    committee::{Committee, CommitteeError},
+    crypto::{
+        encrypt::{DEFAULT_THRESHOLD, encrypt_key, plaintext_commitment},
+        envelope::{Envelope, EnvelopeError, ExpectedHeader, intent_hash},
+        migration::{MigrationAuth, sign_owner_authorization},
+        reader::{DocumentReader, ReaderError},
+        stream::KEY_LEN,
+        transport::{PUBLIC_KEY_LEN, TransportKeypair, partdec_context, seal, witness_context},
+    },
+    fanout::{AttemptError, FanoutPolicy, FanoutReport, NodeOutcome, fan_out, fan_out_preferring},
+    gadget::{GadgetRegistry, Intent},
+    rpc::server::PartDecRequest,
     storage::*,
     types::*,
+    universe::UniverseId,
 };
 use anyhow::Result;
 use ark_serialize::CanonicalDeserialize;
+use cid::Cid;
 use codec::Encode;
+use futures::{StreamExt, stream};
+use iroh::Endpoint;
 use silent_threshold_encryption::{
     aggregate::{AggregateKey, SystemPublicKeys},
     decryption::agg_dec,
     setup::PartialDecryption,
     types::Ciphertext,
 };
-use std::fs;
 use thiserror::Error;
+use tonic::{Code, Status};
 
-const MAX_COMMITTEE_SIZE: usize = 3;
+/// The most subsets of shares tried when some shares are wrong
+const MAX_QUORUM_ATTEMPTS: usize = 1024;
 
 #[derive(Error, Debug)]
 pub enum DecryptionClientError {
@@ -25,6 +41,8 @@ pub enum DecryptionClientError {
     IntentStoreError(String),
     #[error("Decryption failed: {0}")]
     DecryptionError(String),
+    #[error("Re-encryption failed: {0}")]
+    EncryptionError(String),
     #[error("Serialization failed")]
     SerializationError,
     #[error("Deserialization failed")]
@@ -33,6 +51,10 @@ pub enum DecryptionClientError {
     RpcError(String),
     #[error("Hex decoding error: {0}")]
     DecodingError(String),
+    #[error("Transport encryption error: {0}")]
+    TransportError(String),
+    #[error("Failed to prepare witness: {0}")]
+    WitnessError(String),
     #[error("Failed to read config: {0}")]
     ConfigReadError(String),
     #[error("Failed to write plaintext: {0}")]
@@ -41,50 +63,204 @@ pub enum DecryptionClientError {
     IntentNotFound(String),
     #[error("Ciphertext not found")]
     CiphertextNotFound,
+    #[error("Invalid document header: {0}")]
+    InvalidHeader(String),
+    #[error("Integrity check failed: the decrypted key does not match the ciphertext commitment")]
+    IntegrityError,
+    #[error("Not enough partial decryptions were collected: {0}")]
+    ThresholdNotReached(FanoutReport),
+    #[error("The keys of epoch {epoch} are unavailable: {reason}")]
+    EpochUnavailable { epoch: u64, reason: String },
+    #[error("The document is already in epoch {0}")]
+    AlreadyMigrated(u64),
 }
 
 pub struct DecryptionClient<D: DocStore, I: IntentStore, P: PlaintextStore> {
     config: Config<E>,
-    // the Fangorn system keys for the given universe
-    // at some point we will want to enable 'multiverse' support
-    // and will need to revisit this
+    // the universe of the config, named in every request to the committee
+    universe_id: UniverseId,
+    // the current committee epoch
+    epoch: u64,
+    // the Fangorn system keys for the universe, in the current epoch
     system_keys: SystemPublicKeys<E>,
-    // the threshold of shares we need to decrypt
+    // the threshold migrated documents are re-encrypted with
+    // (documents are decrypted with the threshold of their header)
     threshold: u8,
     // the app store
     app_store: AppStore<D, I, P>,
+    // the gadget registry (used to prepare witnesses)
+    registry: GadgetRegistry,
+    // where to reach the members of the current committee
+    committee: Committee,
+    // dials members that are reachable over iroh, if set
+    iroh: Option<Endpoint>,
+    // deadlines and retries for the requests to the committee
+    policy: FanoutPolicy,
 }
 
 impl<D: DocStore, I: IntentStore, P: PlaintextStore> DecryptionClient<D, I, P> {
     pub fn new(
         config_path: &str,
+        epoch: u64,
         system_keys: SystemPublicKeys<E>,
         app_store: AppStore<D, I, P>,
+        registry: GadgetRegistry,
+        committee: Committee,
     ) -> Result<Self, DecryptionClientError> {
-        let config_hex = fs::read_to_string(config_path)
+        let config = Config::<E>::load(config_path)
             .map_err(|e| DecryptionClientError::ConfigReadError(e.to_string()))?;
-        let config_bytes = hex::decode(&config_hex)
-            .map_err(|e| DecryptionClientError::DecodingError(e.to_string()))?;
-        let config = Config::<E>::deserialize_compressed(&config_bytes[..])
-            .map_err(|_| DecryptionClientError::DeserializationError)?;
 
         Ok(Self {
+            universe_id: config.universe_id(),
             config,
+            epoch,
             app_store,
             system_keys,
-            threshold: 1, // just hardcoded to 1 for now, easy
+            threshold: DEFAULT_THRESHOLD,
+            registry,
+            committee,
+            iroh: None,
+            policy: FanoutPolicy::default(),
         })
     }
 
+    /// The stores documents are read from
+    pub fn app_store(&self) -> &AppStore<D, I, P> {
+        &self.app_store
+    }
+
+    /// Also reach committee members over iroh, by node id
+    pub fn with_iroh_endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.iroh = Some(endpoint);
+        self
+    }
+
+    /// Set the number of partial decryptions needed to decrypt migrated documents
+    pub fn with_threshold(mut self, threshold: u8) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Set the deadlines and retries for the requests to the committee
+    pub fn with_fanout_policy(mut self, policy: FanoutPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Unlock the document key with a threshold of partial decryptions,
+    /// then stream the decrypted payload into the plaintext store
     pub async fn decrypt(
         &self,
         filename: &str,
         witnesses: &[&str],
         output_filename: &String,
-    ) -> Result<Vec<u8>, DecryptionClientError> {
-        // fetch ciphertext
-        // todo: use intents for verification?
-        let (cid, _intents) = self
+    ) -> Result<FanoutReport, DecryptionClientError> {
+        let (reader, report) = self.open(filename, witnesses).await?;
+
+        let mut writer = self
+            .app_store
+            .pt_store
+            .plaintext_writer(output_filename)
+            .await
+            .map_err(|e| DecryptionClientError::PlaintextWriteError(e.to_string()))?;
+
+        reader.write_to(&mut writer).await.map_err(|e| match e {
+            ReaderError::WriteError(e) => DecryptionClientError::PlaintextWriteError(e),
+            e => DecryptionClientError::DecryptionError(e.to_string()),
+        })?;
+        // the plaintext only appears once every chunk has been authenticated
+        writer
+            .commit()
+            .await
+            .map_err(|e| DecryptionClientError::PlaintextWriteError(e.to_string()))?;
+
+        Ok(report)
+    }
+
+    /// Unlock the document key with a threshold of partial decryptions
+    /// The returned reader can decrypt any range of the document on demand,
+    /// fetching only the chunks it needs from the docstore
+    /// The report holds the outcome of the request to each committee member
+    pub async fn open(
+        &self,
+        filename: &str,
+        witnesses: &[&str],
+    ) -> Result<(DocumentReader<'_, D>, FanoutReport), DecryptionClientError> {
+        let (cid, intents, envelope) = self.load(filename).await?;
+        let (key, report) = self
+            .unlock(
+                filename,
+                &cid,
+                &intents,
+                &envelope,
+                &MigrationAuth::Witnesses(witnesses),
+            )
+            .await?;
+
+        let reader = DocumentReader::new(&self.app_store.doc_store, cid, &envelope, &key);
+        Ok((reader, report))
+    }
+
+    /// Re-encrypt a document of an older epoch to the current epoch
+    /// The old committee releases the document key, which is threshold encrypted to the
+    /// current system keys and only the header is rewritten, the sealed payload is copied as is
+    /// The registry entry only moves to the new document if it still points at the old one,
+    /// after which the old document is removed
+    pub async fn migrate(
+        &self,
+        filename: &str,
+        auth: MigrationAuth<'_>,
+    ) -> Result<(Cid, FanoutReport), DecryptionClientError> {
+        let (cid, intents, envelope) = self.load(filename).await?;
+        if envelope.epoch >= self.epoch {
+            return Err(DecryptionClientError::AlreadyMigrated(envelope.epoch));
+        }
+        let (key, report) = self
+            .unlock(filename, &cid, &intents, &envelope, &auth)
+            .await?;
+
+        let mut migrated = envelope.clone();
+        migrated.epoch = self.epoch;
+        migrated.threshold = self.threshold as u32;
+        migrated.kem = encrypt_key(&self.config, &self.system_keys, self.threshold, &key)
+            .map_err(|e| DecryptionClientError::EncryptionError(e.to_string()))?;
+
+        let doc_store = &self.app_store.doc_store;
+        let reader = DocumentReader::new(doc_store, cid, &envelope, &key);
+        let payload = reader.sealed_payload();
+        let header_bytes = migrated.to_bytes();
+        let header = stream::once(async move { Ok(header_bytes) });
+        let new_cid = doc_store
+            .add_stream(header.chain(payload).boxed())
+            .await
+            .map_err(|e| DecryptionClientError::DocstoreError(e.to_string()))?;
+
+        // nothing references the new document unless the swap succeeds
+        if let Err(e) = self
+            .app_store
+            .intent_store
+            .replace_cid(filename.as_bytes(), &cid, &new_cid)
+            .await
+        {
+            if let Err(e) = doc_store.remove(&new_cid).await {
+                println!("Failed to remove the migrated document {}: {}", new_cid, e);
+            }
+            return Err(DecryptionClientError::IntentStoreError(e.to_string()));
+        }
+
+        if let Err(e) = doc_store.remove(&cid).await {
+            println!("Failed to remove the old document {}: {}", cid, e);
+        }
+
+        Ok((new_cid, report))
+    }
+
+    /// Fetch the intents and the header of a document
+    async fn load(
+        &self,
+        filename: &str,
+    ) -> Result<(Cid, Vec<Intent>, Envelope), DecryptionClientError> {
+        let (cid, intents) = self
             .app_store
             .intent_store
             .get_intent(filename.as_bytes())
@@ -92,100 +268,315 @@ impl<D: DocStore, I: IntentStore, P: PlaintextStore> DecryptionClient<D, I, P> {
             .map_err(|e| DecryptionClientError::IntentStoreError(e.to_string()))?
             .ok_or_else(|| DecryptionClientError::IntentNotFound(filename.to_string()))?;
 
-        let ciphertext_bytes = self
-            .app_store
-            .doc_store
-            .fetch(&cid)
+        let envelope = Envelope::read(&self.app_store.doc_store, &cid)
             .await
-            .map_err(|e| DecryptionClientError::DocstoreError(e.to_string()))?
+            .map_err(|e| match e {
+                EnvelopeError::DocstoreError(e) => DecryptionClientError::DocstoreError(e),
+                e => DecryptionClientError::InvalidHeader(e.to_string()),
+            })?
             .ok_or(DecryptionClientError::CiphertextNotFound)?;
 
-        let ciphertext = Ciphertext::<E>::deserialize_compressed(&ciphertext_bytes[..])
+        // the ciphertext binds the threshold, a wrong one in the header only fails to decrypt
+        if envelope.threshold == 0 || envelope.threshold as usize >= self.config.size {
+            return Err(DecryptionClientError::InvalidHeader(format!(
+                "threshold {} does not fit the universe size {}",
+                envelope.threshold, self.config.size
+            )));
+        }
+
+        // fail before contacting any node if the document was not made for this client
+        envelope
+            .check(&ExpectedHeader {
+                threshold: envelope.threshold,
+                universe_id: self.universe_id,
+                epoch: envelope.epoch,
+                intent_hash: intent_hash(&intents),
+            })
+            .map_err(|e| DecryptionClientError::InvalidHeader(e.to_string()))?;
+
+        Ok((cid, intents, envelope))
+    }
+
+    /// Recover the document key from a threshold of partial decryptions
+    async fn unlock(
+        &self,
+        filename: &str,
+        cid: &Cid,
+        intents: &[Intent],
+        envelope: &Envelope,
+        auth: &MigrationAuth<'_>,
+    ) -> Result<([u8; KEY_LEN], FanoutReport), DecryptionClientError> {
+        let ciphertext = Ciphertext::<E>::deserialize_compressed(&envelope.kem[..])
             .map_err(|_| DecryptionClientError::DeserializationError)?;
 
-        // prepare witnesses
-        let witness_hex = self.encode_witnesses(witnesses)?;
+        // documents encrypted under an older (not yet retired) epoch are decrypted by its committee
+        let fetched;
+        let (system_keys, committee) = if envelope.epoch == self.epoch {
+            (&self.system_keys, &self.committee)
+        } else {
+            fetched = self.fetch_epoch(envelope.epoch).await?;
+            (&fetched.0, &fetched.1)
+        };
 
-        let subset = vec![0, self.threshold as usize];
+        let threshold = envelope.threshold as usize;
+        let subset = vec![0, threshold];
         let (ak, _ek) =
-            self.system_keys
-                .get_aggregate_key(&subset, &self.config.crs, &self.config.lag_polys);
+            system_keys.get_aggregate_key(&subset, &self.config.crs, &self.config.lag_polys);
 
-        // collect partial decryptions
-        let partial_decryptions = self
-            .collect_partial_decryptions(filename, &witness_hex, &ak)
+        // collect partial decryptions and recover the data encryption key
+        let (key, report) = self
+            .collect_partial_decryptions(
+                committee,
+                filename,
+                cid,
+                intents,
+                auth,
+                &ak,
+                &ciphertext,
+                threshold,
+            )
             .await?;
 
-        // decrypt
-        let plaintext = self.aggregate_decrypt(&partial_decryptions, &ciphertext, &ak)?;
+        let key: [u8; KEY_LEN] = key
+            .try_into()
+            .map_err(|_| DecryptionClientError::DecryptionError("Invalid key length".into()))?;
 
-        // write plaintext to store
-        self.app_store
-            .pt_store
-            .write_to_pt_store(output_filename, &plaintext)
-            .await
-            .map_err(|e| DecryptionClientError::PlaintextWriteError(e.to_string()))?;
+        Ok((key, report))
+    }
 
-        Ok(plaintext)
+    /// Read the system keys and the committee of another epoch from the current committee
+    async fn fetch_epoch(
+        &self,
+        epoch: u64,
+    ) -> Result<(SystemPublicKeys<E>, Committee), DecryptionClientError> {
+        let mut reason = String::from("no member of the current committee is reachable");
+        for (index, _) in self.committee.iter() {
+            let fetched = async {
+                let mut client = self
+                    .committee
+                    .connect(index as usize, self.iroh.as_ref())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let response = client
+                    .preprocess(self.universe_id, Some(epoch))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let bytes =
+                    hex::decode(&response.hex_serialized_sys_key).map_err(|e| e.to_string())?;
+                let system_keys = SystemPublicKeys::<E>::deserialize_compressed(&bytes[..])
+                    .map_err(|e| e.to_string())?;
+                let committee =
+                    Committee::from_node(client.as_mut(), self.universe_id, Some(epoch))
+                        .await
+                        .map_err(|e| e.to_string())?;
+                Ok::<_, String>((system_keys, committee))
+            };
+            match fetched.await {
+                Ok(fetched) => return Ok(fetched),
+                Err(e) => reason = format!("member {}: {}", index, e),
+            }
+        }
+        Err(DecryptionClientError::EpochUnavailable { epoch, reason })
     }
 
-    fn encode_witnesses(&self, witnesses: &[&str]) -> Result<String, DecryptionClientError> {
-        let witness_bytes: Vec<Vec<u8>> = witnesses.iter().map(|w| w.as_bytes().to_vec()).collect();
+    /// Prepare the witnesses for a specific node and seal them to its transport key
+    /// so that only that node learns them (and can only use them against itself)
+    async fn encode_witnesses(
+        &self,
+        filename: &str,
+        intents: &[Intent],
+        witnesses: &[&str],
+        node_transport_key: [u8; PUBLIC_KEY_LEN],
+    ) -> Result<String, DecryptionClientError> {
+        let node_pk = node_transport_key.into();
+        let context = witness_context(filename.as_bytes(), &node_pk);
+
+        let witness_bytes = self
+            .registry
+            .prepare_witnesses(intents, witnesses, &context)
+            .await
+            .map_err(|e| DecryptionClientError::WitnessError(e.to_string()))?;
 
-        Ok(hex::encode(witness_bytes.encode()))
+        let sealed = seal(&node_pk, &context, &witness_bytes.encode())
+            .map_err(|e| DecryptionClientError::TransportError(e.to_string()))?;
+
+        Ok(hex::encode(sealed))
     }
 
+    /// Request partial decryptions from the committee of the document's epoch (live members
+    /// first) and recover the key from a threshold of them
+    /// A well-formed but wrong share only shows once the shares are aggregated, so if the
+    /// first shares do not recover the key the ciphertext commits to, the other members are
+    /// asked as well and the wrong shares are left out (and reported as rejected)
     async fn collect_partial_decryptions(
         &self,
+        committee: &Committee,
         filename: &str,
-        witness_hex: &str,
+        cid: &Cid,
+        intents: &[Intent],
+        auth: &MigrationAuth<'_>,
         ak: &AggregateKey<E>,
-    ) -> Result<Vec<PartialDecryption<E>>, DecryptionClientError> {
-        let mut partial_decryptions = vec![PartialDecryption::zero(); ak.lag_pks.len()];
+        ciphertext: &Ciphertext<E>,
+        threshold: usize,
+    ) -> Result<(Vec<u8>, FanoutReport), DecryptionClientError> {
+        // nodes seal their shares to this key, so only we can aggregate them
+        let ephemeral = TransportKeypair::generate();
+        let context = partdec_context(filename.as_bytes());
+        let request = |i: usize| {
+            self.request_partial_decryption(
+                committee,
+                ak.lag_pks[i].id,
+                filename,
+                cid,
+                intents,
+                auth,
+                &ephemeral,
+                &context,
+            )
+        };
+
+        // members known to be live are asked first (least loaded first), the others if needed
+        let (live, others) =
+            committee.live_first((0..ak.lag_pks.len()).map(|i| (i, ak.lag_pks[i].id)));
+        let (mut shares, mut report) =
+            fan_out_preferring(live, others, threshold, &self.policy, &request).await;
+
+        if !report.is_complete() {
+            return Err(DecryptionClientError::ThresholdNotReached(report));
+        }
+        if let Some(key) = self.recover_key(&shares, ciphertext, ak) {
+            return Ok((key, report));
+        }
+
+        // a share is wrong: ask everyone who was not asked (or not waited for) yet
+        let rest: Vec<usize> = (0..ak.lag_pks.len())
+            .filter(|i| matches!(report.outcomes.get(i), None | Some(NodeOutcome::NotNeeded)))
+            .collect();
+        let (more, more_report) = fan_out(rest.clone(), rest.len(), &self.policy, &request).await;
+        shares.extend(more);
+        report.outcomes.extend(more_report.outcomes);
+
+        let recovers = |subset: &[usize]| {
+            let picked: Vec<_> = subset.iter().map(|&s| shares[s].clone()).collect();
+            self.recover_key(&picked, ciphertext, ak).is_some()
+        };
+        let Some(quorum) = find_quorum(shares.len(), threshold, &recovers) else {
+            return Err(DecryptionClientError::IntegrityError);
+        };
 
-        // TODO: make this configurable/dynamic based on threshold
-        for i in 0..self.threshold as usize {
-            let node_id = ak.lag_pks[i].id;
-            let rpc_port = get_rpc_port(node_id)?;
+        // a share is right if it can stand in for one of the quorum
+        for other in (0..shares.len()).filter(|s| !quorum.contains(s)) {
+            let mut swapped = quorum.clone();
+            swapped[0] = other;
+            if !recovers(&swapped) {
+                report.outcomes.insert(
+                    shares[other].0,
+                    NodeOutcome::Rejected("wrong partial decryption".into()),
+                );
+            }
+        }
 
-            let mut client = RpcClient::connect(format!("http://127.0.0.1:{}", rpc_port))
-                .await
-                .map_err(|e| DecryptionClientError::RpcError(e.to_string()))?;
+        let picked: Vec<_> = quorum.iter().map(|&s| shares[s].clone()).collect();
+        let key = self
+            .recover_key(&picked, ciphertext, ak)
+            .ok_or(DecryptionClientError::IntegrityError)?;
+        Ok((key, report))
+    }
 
-            let request = tonic::Request::new(PartDecRequest {
-                filename: filename.to_string(),
-                witness_hex: witness_hex.to_string(),
-            });
+    /// Aggregate the shares, keeping the key only if it matches the ciphertext commitment
+    /// (bad shares make agg_dec return garbage rather than fail)
+    fn recover_key(
+        &self,
+        shares: &[(usize, PartialDecryption<E>)],
+        ciphertext: &Ciphertext<E>,
+        ak: &AggregateKey<E>,
+    ) -> Option<Vec<u8>> {
+        let mut partial_decryptions = vec![PartialDecryption::zero(); ak.lag_pks.len()];
+        let mut selector = vec![false; ak.lag_pks.len()];
+        for (i, share) in shares {
+            partial_decryptions[*i] = share.clone();
+            selector[*i] = true;
+        }
 
-            let response = client
-                .partdec(request)
-                .await
-                .map_err(|e| DecryptionClientError::RpcError(e.to_string()))?;
+        let key = self
+            .aggregate_decrypt(&partial_decryptions, &selector, ciphertext, ak)
+            .ok()?;
+        (plaintext_commitment(&key) == ciphertext.gamma_g2).then_some(key)
+    }
 
-            let part_dec_hex = response.into_inner().hex_serialized_decryption;
-            let part_dec_bytes = hex::decode(&part_dec_hex)
-                .map_err(|e| DecryptionClientError::DecodingError(e.to_string()))?;
+    /// Request a partial decryption from a single node
+    async fn request_partial_decryption(
+        &self,
+        committee: &Committee,
+        node_id: usize,
+        filename: &str,
+        cid: &Cid,
+        intents: &[Intent],
+        auth: &MigrationAuth<'_>,
+        ephemeral: &TransportKeypair,
+        context: &[u8],
+    ) -> Result<PartialDecryption<E>, AttemptError> {
+        let mut client = committee
+            .connect(node_id, self.iroh.as_ref())
+            .await
+            .map_err(|e| match e {
+                CommitteeError::Unreachable { .. } => AttemptError::Retry(e.to_string()),
+                e => AttemptError::Reject(e.to_string()),
+            })?;
 
-            partial_decryptions[i] = PartialDecryption::deserialize_compressed(&part_dec_bytes[..])
-                .map_err(|_| DecryptionClientError::DeserializationError)?;
+        let mut request = PartDecRequest {
+            filename: filename.to_string(),
+            ephemeral_pubkey_hex: ephemeral.public_hex(),
+            universe_id: self.universe_id.to_vec(),
+            ..Default::default()
+        };
+        match auth {
+            MigrationAuth::Witnesses(witnesses) => {
+                let identity = client
+                    .identity(self.universe_id)
+                    .await
+                    .map_err(status_error)?;
+                // witnesses are only sealed to a transport key signed by the member's node id
+                let identity = committee
+                    .check_identity(node_id, &identity)
+                    .map_err(|e| AttemptError::Reject(e.to_string()))?;
+                request.witness_hex = self
+                    .encode_witnesses(filename, intents, witnesses, identity.transport_key)
+                    .await
+                    .map_err(|e| AttemptError::Reject(e.to_string()))?;
+            }
+            MigrationAuth::Owner(owner) => {
+                let signature =
+                    sign_owner_authorization(owner, filename.as_bytes(), cid, &ephemeral.public());
+                request.owner_signature_hex = hex::encode(signature);
+            }
         }
 
-        Ok(partial_decryptions)
+        let response = client.partdec(request).await.map_err(status_error)?;
+
+        // the node answered: a share we cannot use will not get better by asking again
+        let sealed_bytes = hex::decode(&response.hex_serialized_decryption)
+            .map_err(|e| AttemptError::Reject(e.to_string()))?;
+        let part_dec_bytes = ephemeral
+            .open(context, &sealed_bytes)
+            .map_err(|e| AttemptError::Reject(e.to_string()))?;
+
+        // nodes that reject the witnesses answer with an empty share
+        PartialDecryption::deserialize_compressed(&part_dec_bytes[..])
+            .map_err(|_| AttemptError::Reject("no valid partial decryption".into()))
     }
 
     fn aggregate_decrypt(
         &self,
         partial_decryptions: &[PartialDecryption<E>],
+        selector: &[bool],
         ciphertext: &Ciphertext<E>,
         ak: &AggregateKey<E>,
     ) -> Result<Vec<u8>, DecryptionClientError> {
-        let mut selector = vec![false; MAX_COMMITTEE_SIZE];
-        selector[0] = true;
-
         agg_dec(
             partial_decryptions,
             ciphertext,
-            &selector,
+            selector,
             ak,
             &self.config.crs,
         )
@@ -193,19 +584,56 @@ impl<D: DocStore, I: IntentStore, P: PlaintextStore> DecryptionClient<D, I, P> {
     }
 }
 
-// todo: This needs to be made dynamic when new nodes join
-// mapping their index to their ip and port
-// in this case we drop ip since everything is runnning locally
-// note: this means we can only support three nodes max right now,
-// due to the poor design...
-fn get_rpc_port(node_id: usize) -> Result<u16, DecryptionClientError> {
-    match node_id {
-        0 => Ok(30332),
-        1 => Ok(30334),
-        2 => Ok(30335),
-        _ => Err(DecryptionClientError::RpcError(format!(
-            "Unknown node ID: {}",
-            node_id
-        ))),
+/// Find `threshold` of `count` shares that recover the key, trying subsets in order
+/// Gives up after `MAX_QUORUM_ATTEMPTS` subsets, as their number grows quickly
+fn find_quorum(
+    count: usize,
+    threshold: usize,
+    recovers: impl Fn(&[usize]) -> bool,
+) -> Option<Vec<usize>> {
+    if threshold == 0 || threshold > count {
+        return None;
+    }
+    let mut subset: Vec<usize> = (0..threshold).collect();
+    for _ in 0..MAX_QUORUM_ATTEMPTS {
+        if recovers(&subset) {
+            return Some(subset);
+        }
+        // the next subset in lexicographic order
+        let i = (0..threshold)
+            .rev()
+            .find(|&i| subset[i] < count - threshold + i)?;
+        subset[i] += 1;
+        for j in i + 1..threshold {
+            subset[j] = subset[j - 1] + 1;
+        }
+    }
+    None
+}
+
+/// Retry the RPC errors that may be transient
+fn status_error(status: Status) -> AttemptError {
+    match status.code() {
+        Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted => {
+            AttemptError::Retry(status.message().to_string())
+        }
+        _ => AttemptError::Reject(status.message().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quorum_leaves_out_wrong_shares() {
+        // shares 1 and 3 are wrong
+        let right = |subset: &[usize]| subset.iter().all(|s| *s != 1 && *s != 3);
+        assert_eq!(find_quorum(5, 3, right), Some(vec![0, 2, 4]));
+        assert_eq!(find_quorum(5, 1, right), Some(vec![0]));
+        // not enough right shares
+        assert_eq!(find_quorum(4, 3, right), None);
+        assert_eq!(find_quorum(2, 3, |_| true), None);
+        assert_eq!(find_quorum(2, 0, |_| true), None);
     }
 }
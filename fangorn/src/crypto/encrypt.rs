@@ -1,4 +1,8 @@
 use crate::{
+    crypto::{
+        envelope::{Envelope, intent_hash},
+        stream::{KEY_LEN, NONCE_PREFIX_LEN, encrypt_stream},
+    },
     gadget::GadgetRegistry,
     storage::*,
     types::*,
@@ -12,11 +16,13 @@ use ark_ec::hashing::{
 };
 
 use ark_ff::field_hashers::DefaultFieldHasher;
-use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_serialize::CanonicalSerialize;
+use ark_std::rand::{RngCore, rngs::OsRng};
+use futures::{StreamExt, stream};
 use sha2::{Digest, Sha256};
 use silent_threshold_encryption::{aggregate::SystemPublicKeys, encryption::encrypt};
-use std::fs;
 use thiserror::Error;
+use tokio::io::AsyncRead;
 
 #[derive(Error, Debug)]
 pub enum EncryptionClientError {
@@ -28,13 +34,20 @@ pub enum EncryptionClientError {
     IntentParsingError(String),
     #[error("Serialization failed")]
     SerializationError,
+    #[error("Failed to read plaintext: {0}")]
+    PlaintextReadError(String),
+    #[error("Invalid threshold {threshold}: it must be between 1 and the universe size {size}")]
+    InvalidThreshold { threshold: u8, size: usize },
 }
 
+/// The threshold documents are encrypted with unless another one is set
+pub const DEFAULT_THRESHOLD: u8 = 1;
+
 pub struct EncryptionClient<D: DocStore, I: IntentStore, P: PlaintextStore> {
     config: Config<E>,
-    // the Fangorn encryption key for the given universe
-    // at some point we will want to enable 'multiverse' support
-    // and will need to revisit this
+    // the committee epoch the system keys belong to
+    epoch: u64,
+    // the Fangorn encryption key for the universe of the config
     system_keys: SystemPublicKeys<E>,
     // the threshold to use when encrypting
     threshold: u8,
@@ -47,28 +60,68 @@ pub struct EncryptionClient<D: DocStore, I: IntentStore, P: PlaintextStore> {
 impl<D: DocStore, I: IntentStore, P: PlaintextStore> EncryptionClient<D, I, P> {
     pub fn new(
         config_path: &str,
+        epoch: u64,
         system_keys: SystemPublicKeys<E>,
         app_store: AppStore<D, I, P>,
         registry: GadgetRegistry,
     ) -> Self {
-        let config_hex = fs::read_to_string(config_path).expect("Failed to read config file");
-        let config_bytes = hex::decode(&config_hex).unwrap();
-        let config = Config::<E>::deserialize_compressed(&config_bytes[..]).unwrap();
+        let config = Config::<E>::load(config_path).expect("Failed to load config file");
 
         Self {
             config,
+            epoch,
             system_keys,
-            threshold: 1, // just hardcoded to 1 for now, easy
+            threshold: DEFAULT_THRESHOLD,
             app_store,
             registry,
         }
     }
 
+    /// Set the number of partial decryptions needed to decrypt new documents
+    pub fn with_threshold(mut self, threshold: u8) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// The stores documents are encrypted into
+    pub fn app_store(&self) -> &AppStore<D, I, P> {
+        &self.app_store
+    }
+
+    /// encrypt an in-memory plaintext
     pub async fn encrypt(
         &self,
         plaintext: &[u8],
         filename: &[u8],
         raw_intent: &str,
+    ) -> Result<(), EncryptionClientError> {
+        self.encrypt_reader(plaintext, filename, raw_intent).await
+    }
+
+    /// encrypt a plaintext streamed from the plaintext store
+    pub async fn encrypt_file(
+        &self,
+        message_path: &String,
+        filename: &[u8],
+        raw_intent: &str,
+    ) -> Result<(), EncryptionClientError> {
+        let reader = self
+            .app_store
+            .pt_store
+            .plaintext_reader(message_path)
+            .await
+            .map_err(|e| EncryptionClientError::PlaintextReadError(e.to_string()))?;
+        self.encrypt_reader(reader, filename, raw_intent).await
+    }
+
+    /// encrypt everything read from the reader
+    /// the symmetric key is threshold encrypted, the payload is streamed through
+    /// the chunked AEAD into the docstore, so memory use is bounded by the chunk size
+    pub async fn encrypt_reader<R: AsyncRead + Unpin + Send>(
+        &self,
+        reader: R,
+        filename: &[u8],
+        raw_intent: &str,
     ) -> Result<(), EncryptionClientError> {
         // parse the intents (fail early on invalid intent)
         let intents = self
@@ -77,13 +130,32 @@ impl<D: DocStore, I: IntentStore, P: PlaintextStore> EncryptionClient<D, I, P> {
             .await
             .map_err(|e| EncryptionClientError::IntentParsingError(e.to_string()))?;
 
-        let ciphertext = self.encrypt_inner(plaintext)?;
+        // the data encryption key and stream parameters
+        let mut key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut nonce_prefix);
+
+        // the self-describing header, bound to every chunk
+        let envelope = Envelope::new(
+            self.encrypt_inner(&key)?,
+            self.threshold as u32,
+            self.config.universe_id(),
+            self.epoch,
+            intent_hash(&intents),
+            nonce_prefix,
+        );
+
+        let header_bytes = envelope.to_bytes();
+        let header = stream::once(async move { Ok(header_bytes) });
+        let chunks = encrypt_stream(envelope.chunk_cipher(&key), reader)
+            .map(|chunk| chunk.map_err(anyhow::Error::from));
 
         // add to shared storage, get cid
         let cid = self
             .app_store
             .doc_store
-            .add(&ciphertext)
+            .add_stream(header.chain(chunks).boxed())
             .await
             .map_err(|e| EncryptionClientError::DocstoreError(e.to_string()))?;
 
@@ -98,36 +170,57 @@ impl<D: DocStore, I: IntentStore, P: PlaintextStore> EncryptionClient<D, I, P> {
         Ok(())
     }
 
+    /// threshold encrypt the data encryption key
     fn encrypt_inner(&self, plaintext: &[u8]) -> Result<Vec<u8>, EncryptionClientError> {
-        // Encrypt ciphertext
-        let commitment = Sha256::digest(plaintext);
-        let gamma_g2 = hash_to_g2(&commitment, b"fangorn");
-
-        // get the encryption key
-        let subset = vec![0, self.threshold as usize];
-        let (_ak, ek) =
-            self.system_keys
-                .get_aggregate_key(&subset, &self.config.crs, &self.config.lag_polys);
-
-        let ciphertext = encrypt::<E>(
-            &ek,
-            self.threshold as usize,
-            &self.config.crs,
-            gamma_g2.into(),
-            plaintext,
-        )
-        .map_err(|e| EncryptionClientError::EncryptionError(e.to_string()))?;
-
-        // Serialize
-        let mut ciphertext_bytes = Vec::new();
-        ciphertext
-            .serialize_compressed(&mut ciphertext_bytes)
-            .map_err(|_| EncryptionClientError::SerializationError)?;
-
-        Ok(ciphertext_bytes)
+        encrypt_key(&self.config, &self.system_keys, self.threshold, plaintext)
     }
 }
 
+/// Threshold encrypt a data encryption key under the given system keys
+pub fn encrypt_key(
+    config: &Config<E>,
+    system_keys: &SystemPublicKeys<E>,
+    threshold: u8,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EncryptionClientError> {
+    if threshold == 0 || threshold as usize >= config.size {
+        return Err(EncryptionClientError::InvalidThreshold {
+            threshold,
+            size: config.size,
+        });
+    }
+
+    // Encrypt ciphertext
+    let gamma_g2 = plaintext_commitment(plaintext);
+
+    // get the encryption key
+    let subset = vec![0, threshold as usize];
+    let (_ak, ek) = system_keys.get_aggregate_key(&subset, &config.crs, &config.lag_polys);
+
+    let ciphertext = encrypt::<E>(
+        &ek,
+        threshold as usize,
+        &config.crs,
+        gamma_g2.into(),
+        plaintext,
+    )
+    .map_err(|e| EncryptionClientError::EncryptionError(e.to_string()))?;
+
+    // Serialize
+    let mut ciphertext_bytes = Vec::new();
+    ciphertext
+        .serialize_compressed(&mut ciphertext_bytes)
+        .map_err(|_| EncryptionClientError::SerializationError)?;
+
+    Ok(ciphertext_bytes)
+}
+
+/// The commitment to the plaintext carried by the ciphertext (its `gamma_g2`)
+/// Decryption recomputes it to check the recovered plaintext
+pub fn plaintext_commitment(plaintext: &[u8]) -> G2Projective {
+    hash_to_g2(&Sha256::digest(plaintext), b"fangorn")
+}
+
 pub fn hash_to_g2(message: &[u8], ctx: &[u8]) -> G2Projective {
     // Create the hasher with domain separation tag
     let hasher = MapToCurveBasedHasher::<
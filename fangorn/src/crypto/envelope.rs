@@ -0,0 +1,351 @@
+//! The layout of encrypted documents
+//!
+//! A document is `header_len (u32 LE) || header || sealed chunks`, where the header
+//! describes how the document was encrypted (format version, curve, threshold,
+//! universe and epoch of the committee keys, AEAD parameters and intents), carries
+//! the threshold-encrypted symmetric key (the KEM). The fields describing the
+//! payload are bound to every chunk of the AEAD stream (the DEM), while the KEM,
+//! epoch and threshold are not, so a document moves to a new epoch by rewriting its
+//! header only. Readers only need the header to unlock the key, so nodes never
+//! have to download the payload.
+use crate::crypto::stream::{CHUNK_SIZE, ChunkCipher, KEY_LEN, NONCE_PREFIX_LEN};
+use crate::gadget::Intent;
+use crate::storage::DocStore;
+use cid::Cid;
+use codec::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Identifies fangorn documents
+pub const MAGIC: [u8; 4] = *b"FGRN";
+/// The current document format version
+pub const FORMAT_VERSION: u16 = 2;
+/// The oldest format version that can still be read
+pub const MIN_FORMAT_VERSION: u16 = FORMAT_VERSION;
+/// Curve identifiers
+pub const CURVE_BLS12_381: u8 = 1;
+/// AEAD identifiers
+pub const AEAD_XCHACHA20POLY1305_STREAM: u8 = 1;
+
+/// The number of bytes used to encode the header length
+pub const HEADER_LEN_PREFIX: u64 = 4;
+/// An upper bound on the header size, to avoid reading garbage lengths
+pub const MAX_HEADER_LEN: u32 = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum EnvelopeError {
+    #[error("An error occurred while communicating with the docstore: {0}")]
+    DocstoreError(String),
+    #[error("Not a fangorn document (bad magic bytes)")]
+    BadMagic,
+    #[error(
+        "Unsupported document format version {0} (supported: {MIN_FORMAT_VERSION} to {FORMAT_VERSION})"
+    )]
+    UnsupportedVersion(u16),
+    #[error("Unsupported curve id {0}")]
+    UnsupportedCurve(u8),
+    #[error("Unsupported AEAD id {0}")]
+    UnsupportedAead(u8),
+    #[error("The document header is malformed: {0}")]
+    Malformed(String),
+    #[error("Threshold mismatch: the document requires {found}, expected {expected}")]
+    ThresholdMismatch { expected: u32, found: u32 },
+    #[error(
+        "Universe mismatch: the document was encrypted for universe {found}, expected {expected}"
+    )]
+    UniverseMismatch { expected: String, found: String },
+    #[error("Epoch mismatch: the document was encrypted under epoch {found}, expected {expected}")]
+    EpochMismatch { expected: u64, found: u64 },
+    #[error("The registered intents do not match the intents the document was encrypted under")]
+    IntentMismatch,
+}
+
+/// The parameters of the chunked AEAD stream
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct AeadParams {
+    /// The AEAD identifier
+    pub algorithm: u8,
+    /// The plaintext size of each chunk
+    pub chunk_size: u32,
+    /// The random nonce prefix of the chunk stream
+    pub nonce_prefix: [u8; NONCE_PREFIX_LEN],
+}
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct Envelope {
+    /// Always [`MAGIC`]
+    pub magic: [u8; 4],
+    /// The format version
+    pub version: u16,
+    /// The pairing-friendly curve of the threshold ciphertext
+    pub curve: u8,
+    /// The number of partial decryptions needed to unlock the key
+    pub threshold: u32,
+    /// The universe (config) the document was encrypted for
+    pub universe_id: [u8; 32],
+    /// The epoch of the hint set the encryption key was derived from
+    pub epoch: u64,
+    /// The chunked AEAD parameters
+    pub aead: AeadParams,
+    /// The hash of the (SCALE encoded) intents the document was encrypted under
+    pub intent_hash: [u8; 32],
+    /// The symmetric key, threshold encrypted (a compressed STE ciphertext)
+    pub kem: Vec<u8>,
+}
+
+/// What a reader expects to find in a document header
+pub struct ExpectedHeader {
+    pub threshold: u32,
+    pub universe_id: [u8; 32],
+    pub epoch: u64,
+    pub intent_hash: [u8; 32],
+}
+
+impl Envelope {
+    /// A header for the current format version
+    pub fn new(
+        kem: Vec<u8>,
+        threshold: u32,
+        universe_id: [u8; 32],
+        epoch: u64,
+        intent_hash: [u8; 32],
+        nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    ) -> Self {
+        Self {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            curve: CURVE_BLS12_381,
+            threshold,
+            universe_id,
+            epoch,
+            aead: AeadParams {
+                algorithm: AEAD_XCHACHA20POLY1305_STREAM,
+                chunk_size: CHUNK_SIZE,
+                nonce_prefix,
+            },
+            intent_hash,
+            kem,
+        }
+    }
+
+    /// Encode the header with its length prefix
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = self.encode();
+        let mut bytes = (header.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(header);
+        bytes
+    }
+
+    /// Decode a header, checking the magic and version before anything else
+    pub fn parse(header: &[u8]) -> Result<Self, EnvelopeError> {
+        if header.len() < 6 || header[..4] != MAGIC {
+            return Err(EnvelopeError::BadMagic);
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+
+        let envelope = Envelope::decode(&mut &header[..])
+            .map_err(|e| EnvelopeError::Malformed(e.to_string()))?;
+        if envelope.curve != CURVE_BLS12_381 {
+            return Err(EnvelopeError::UnsupportedCurve(envelope.curve));
+        }
+        if envelope.aead.algorithm != AEAD_XCHACHA20POLY1305_STREAM {
+            return Err(EnvelopeError::UnsupportedAead(envelope.aead.algorithm));
+        }
+        if envelope.aead.chunk_size == 0 {
+            return Err(EnvelopeError::Malformed("zero chunk size".into()));
+        }
+
+        Ok(envelope)
+    }
+
+    /// Check the header against what the reader expects
+    pub fn check(&self, expected: &ExpectedHeader) -> Result<(), EnvelopeError> {
+        if self.universe_id != expected.universe_id {
+            return Err(EnvelopeError::UniverseMismatch {
+                expected: hex::encode(expected.universe_id),
+                found: hex::encode(self.universe_id),
+            });
+        }
+        if self.epoch != expected.epoch {
+            return Err(EnvelopeError::EpochMismatch {
+                expected: expected.epoch,
+                found: self.epoch,
+            });
+        }
+        if self.threshold != expected.threshold {
+            return Err(EnvelopeError::ThresholdMismatch {
+                expected: expected.threshold,
+                found: self.threshold,
+            });
+        }
+        if self.intent_hash != expected.intent_hash {
+            return Err(EnvelopeError::IntentMismatch);
+        }
+        Ok(())
+    }
+
+    /// The offset of the first sealed chunk in a document with this header
+    pub fn payload_offset(&self) -> u64 {
+        HEADER_LEN_PREFIX + self.encoded_size() as u64
+    }
+
+    /// The chunk cipher for the payload, bound to this header
+    pub fn chunk_cipher(&self, key: &[u8; KEY_LEN]) -> ChunkCipher {
+        ChunkCipher::new(
+            key,
+            self.aead.nonce_prefix,
+            self.aead.chunk_size,
+            &self.payload_binding(),
+        )
+    }
+
+    /// The associated data of every chunk, the fields describing the payload
+    /// the KEM, epoch and threshold are left out, so the payload is kept as is when they change
+    fn payload_binding(&self) -> Vec<u8> {
+        (
+            self.magic,
+            self.version,
+            self.curve,
+            self.universe_id,
+            &self.aead,
+            self.intent_hash,
+        )
+            .encode()
+    }
+
+    /// Read only the header of a document from the docstore
+    pub async fn read<D: DocStore + ?Sized>(
+        doc_store: &D,
+        cid: &Cid,
+    ) -> Result<Option<Self>, EnvelopeError> {
+        let Some(len_bytes) = doc_store
+            .fetch_range(cid, 0, HEADER_LEN_PREFIX)
+            .await
+            .map_err(|e| EnvelopeError::DocstoreError(e.to_string()))?
+        else {
+            return Ok(None);
+        };
+        let len_bytes: [u8; 4] = len_bytes
+            .try_into()
+            .map_err(|_| EnvelopeError::Malformed("the document is too short".into()))?;
+        let header_len = u32::from_le_bytes(len_bytes);
+        if header_len > MAX_HEADER_LEN {
+            return Err(EnvelopeError::Malformed("the header is too large".into()));
+        }
+
+        let header = doc_store
+            .fetch_range(cid, HEADER_LEN_PREFIX, header_len as u64)
+            .await
+            .map_err(|e| EnvelopeError::DocstoreError(e.to_string()))?
+            .ok_or_else(|| EnvelopeError::DocstoreError("the document disappeared".into()))?;
+
+        Envelope::parse(&header).map(Some)
+    }
+}
+
+/// The hash of the SCALE encoded intents
+pub fn intent_hash(intents: &[Intent]) -> [u8; 32] {
+    Sha256::digest(intents.encode()).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn envelope() -> Envelope {
+        Envelope::new(
+            vec![1, 2, 3],
+            1,
+            [7u8; 32],
+            0,
+            [9u8; 32],
+            [3u8; NONCE_PREFIX_LEN],
+        )
+    }
+
+    fn expected() -> ExpectedHeader {
+        ExpectedHeader {
+            threshold: 1,
+            universe_id: [7u8; 32],
+            epoch: 0,
+            intent_hash: [9u8; 32],
+        }
+    }
+
+    #[test]
+    fn header_roundtrip_and_check_works() {
+        let bytes = envelope().to_bytes();
+        let parsed = Envelope::parse(&bytes[HEADER_LEN_PREFIX as usize..]).unwrap();
+        assert_eq!(parsed, envelope());
+        assert!(parsed.check(&expected()).is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_unknown_magic_and_versions() {
+        let mut header = envelope().encode();
+        header[0] = b'X';
+        assert!(matches!(
+            Envelope::parse(&header),
+            Err(EnvelopeError::BadMagic)
+        ));
+
+        let mut header = envelope().encode();
+        header[4] = 3;
+        assert!(matches!(
+            Envelope::parse(&header),
+            Err(EnvelopeError::UnsupportedVersion(3))
+        ));
+
+        let mut header = envelope().encode();
+        header[4] = 1;
+        assert!(matches!(
+            Envelope::parse(&header),
+            Err(EnvelopeError::UnsupportedVersion(1))
+        ));
+    }
+
+    #[test]
+    fn new_kem_keeps_the_payload_readable() {
+        let key = [5u8; KEY_LEN];
+        let sealed = envelope()
+            .chunk_cipher(&key)
+            .encrypt_chunk(0, true, b"payload")
+            .unwrap();
+
+        let mut migrated = envelope();
+        migrated.kem = vec![4, 5, 6];
+        migrated.epoch = 1;
+        migrated.threshold = 2;
+        let (plaintext, last) = migrated.chunk_cipher(&key).open_chunk(0, &sealed).unwrap();
+        assert_eq!(plaintext, b"payload");
+        assert!(last);
+
+        // the fields describing the payload stay bound to it
+        let mut tampered = envelope();
+        tampered.intent_hash = [0u8; 32];
+        assert!(tampered.chunk_cipher(&key).open_chunk(0, &sealed).is_err());
+    }
+
+    #[test]
+    fn check_reports_mismatches() {
+        let mut wrong = expected();
+        wrong.threshold = 2;
+        assert!(matches!(
+            envelope().check(&wrong),
+            Err(EnvelopeError::ThresholdMismatch {
+                expected: 2,
+                found: 1
+            })
+        ));
+
+        let mut wrong = expected();
+        wrong.intent_hash = [0u8; 32];
+        assert!(matches!(
+            envelope().check(&wrong),
+            Err(EnvelopeError::IntentMismatch)
+        ));
+    }
+}
@@ -0,0 +1,269 @@
+//! Validating committee hints
+//!
+//! Hints are kept per committee epoch, and keyed by committee index. A hint is only accepted
+//! if it decodes to valid curve points, claims the index it is announced for, fits in the
+//! committee and passes the STE consistency checks against the CRS. The first valid hint for
+//! an index wins: announcing the same hint again is a no-op (nodes republish theirs on
+//! restart), while a different hint for a taken index is rejected. Hints announced before
+//! the config is known are kept until it is, then validated.
+use crate::epoch::EpochError;
+use crate::types::Config;
+use ark_ec::pairing::Pairing;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use codec::{Decode, Encode};
+use silent_threshold_encryption::setup::PublicKey;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum HintError {
+    #[error("The hint announcement is malformed: {0}")]
+    Malformed(String),
+    #[error("There is no config to validate hints against yet")]
+    MissingConfig,
+    #[error("The hint is for index {found}, but was announced for index {expected}")]
+    IndexMismatch { expected: u32, found: usize },
+    #[error("Index {index} is outside of the committee (size {size})")]
+    OutOfRange { index: u32, size: usize },
+    #[error("The hint for index {0} is inconsistent with the CRS")]
+    Invalid(u32),
+    #[error("A different hint was already accepted for index {0}")]
+    Conflict(u32),
+    #[error("No hint was accepted for index {0} yet")]
+    Missing(u32),
+    #[error("The hint for index {index} was signed by member {signer}")]
+    SignerMismatch { signer: u32, index: u32 },
+    #[error("The hint for index {0} does not match the one registered on-chain")]
+    RegistryMismatch(u32),
+    #[error(transparent)]
+    Epoch(#[from] EpochError),
+}
+
+/// The doc key prefix of hints (`hint-<epoch>-<index>`)
+pub const HINT_KEY: &str = "hint";
+
+/// The doc key of a member's hint for an epoch
+pub fn hint_key(epoch: u64, index: u32) -> String {
+    format!("{}-{}-{}", HINT_KEY, epoch, index)
+}
+
+/// The data of a `Tag::Hint` announcement
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct HintAnnouncement {
+    /// the committee index of the node
+    pub index: u32,
+    /// the compressed STE public key (hint)
+    pub hint: Vec<u8>,
+    /// the RPC endpoints the node can be reached at, in order of preference
+    pub rpc_endpoints: Vec<String>,
+}
+
+impl HintAnnouncement {
+    pub fn new<C: Pairing>(
+        index: u32,
+        hint: &PublicKey<C>,
+        rpc_endpoints: Vec<String>,
+    ) -> Result<Self, HintError> {
+        let mut bytes = Vec::new();
+        hint.serialize_compressed(&mut bytes)
+            .map_err(|e| HintError::Malformed(e.to_string()))?;
+        Ok(Self {
+            index,
+            hint: bytes,
+            rpc_endpoints,
+        })
+    }
+}
+
+/// The accepted hints, by committee index
+#[derive(Clone)]
+pub struct HintSet<C: Pairing> {
+    hints: BTreeMap<u32, (Vec<u8>, PublicKey<C>)>,
+}
+
+impl<C: Pairing> Default for HintSet<C> {
+    fn default() -> Self {
+        Self {
+            hints: BTreeMap::new(),
+        }
+    }
+}
+
+impl<C: Pairing> HintSet<C> {
+    /// Validate and add a hint
+    /// Returns false if the exact same hint was already accepted for the index
+    pub fn insert(
+        &mut self,
+        config: &Config<C>,
+        announcement: &HintAnnouncement,
+    ) -> Result<bool, HintError> {
+        let index = announcement.index;
+        if index as usize >= config.size {
+            return Err(HintError::OutOfRange {
+                index,
+                size: config.size,
+            });
+        }
+
+        if let Some((accepted, _)) = self.hints.get(&index) {
+            if *accepted == announcement.hint {
+                return Ok(false);
+            }
+            return Err(HintError::Conflict(index));
+        }
+
+        // checks that the points are on the curve and in the right subgroup
+        let hint = PublicKey::<C>::deserialize_compressed(&announcement.hint[..])
+            .map_err(|e| HintError::Malformed(e.to_string()))?;
+        if hint.id != index as usize {
+            return Err(HintError::IndexMismatch {
+                expected: index,
+                found: hint.id,
+            });
+        }
+        if !hint.verify(&config.crs, &config.lag_polys) {
+            return Err(HintError::Invalid(index));
+        }
+
+        self.hints.insert(index, (announcement.hint.clone(), hint));
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hints.is_empty()
+    }
+
+    /// The hints, each at the position of its committee index
+    /// The system keys need a hint for every position, so a missing one is an error
+    pub fn to_vec(&self) -> Result<Vec<PublicKey<C>>, HintError> {
+        self.hints
+            .iter()
+            .enumerate()
+            .map(|(position, (index, (_, hint)))| {
+                if *index as usize != position {
+                    return Err(HintError::Missing(position as u32));
+                }
+                Ok(hint.clone())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::membership::{AnnouncementSigner, Members, member_entry};
+    use crate::types::{Announcement, E, GENESIS_EPOCH, NodeKeys, State, Tag};
+    use ark_std::rand::rngs::OsRng;
+    use silent_threshold_encryption::setup::SecretKey;
+
+    #[test]
+    fn hints_are_keyed_by_index_and_validated() {
+        let config = Config::<E>::rand(2);
+        let hint = |index: usize| {
+            let pk = SecretKey::<E>::new(&mut OsRng, index).get_pk(&config.crs);
+            HintAnnouncement::new(index as u32, &pk, vec![]).unwrap()
+        };
+        let mut hints = HintSet::<E>::default();
+
+        // a hint announced for another index
+        let mut stolen = hint(1);
+        stolen.index = 0;
+        assert!(matches!(
+            hints.insert(&config, &stolen),
+            Err(HintError::IndexMismatch { .. })
+        ));
+
+        let first = hint(0);
+        assert!(hints.insert(&config, &first).unwrap());
+        // the same hint again is ignored, a different one is rejected
+        assert!(!hints.insert(&config, &first).unwrap());
+        assert!(matches!(
+            hints.insert(&config, &hint(0)),
+            Err(HintError::Conflict(0))
+        ));
+
+        let mut outside = hint(1);
+        outside.index = 2;
+        assert!(matches!(
+            hints.insert(&config, &outside),
+            Err(HintError::OutOfRange { .. })
+        ));
+
+        assert!(matches!(
+            hints.insert(
+                &config,
+                &HintAnnouncement {
+                    index: 1,
+                    hint: vec![1, 2, 3],
+                    rpc_endpoints: vec![],
+                }
+            ),
+            Err(HintError::Malformed(_))
+        ));
+
+        assert!(hints.insert(&config, &hint(1)).unwrap());
+        assert_eq!(hints.len(), 2);
+    }
+
+    #[test]
+    fn hints_keep_their_position() {
+        let config = Config::<E>::rand(4);
+        let hint = |index: usize| {
+            let pk = SecretKey::<E>::new(&mut OsRng, index).get_pk(&config.crs);
+            HintAnnouncement::new(index as u32, &pk, vec![]).unwrap()
+        };
+        let mut hints = HintSet::<E>::default();
+        for index in [2, 0] {
+            hints.insert(&config, &hint(index)).unwrap();
+        }
+        // the hint of index 2 cannot take the place of index 1
+        assert!(matches!(hints.to_vec(), Err(HintError::Missing(1))));
+
+        hints.insert(&config, &hint(1)).unwrap();
+        let ids: Vec<usize> = hints.to_vec().unwrap().iter().map(|pk| pk.id).collect();
+        assert_eq!(ids, [0, 1, 2]);
+    }
+
+    #[test]
+    fn hints_announced_before_the_config_are_kept() {
+        let config = Config::<E>::rand(2);
+        let keys = NodeKeys::<E>::generate(0);
+        let pk = keys.secret_key.get_pk(&config.crs);
+        let signer = AnnouncementSigner::new(0, keys.iroh_secret_key.clone());
+        let members = Members::parse(&member_entry(0, &signer.node_id())).unwrap();
+        let mut state = State::<E>::empty(&signer, keys.secret_key, keys.transport_key, members)
+            .with_bootstrap(signer.node_id());
+        let hints = |state: &State<E>| state.epochs.get(GENESIS_EPOCH).unwrap().hints.len();
+
+        let hint = HintAnnouncement::new(0, &pk, vec![]).unwrap();
+        let announcement = Announcement {
+            tag: Tag::Hint,
+            data: hint.encode(),
+        };
+        state.receive(signer.sign(announcement, GENESIS_EPOCH));
+        assert_eq!(hints(&state), 0);
+
+        // a malformed config is reported, the hint keeps waiting for a valid one
+        let announcement = Announcement {
+            tag: Tag::Config,
+            data: vec![1, 2, 3],
+        };
+        state.receive(signer.sign(announcement, GENESIS_EPOCH));
+        assert!(state.config.is_none());
+        assert_eq!(hints(&state), 0);
+
+        let mut data = Vec::new();
+        config.serialize_compressed(&mut data).unwrap();
+        let announcement = Announcement {
+            tag: Tag::Config,
+            data,
+        };
+        state.receive(signer.sign(announcement, GENESIS_EPOCH));
+        assert_eq!(hints(&state), 1);
+    }
+}
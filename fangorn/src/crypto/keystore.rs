@@ -1,4 +1,15 @@
+use crate::{crypto::transport::TransportKeypair, types::NodeKeys};
+use ark_ec::pairing::Pairing;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_std::rand::{RngCore, rngs::OsRng};
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit,
+    aead::{Aead, Payload},
+};
+use codec::{Decode, Encode};
+use iroh::SecretKey as IrohSecretKey;
 use sc_keystore::LocalKeystore;
+use silent_threshold_encryption::setup::SecretKey;
 use sp_application_crypto::RuntimePublic;
 use sp_core::{
     Pair as PairT,
@@ -6,6 +17,8 @@ use sp_core::{
     sr25519,
 };
 use sp_keystore::{Keystore as SubstrateKeystore, KeystorePtr};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use std::path::PathBuf;
 use std::sync::Arc;
 use thiserror::Error;
@@ -22,6 +35,14 @@ pub enum KeystoreError {
     Io(#[from] std::io::Error),
     #[error("Secret string error: {0}")]
     SecretString(#[from] sp_core::crypto::SecretStringError),
+    #[error("A node keystore already exists at {0}")]
+    AlreadyExists(String),
+    #[error("No node keystore found at {0}, run `fangorn setup` first")]
+    NotFound(String),
+    #[error("Invalid keystore password")]
+    InvalidPassword,
+    #[error("The node keystore is corrupted: {0}")]
+    Corrupted(String),
 }
 
 /// Generic keystore trait that supports multiple cryptographic curves
@@ -165,3 +186,216 @@ impl Keystore for Sr25519Keystore {
         self.keystore.clone()
     }
 }
+
+/// The file holding the node keys within a node keystore directory
+pub const NODE_KEYSTORE_FILE: &str = "node.keystore";
+
+/// The current node keystore format version
+const NODE_KEYSTORE_VERSION: u8 = 1;
+
+/// scrypt cost parameters (N = 2^15, r = 8, p = 1)
+/// They are stored in the keystore file, so they can be raised later
+#[cfg(not(test))]
+const SCRYPT_LOG_N: u8 = 15;
+#[cfg(test)]
+const SCRYPT_LOG_N: u8 = 10;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// The node keystore file: the encoded node keys, encrypted under a key derived from the password
+#[derive(Encode, Decode)]
+struct SealedNodeKeys {
+    version: u8,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Encode, Decode)]
+struct EncodedNodeKeys {
+    index: u32,
+    secret_key: Vec<u8>,
+    iroh_secret_key: [u8; 32],
+    transport_key: [u8; 32],
+}
+
+/// A password protected keystore for the long-lived secrets of a node
+///
+/// It holds the threshold secret key and its committee index, the iroh identity
+/// and the transport key, so that a node keeps its hint (and can still decrypt
+/// everything encrypted to its committee) across restarts.
+pub struct NodeKeystore {
+    path: PathBuf,
+}
+
+impl NodeKeystore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            path: dir.join(NODE_KEYSTORE_FILE),
+        }
+    }
+
+    /// Whether the keystore file exists
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Write the node keys to a new keystore, never overwriting an existing one
+    pub fn create<C: Pairing>(
+        &self,
+        keys: &NodeKeys<C>,
+        password: &str,
+    ) -> Result<(), KeystoreError> {
+        let mut secret_key = Vec::new();
+        keys.secret_key
+            .serialize_compressed(&mut secret_key)
+            .map_err(|e| KeystoreError::Keystore(e.to_string()))?;
+        let encoded = EncodedNodeKeys {
+            index: keys.index as u32,
+            secret_key,
+            iroh_secret_key: keys.iroh_secret_key.to_bytes(),
+            transport_key: keys.transport_key.to_bytes(),
+        };
+
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let mut sealed = SealedNodeKeys {
+            version: NODE_KEYSTORE_VERSION,
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt,
+            nonce,
+            ciphertext: Vec::new(),
+        };
+        let cipher = derive_cipher(password, &sealed)?;
+        sealed.ciphertext = cipher
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: &encoded.encode(),
+                    aad: &[NODE_KEYSTORE_VERSION],
+                },
+            )
+            .map_err(|_| KeystoreError::Keystore("Failed to encrypt node keys".into()))?;
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&self.path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => {
+                KeystoreError::AlreadyExists(self.path.display().to_string())
+            }
+            _ => KeystoreError::Io(e),
+        })?;
+        file.write_all(&sealed.encode())?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Decrypt the node keys
+    pub fn load<C: Pairing>(&self, password: &str) -> Result<NodeKeys<C>, KeystoreError> {
+        let bytes = fs::read(&self.path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                KeystoreError::NotFound(self.path.display().to_string())
+            }
+            _ => KeystoreError::Io(e),
+        })?;
+
+        let sealed = SealedNodeKeys::decode(&mut &bytes[..])
+            .map_err(|e| KeystoreError::Corrupted(e.to_string()))?;
+        if sealed.version != NODE_KEYSTORE_VERSION {
+            return Err(KeystoreError::Corrupted(format!(
+                "unsupported version {}",
+                sealed.version
+            )));
+        }
+
+        let plaintext = derive_cipher(password, &sealed)?
+            .decrypt(
+                &sealed.nonce.into(),
+                Payload {
+                    msg: &sealed.ciphertext,
+                    aad: &[sealed.version],
+                },
+            )
+            .map_err(|_| KeystoreError::InvalidPassword)?;
+
+        let encoded = EncodedNodeKeys::decode(&mut &plaintext[..])
+            .map_err(|e| KeystoreError::Corrupted(e.to_string()))?;
+        let secret_key = SecretKey::<C>::deserialize_compressed(&encoded.secret_key[..])
+            .map_err(|e| KeystoreError::Corrupted(e.to_string()))?;
+
+        Ok(NodeKeys {
+            index: encoded.index as usize,
+            secret_key,
+            iroh_secret_key: IrohSecretKey::from_bytes(&encoded.iroh_secret_key),
+            transport_key: TransportKeypair::from_bytes(encoded.transport_key),
+        })
+    }
+}
+
+/// Derive the keystore cipher from the password with scrypt
+fn derive_cipher(
+    password: &str,
+    sealed: &SealedNodeKeys,
+) -> Result<ChaCha20Poly1305, KeystoreError> {
+    let params = scrypt::Params::new(sealed.log_n, sealed.r, sealed.p, 32)
+        .map_err(|e| KeystoreError::Corrupted(e.to_string()))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(password.as_bytes(), &sealed.salt, &params, &mut key)
+        .map_err(|e| KeystoreError::Keystore(e.to_string()))?;
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::E;
+
+    #[test]
+    fn node_keystore_roundtrip_works() {
+        let dir = std::env::temp_dir().join(format!("fangorn-keystore-{}", rand::random::<u64>()));
+        let keystore = NodeKeystore::new(dir.clone());
+        let keys = NodeKeys::<E>::generate(1);
+        keystore.create(&keys, "password").unwrap();
+
+        // never overwrite an existing keystore
+        assert!(matches!(
+            keystore.create(&keys, "password"),
+            Err(KeystoreError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            keystore.load::<E>("wrong"),
+            Err(KeystoreError::InvalidPassword)
+        ));
+
+        let loaded = keystore.load::<E>("password").unwrap();
+        assert_eq!(loaded.index, 1);
+        assert_eq!(
+            loaded.iroh_secret_key.to_bytes(),
+            keys.iroh_secret_key.to_bytes()
+        );
+        assert_eq!(
+            loaded.transport_key.to_bytes(),
+            keys.transport_key.to_bytes()
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+//! Migrating documents to the current committee epoch
+//!
+//! The committee of an older epoch releases the key of a document so that it can be
+//! re-encrypted to the current epoch's system keys. The key is released either under the
+//! document's own intents, or to its owner (the account that registered it), who signs the
+//! request instead of providing witnesses.
+use cid::Cid;
+use codec::Encode;
+use sp_core::{Pair, sr25519};
+use x25519_dalek::PublicKey;
+
+/// Domain separation for owner authorizations
+pub const MIGRATION_CONTEXT: &[u8] = b"fangorn-migrate-v1";
+
+/// How the committee of the old epoch is convinced to release the document key
+pub enum MigrationAuth<'a> {
+    /// witnesses satisfying the document's intents
+    Witnesses(&'a [&'a str]),
+    /// the owner of the registry entry
+    Owner(&'a sr25519::Pair),
+}
+
+/// The message an owner signs to release the key of a document version to a requester
+/// Binding the requester key means the authorization cannot be replayed to get the share
+pub fn owner_authorization_message(filename: &[u8], cid: &Cid, requester: &PublicKey) -> Vec<u8> {
+    (
+        MIGRATION_CONTEXT,
+        filename,
+        cid.to_bytes(),
+        requester.as_bytes(),
+    )
+        .encode()
+}
+
+pub fn sign_owner_authorization(
+    owner: &sr25519::Pair,
+    filename: &[u8],
+    cid: &Cid,
+    requester: &PublicKey,
+) -> Vec<u8> {
+    owner
+        .sign(&owner_authorization_message(filename, cid, requester))
+        .0
+        .to_vec()
+}
+
+pub fn verify_owner_authorization(
+    owner: &[u8; 32],
+    filename: &[u8],
+    cid: &Cid,
+    requester: &PublicKey,
+    signature: &[u8],
+) -> bool {
+    let Ok(signature) = sr25519::Signature::try_from(signature) else {
+        return false;
+    };
+    sr25519::Pair::verify(
+        &signature,
+        owner_authorization_message(filename, cid, requester),
+        &sr25519::Public::from_raw(*owner),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::transport::TransportKeypair;
+    use multihash_codetable::{Code, MultihashDigest};
+
+    #[test]
+    fn owner_authorizations_are_bound_to_the_request() {
+        let (owner, _) = sr25519::Pair::generate();
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(b"document"));
+        let requester = TransportKeypair::generate().public();
+        let signature = sign_owner_authorization(&owner, b"file", &cid, &requester);
+
+        let owner_key = owner.public().0;
+        assert!(verify_owner_authorization(
+            &owner_key, b"file", &cid, &requester, &signature
+        ));
+
+        // another requester, document or signer
+        let other = TransportKeypair::generate().public();
+        assert!(!verify_owner_authorization(
+            &owner_key, b"file", &cid, &other, &signature
+        ));
+        assert!(!verify_owner_authorization(
+            &owner_key, b"other", &cid, &requester, &signature
+        ));
+        let (stranger, _) = sr25519::Pair::generate();
+        assert!(!verify_owner_authorization(
+            &stranger.public().0,
+            b"file",
+            &cid,
+            &requester,
+            &signature
+        ));
+    }
+}
@@ -1,9 +1,17 @@
 use sp_core::crypto::KeyTypeId;
 
+pub mod ceremony;
 pub mod cipher;
+pub mod crs;
 pub mod decrypt;
 pub mod encrypt;
+pub mod envelope;
+pub mod hints;
 pub mod keystore;
+pub mod migration;
+pub mod reader;
+pub mod stream;
+pub mod transport;
 
 // fangorn key type
 pub const FANGORN: KeyTypeId = KeyTypeId(*b"fang");
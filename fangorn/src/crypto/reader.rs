@@ -0,0 +1,332 @@
+//! Random-access reads over an unlocked document
+use crate::{
+    crypto::{
+        envelope::Envelope,
+        stream::{ChunkCipher, KEY_LEN, StreamError},
+    },
+    storage::*,
+};
+use cid::Cid;
+use futures::{StreamExt, stream};
+use std::ops::Range;
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+#[derive(Error, Debug)]
+pub enum ReaderError {
+    #[error("An error occurred while communicating with the docstore: {0}")]
+    DocstoreError(String),
+    #[error("Ciphertext not found")]
+    CiphertextNotFound,
+    #[error("Chunk decryption failed: {0}")]
+    StreamError(#[from] StreamError),
+    #[error("The requested range starts past the end of the document")]
+    OutOfRange,
+    #[error("Failed to write plaintext: {0}")]
+    WriteError(String),
+}
+
+/// A reader over a document whose key has already been recovered
+///
+/// Any chunk range can be decrypted on demand, and only the ciphertext
+/// chunks covering the range are fetched from the docstore.
+pub struct DocumentReader<'a, D: DocStore> {
+    doc_store: &'a D,
+    cid: Cid,
+    payload_offset: u64,
+    cipher: ChunkCipher,
+}
+
+impl<'a, D: DocStore> DocumentReader<'a, D> {
+    pub fn new(doc_store: &'a D, cid: Cid, envelope: &Envelope, key: &[u8; KEY_LEN]) -> Self {
+        Self {
+            doc_store,
+            cid,
+            payload_offset: envelope.payload_offset(),
+            cipher: envelope.chunk_cipher(key),
+        }
+    }
+
+    /// The plaintext size of every chunk but the last
+    pub fn chunk_size(&self) -> u32 {
+        self.cipher.chunk_size()
+    }
+
+    /// The range of chunks holding the given plaintext byte range
+    /// A range running past the last possible chunk is cut short, one starting there is refused
+    pub fn chunks_for(&self, offset: u64, len: u64) -> Result<Range<u32>, ReaderError> {
+        let chunk_size = self.chunk_size() as u64;
+        let start = u32::try_from(offset / chunk_size)
+            .ok()
+            .filter(|start| *start < u32::MAX)
+            .ok_or(ReaderError::OutOfRange)?;
+        let end = offset
+            .saturating_add(len)
+            .div_ceil(chunk_size)
+            .clamp(start as u64 + 1, u32::MAX as u64);
+        Ok(start..end as u32)
+    }
+
+    /// Decrypt `len` plaintext bytes starting at `offset`
+    /// The result is shorter than `len` when the range runs past the end of the document
+    pub async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>, ReaderError> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let chunks = self.chunks_for(offset, len)?;
+        let skip = (offset - chunks.start as u64 * self.chunk_size() as u64) as usize;
+
+        let (plaintext, _) = self.read_chunks(chunks).await?;
+        let len = usize::try_from(len).unwrap_or(usize::MAX);
+        let end = skip.saturating_add(len).min(plaintext.len());
+        Ok(plaintext.get(skip..end).unwrap_or_default().to_vec())
+    }
+
+    /// Decrypt a range of chunks, returning the plaintext and whether the last chunk was reached
+    pub async fn read_chunks(&self, chunks: Range<u32>) -> Result<(Vec<u8>, bool), ReaderError> {
+        if chunks.is_empty() {
+            return Ok((Vec::new(), false));
+        }
+        let sealed_size = self.cipher.sealed_chunk_size();
+        let len = ((chunks.end - chunks.start) as u64)
+            .checked_mul(sealed_size)
+            .ok_or(ReaderError::OutOfRange)?;
+        let sealed = self.fetch(self.sealed_offset(chunks.start)?, len).await?;
+
+        if sealed.is_empty() {
+            return self
+                .check_end(chunks.start)
+                .await
+                .map(|_| (Vec::new(), true));
+        }
+
+        let mut plaintext = Vec::new();
+        for (index, piece) in chunks.clone().zip(sealed.chunks(sealed_size as usize)) {
+            let (chunk, last) = self.cipher.open_chunk(index, piece)?;
+            plaintext.extend(chunk);
+            if last {
+                return Ok((plaintext, true));
+            }
+        }
+
+        // the data ended on a chunk that is not the last one
+        if (sealed.len() as u64) < len {
+            return Err(StreamError::Truncated.into());
+        }
+
+        Ok((plaintext, false))
+    }
+
+    /// Decrypt the whole document into the writer, one chunk at a time
+    pub async fn write_to<W: AsyncWrite + Unpin + ?Sized>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), ReaderError> {
+        let mut index = 0u32;
+        loop {
+            let (chunk, last) = self.read_chunks(index..index + 1).await?;
+            writer
+                .write_all(&chunk)
+                .await
+                .map_err(|e| ReaderError::WriteError(e.to_string()))?;
+            if last {
+                break;
+            }
+            index += 1;
+        }
+
+        writer
+            .flush()
+            .await
+            .map_err(|e| ReaderError::WriteError(e.to_string()))
+    }
+
+    /// The sealed payload as stored, one chunk at a time
+    /// Used when only the header of a document changes, the chunks are not opened
+    pub fn sealed_payload(&self) -> DataStream<'_> {
+        let sealed_size = self.cipher.sealed_chunk_size();
+        stream::try_unfold((0u32, false), move |(index, done)| async move {
+            if done {
+                return Ok(None);
+            }
+            let sealed = self.fetch(self.sealed_offset(index)?, sealed_size).await?;
+            // the last chunk always carries a tag, so an empty read means the payload was truncated
+            if sealed.is_empty() {
+                return Err(StreamError::Truncated.into());
+            }
+            let last = (sealed.len() as u64) < sealed_size;
+            Ok::<_, ReaderError>(Some((sealed, (index + 1, last))))
+        })
+        .map(|chunk| chunk.map_err(anyhow::Error::from))
+        .boxed()
+    }
+
+    /// A read that starts right past the data is only valid if the previous chunk is the last one
+    async fn check_end(&self, index: u32) -> Result<(), ReaderError> {
+        if index == 0 {
+            return Err(StreamError::Truncated.into());
+        }
+        let sealed_size = self.cipher.sealed_chunk_size();
+        let previous = self
+            .fetch(self.sealed_offset(index - 1)?, sealed_size)
+            .await?;
+        if previous.is_empty() {
+            return Err(ReaderError::OutOfRange);
+        }
+        match self.cipher.open_chunk(index - 1, &previous)? {
+            (_, true) => Ok(()),
+            (_, false) => Err(StreamError::Truncated.into()),
+        }
+    }
+
+    /// The offset of a sealed chunk in the document
+    fn sealed_offset(&self, index: u32) -> Result<u64, ReaderError> {
+        (index as u64)
+            .checked_mul(self.cipher.sealed_chunk_size())
+            .and_then(|offset| offset.checked_add(self.payload_offset))
+            .ok_or(ReaderError::OutOfRange)
+    }
+
+    async fn fetch(&self, offset: u64, len: u64) -> Result<Vec<u8>, ReaderError> {
+        self.doc_store
+            .fetch_range(&self.cid, offset, len)
+            .await
+            .map_err(|e| ReaderError::DocstoreError(e.to_string()))?
+            .ok_or(ReaderError::CiphertextNotFound)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::stream::{NONCE_PREFIX_LEN, encrypt_stream};
+    use crate::storage::local_store::LocalDocStore;
+    use futures::TryStreamExt;
+
+    const KEY: [u8; KEY_LEN] = [5u8; KEY_LEN];
+    const PLAINTEXT: &[u8] = b"a document spread over a few chunks";
+
+    fn envelope() -> Envelope {
+        let mut envelope = Envelope::new(
+            vec![1, 2, 3],
+            1,
+            [7u8; 32],
+            0,
+            [9u8; 32],
+            [3u8; NONCE_PREFIX_LEN],
+        );
+        envelope.aead.chunk_size = 4;
+        envelope
+    }
+
+    fn docs_dir() -> String {
+        let dir = std::env::temp_dir().join(format!("fangorn-reader-{}", rand::random::<u64>()));
+        dir.to_str().unwrap().to_string()
+    }
+
+    async fn add_document(store: &LocalDocStore, envelope: &Envelope) -> Cid {
+        let mut bytes = envelope.to_bytes();
+        let chunks: Vec<Vec<u8>> = encrypt_stream(envelope.chunk_cipher(&KEY), PLAINTEXT)
+            .try_collect()
+            .await
+            .unwrap();
+        bytes.extend(chunks.concat());
+        store.add(&bytes).await.unwrap()
+    }
+
+    async fn migrate(store: &LocalDocStore, cid: Cid, from: &Envelope, to: &Envelope) -> Cid {
+        let reader = DocumentReader::new(store, cid, from, &KEY);
+        let payload = reader.sealed_payload();
+        let header = stream::once(async move { Ok(to.to_bytes()) });
+        store
+            .add_stream(header.chain(payload).boxed())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn migration_only_rewrites_the_header() {
+        let dir = docs_dir();
+        let store = LocalDocStore::new(&dir);
+        let original = envelope();
+        let cid = add_document(&store, &original).await;
+
+        let mut migrated = original.clone();
+        migrated.kem = vec![4, 5, 6, 7];
+        migrated.epoch = 1;
+        migrated.threshold = 2;
+        let new_cid = migrate(&store, cid, &original, &migrated).await;
+
+        // the sealed chunks are copied byte for byte
+        let old = store.fetch(&cid).await.unwrap().unwrap();
+        let new = store.fetch(&new_cid).await.unwrap().unwrap();
+        assert_eq!(
+            old[original.payload_offset() as usize..],
+            new[migrated.payload_offset() as usize..]
+        );
+
+        let reader = DocumentReader::new(&store, new_cid, &migrated, &KEY);
+        let mut plaintext = Vec::new();
+        reader.write_to(&mut plaintext).await.unwrap();
+        assert_eq!(plaintext, PLAINTEXT);
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ranges_are_mapped_to_chunks() {
+        let dir = docs_dir();
+        let store = LocalDocStore::new(&dir);
+        let envelope = envelope();
+        let cid = add_document(&store, &envelope).await;
+        let reader = DocumentReader::new(&store, cid, &envelope, &KEY);
+
+        assert_eq!(reader.chunks_for(0, 1).unwrap(), 0..1);
+        assert_eq!(reader.chunks_for(3, 2).unwrap(), 0..2);
+        assert_eq!(reader.chunks_for(4, 4).unwrap(), 1..2);
+        // an empty range still names the chunk it starts in
+        assert_eq!(reader.chunks_for(5, 0).unwrap(), 1..2);
+        // ranges running past the last possible chunk are cut short
+        assert_eq!(reader.chunks_for(0, u64::MAX).unwrap(), 0..u32::MAX);
+        let last = (u32::MAX as u64 - 1) * 4;
+        assert_eq!(
+            reader.chunks_for(last, u64::MAX).unwrap(),
+            u32::MAX - 1..u32::MAX
+        );
+        assert!(matches!(
+            reader.chunks_for(u32::MAX as u64 * 4, 1),
+            Err(ReaderError::OutOfRange)
+        ));
+        assert!(matches!(
+            reader.chunks_for(u64::MAX, u64::MAX),
+            Err(ReaderError::OutOfRange)
+        ));
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ranges_are_read_up_to_the_end() {
+        let dir = docs_dir();
+        let store = LocalDocStore::new(&dir);
+        let envelope = envelope();
+        let cid = add_document(&store, &envelope).await;
+        let reader = DocumentReader::new(&store, cid, &envelope, &KEY);
+
+        assert_eq!(reader.read_range(3, 6).await.unwrap(), &PLAINTEXT[3..9]);
+        assert_eq!(reader.read_range(30, 100).await.unwrap(), &PLAINTEXT[30..]);
+        assert_eq!(reader.read_range(0, u64::MAX).await.unwrap(), PLAINTEXT);
+        // right past the end there is nothing left to read
+        let end = PLAINTEXT.len() as u64;
+        assert!(reader.read_range(end, 4).await.unwrap().is_empty());
+
+        for offset in [end + 8, (u32::MAX as u64 - 1) * 4, u64::MAX - 3] {
+            assert!(matches!(
+                reader.read_range(offset, 4).await,
+                Err(ReaderError::OutOfRange)
+            ));
+        }
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
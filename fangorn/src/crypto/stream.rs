@@ -0,0 +1,228 @@
+//! Chunked authenticated encryption for large payloads (the DEM)
+//!
+//! Payloads are split into fixed-size chunks, each sealed with XChaCha20-Poly1305
+//! following the STREAM construction: the nonce is `prefix || counter || last_flag`,
+//! so chunks cannot be reordered, dropped or truncated without detection.
+//! Only one chunk is ever held in memory at a time.
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+use futures::stream::{self, BoxStream, StreamExt};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The default plaintext chunk size (64 KiB)
+pub const CHUNK_SIZE: u32 = 64 * 1024;
+/// The length of the Poly1305 tag appended to every chunk
+pub const TAG_LEN: usize = 16;
+/// The length of the random nonce prefix (24 byte nonce - 4 byte counter - 1 byte flag)
+pub const NONCE_PREFIX_LEN: usize = 19;
+/// The length of the symmetric key
+pub const KEY_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum StreamError {
+    #[error("Failed to encrypt chunk {0}")]
+    EncryptionError(u32),
+    #[error("Failed to decrypt chunk {0}")]
+    DecryptionError(u32),
+    #[error("The payload has too many chunks")]
+    TooManyChunks,
+    #[error("The payload is truncated")]
+    Truncated,
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Encrypts and decrypts individual chunks of a payload
+#[derive(Clone)]
+pub struct ChunkCipher {
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    chunk_size: u32,
+    aad: Vec<u8>,
+}
+
+impl ChunkCipher {
+    /// Build a chunk cipher
+    /// The associated data is bound to every chunk
+    pub fn new(
+        key: &[u8; KEY_LEN],
+        nonce_prefix: [u8; NONCE_PREFIX_LEN],
+        chunk_size: u32,
+        aad: &[u8],
+    ) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+            nonce_prefix,
+            chunk_size,
+            aad: aad.to_vec(),
+        }
+    }
+
+    /// The plaintext size of every chunk but the last
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    /// The ciphertext size of every chunk but the last
+    pub fn sealed_chunk_size(&self) -> u64 {
+        self.chunk_size as u64 + TAG_LEN as u64
+    }
+
+    /// Encrypt the chunk at the given index
+    pub fn encrypt_chunk(
+        &self,
+        index: u32,
+        last: bool,
+        chunk: &[u8],
+    ) -> Result<Vec<u8>, StreamError> {
+        self.cipher
+            .encrypt(
+                &self.nonce(index, last),
+                Payload {
+                    msg: chunk,
+                    aad: &self.aad,
+                },
+            )
+            .map_err(|_| StreamError::EncryptionError(index))
+    }
+
+    /// Decrypt the chunk at the given index
+    pub fn decrypt_chunk(
+        &self,
+        index: u32,
+        last: bool,
+        chunk: &[u8],
+    ) -> Result<Vec<u8>, StreamError> {
+        self.cipher
+            .decrypt(
+                &self.nonce(index, last),
+                Payload {
+                    msg: chunk,
+                    aad: &self.aad,
+                },
+            )
+            .map_err(|_| StreamError::DecryptionError(index))
+    }
+
+    /// Decrypt a sealed chunk fetched from storage
+    /// A chunk shorter than a full sealed chunk is the last one; the last chunk
+    /// always carries at least a tag, so an empty read means the payload was truncated.
+    pub fn open_chunk(&self, index: u32, sealed: &[u8]) -> Result<(Vec<u8>, bool), StreamError> {
+        if sealed.len() < TAG_LEN {
+            return Err(StreamError::Truncated);
+        }
+        let last = (sealed.len() as u64) < self.sealed_chunk_size();
+        let plaintext = self.decrypt_chunk(index, last, sealed)?;
+        Ok((plaintext, last))
+    }
+
+    /// nonce = prefix || counter (big endian) || last flag
+    fn nonce(&self, index: u32, last: bool) -> XNonce {
+        let mut nonce = [0u8; 24];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..NONCE_PREFIX_LEN + 4].copy_from_slice(&index.to_be_bytes());
+        nonce[23] = last as u8;
+        nonce.into()
+    }
+}
+
+/// Lazily encrypt everything read from the reader, yielding sealed chunks
+/// Every chunk is full-size except the last, which may be empty
+pub fn encrypt_stream<'a, R>(
+    cipher: ChunkCipher,
+    reader: R,
+) -> BoxStream<'a, Result<Vec<u8>, StreamError>>
+where
+    R: AsyncRead + Unpin + Send + 'a,
+{
+    stream::try_unfold(
+        (cipher, reader, 0u32, false),
+        |(cipher, mut reader, index, done)| async move {
+            if done {
+                return Ok(None);
+            }
+            let chunk = read_chunk(&mut reader, cipher.chunk_size() as usize).await?;
+            let last = chunk.len() < cipher.chunk_size() as usize;
+            let sealed = cipher.encrypt_chunk(index, last, &chunk)?;
+            let next = if last {
+                index
+            } else {
+                index.checked_add(1).ok_or(StreamError::TooManyChunks)?
+            };
+            Ok(Some((sealed, (cipher, reader, next, last))))
+        },
+    )
+    .boxed()
+}
+
+/// Read up to `size` bytes, stopping early only at EOF
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, size: usize) -> std::io::Result<Vec<u8>> {
+    let mut chunk = vec![0u8; size];
+    let mut filled = 0;
+    while filled < size {
+        let n = reader.read(&mut chunk[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    chunk.truncate(filled);
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::TryStreamExt;
+
+    fn cipher(chunk_size: u32) -> ChunkCipher {
+        ChunkCipher::new(
+            &[7u8; KEY_LEN],
+            [3u8; NONCE_PREFIX_LEN],
+            chunk_size,
+            b"header",
+        )
+    }
+
+    #[tokio::test]
+    async fn encrypt_and_open_chunks_roundtrip() {
+        // an exact multiple of the chunk size ends with an empty last chunk
+        for len in [0usize, 5, 16, 40] {
+            let plaintext: Vec<u8> = (0..len as u8).collect();
+            let sealed: Vec<Vec<u8>> = encrypt_stream(cipher(8), &plaintext[..])
+                .try_collect()
+                .await
+                .unwrap();
+            assert_eq!(sealed.len(), len / 8 + 1);
+
+            let mut recovered = Vec::new();
+            for (i, chunk) in sealed.iter().enumerate() {
+                let (pt, last) = cipher(8).open_chunk(i as u32, chunk).unwrap();
+                assert_eq!(last, i == sealed.len() - 1);
+                recovered.extend(pt);
+            }
+            assert_eq!(recovered, plaintext);
+        }
+    }
+
+    #[tokio::test]
+    async fn reordered_or_truncated_chunks_fail() {
+        let plaintext = [1u8; 20];
+        let sealed: Vec<Vec<u8>> = encrypt_stream(cipher(8), &plaintext[..])
+            .try_collect()
+            .await
+            .unwrap();
+        // swapped chunks
+        assert!(cipher(8).open_chunk(0, &sealed[1]).is_err());
+        // a full chunk can never be opened as the last chunk
+        assert!(cipher(8).decrypt_chunk(1, true, &sealed[1]).is_err());
+        // missing tail
+        assert!(matches!(
+            cipher(8).open_chunk(3, &[]),
+            Err(StreamError::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,215 @@
+//! Transport encryption for data exchanged with Fangorn nodes
+//!
+//! A minimal HPKE-style construction (base mode): X25519 for key agreement,
+//! HKDF-SHA256 for key derivation and ChaCha20-Poly1305 as the AEAD.
+//! Every call to [`seal`] uses a fresh ephemeral key, so the derived AEAD key
+//! is only ever used once and a fixed nonce is safe.
+use ark_std::rand::rngs::OsRng;
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit,
+    aead::{Aead, Payload},
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// The size of an encoded X25519 public key
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Domain separator for key derivation
+const KDF_LABEL: &[u8] = b"fangorn-transport-v1";
+
+/// Context prefix for partial decryptions sealed to a requester
+const PARTDEC_CONTEXT: &[u8] = b"fangorn-partdec:";
+
+/// Context prefix for witnesses sealed to a node
+const WITNESS_CONTEXT: &[u8] = b"fangorn-witness:";
+
+#[derive(Error, Debug)]
+pub enum TransportError {
+    #[error("Invalid transport public key: {0}")]
+    InvalidPublicKey(String),
+    #[error("Sealed message is malformed")]
+    Malformed,
+    #[error("Key agreement produced a non-contributory shared secret")]
+    NonContributory,
+    #[error("Failed to seal message")]
+    SealError,
+    #[error("Failed to open sealed message")]
+    OpenError,
+}
+
+/// An X25519 keypair that can open messages sealed to its public key
+#[derive(Clone)]
+pub struct TransportKeypair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl TransportKeypair {
+    /// Generate a new random keypair
+    pub fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// Rebuild a keypair from raw secret key bytes
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// The raw secret key bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    /// The public key
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+
+    /// The hex-encoded public key
+    pub fn public_hex(&self) -> String {
+        hex::encode(self.public.as_bytes())
+    }
+
+    /// Open a message that was sealed to this keypair under the given context
+    pub fn open(&self, context: &[u8], sealed: &[u8]) -> Result<Vec<u8>, TransportError> {
+        if sealed.len() < PUBLIC_KEY_LEN {
+            return Err(TransportError::Malformed);
+        }
+        let (enc, ciphertext) = sealed.split_at(PUBLIC_KEY_LEN);
+        let enc: [u8; PUBLIC_KEY_LEN] = enc.try_into().map_err(|_| TransportError::Malformed)?;
+        let ephemeral = PublicKey::from(enc);
+
+        let shared = self.secret.diffie_hellman(&ephemeral);
+        if !shared.was_contributory() {
+            return Err(TransportError::NonContributory);
+        }
+
+        let cipher = derive_cipher(shared.as_bytes(), &ephemeral, &self.public, context);
+        cipher
+            .decrypt(
+                &[0u8; 12].into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: context,
+                },
+            )
+            .map_err(|_| TransportError::OpenError)
+    }
+}
+
+/// The context a partial decryption of the given file is sealed under
+pub fn partdec_context(filename: &[u8]) -> Vec<u8> {
+    let mut context = PARTDEC_CONTEXT.to_vec();
+    context.extend_from_slice(filename);
+    context
+}
+
+/// The context a witness for the given file is sealed under when sent to a node
+/// It also serves as the verification context for gadgets, binding witnesses to that node
+pub fn witness_context(filename: &[u8], node: &PublicKey) -> Vec<u8> {
+    let mut context = WITNESS_CONTEXT.to_vec();
+    context.extend_from_slice(node.as_bytes());
+    context.extend_from_slice(filename);
+    context
+}
+
+/// The transport key of the node a witness context was built for
+pub fn witness_node(context: &[u8]) -> Option<[u8; PUBLIC_KEY_LEN]> {
+    context
+        .strip_prefix(WITNESS_CONTEXT)?
+        .get(..PUBLIC_KEY_LEN)?
+        .try_into()
+        .ok()
+}
+
+/// Parse a hex-encoded X25519 public key
+pub fn decode_public_key(public_hex: &str) -> Result<PublicKey, TransportError> {
+    let bytes =
+        hex::decode(public_hex).map_err(|e| TransportError::InvalidPublicKey(e.to_string()))?;
+    let bytes: [u8; PUBLIC_KEY_LEN] = bytes.try_into().map_err(|_| {
+        TransportError::InvalidPublicKey(format!("expected {} bytes", PUBLIC_KEY_LEN))
+    })?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Seal a message to the recipient's public key
+/// The context is bound to the ciphertext and must match when opening
+/// Output: ephemeral public key (32 bytes) || ciphertext
+pub fn seal(
+    recipient: &PublicKey,
+    context: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, TransportError> {
+    let ephemeral_secret = StaticSecret::random_from_rng(OsRng);
+    let ephemeral = PublicKey::from(&ephemeral_secret);
+
+    let shared = ephemeral_secret.diffie_hellman(recipient);
+    if !shared.was_contributory() {
+        return Err(TransportError::NonContributory);
+    }
+
+    let cipher = derive_cipher(shared.as_bytes(), &ephemeral, recipient, context);
+    let ciphertext = cipher
+        .encrypt(
+            &[0u8; 12].into(),
+            Payload {
+                msg: plaintext,
+                aad: context,
+            },
+        )
+        .map_err(|_| TransportError::SealError)?;
+
+    let mut sealed = ephemeral.as_bytes().to_vec();
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+/// Derive the AEAD from the shared secret, binding both public keys and the context
+fn derive_cipher(
+    shared: &[u8],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+    context: &[u8],
+) -> ChaCha20Poly1305 {
+    let mut salt = ephemeral.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let mut info = KDF_LABEL.to_vec();
+    info.extend_from_slice(context);
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+    ChaCha20Poly1305::new(&key.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seal_and_open_works() {
+        let keypair = TransportKeypair::generate();
+        let sealed = seal(&keypair.public(), b"ctx", b"hello fangorn").unwrap();
+        let opened = keypair.open(b"ctx", &sealed).unwrap();
+        assert_eq!(opened, b"hello fangorn");
+    }
+
+    #[test]
+    fn open_fails_with_wrong_key_or_context() {
+        let keypair = TransportKeypair::generate();
+        let other = TransportKeypair::generate();
+        let sealed = seal(&keypair.public(), b"ctx", b"hello fangorn").unwrap();
+        assert!(other.open(b"ctx", &sealed).is_err());
+        assert!(keypair.open(b"other-ctx", &sealed).is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//! Committee epochs
+//!
+//! The committee of an epoch is fixed: its members, their hints (and so its system keys) and
+//! where to reach them. Members read the committee they want from their members file or the
+//! committee registry, and approve a change to it; the next epoch starts once a majority of
+//! the current members approved the same change, and its members then publish hints for it.
+//! Ciphertexts name the epoch they were encrypted under, so old epochs stay usable for
+//! decryption until a majority of the current members approves their retirement.
+use crate::committee::Committee;
+use crate::crypto::hints::HintSet;
+use crate::membership::{MemberEntry, Members, MembershipError};
+use ark_ec::pairing::Pairing;
+use codec::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use thiserror::Error;
+
+/// The doc key prefix of epoch change approvals (`epoch-<epoch>-<index>`)
+pub const EPOCH_CHANGE_KEY: &str = "epoch";
+/// The doc key prefix of epoch retirement approvals (`retire-<epoch>-<index>`)
+pub const EPOCH_RETIREMENT_KEY: &str = "retire";
+
+#[derive(Error, Debug)]
+pub enum EpochError {
+    #[error("Epoch {0} is unknown")]
+    UnknownEpoch(u64),
+    #[error("Epoch {0} is retired")]
+    Retired(u64),
+    #[error("No epoch can follow epoch {0}")]
+    Exhausted(u64),
+    #[error("Expected a change to epoch {expected}, found epoch {found}")]
+    UnexpectedEpoch { expected: u64, found: u64 },
+    #[error("The committee of epoch {epoch} is invalid: {reason}")]
+    InvalidCommittee { epoch: u64, reason: String },
+    #[error(transparent)]
+    Membership(#[from] MembershipError),
+}
+
+/// A change of the committee, starting `epoch`
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct EpochChange {
+    pub epoch: u64,
+    pub members: Vec<MemberEntry>,
+}
+
+impl EpochChange {
+    /// Approvals are counted per change, so members must approve identical changes
+    pub fn id(&self) -> [u8; 32] {
+        Sha256::digest(self.encode()).into()
+    }
+}
+
+/// The retirement of every epoch before `retire_before`
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct EpochRetirement {
+    pub retire_before: u64,
+}
+
+/// The doc key of a member's approval of an epoch change
+pub fn epoch_change_key(epoch: u64, index: u32) -> String {
+    format!("{}-{}-{}", EPOCH_CHANGE_KEY, epoch, index)
+}
+
+/// The doc key of a member's approval of an epoch retirement
+pub fn epoch_retirement_key(retire_before: u64, index: u32) -> String {
+    format!("{}-{}-{}", EPOCH_RETIREMENT_KEY, retire_before, index)
+}
+
+/// The committee of an epoch
+#[derive(Clone)]
+pub struct Epoch<C: Pairing> {
+    /// the admitted members, only their announcements for the epoch are applied
+    pub members: Members,
+    /// the validated hints, by committee index
+    pub hints: HintSet<C>,
+    /// the RPC endpoints of the members, published with their hints (or registered on-chain)
+    pub committee: Committee,
+    /// retired epochs are no longer served
+    pub retired: bool,
+}
+
+impl<C: Pairing> Epoch<C> {
+    fn new(members: Members) -> Self {
+        Self {
+            committee: Committee::from_members(&members),
+            members,
+            hints: HintSet::default(),
+            retired: false,
+        }
+    }
+}
+
+/// The committee epochs known to a node
+#[derive(Clone)]
+pub struct Epochs<C: Pairing> {
+    epochs: BTreeMap<u64, Epoch<C>>,
+    current: u64,
+    /// the epochs before this one are retired
+    retire_before: u64,
+    /// approvals of changes to the next epoch, by change id
+    changes: BTreeMap<[u8; 32], (EpochChange, BTreeSet<u32>)>,
+    /// approvals of later epochs (of the current committee), by change id
+    adoptions: BTreeMap<[u8; 32], (EpochChange, BTreeSet<u32>)>,
+    /// approvals of retirements, by the epoch to retire before
+    retirements: BTreeMap<u64, BTreeSet<u32>>,
+}
+
+impl<C: Pairing> Epochs<C> {
+    /// Start at `epoch` with the given committee
+    pub fn new(epoch: u64, members: Members) -> Self {
+        Self {
+            epochs: BTreeMap::from([(epoch, Epoch::new(members))]),
+            current: epoch,
+            retire_before: 0,
+            changes: BTreeMap::new(),
+            adoptions: BTreeMap::new(),
+            retirements: BTreeMap::new(),
+        }
+    }
+
+    /// The current epoch
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    /// The committee of the current epoch
+    pub fn current_epoch(&self) -> &Epoch<C> {
+        &self.epochs[&self.current]
+    }
+
+    pub fn retire_before(&self) -> u64 {
+        self.retire_before
+    }
+
+    /// The committee of an epoch that is known and not retired
+    pub fn get(&self, epoch: u64) -> Result<&Epoch<C>, EpochError> {
+        match self.epochs.get(&epoch) {
+            Some(committee) if committee.retired => Err(EpochError::Retired(epoch)),
+            Some(committee) => Ok(committee),
+            None => Err(EpochError::UnknownEpoch(epoch)),
+        }
+    }
+
+    pub fn get_mut(&mut self, epoch: u64) -> Result<&mut Epoch<C>, EpochError> {
+        match self.epochs.get_mut(&epoch) {
+            Some(committee) if committee.retired => Err(EpochError::Retired(epoch)),
+            Some(committee) => Ok(committee),
+            None => Err(EpochError::UnknownEpoch(epoch)),
+        }
+    }
+
+    /// Record a current member's approval of a change
+    /// Returns true if the change was approved by a majority and the next epoch started
+    /// `capacity` is the committee size the CRS supports, if known
+    pub fn approve_change(
+        &mut self,
+        signer: u32,
+        change: EpochChange,
+        capacity: Option<usize>,
+    ) -> Result<bool, EpochError> {
+        let next = self
+            .current
+            .checked_add(1)
+            .ok_or(EpochError::Exhausted(self.current))?;
+        if change.epoch != next {
+            return Err(EpochError::UnexpectedEpoch {
+                expected: next,
+                found: change.epoch,
+            });
+        }
+        let members = Self::check_committee(&change, capacity)?;
+
+        let (_, approvals) = self
+            .changes
+            .entry(change.id())
+            .or_insert_with(|| (change, BTreeSet::new()));
+        approvals.insert(signer);
+        let approvals = approvals.len();
+        if !self.is_majority(approvals) {
+            return Ok(false);
+        }
+
+        self.epochs.insert(next, Epoch::new(members));
+        self.current = next;
+        self.changes.clear();
+        Ok(true)
+    }
+
+    /// Join the committee at a later epoch
+    /// A node that starts after the committee changed cannot follow the changes it missed,
+    /// but it trusts its members source: once a majority of those members approved a change
+    /// to exactly that committee, it tells which epoch the committee is in
+    /// Returns true if the epoch was adopted; the epochs known so far are kept
+    pub fn adopt(
+        &mut self,
+        signer: u32,
+        change: EpochChange,
+        capacity: Option<usize>,
+    ) -> Result<bool, EpochError> {
+        if change.epoch <= self.current
+            || change.members != self.current_epoch().members.entries()
+            || self.current_epoch().members.get(signer).is_none()
+        {
+            return Ok(false);
+        }
+        let members = Self::check_committee(&change, capacity)?;
+
+        let epoch = change.epoch;
+        let (_, approvals) = self
+            .adoptions
+            .entry(change.id())
+            .or_insert_with(|| (change, BTreeSet::new()));
+        approvals.insert(signer);
+        let approvals = approvals.len();
+        if !self.is_majority(approvals) {
+            return Ok(false);
+        }
+
+        self.epochs.insert(epoch, Epoch::new(members));
+        self.current = epoch;
+        self.changes.clear();
+        self.adoptions.clear();
+        Ok(true)
+    }
+
+    /// Record a current member's approval of a retirement
+    /// Returns true if the retirement was approved by a majority and applied
+    pub fn approve_retirement(
+        &mut self,
+        signer: u32,
+        retirement: EpochRetirement,
+    ) -> Result<bool, EpochError> {
+        let retire_before = retirement.retire_before;
+        // the current epoch is never retired
+        if retire_before > self.current {
+            return Err(EpochError::UnexpectedEpoch {
+                expected: self.current,
+                found: retire_before,
+            });
+        }
+        if retire_before <= self.retire_before {
+            return Ok(false);
+        }
+
+        let approvals = self.retirements.entry(retire_before).or_default();
+        approvals.insert(signer);
+        let approvals = approvals.len();
+        if !self.is_majority(approvals) {
+            return Ok(false);
+        }
+
+        for (_, committee) in self.epochs.range_mut(..retire_before) {
+            committee.retired = true;
+            committee.hints = HintSet::default();
+        }
+        self.retire_before = retire_before;
+        self.retirements.retain(|epoch, _| *epoch > retire_before);
+        Ok(true)
+    }
+
+    fn is_majority(&self, approvals: usize) -> bool {
+        approvals * 2 > self.current_epoch().members.len()
+    }
+
+    fn check_committee(
+        change: &EpochChange,
+        capacity: Option<usize>,
+    ) -> Result<Members, EpochError> {
+        let invalid = |reason: String| EpochError::InvalidCommittee {
+            epoch: change.epoch,
+            reason,
+        };
+        if change.members.is_empty() {
+            return Err(invalid("the committee is empty".into()));
+        }
+        let beyond = capacity.and_then(|capacity| {
+            change
+                .members
+                .iter()
+                .find(|member| member.index as usize >= capacity)
+                .map(|member| (member.index, capacity))
+        });
+        if let Some((index, capacity)) = beyond {
+            return Err(invalid(format!(
+                "index {} is beyond the CRS capacity ({})",
+                index, capacity
+            )));
+        }
+        Ok(Members::from_entries(&change.members)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::E;
+    use ark_std::rand::rngs::OsRng;
+    use iroh::SecretKey as IrohSecretKey;
+
+    fn committee(indices: &[u32]) -> Members {
+        let mut members = Members::default();
+        for index in indices {
+            members
+                .admit(*index, IrohSecretKey::generate(OsRng).public())
+                .unwrap();
+        }
+        members
+    }
+
+    #[test]
+    fn a_majority_changes_and_retires_epochs() {
+        let mut epochs = Epochs::<E>::new(0, committee(&[0, 1, 2]));
+        let grown = EpochChange {
+            epoch: 1,
+            members: committee(&[0, 1, 2, 3]).entries(),
+        };
+
+        // a single member cannot change the committee
+        assert!(!epochs.approve_change(0, grown.clone(), Some(4)).unwrap());
+        // members must agree on the same change
+        let other = EpochChange {
+            epoch: 1,
+            members: committee(&[0]).entries(),
+        };
+        assert!(!epochs.approve_change(1, other, Some(4)).unwrap());
+        assert!(epochs.approve_change(2, grown.clone(), Some(4)).unwrap());
+        assert_eq!(epochs.current(), 1);
+        assert_eq!(epochs.current_epoch().members.len(), 4);
+        // the old epoch is still usable
+        assert!(epochs.get(0).is_ok());
+
+        // changes must start the next epoch and fit in the CRS
+        assert!(matches!(
+            epochs.approve_change(0, grown, Some(4)),
+            Err(EpochError::UnexpectedEpoch { .. })
+        ));
+        let too_big = EpochChange {
+            epoch: 2,
+            members: committee(&[0, 4]).entries(),
+        };
+        assert!(matches!(
+            epochs.approve_change(0, too_big, Some(4)),
+            Err(EpochError::InvalidCommittee { .. })
+        ));
+
+        // retiring needs a majority of the current (four) members
+        let retirement = EpochRetirement { retire_before: 1 };
+        assert!(!epochs.approve_retirement(0, retirement.clone()).unwrap());
+        assert!(!epochs.approve_retirement(1, retirement.clone()).unwrap());
+        assert!(epochs.approve_retirement(3, retirement).unwrap());
+        assert!(matches!(epochs.get(0), Err(EpochError::Retired(0))));
+        assert!(epochs.get(1).is_ok());
+        assert!(matches!(
+            epochs.approve_retirement(0, EpochRetirement { retire_before: 2 }),
+            Err(EpochError::UnexpectedEpoch { .. })
+        ));
+    }
+
+    #[test]
+    fn late_joiners_adopt_the_current_epoch() {
+        let members = committee(&[0, 3]);
+        let mut epochs = Epochs::<E>::new(0, members.clone());
+        let change = EpochChange {
+            epoch: 5,
+            members: members.entries(),
+        };
+
+        // only a change to the committee we trust, approved by a majority of its members
+        let mut other = change.clone();
+        other.members.pop();
+        assert!(!epochs.adopt(0, other, None).unwrap());
+        assert!(!epochs.adopt(1, change.clone(), None).unwrap());
+        assert!(!epochs.adopt(3, change.clone(), None).unwrap());
+        assert!(epochs.adopt(0, change, None).unwrap());
+        assert_eq!(epochs.current(), 5);
+        // documents of the epochs known before stay decryptable
+        assert!(epochs.get(0).is_ok());
+
+        // there is no epoch after the last one
+        let last = EpochChange {
+            epoch: u64::MAX,
+            members: members.entries(),
+        };
+        assert!(!epochs.adopt(0, last.clone(), None).unwrap());
+        assert!(epochs.adopt(3, last, None).unwrap());
+        let next = EpochChange {
+            epoch: 0,
+            members: members.entries(),
+        };
+        assert!(matches!(
+            epochs.approve_change(0, next, None),
+            Err(EpochError::Exhausted(u64::MAX))
+        ));
+    }
+}
@@ -0,0 +1,326 @@
+//! Querying the committee concurrently
+//!
+//! Requests are sent to every member at once. Each member gets a deadline and a number of
+//! attempts (with exponential backoff between them), so a slow or hung node never stalls the
+//! caller. Collection stops as soon as enough members have answered, and the outcome for each
+//! member is reported back. Members known to be live can be asked first, and the others only
+//! for the answers still missing.
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::{collections::BTreeMap, fmt, future::Future, time::Duration};
+
+/// How long to wait for each member, and how often to retry
+#[derive(Clone, Debug)]
+pub struct FanoutPolicy {
+    /// the time a member has to answer, over all attempts
+    pub deadline: Duration,
+    /// the time a single attempt may take
+    pub attempt_timeout: Duration,
+    /// the number of attempts per member
+    pub attempts: u32,
+    /// the wait before the first retry, doubled after every failed attempt
+    pub initial_backoff: Duration,
+    /// the longest wait between attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for FanoutPolicy {
+    fn default() -> Self {
+        Self {
+            deadline: Duration::from_secs(30),
+            attempt_timeout: Duration::from_secs(10),
+            attempts: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(4),
+        }
+    }
+}
+
+impl FanoutPolicy {
+    /// The wait after the given (zero based) failed attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+/// Why an attempt failed
+#[derive(Clone, Debug)]
+pub enum AttemptError {
+    /// the member could not be reached or failed transiently, worth retrying
+    Retry(String),
+    /// the member answered, but the answer is not usable (retrying won't help)
+    Reject(String),
+}
+
+/// What happened with a member
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeOutcome {
+    /// the member answered
+    Answered,
+    /// the member answered with something unusable
+    Rejected(String),
+    /// every attempt failed
+    Unreachable(String),
+    /// the member did not answer before its deadline
+    TimedOut,
+    /// enough members answered before this one did
+    NotNeeded,
+}
+
+impl fmt::Display for NodeOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeOutcome::Answered => write!(f, "answered"),
+            NodeOutcome::Rejected(reason) => write!(f, "rejected ({})", reason),
+            NodeOutcome::Unreachable(reason) => write!(f, "unreachable ({})", reason),
+            NodeOutcome::TimedOut => write!(f, "timed out"),
+            NodeOutcome::NotNeeded => write!(f, "not needed"),
+        }
+    }
+}
+
+/// The outcome for each member that was queried
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FanoutReport {
+    /// the number of answers that were needed
+    pub threshold: usize,
+    /// the outcome by committee index
+    pub outcomes: BTreeMap<usize, NodeOutcome>,
+}
+
+impl FanoutReport {
+    /// The number of members that answered
+    pub fn answered(&self) -> usize {
+        self.outcomes
+            .values()
+            .filter(|outcome| **outcome == NodeOutcome::Answered)
+            .count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.answered() >= self.threshold
+    }
+}
+
+impl fmt::Display for FanoutReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} answers", self.answered(), self.threshold)?;
+        for (index, outcome) in &self.outcomes {
+            write!(f, "; node {}: {}", index, outcome)?;
+        }
+        Ok(())
+    }
+}
+
+/// Send a request to all members concurrently and collect the first `threshold` answers
+pub async fn fan_out<T, F, Fut>(
+    members: impl IntoIterator<Item = usize>,
+    threshold: usize,
+    policy: &FanoutPolicy,
+    request: F,
+) -> (Vec<(usize, T)>, FanoutReport)
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = Result<T, AttemptError>>,
+{
+    let mut report = FanoutReport {
+        threshold,
+        outcomes: BTreeMap::new(),
+    };
+    let mut pending = FuturesUnordered::new();
+    for index in members {
+        report.outcomes.insert(index, NodeOutcome::NotNeeded);
+        let attempt = &request;
+        pending.push(async move {
+            let outcome =
+                tokio::time::timeout(policy.deadline, with_retries(policy, || attempt(index)))
+                    .await
+                    .unwrap_or(Err(NodeOutcome::TimedOut));
+            (index, outcome)
+        });
+    }
+
+    let mut answers = Vec::new();
+    while answers.len() < threshold {
+        let Some((index, outcome)) = pending.next().await else {
+            break;
+        };
+        match outcome {
+            Ok(answer) => {
+                report.outcomes.insert(index, NodeOutcome::Answered);
+                answers.push((index, answer));
+            }
+            Err(outcome) => {
+                report.outcomes.insert(index, outcome);
+            }
+        }
+    }
+    // dropping the pending requests cancels them
+
+    (answers, report)
+}
+
+/// Ask the preferred members first, and the others only for the answers still missing
+/// Without enough preferred members to reach the threshold, everyone is asked at once
+pub async fn fan_out_preferring<T, F, Fut>(
+    preferred: Vec<usize>,
+    others: Vec<usize>,
+    threshold: usize,
+    policy: &FanoutPolicy,
+    request: F,
+) -> (Vec<(usize, T)>, FanoutReport)
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = Result<T, AttemptError>>,
+{
+    if preferred.len() < threshold {
+        return fan_out(
+            preferred.into_iter().chain(others),
+            threshold,
+            policy,
+            request,
+        )
+        .await;
+    }
+    let (mut answers, mut report) = fan_out(preferred, threshold, policy, &request).await;
+    if !report.is_complete() && !others.is_empty() {
+        let missing = threshold - answers.len();
+        let (more, more_report) = fan_out(others, missing, policy, &request).await;
+        answers.extend(more);
+        report.outcomes.extend(more_report.outcomes);
+    }
+    (answers, report)
+}
+
+async fn with_retries<T, F, Fut>(policy: &FanoutPolicy, attempt: F) -> Result<T, NodeOutcome>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, AttemptError>>,
+{
+    let mut reason = String::from("no attempt was made");
+    for i in 0..policy.attempts {
+        if i > 0 {
+            tokio::time::sleep(policy.backoff(i - 1)).await;
+        }
+        match tokio::time::timeout(policy.attempt_timeout, attempt()).await {
+            Ok(Ok(answer)) => return Ok(answer),
+            Ok(Err(AttemptError::Reject(reason))) => return Err(NodeOutcome::Rejected(reason)),
+            Ok(Err(AttemptError::Retry(e))) => reason = e,
+            Err(_) => reason = "the attempt timed out".into(),
+        }
+    }
+    Err(NodeOutcome::Unreachable(reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn collects_a_threshold_of_answers_despite_failing_nodes() {
+        let policy = FanoutPolicy {
+            deadline: Duration::from_millis(500),
+            attempt_timeout: Duration::from_millis(200),
+            attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+        };
+        let flaky_attempts = AtomicU32::new(0);
+
+        // 0 hangs, 1 rejects, 2 is down, 3 answers (slowly) after two failures, 4 answers
+        let (answers, report) = fan_out(0..5, 2, &policy, |index| {
+            let flaky_attempts = &flaky_attempts;
+            async move {
+                match index {
+                    0 => std::future::pending().await,
+                    1 => Err(AttemptError::Reject("bad share".into())),
+                    2 => Err(AttemptError::Retry("connection refused".into())),
+                    3 if flaky_attempts.fetch_add(1, Ordering::SeqCst) < 2 => {
+                        Err(AttemptError::Retry("reset".into()))
+                    }
+                    3 => {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok(30)
+                    }
+                    _ => Ok(index * 10),
+                }
+            }
+        })
+        .await;
+
+        let mut answers = answers;
+        answers.sort();
+        assert_eq!(answers, vec![(3, 30), (4, 40)]);
+        assert!(report.is_complete());
+        assert_eq!(
+            report.outcomes[&1],
+            NodeOutcome::Rejected("bad share".into())
+        );
+        assert_eq!(
+            report.outcomes[&2],
+            NodeOutcome::Unreachable("connection refused".into())
+        );
+        // the hung node is abandoned once the threshold is reached
+        assert_eq!(report.outcomes[&0], NodeOutcome::NotNeeded);
+
+        // without enough healthy nodes, the hung one runs into its deadline
+        let (answers, report) = fan_out(0..3, 2, &policy, |index| async move {
+            match index {
+                0 => std::future::pending().await,
+                1 => Ok(()),
+                _ => Err(AttemptError::Reject("bad share".into())),
+            }
+        })
+        .await;
+        assert_eq!(answers.len(), 1);
+        assert!(!report.is_complete());
+        assert_eq!(report.outcomes[&0], NodeOutcome::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn asks_the_others_only_for_missing_answers() {
+        let policy = FanoutPolicy {
+            deadline: Duration::from_millis(200),
+            attempt_timeout: Duration::from_millis(100),
+            attempts: 1,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let asked = AtomicU32::new(0);
+        let request = |index: usize| {
+            let asked = &asked;
+            async move {
+                asked.fetch_add(1, Ordering::SeqCst);
+                match index {
+                    1 => Err(AttemptError::Retry("down".into())),
+                    _ => Ok(index),
+                }
+            }
+        };
+
+        // enough preferred members answer, the others are never asked
+        let (answers, report) =
+            fan_out_preferring(vec![2, 0], vec![1, 3], 2, &policy, request).await;
+        assert_eq!(answers.len(), 2);
+        assert_eq!(report.outcomes.len(), 2);
+        assert_eq!(asked.load(Ordering::SeqCst), 2);
+
+        // a preferred member fails, another member fills in
+        let (answers, report) = fan_out_preferring(vec![2, 1], vec![3], 2, &policy, request).await;
+        let mut answers = answers;
+        answers.sort();
+        assert_eq!(answers, vec![(2, 2), (3, 3)]);
+        assert!(report.is_complete());
+        assert_eq!(report.outcomes[&1], NodeOutcome::Unreachable("down".into()));
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_capped() {
+        let policy = FanoutPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_millis(250));
+        assert_eq!(policy.backoff(2), Duration::from_secs(1));
+        assert_eq!(policy.backoff(40), Duration::from_secs(4));
+    }
+}
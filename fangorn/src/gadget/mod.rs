@@ -8,10 +8,12 @@ use std::{collections::HashMap, fmt::Debug, sync::Arc};
 pub mod password;
 pub mod psp22;
 pub mod sr25519;
+pub mod zk_password;
 
 pub use password::PasswordGadget;
 pub use psp22::Psp22Gadget;
 pub use sr25519::Sr25519Gadget;
+pub use zk_password::ZkPasswordGadget;
 
 #[async_trait]
 pub trait Gadget: Send + Sync + Debug {
@@ -22,7 +24,30 @@ pub trait Gadget: Send + Sync + Debug {
     async fn verify_witness(&self, witness: &[u8], statement: &[u8]) -> Result<bool, IntentError >;
 
     /// Parse intent-specific data from string (todo: define parsing logic)
-    fn parse_intent_data(&self, data: &str) -> Result<Vec<u8>, IntentError>;
+    async fn parse_intent_data(&self, data: &str) -> Result<Vec<u8>, IntentError>;
+
+    /// Verify a witness against a statement within a verification context
+    /// (the verifying node and the requested file). Gadgets whose witnesses
+    /// are bound to the context override this; by default the context is ignored.
+    async fn verify_witness_in_context(
+        &self,
+        witness: &[u8],
+        statement: &[u8],
+        _context: &[u8],
+    ) -> Result<bool, IntentError> {
+        self.verify_witness(witness, statement).await
+    }
+
+    /// Prepare a raw witness on the client before it is sent to a node
+    /// for the given verification context. By default the raw witness is sent as-is.
+    async fn prepare_witness(
+        &self,
+        witness: &[u8],
+        _statement: &[u8],
+        _context: &[u8],
+    ) -> Result<Vec<u8>, IntentError> {
+        Ok(witness.to_vec())
+    }
 }
 
 // TODO: use thiserror instead
@@ -32,6 +57,7 @@ pub enum IntentError {
     VerificationError(String),
     UnknownIntentType(String),
     SerializationError(String),
+    MalformedWitness(String),
 }
 
 impl std::fmt::Display for IntentError {
@@ -41,6 +67,7 @@ impl std::fmt::Display for IntentError {
             IntentError::VerificationError(s) => write!(f, "Verification error: {}", s),
             IntentError::UnknownIntentType(s) => write!(f, "Unknown intent type: {}", s),
             IntentError::SerializationError(s) => write!(f, "Serialization error: {}", s),
+            IntentError::MalformedWitness(s) => write!(f, "Malformed witness: {}", s),
         }
     }
 }
@@ -82,7 +109,7 @@ impl GadgetRegistry {
                 .get_gadget(intent_type_str)
                 .ok_or_else(|| IntentError::UnknownIntentType(intent_type_str.to_string()))?;
 
-            let statement = gadget.parse_intent_data(data)?;
+            let statement = gadget.parse_intent_data(data).await?;
             let intent = Intent {
                 intent_type: intent_type_str.to_string(),
                 statement,
@@ -96,19 +123,58 @@ impl GadgetRegistry {
         Ok(intents)
     }
 
+    /// Prepare the raw witnesses for each intent, for a specific verification context
+    /// witnesses MUST be ordered in the same order as the intents
+    pub async fn prepare_witnesses(
+        &self,
+        intents: &[Intent],
+        witnesses: &[&str],
+        context: &[u8],
+    ) -> Result<Vec<Vec<u8>>, IntentError> {
+        if intents.len() != witnesses.len() {
+            return Err(IntentError::ParseError(format!(
+                "Expected {} witnesses, got {}",
+                intents.len(),
+                witnesses.len()
+            )));
+        }
+
+        let mut prepared = Vec::new();
+        for (intent, witness) in intents.iter().zip(witnesses.iter()) {
+            let gadget = self
+                .get_gadget(&intent.intent_type)
+                .ok_or_else(|| IntentError::UnknownIntentType(intent.intent_type.clone()))?;
+            prepared.push(
+                gadget
+                    .prepare_witness(witness.as_bytes(), &intent.statement, context)
+                    .await?,
+            );
+        }
+        Ok(prepared)
+    }
+
     pub async fn verify_intents(
         &self,
         intents: Vec<Intent>,
         mut witness: &[u8],
+        context: &[u8],
     ) -> Result<bool, IntentError> { // TODO: this coudl return Result<(), IntentError> instead
         // first we need to recover the witnesses
-        let decoded_witnesses = Vec::<Vec<u8>>::decode(&mut witness).unwrap();
-        assert!(decoded_witnesses.len() == intents.len(), "Mismatched intents and witnesses");
+        // the witness comes from the requester, it is never trusted to be well-formed
+        let decoded_witnesses = Vec::<Vec<u8>>::decode(&mut witness)
+            .map_err(|e| IntentError::MalformedWitness(e.to_string()))?;
+        if decoded_witnesses.len() != intents.len() {
+            return Err(IntentError::MalformedWitness(format!(
+                "Expected {} witnesses, got {}",
+                intents.len(),
+                decoded_witnesses.len()
+            )));
+        }
         // TODO: this is a little dangerous: witnesses MUST be ordered
         // in the same order that gadgets were described when encrypting the message
         // if any single one fails, they all fail
         for (intent, witness) in intents.iter().zip(decoded_witnesses.iter()) {
-            if !self.verify_intent(&intent, &witness).await? {
+            if !self.verify_intent(&intent, &witness, context).await? {
                 // return an error if an intent is not valid
                 return Err(IntentError::VerificationError(intent.intent_type.clone()));
             }
@@ -123,6 +189,7 @@ impl GadgetRegistry {
         &self,
         intent: &Intent,
         witness: &[u8],
+        context: &[u8],
     ) -> Result<bool, IntentError> {
         let gadget = match &intent.gadget {
             Some(m) => m.clone(),
@@ -131,7 +198,9 @@ impl GadgetRegistry {
                 .ok_or_else(|| IntentError::UnknownIntentType(intent.intent_type.clone()))?,
         };
 
-        gadget.verify_witness(witness, &intent.statement).await
+        gadget
+            .verify_witness_in_context(witness, &intent.statement, context)
+            .await
     }
 }
 
@@ -215,4 +284,23 @@ pub mod test {
         let actual_output = parse_intent_string(&intent).unwrap();
         assert!(expected_output == actual_output);
     }
+
+    #[tokio::test]
+    async fn malformed_witnesses_are_rejected() {
+        let registry = GadgetRegistry::new();
+        let intents = || {
+            vec![Intent {
+                intent_type: "Password".to_string(),
+                statement: vec![],
+                gadget: None,
+            }]
+        };
+
+        let undecodable = registry.verify_intents(intents(), &[0xff], b"").await;
+        assert!(matches!(undecodable, Err(IntentError::MalformedWitness(_))));
+
+        let short = Vec::<Vec<u8>>::new().encode();
+        let short = registry.verify_intents(intents(), &short, b"").await;
+        assert!(matches!(short, Err(IntentError::MalformedWitness(_))));
+    }
 }
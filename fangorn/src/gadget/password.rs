@@ -20,7 +20,7 @@ impl Gadget for PasswordGadget {
 
     // parse raw data to a password verification intent
     // where the question is the hash and the answer is the password
-    fn parse_intent_data(&self, data: &str) -> Result<Vec<u8>, IntentError> {
+    async fn parse_intent_data(&self, data: &str) -> Result<Vec<u8>, IntentError> {
         let answer = data.as_bytes().to_vec();
         let question = Sha256::digest(&answer);
 
@@ -32,12 +32,12 @@ impl Gadget for PasswordGadget {
 mod test {
     use super::*;
 
-    #[test]
-    fn test_can_parse_valid_intent_data() {
+    #[tokio::test]
+    async fn test_can_parse_valid_intent_data() {
         let gadget = PasswordGadget {};
         let data: &str = "Password(HelloWorld!)";
         let expected_hash = Sha256::digest(data.as_bytes().to_vec());
-        let actual_hash = gadget.parse_intent_data(data).unwrap();
+        let actual_hash = gadget.parse_intent_data(data).await.unwrap();
         assert_eq!(&expected_hash[..], actual_hash);
     }
 }
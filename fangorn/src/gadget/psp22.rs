@@ -31,7 +31,6 @@ impl Gadget for Psp22Gadget {
 
         let pubkey_string: String =
             String::from_utf8(witness.to_vec()).expect("Invalid UTF-8 sequence");
-        println!("WE DECODED THE pubkey: {:?}", pubkey_string.clone());
         let witness = crate::utils::decode_public_key(&pubkey_string);
 
         if witness.len() != 32 {
@@ -81,7 +80,7 @@ impl Gadget for Psp22Gadget {
 
     /// defines the data format for the Psp22 command
     /// expected format: data = "contract_addr, min_balance"
-    fn parse_intent_data(&self, data: &str) -> Result<Vec<u8>, IntentError> {
+    async fn parse_intent_data(&self, data: &str) -> Result<Vec<u8>, IntentError> {
         // the intent is the contract_addr and min_balance encoded in a vec
         let parts: Vec<&str> = data.split(',').collect();
         if parts.len() != 2 {
@@ -0,0 +1,533 @@
+use crate::{
+    crypto::{
+        encrypt::hash_to_g2,
+        transport::{PUBLIC_KEY_LEN, TransportKeypair, witness_node},
+    },
+    gadget::*,
+};
+use ark_bls12_381::{Fr, G1Affine, G1Projective, G2Affine, G2Projective};
+use ark_ec::{CurveGroup, PrimeGroup};
+use ark_ff::{Field, PrimeField};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_std::{
+    UniformRand,
+    rand::{RngCore, rngs::OsRng},
+};
+use async_trait::async_trait;
+use sha2::{Digest, Sha512};
+use std::fmt::Debug;
+
+/// The length of the random salt mixed into the password
+const SALT_LEN: usize = 16;
+/// The length of a compressed G1 point
+const POINT_LEN: usize = 48;
+/// The length of a compressed scalar
+const SCALAR_LEN: usize = 32;
+/// The length of a statement record: the node's transport key and its X
+const RECORD_LEN: usize = PUBLIC_KEY_LEN + POINT_LEN;
+
+/// Domain separators
+const PASSWORD_DST: &[u8] = b"fangorn-zk-password-key";
+const CHALLENGE_DST: &[u8] = b"fangorn-zk-password-challenge";
+const OPRF_KEY_DST: &[u8] = b"fangorn-oprf-key";
+const OPRF_INPUT_DST: &[u8] = b"fangorn-oprf-input";
+const OPRF_OUTPUT_DST: &[u8] = b"fangorn-oprf-output";
+const DLEQ_DST: &[u8] = b"fangorn-oprf-dleq";
+
+/// A node's answer to a blinded OPRF query
+pub struct OprfEvaluation {
+    /// k * B, compressed
+    pub evaluated: Vec<u8>,
+    /// the proof that the node used the key behind `public_key`
+    pub proof: Vec<u8>,
+    /// K = k * G2, compressed (authenticated by whoever fetched it)
+    pub public_key: Vec<u8>,
+}
+
+/// Reaches the nodes that evaluate the password OPRF
+#[async_trait]
+pub trait OprfEvaluator: Send + Sync {
+    /// The transport keys of the nodes a new statement is made for
+    async fn nodes(&self) -> Result<Vec<[u8; PUBLIC_KEY_LEN]>, IntentError>;
+
+    /// Ask the node with the given transport key to evaluate a blinded input
+    async fn evaluate(
+        &self,
+        node: &[u8; PUBLIC_KEY_LEN],
+        blinded: &[u8],
+    ) -> Result<OprfEvaluation, IntentError>;
+}
+
+/// A password gadget where nodes never learn the password
+///
+/// The salted password is never turned into a public key directly. For each
+/// node, the client first runs an OPRF with it (2HashDH over G2, with a DLEQ
+/// proof), so the derived secret `x_i` depends on a key only that node holds.
+/// The statement is `salt || (node, X_i)*` where `X_i = x_i*G`, and the witness
+/// is a Schnorr proof of knowledge of `x_i` bound to the verification context
+/// (the target node's transport key and the filename). Guessing the password
+/// takes an online query to a node per guess; only a node can guess offline,
+/// and only against its own record.
+#[derive(Default)]
+pub struct ZkPasswordGadget {
+    oprf: Option<Arc<dyn OprfEvaluator>>,
+}
+
+impl ZkPasswordGadget {
+    /// A gadget that can only verify witnesses (as run by the nodes)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A gadget that can also build statements and witnesses (as run by clients)
+    pub fn with_oprf(oprf: Arc<dyn OprfEvaluator>) -> Self {
+        Self { oprf: Some(oprf) }
+    }
+
+    fn evaluator(&self) -> Result<&dyn OprfEvaluator, IntentError> {
+        self.oprf
+            .as_deref()
+            .ok_or_else(|| IntentError::ParseError("No OPRF evaluator is configured".into()))
+    }
+}
+
+impl Debug for ZkPasswordGadget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ZkPasswordGadget")
+            .field("oprf", &self.oprf.is_some())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Gadget for ZkPasswordGadget {
+    fn intent_type_id(&self) -> &'static str {
+        "ZkPassword"
+    }
+
+    /// Proofs are bound to a node, so they never verify without a context
+    async fn verify_witness(&self, witness: &[u8], statement: &[u8]) -> Result<bool, IntentError> {
+        self.verify_witness_in_context(witness, statement, &[])
+            .await
+    }
+
+    /// verify the Schnorr proof (R, s) against the record of the verifying node: s*G == R + c*X_i
+    async fn verify_witness_in_context(
+        &self,
+        witness: &[u8],
+        statement: &[u8],
+        context: &[u8],
+    ) -> Result<bool, IntentError> {
+        let (_salt, records) = parse_statement(statement)?;
+        let Some(public) = witness_node(context).and_then(|node| find_record(&records, &node))
+        else {
+            return Ok(false);
+        };
+
+        if witness.len() != POINT_LEN + SCALAR_LEN {
+            return Err(IntentError::VerificationError(format!(
+                "Witness must be {} bytes (commitment + response). Got {}",
+                POINT_LEN + SCALAR_LEN,
+                witness.len()
+            )));
+        }
+
+        let commitment = G1Affine::deserialize_compressed(&witness[..POINT_LEN])
+            .map_err(|_| IntentError::VerificationError("Invalid commitment".into()))?;
+        let response = Fr::deserialize_compressed(&witness[POINT_LEN..])
+            .map_err(|_| IntentError::VerificationError("Invalid response".into()))?;
+
+        let challenge = challenge(&public, &commitment, context)?;
+        let lhs = G1Projective::generator() * response;
+        let rhs = G1Projective::from(commitment) + G1Projective::from(public) * challenge;
+
+        Ok(lhs == rhs)
+    }
+
+    /// the statement is salt || (node, X_i)*, where X_i = H(salt, node, OPRF_i(password)) * G
+    async fn parse_intent_data(&self, data: &str) -> Result<Vec<u8>, IntentError> {
+        let oprf = self.evaluator()?;
+        let nodes = oprf.nodes().await?;
+        if nodes.is_empty() {
+            return Err(IntentError::ParseError(
+                "No node can evaluate the OPRF".into(),
+            ));
+        }
+
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        let mut statement = salt.to_vec();
+        for node in nodes {
+            let output = password_output(oprf, &node, data.as_bytes()).await?;
+            let public = (G1Projective::generator() * password_to_scalar(&salt, &node, &output))
+                .into_affine();
+            statement.extend_from_slice(&node);
+            public
+                .serialize_compressed(&mut statement)
+                .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+        }
+
+        Ok(statement)
+    }
+
+    /// turn the raw password into a proof of knowledge bound to the context
+    async fn prepare_witness(
+        &self,
+        witness: &[u8],
+        statement: &[u8],
+        context: &[u8],
+    ) -> Result<Vec<u8>, IntentError> {
+        let (salt, records) = parse_statement(statement)?;
+        let node = witness_node(context)
+            .ok_or_else(|| IntentError::VerificationError("Not a witness context".into()))?;
+        let public = find_record(&records, &node).ok_or_else(|| {
+            IntentError::VerificationError("The statement has no record for this node".into())
+        })?;
+
+        let output = password_output(self.evaluator()?, &node, witness).await?;
+        let secret = password_to_scalar(salt, &node, &output);
+
+        // fail early (and locally) on a wrong password
+        if (G1Projective::generator() * secret).into_affine() != public {
+            return Err(IntentError::VerificationError("Incorrect password".into()));
+        }
+
+        let nonce = Fr::rand(&mut OsRng);
+        let commitment = (G1Projective::generator() * nonce).into_affine();
+        let challenge = challenge(&public, &commitment, context)?;
+        let response = nonce + challenge * secret;
+
+        let mut proof = Vec::new();
+        commitment
+            .serialize_compressed(&mut proof)
+            .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+        response
+            .serialize_compressed(&mut proof)
+            .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+
+        Ok(proof)
+    }
+}
+
+/// The OPRF public key K = k * G2 of a node, compressed
+pub fn oprf_public_key(transport_key: &TransportKeypair) -> Vec<u8> {
+    let public = (G2Projective::generator() * oprf_secret(transport_key)).into_affine();
+    let mut bytes = Vec::new();
+    // serializing into a vec cannot fail
+    public.serialize_compressed(&mut bytes).unwrap();
+    bytes
+}
+
+/// Evaluate a blinded OPRF input with the node's key: returns (k * B, DLEQ proof)
+pub fn evaluate_oprf(
+    transport_key: &TransportKeypair,
+    blinded: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), IntentError> {
+    let blinded = G2Affine::deserialize_compressed(blinded)
+        .map_err(|_| IntentError::ParseError("Invalid blinded input".into()))?;
+    let secret = oprf_secret(transport_key);
+    let public = (G2Projective::generator() * secret).into_affine();
+    let evaluated = (G2Projective::from(blinded) * secret).into_affine();
+
+    // Chaum-Pedersen: log_G2(K) == log_B(E)
+    let nonce = Fr::rand(&mut OsRng);
+    let a1 = (G2Projective::generator() * nonce).into_affine();
+    let a2 = (G2Projective::from(blinded) * nonce).into_affine();
+    let c = dleq_challenge(&[public, blinded, evaluated, a1, a2])?;
+    let s = nonce + c * secret;
+
+    let mut proof = Vec::new();
+    c.serialize_compressed(&mut proof)
+        .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+    s.serialize_compressed(&mut proof)
+        .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+
+    let mut bytes = Vec::new();
+    evaluated
+        .serialize_compressed(&mut bytes)
+        .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+
+    Ok((bytes, proof))
+}
+
+/// the OPRF key of a node, derived from its transport secret
+fn oprf_secret(transport_key: &TransportKeypair) -> Fr {
+    let digest = Sha512::new()
+        .chain_update(OPRF_KEY_DST)
+        .chain_update(transport_key.to_bytes())
+        .finalize();
+    Fr::from_be_bytes_mod_order(&digest)
+}
+
+/// run the OPRF on the password with a node: H(password, k * H(password))
+async fn password_output(
+    oprf: &dyn OprfEvaluator,
+    node: &[u8; PUBLIC_KEY_LEN],
+    password: &[u8],
+) -> Result<Vec<u8>, IntentError> {
+    let input = hash_to_g2(password, OPRF_INPUT_DST);
+    let blind = Fr::rand(&mut OsRng);
+    let blinded = (input * blind).into_affine();
+
+    let mut blinded_bytes = Vec::new();
+    blinded
+        .serialize_compressed(&mut blinded_bytes)
+        .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+
+    let evaluation = oprf.evaluate(node, &blinded_bytes).await?;
+    let public = G2Affine::deserialize_compressed(&evaluation.public_key[..])
+        .map_err(|_| IntentError::VerificationError("Invalid OPRF public key".into()))?;
+    let evaluated = G2Affine::deserialize_compressed(&evaluation.evaluated[..])
+        .map_err(|_| IntentError::VerificationError("Invalid OPRF evaluation".into()))?;
+    if !verify_dleq(&public, &blinded, &evaluated, &evaluation.proof)? {
+        return Err(IntentError::VerificationError(
+            "The node did not evaluate the OPRF with its key".into(),
+        ));
+    }
+
+    let unblind = blind
+        .inverse()
+        .ok_or_else(|| IntentError::VerificationError("Zero blinding factor".into()))?;
+    let mut unblinded = Vec::new();
+    (G2Projective::from(evaluated) * unblind)
+        .into_affine()
+        .serialize_compressed(&mut unblinded)
+        .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+
+    Ok(Sha512::new()
+        .chain_update(OPRF_OUTPUT_DST)
+        .chain_update(password)
+        .chain_update(&unblinded)
+        .finalize()
+        .to_vec())
+}
+
+/// check the DLEQ proof (c, s): c == H(K, B, E, s*G2 - c*K, s*B - c*E)
+fn verify_dleq(
+    public: &G2Affine,
+    blinded: &G2Affine,
+    evaluated: &G2Affine,
+    proof: &[u8],
+) -> Result<bool, IntentError> {
+    if proof.len() != 2 * SCALAR_LEN {
+        return Ok(false);
+    }
+    let (Ok(c), Ok(s)) = (
+        Fr::deserialize_compressed(&proof[..SCALAR_LEN]),
+        Fr::deserialize_compressed(&proof[SCALAR_LEN..]),
+    ) else {
+        return Ok(false);
+    };
+
+    let a1 = (G2Projective::generator() * s - G2Projective::from(*public) * c).into_affine();
+    let a2 = (G2Projective::from(*blinded) * s - G2Projective::from(*evaluated) * c).into_affine();
+
+    Ok(dleq_challenge(&[*public, *blinded, *evaluated, a1, a2])? == c)
+}
+
+fn dleq_challenge(points: &[G2Affine]) -> Result<Fr, IntentError> {
+    let mut bytes = Vec::new();
+    for point in points {
+        point
+            .serialize_compressed(&mut bytes)
+            .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+    }
+    let digest = Sha512::new()
+        .chain_update(DLEQ_DST)
+        .chain_update(&bytes)
+        .finalize();
+    Ok(Fr::from_be_bytes_mod_order(&digest))
+}
+
+/// split the statement into (salt, [(node, X_i)])
+fn parse_statement(
+    statement: &[u8],
+) -> Result<(&[u8], Vec<([u8; PUBLIC_KEY_LEN], G1Affine)>), IntentError> {
+    if statement.len() <= SALT_LEN || (statement.len() - SALT_LEN) % RECORD_LEN != 0 {
+        return Err(IntentError::VerificationError(format!(
+            "Statement must be a {} byte salt followed by {} byte records. Got {} bytes",
+            SALT_LEN,
+            RECORD_LEN,
+            statement.len()
+        )));
+    }
+
+    let (salt, records) = statement.split_at(SALT_LEN);
+    let records = records
+        .chunks_exact(RECORD_LEN)
+        .map(|record| {
+            let (node, public) = record.split_at(PUBLIC_KEY_LEN);
+            let public = G1Affine::deserialize_compressed(public)
+                .map_err(|_| IntentError::VerificationError("Invalid public key".into()))?;
+            // the split leaves exactly PUBLIC_KEY_LEN bytes
+            Ok((node.try_into().unwrap(), public))
+        })
+        .collect::<Result<Vec<_>, IntentError>>()?;
+
+    Ok((salt, records))
+}
+
+fn find_record(
+    records: &[([u8; PUBLIC_KEY_LEN], G1Affine)],
+    node: &[u8; PUBLIC_KEY_LEN],
+) -> Option<G1Affine> {
+    records
+        .iter()
+        .find(|(key, _)| key == node)
+        .map(|(_, public)| *public)
+}
+
+/// hash the salted OPRF output of a node to a scalar
+fn password_to_scalar(salt: &[u8], node: &[u8; PUBLIC_KEY_LEN], output: &[u8]) -> Fr {
+    let digest = Sha512::new()
+        .chain_update(PASSWORD_DST)
+        .chain_update(salt)
+        .chain_update(node)
+        .chain_update(output)
+        .finalize();
+    Fr::from_be_bytes_mod_order(&digest)
+}
+
+/// the Fiat-Shamir challenge c = H(X, R, context)
+fn challenge(public: &G1Affine, commitment: &G1Affine, context: &[u8]) -> Result<Fr, IntentError> {
+    let mut bytes = Vec::new();
+    public
+        .serialize_compressed(&mut bytes)
+        .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+    commitment
+        .serialize_compressed(&mut bytes)
+        .map_err(|e| IntentError::SerializationError(e.to_string()))?;
+
+    let digest = Sha512::new()
+        .chain_update(CHALLENGE_DST)
+        .chain_update(&bytes)
+        .chain_update(context)
+        .finalize();
+
+    Ok(Fr::from_be_bytes_mod_order(&digest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::transport::witness_context;
+
+    /// nodes evaluating the OPRF in process, optionally with the wrong key
+    struct LocalOprf {
+        nodes: Vec<TransportKeypair>,
+        cheat: bool,
+    }
+
+    #[async_trait]
+    impl OprfEvaluator for LocalOprf {
+        async fn nodes(&self) -> Result<Vec<[u8; PUBLIC_KEY_LEN]>, IntentError> {
+            Ok(self.nodes.iter().map(|k| *k.public().as_bytes()).collect())
+        }
+
+        async fn evaluate(
+            &self,
+            node: &[u8; PUBLIC_KEY_LEN],
+            blinded: &[u8],
+        ) -> Result<OprfEvaluation, IntentError> {
+            let key = self
+                .nodes
+                .iter()
+                .find(|k| k.public().as_bytes() == node)
+                .ok_or_else(|| IntentError::ParseError("unknown node".into()))?;
+            let other = TransportKeypair::generate();
+            let evaluator = if self.cheat { &other } else { key };
+            let (evaluated, proof) = evaluate_oprf(evaluator, blinded)?;
+            Ok(OprfEvaluation {
+                evaluated,
+                proof,
+                public_key: oprf_public_key(key),
+            })
+        }
+    }
+
+    fn gadget(nodes: &[TransportKeypair], cheat: bool) -> ZkPasswordGadget {
+        ZkPasswordGadget::with_oprf(Arc::new(LocalOprf {
+            nodes: nodes.to_vec(),
+            cheat,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_proof_verifies_in_its_own_context_only() {
+        let nodes = [TransportKeypair::generate(), TransportKeypair::generate()];
+        let gadget = gadget(&nodes, false);
+        let statement = gadget.parse_intent_data("HelloWorld!").await.unwrap();
+
+        let node_1 = witness_context(b"file", &nodes[0].public());
+        let node_2 = witness_context(b"file", &nodes[1].public());
+        let proof = gadget
+            .prepare_witness(b"HelloWorld!", &statement, &node_1)
+            .await
+            .unwrap();
+
+        // nodes only verify, they need no evaluator
+        let verifier = ZkPasswordGadget::new();
+        assert!(
+            verifier
+                .verify_witness_in_context(&proof, &statement, &node_1)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !verifier
+                .verify_witness_in_context(&proof, &statement, &node_2)
+                .await
+                .unwrap()
+        );
+        assert!(!verifier.verify_witness(&proof, &statement).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password_is_rejected_locally() {
+        let nodes = [TransportKeypair::generate()];
+        let gadget = gadget(&nodes, false);
+        let statement = gadget.parse_intent_data("HelloWorld!").await.unwrap();
+        let context = witness_context(b"file", &nodes[0].public());
+        assert!(
+            gadget
+                .prepare_witness(b"GoodbyeWorld!", &statement, &context)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_guessing_needs_the_node_key() {
+        let nodes = [TransportKeypair::generate()];
+        let statement = gadget(&nodes, false)
+            .parse_intent_data("HelloWorld!")
+            .await
+            .unwrap();
+        let (salt, records) = parse_statement(&statement).unwrap();
+        let (node, public) = records[0];
+
+        // the right password through any other OPRF key does not match the record
+        let other = LocalOprf {
+            nodes: vec![TransportKeypair::generate()],
+            cheat: false,
+        };
+        let other_node = *other.nodes[0].public().as_bytes();
+        let output = password_output(&other, &other_node, b"HelloWorld!")
+            .await
+            .unwrap();
+        let guess = G1Projective::generator() * password_to_scalar(salt, &node, &output);
+        assert_ne!(guess.into_affine(), public);
+    }
+
+    #[tokio::test]
+    async fn test_cheating_evaluator_is_detected() {
+        let nodes = [TransportKeypair::generate()];
+        assert!(
+            gadget(&nodes, true)
+                .parse_intent_data("HelloWorld!")
+                .await
+                .is_err()
+        );
+    }
+}
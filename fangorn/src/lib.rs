@@ -2,13 +2,22 @@ pub static WS_URL: &str = "ws://localhost:9944";
 
 pub mod backend;
 pub mod cli;
+pub mod committee;
 pub mod crypto;
+pub mod epoch;
+pub mod fanout;
 pub mod gadget;
+pub mod liveness;
+pub mod membership;
 pub mod node;
+pub mod readiness;
+pub mod registry;
 pub mod rpc;
 pub mod service;
+pub mod snapshot;
 pub mod storage;
 pub mod types;
+pub mod universe;
 pub mod utils;
 
 pub mod test;
@@ -0,0 +1,129 @@
+//! Committee liveness
+//!
+//! Committee members publish a signed heartbeat (with their version and load) to the universe
+//! doc every `HEARTBEAT_INTERVAL`. Each node keeps the last heartbeat of every member, so it can
+//! tell clients which members are live: those heard from within `LIVENESS_TIMEOUT`.
+use codec::{Decode, Encode};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The doc key prefix of heartbeats (each member overwrites its own)
+pub const HEARTBEAT_KEY: &str = "heartbeat";
+
+/// How often members publish a heartbeat
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a member counts as live after its last heartbeat
+pub const LIVENESS_TIMEOUT: Duration = Duration::from_secs(45);
+
+pub fn heartbeat_key(index: u32) -> String {
+    format!("{}-{}", HEARTBEAT_KEY, index)
+}
+
+/// The seconds since the unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// A member's sign of life
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct Heartbeat {
+    /// the version of the node software
+    pub version: String,
+    /// the partial decryptions served since the previous heartbeat
+    pub load: u32,
+    /// when the heartbeat was sent (unix seconds)
+    pub timestamp: u64,
+}
+
+impl Heartbeat {
+    pub fn new(load: u32) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            load,
+            timestamp: unix_now(),
+        }
+    }
+}
+
+/// The last heartbeat of a member
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemberHealth {
+    pub version: String,
+    pub load: u32,
+    /// when the member was last heard from (unix seconds)
+    pub last_seen: u64,
+}
+
+/// The health of the committee members, by committee index
+#[derive(Clone, Debug, Default)]
+pub struct Liveness {
+    members: BTreeMap<u32, MemberHealth>,
+}
+
+impl Liveness {
+    /// Record a (verified) heartbeat of a member received at `now`
+    /// Heartbeats claiming to be from the future count as sent now, older ones are ignored
+    pub fn record(&mut self, index: u32, heartbeat: Heartbeat, now: u64) {
+        let last_seen = heartbeat.timestamp.min(now);
+        if self
+            .members
+            .get(&index)
+            .is_some_and(|health| health.last_seen > last_seen)
+        {
+            return;
+        }
+        self.members.insert(
+            index,
+            MemberHealth {
+                version: heartbeat.version,
+                load: heartbeat.load,
+                last_seen,
+            },
+        );
+    }
+
+    pub fn get(&self, index: u32) -> Option<&MemberHealth> {
+        self.members.get(&index)
+    }
+
+    /// Whether a member was heard from within the liveness timeout
+    pub fn is_live(&self, index: u32, now: u64) -> bool {
+        self.get(index).is_some_and(|health| {
+            now.saturating_sub(health.last_seen) <= LIVENESS_TIMEOUT.as_secs()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn members_are_live_until_their_heartbeats_time_out() {
+        let mut liveness = Liveness::default();
+        let now = 1_000_000;
+        let heartbeat = |load, timestamp| Heartbeat {
+            version: "1.0.0".into(),
+            load,
+            timestamp,
+        };
+
+        liveness.record(0, heartbeat(3, now - 10), now);
+        // synced from the doc long after it was sent
+        liveness.record(1, heartbeat(0, now - 600), now);
+        assert!(liveness.is_live(0, now));
+        assert!(!liveness.is_live(1, now));
+        assert!(!liveness.is_live(2, now));
+        assert!(!liveness.is_live(0, now + LIVENESS_TIMEOUT.as_secs() + 10));
+
+        // older heartbeats do not replace newer ones, future ones count as sent now
+        liveness.record(0, heartbeat(9, now - 20), now);
+        assert_eq!(liveness.get(0).unwrap().load, 3);
+        liveness.record(1, heartbeat(1, now + 3600), now);
+        assert_eq!(liveness.get(1).unwrap().last_seen, now);
+    }
+}
@@ -1,57 +1,221 @@
 use anyhow::Result;
+use ark_serialize::CanonicalSerialize;
 use clap::Parser;
+use fangorn::backend::SubstrateBackend;
 use fangorn::cli::{FangornNodeCli as Cli, FangornNodeCommands as Commands};
-use fangorn::service::{ServiceConfig, build_full_service};
+use fangorn::crypto::crs::{CONFIG_FILE, import_crs as import_crs_file};
+use fangorn::crypto::keystore::{KeystoreError, NodeKeystore};
+use fangorn::membership::{MEMBER_ENTRY_FILE, MEMBERS_FILE, member_entry};
+use fangorn::node::NetworkConfig;
+use fangorn::registry::{CommitteeRegistry, ContractCommitteeRegistry};
+use fangorn::service::{ServiceConfig, UniverseConfig, build_full_service};
 use fangorn::types::*;
+use fangorn::utils::{load_mnemonic, read_keystore_password};
+use std::{path::PathBuf, sync::Arc};
 
 // https://hackmd.io/3968Gr5hSSmef-nptg2GRw
 // https://hackmd.io/xqYBrigYQwyKM_0Sn5Xf4w
 // https://eprint.iacr.org/2024/263.pdf
-const MAX_COMMITTEE_SIZE: usize = 2;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Cli::parse();
 
     match &args.command {
-        Some(Commands::Setup { out_dir: _ }) => {
-            // TODO: keygen, save to keystore, etc
-            println!("> Nothing happened");
+        Some(Commands::Setup {
+            out_dir,
+            index,
+            committee_size,
+            import_crs,
+            node_identity,
+        }) => {
+            let keystore = NodeKeystore::new(PathBuf::from(out_dir));
+            let config_path = PathBuf::from(out_dir).join(CONFIG_FILE);
+            if keystore.exists() {
+                return Err(KeystoreError::AlreadyExists(out_dir.clone()).into());
+            }
+            if config_path.exists() {
+                anyhow::bail!("A config already exists at {}", config_path.display());
+            }
+
+            // the CRS: imported (and verified) or generated locally
+            // it bounds the size committees can grow to in later epochs
+            let size = *committee_size;
+            let config = match import_crs {
+                Some(path) => {
+                    println!("> Importing CRS from {}", path);
+                    import_crs_file::<E>(path, size)?
+                }
+                None => {
+                    println!("> Generating CRS (this node will know the trapdoor)");
+                    Config::<E>::rand(size)
+                }
+            };
+
+            // all keystores of a node are protected by the same password
+            let password = read_keystore_password(true)?;
+            let mut keys = NodeKeys::<E>::generate(*index);
+            // a node takes part in all its universes with one node id
+            if let Some(dir) = node_identity {
+                keys.iroh_secret_key = NodeKeystore::new(PathBuf::from(dir))
+                    .load::<E>(&password)?
+                    .iroh_secret_key;
+            }
+            keystore.create(&keys, &password)?;
+            config.save(&config_path)?;
+            // the entry other members admit this node with
+            let node_id = keys.iroh_secret_key.public();
+            std::fs::write(
+                PathBuf::from(out_dir).join(MEMBER_ENTRY_FILE),
+                member_entry(*index as u32, &node_id),
+            )?;
+
+            println!("> Created node keystore and config in {}", out_dir);
+            println!("> Node id: {}", node_id);
+            println!("> Universe id: {}", hex::encode(config.universe_id()));
         }
         Some(Commands::Run {
             bind_port,
             rpc_port,
-            index,
+            rpc_host,
+            rpc_endpoints,
+            keystore_dir,
             bootstrap_pubkey,
             bootstrap_ip,
+            peers,
+            offline,
+            relay_url,
             is_bootstrap,
             ticket,
             contract_addr,
+            ceremony_participants,
+            ceremony_size,
+            members,
+            registry_addr,
+            data_dir,
+            universes,
         }) => {
-            let config = ServiceConfig {
-                bind_port: *bind_port,
-                rpc_port: *rpc_port,
-                index: *index,
+            // load the node keys of every universe (fail before touching the network)
+            // all keystores are protected by the same password
+            let password = read_keystore_password(false)?;
+            let mut keys =
+                vec![NodeKeystore::new(PathBuf::from(keystore_dir)).load::<E>(&password)?];
+            let members_path = |dir: &str, members: &Option<String>| {
+                members
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from(dir).join(MEMBERS_FILE).display().to_string())
+            };
+
+            // the universe of the keystore dir is the default one
+            let mut universe_configs = vec![UniverseConfig {
                 is_bootstrap: *is_bootstrap,
                 ticket: if ticket.is_empty() {
                     None
                 } else {
                     Some(ticket.clone())
                 },
-                bootstrap_peers: ServiceConfig::build_bootstrap_peers(
-                    bootstrap_pubkey.clone(),
-                    bootstrap_ip.clone(),
-                ),
+                config_path: PathBuf::from(keystore_dir)
+                    .join(CONFIG_FILE)
+                    .display()
+                    .to_string(),
                 contract_addr: contract_addr.to_string(),
+                ceremony_participants: *ceremony_participants,
+                ceremony_size: *ceremony_size,
+                members_path: members_path(keystore_dir, members),
+                registry_addr: registry_addr.clone(),
+                out_dir: ".".to_string(),
+            }];
+            for universe in universes {
+                let dir = &universe.keystore_dir;
+                keys.push(NodeKeystore::new(PathBuf::from(dir)).load::<E>(&password)?);
+                universe_configs.push(UniverseConfig {
+                    is_bootstrap: universe.bootstrap,
+                    ticket: universe.ticket.clone(),
+                    config_path: PathBuf::from(dir).join(CONFIG_FILE).display().to_string(),
+                    contract_addr: universe.contract_addr.clone(),
+                    ceremony_participants: None,
+                    ceremony_size: None,
+                    members_path: members_path(dir, &universe.members),
+                    registry_addr: universe.registry_addr.clone(),
+                    out_dir: dir.clone(),
+                });
+            }
+
+            // explicit peers, next to the bootstrap node
+            let mut bootstrap_peers = ServiceConfig::build_bootstrap_peers(
+                bootstrap_pubkey.clone(),
+                bootstrap_ip.clone(),
+            )
+            .unwrap_or_default();
+            for peer in peers {
+                bootstrap_peers.push(ServiceConfig::parse_peer(peer)?);
+            }
+            let bootstrap_peers = (!bootstrap_peers.is_empty()).then_some(bootstrap_peers);
+
+            let config = ServiceConfig {
+                bind_port: *bind_port,
+                rpc_port: *rpc_port,
+                rpc_host: rpc_host.clone(),
+                rpc_endpoints: rpc_endpoints.clone(),
+                bootstrap_peers,
+                data_dir: data_dir.clone(),
+                network: NetworkConfig {
+                    offline: *offline,
+                    relay_url: relay_url.clone(),
+                    entry_node: None,
+                },
+                universes: universe_configs,
             };
             // start the service
             // tokio::spawn(async move {
             //     loop
-            build_full_service::<E>(config, MAX_COMMITTEE_SIZE).await?;
+            let service = build_full_service::<E>(config, keys).await?;
+            tokio::select! {
+                _ = service.ready() => println!("> Node is ready"),
+                _ = tokio::signal::ctrl_c() => return Ok(()),
+            }
             tokio::signal::ctrl_c().await?;
             // });
 
-            // build_full_service::<E>(config, MAX_COMMITTEE_SIZE).await;
+            // build_full_service::<E>(config).await;
+        }
+        Some(Commands::Register {
+            keystore_dir,
+            registry_addr,
+            rpc_endpoint,
+            stake,
+            with_hint,
+            operator_keystore,
+        }) => {
+            let password = read_keystore_password(false)?;
+            let keys = NodeKeystore::new(PathBuf::from(keystore_dir)).load::<E>(&password)?;
+
+            // the hint only matches the network's if the network uses this node's config
+            let mut hint = Vec::new();
+            if *with_hint {
+                let config = Config::<E>::load(PathBuf::from(keystore_dir).join(CONFIG_FILE))?;
+                keys.secret_key
+                    .get_pk(&config.crs)
+                    .serialize_compressed(&mut hint)?;
+            }
+
+            let seed = operator_keystore.as_ref().map(load_mnemonic);
+            let backend = Arc::new(
+                SubstrateBackend::new(fangorn::WS_URL.to_string(), seed.as_deref()).await?,
+            );
+            let registry = ContractCommitteeRegistry::new(registry_addr.clone(), backend);
+            let node_id = keys.iroh_secret_key.public();
+            registry
+                .register_member(
+                    keys.index as u32,
+                    *node_id.as_bytes(),
+                    hint,
+                    rpc_endpoint.clone(),
+                    *stake,
+                )
+                .await?;
+
+            println!("> Registered node {} at index {}", node_id, keys.index);
         }
         None => {
             // do nothing
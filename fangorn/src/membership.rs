@@ -0,0 +1,415 @@
+//! Committee membership and authenticated announcements
+//!
+//! Anyone holding the doc ticket can write to the shared doc, so every announcement is
+//! signed with the node's iroh key, together with its committee index and the epoch.
+//! Announcements are only applied if the signer is the admitted member for that index.
+//! Members are admitted by a members file, or by the on-chain committee registry.
+use crate::registry::RegisteredMember;
+use crate::types::Announcement;
+use codec::{Decode, Encode};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use iroh::{PublicKey as IrohPublicKey, SecretKey as IrohSecretKey};
+use std::{collections::BTreeMap, fs, path::Path, str::FromStr};
+use thiserror::Error;
+
+/// The name of the members file in a node directory
+pub const MEMBERS_FILE: &str = "members.txt";
+/// The name of the file holding a node's own members entry (written by `fangorn setup`)
+pub const MEMBER_ENTRY_FILE: &str = "member.txt";
+
+/// The members file directive that retires old committee epochs
+const RETIRE_BEFORE_DIRECTIVE: &str = "retire-before";
+
+/// Domain separator for announcement signatures
+const ANNOUNCEMENT_DST: &[u8] = b"fangorn-announcement";
+
+/// Domain separator for the signatures binding a transport key to a node id
+const IDENTITY_DST: &[u8] = b"fangorn-node-identity";
+
+#[derive(Error, Debug)]
+pub enum MembershipError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The members file is malformed: {0}")]
+    Malformed(String),
+    #[error("Index {0} is admitted twice")]
+    DuplicateIndex(u32),
+    #[error("No member is admitted for index {0}")]
+    UnknownMember(u32),
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Epoch mismatch: the announcement is for epoch {found}, expected {expected}")]
+    EpochMismatch { expected: u64, found: u64 },
+}
+
+/// An admitted committee member
+#[derive(Clone, Debug, PartialEq)]
+pub struct Member {
+    /// the iroh node id, announcements must be signed by it
+    pub node_id: IrohPublicKey,
+    /// the compressed STE public key (hint) registered on-chain, if any
+    pub hint: Option<Vec<u8>>,
+    /// the RPC endpoint registered on-chain, if any
+    pub rpc_endpoint: Option<String>,
+}
+
+/// A member, as carried by epoch changes
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct MemberEntry {
+    pub index: u32,
+    pub node_id: [u8; 32],
+    pub hint: Option<Vec<u8>>,
+    pub rpc_endpoint: Option<String>,
+}
+
+/// The admitted committee members, by committee index
+#[derive(Clone, Debug, Default)]
+pub struct Members {
+    members: BTreeMap<u32, Member>,
+    /// committee epochs before this one should be retired (`retire-before <epoch>`)
+    retire_before: Option<u64>,
+}
+
+impl Members {
+    /// Admit a node for a committee index
+    pub fn admit(&mut self, index: u32, node_id: IrohPublicKey) -> Result<(), MembershipError> {
+        match self.members.get(&index) {
+            Some(admitted) if admitted.node_id != node_id => {
+                Err(MembershipError::DuplicateIndex(index))
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.members.insert(
+                    index,
+                    Member {
+                        node_id,
+                        hint: None,
+                        rpc_endpoint: None,
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    pub fn get(&self, index: u32) -> Option<&Member> {
+        self.members.get(&index)
+    }
+
+    /// The admitted members, ordered by committee index
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Member)> {
+        self.members.iter().map(|(index, member)| (*index, member))
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn retire_before(&self) -> Option<u64> {
+        self.retire_before
+    }
+
+    pub fn set_retire_before(&mut self, epoch: u64) {
+        self.retire_before = Some(epoch);
+    }
+
+    /// The members as entries, ordered by committee index
+    pub fn entries(&self) -> Vec<MemberEntry> {
+        self.members
+            .iter()
+            .map(|(index, member)| MemberEntry {
+                index: *index,
+                node_id: *member.node_id.as_bytes(),
+                hint: member.hint.clone(),
+                rpc_endpoint: member.rpc_endpoint.clone(),
+            })
+            .collect()
+    }
+
+    pub fn from_entries(entries: &[MemberEntry]) -> Result<Self, MembershipError> {
+        let mut members = Self::default();
+        for entry in entries {
+            if members.members.contains_key(&entry.index) {
+                return Err(MembershipError::DuplicateIndex(entry.index));
+            }
+            let node_id = IrohPublicKey::from_bytes(&entry.node_id)
+                .map_err(|e| MembershipError::Malformed(e.to_string()))?;
+            members.members.insert(
+                entry.index,
+                Member {
+                    node_id,
+                    hint: entry.hint.clone(),
+                    rpc_endpoint: entry.rpc_endpoint.clone(),
+                },
+            );
+        }
+        Ok(members)
+    }
+
+    /// Parse a members file: one `<index> <node id>` entry per line, `#` starts a comment
+    /// A `retire-before <epoch>` line asks the committee to retire the epochs before `epoch`
+    pub fn parse(contents: &str) -> Result<Self, MembershipError> {
+        let mut members = Self::default();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (index, node_id) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| MembershipError::Malformed(line.into()))?;
+            if index == RETIRE_BEFORE_DIRECTIVE {
+                let epoch = node_id
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|e| MembershipError::Malformed(e.to_string()))?;
+                members.set_retire_before(epoch);
+                continue;
+            }
+            let index = index
+                .parse::<u32>()
+                .map_err(|e| MembershipError::Malformed(e.to_string()))?;
+            let node_id = IrohPublicKey::from_str(node_id.trim())
+                .map_err(|e| MembershipError::Malformed(e.to_string()))?;
+            members.admit(index, node_id)?;
+        }
+        Ok(members)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MembershipError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Admit the members of the on-chain committee registry
+    pub fn from_registry(registered: &[RegisteredMember]) -> Result<Self, MembershipError> {
+        let mut members = Self::default();
+        for member in registered {
+            if members.members.contains_key(&member.index) {
+                return Err(MembershipError::DuplicateIndex(member.index));
+            }
+            let node_id = IrohPublicKey::from_bytes(&member.node_id)
+                .map_err(|e| MembershipError::Malformed(e.to_string()))?;
+            let rpc_endpoint = String::from_utf8(member.rpc_endpoint.clone())
+                .map_err(|e| MembershipError::Malformed(e.to_string()))?;
+            members.members.insert(
+                member.index,
+                Member {
+                    node_id,
+                    hint: (!member.ste_public_key.is_empty())
+                        .then(|| member.ste_public_key.clone()),
+                    rpc_endpoint: (!rpc_endpoint.is_empty()).then_some(rpc_endpoint),
+                },
+            );
+        }
+        Ok(members)
+    }
+}
+
+/// The members file entry of a node
+pub fn member_entry(index: u32, node_id: &IrohPublicKey) -> String {
+    format!("{} {}\n", index, node_id)
+}
+
+/// An announcement signed by a committee member
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct SignedAnnouncement {
+    pub announcement: Announcement,
+    /// the committee index of the signer
+    pub index: u32,
+    /// the epoch the announcement belongs to
+    pub epoch: u64,
+    /// the ed25519 signature of the signer's iroh key
+    pub signature: [u8; 64],
+}
+
+impl SignedAnnouncement {
+    fn message(announcement: &Announcement, index: u32, epoch: u64) -> Vec<u8> {
+        (ANNOUNCEMENT_DST, announcement, index, epoch).encode()
+    }
+
+    /// Check the signature against the member admitted for the index, and the epoch
+    pub fn verify(&self, members: &Members, epoch: u64) -> Result<(), MembershipError> {
+        if self.epoch != epoch {
+            return Err(MembershipError::EpochMismatch {
+                expected: epoch,
+                found: self.epoch,
+            });
+        }
+        let member = members
+            .get(self.index)
+            .ok_or(MembershipError::UnknownMember(self.index))?;
+        let message = Self::message(&self.announcement, self.index, self.epoch);
+        // iroh node ids are ed25519 public keys
+        VerifyingKey::from_bytes(member.node_id.as_bytes())
+            .and_then(|key| key.verify_strict(&message, &Signature::from_bytes(&self.signature)))
+            .map_err(|_| MembershipError::InvalidSignature)
+    }
+}
+
+/// Check that a transport key and OPRF key were signed by the node, so they were not swapped on the way
+pub fn verify_identity(
+    node_id: &IrohPublicKey,
+    transport_key: &[u8; 32],
+    oprf_key: &[u8],
+    signature: &[u8],
+) -> Result<(), MembershipError> {
+    let signature =
+        Signature::from_slice(signature).map_err(|_| MembershipError::InvalidSignature)?;
+    let message = (IDENTITY_DST, transport_key, oprf_key).encode();
+    VerifyingKey::from_bytes(node_id.as_bytes())
+        .and_then(|key| key.verify_strict(&message, &signature))
+        .map_err(|_| MembershipError::InvalidSignature)
+}
+
+/// Signs the announcements of this node
+#[derive(Clone)]
+pub struct AnnouncementSigner {
+    index: u32,
+    key: IrohSecretKey,
+}
+
+impl AnnouncementSigner {
+    pub fn new(index: u32, key: IrohSecretKey) -> Self {
+        Self { index, key }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn node_id(&self) -> IrohPublicKey {
+        self.key.public()
+    }
+
+    /// Sign the transport key clients seal witnesses to and the OPRF key they derive
+    /// password keys with, so they can check both are ours
+    pub fn sign_identity(&self, transport_key: &[u8; 32], oprf_key: &[u8]) -> [u8; 64] {
+        SigningKey::from_bytes(&self.key.to_bytes())
+            .sign(&(IDENTITY_DST, transport_key, oprf_key).encode())
+            .to_bytes()
+    }
+
+    pub fn sign(&self, announcement: Announcement, epoch: u64) -> SignedAnnouncement {
+        let message = SignedAnnouncement::message(&announcement, self.index, epoch);
+        SignedAnnouncement {
+            signature: SigningKey::from_bytes(&self.key.to_bytes())
+                .sign(&message)
+                .to_bytes(),
+            announcement,
+            index: self.index,
+            epoch,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::Tag;
+    use ark_std::rand::rngs::OsRng;
+
+    #[test]
+    fn only_admitted_members_can_announce() {
+        let alice = AnnouncementSigner::new(0, IrohSecretKey::generate(OsRng));
+        let mallory = AnnouncementSigner::new(1, IrohSecretKey::generate(OsRng));
+        let members = Members::parse(&member_entry(0, &alice.node_id())).unwrap();
+
+        let announcement = Announcement {
+            tag: Tag::Hint,
+            data: vec![1, 2, 3],
+        };
+        let signed = alice.sign(announcement.clone(), 0);
+        assert!(signed.verify(&members, 0).is_ok());
+        assert!(matches!(
+            signed.verify(&members, 1),
+            Err(MembershipError::EpochMismatch { .. })
+        ));
+
+        // tampered data
+        let mut tampered = signed.clone();
+        tampered.announcement.data = vec![4];
+        assert!(matches!(
+            tampered.verify(&members, 0),
+            Err(MembershipError::InvalidSignature)
+        ));
+
+        // not admitted, or claiming another member's index
+        assert!(matches!(
+            mallory.sign(announcement.clone(), 0).verify(&members, 0),
+            Err(MembershipError::UnknownMember(1))
+        ));
+        let mut impostor = mallory.sign(announcement, 0);
+        impostor.index = 0;
+        assert!(matches!(
+            impostor.verify(&members, 0),
+            Err(MembershipError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn identities_are_signed_by_their_node() {
+        let alice = AnnouncementSigner::new(0, IrohSecretKey::generate(OsRng));
+        let mallory = AnnouncementSigner::new(1, IrohSecretKey::generate(OsRng));
+        let key = [7u8; 32];
+        let oprf = [9u8; 96];
+
+        let signature = alice.sign_identity(&key, &oprf);
+        assert!(verify_identity(&alice.node_id(), &key, &oprf, &signature).is_ok());
+        // swapped keys, or signed by another node
+        assert!(verify_identity(&alice.node_id(), &[8u8; 32], &oprf, &signature).is_err());
+        assert!(verify_identity(&alice.node_id(), &key, &[8u8; 96], &signature).is_err());
+        let forged = mallory.sign_identity(&[8u8; 32], &oprf);
+        assert!(verify_identity(&alice.node_id(), &[8u8; 32], &oprf, &forged).is_err());
+        assert!(verify_identity(&alice.node_id(), &key, &oprf, &signature[..63]).is_err());
+    }
+
+    #[test]
+    fn registry_members_are_admitted() {
+        let node_id = IrohSecretKey::generate(OsRng).public();
+        let registered = |index: u32, endpoint: &str| RegisteredMember {
+            operator: [1u8; 32],
+            index,
+            node_id: *node_id.as_bytes(),
+            ste_public_key: vec![],
+            rpc_endpoint: endpoint.as_bytes().to_vec(),
+            stake: 0,
+        };
+
+        let members =
+            Members::from_registry(&[registered(0, "http://127.0.0.1:30332"), registered(1, "")])
+                .unwrap();
+        assert_eq!(members.get(0).unwrap().node_id, node_id);
+        assert_eq!(
+            members.get(0).unwrap().rpc_endpoint.as_deref(),
+            Some("http://127.0.0.1:30332")
+        );
+        assert_eq!(members.get(1).unwrap().rpc_endpoint, None);
+        assert_eq!(members.get(1).unwrap().hint, None);
+
+        assert!(matches!(
+            Members::from_registry(&[registered(0, ""), registered(0, "")]),
+            Err(MembershipError::DuplicateIndex(0))
+        ));
+
+        // epoch changes carry the members as entries
+        let restored = Members::from_entries(&members.entries()).unwrap();
+        assert_eq!(restored.entries(), members.entries());
+    }
+
+    #[test]
+    fn members_file_can_retire_epochs() {
+        let node_id = IrohSecretKey::generate(OsRng).public();
+        let contents = format!(
+            "# committee\n{}retire-before 2\n",
+            member_entry(0, &node_id)
+        );
+        let members = Members::parse(&contents).unwrap();
+        assert_eq!(members.len(), 1);
+        assert_eq!(members.retire_before(), Some(2));
+        assert!(Members::parse("retire-before two").is_err());
+    }
+}
@@ -1,17 +1,23 @@
 use anyhow::Result;
-use iroh::{Endpoint, NodeAddr, protocol::Router};
+use iroh::{
+    Endpoint, NodeAddr, NodeId, RelayMap, RelayMode, RelayUrl,
+    endpoint::Builder,
+    protocol::{Router, RouterBuilder},
+};
 use iroh_blobs::{ALPN as BLOBS_ALPN, net_protocol::Blobs};
 use iroh_docs::{ALPN as DOCS_ALPN, protocol::Docs};
 use iroh_gossip::{ALPN as GOSSIP_ALPN, net::Gossip};
 
+use crate::rpc::protocol::{FANGORN_ALPN, RpcProtocol};
 use crate::types::*;
+use crate::universe::Universes;
 use ark_ec::pairing::Pairing;
-use silent_threshold_encryption::setup::PublicKey;
 use std::{
     net::{Ipv4Addr, SocketAddrV4},
+    path::Path,
+    str::FromStr,
     sync::Arc,
 };
-use tokio::sync::Mutex;
 
 use quic_rpc::transport::flume::FlumeConnector;
 
@@ -23,6 +29,51 @@ pub(crate) type BlobsClient = iroh_blobs::rpc::client::blobs::Client<
 pub(crate) type DocsClient = iroh_docs::rpc::client::docs::Client<
     FlumeConnector<iroh_docs::rpc::proto::Response, iroh_docs::rpc::proto::Request>,
 >;
+/// How a node (or client) finds and reaches its peers
+#[derive(Clone, Debug, Default)]
+pub struct NetworkConfig {
+    /// turn off n0 discovery and the n0 relays: peers are found on the local network,
+    /// from explicit addresses or through the self-hosted relay
+    pub offline: bool,
+    /// a self-hosted relay to use instead of the n0 relays
+    pub relay_url: Option<String>,
+    /// the node clients read the system keys and the committee from,
+    /// an http(s) RPC endpoint or `iroh:<node id>`
+    pub entry_node: Option<String>,
+}
+
+/// The entry node of a local testnet
+pub const DEFAULT_ENTRY_NODE: &str = "http://127.0.0.1:30332";
+
+impl NetworkConfig {
+    /// The node clients read the system keys and the committee from
+    pub fn entry_node(&self) -> &str {
+        self.entry_node.as_deref().unwrap_or(DEFAULT_ENTRY_NODE)
+    }
+
+    /// An endpoint builder for the network, which always discovers peers on the local network
+    pub fn endpoint_builder(&self) -> Result<Builder> {
+        let mut builder = Endpoint::builder().discovery_local_network();
+        if !self.offline {
+            builder = builder.discovery_n0();
+        }
+        Ok(builder.relay_mode(self.relay_mode()?))
+    }
+
+    /// The relays to use: the self-hosted one if given, none when offline, else the n0 relays
+    pub fn relay_mode(&self) -> Result<RelayMode> {
+        Ok(match &self.relay_url {
+            Some(url) => {
+                let url = RelayUrl::from_str(url)
+                    .map_err(|e| anyhow::anyhow!("Invalid relay url {}: {}", url, e))?;
+                RelayMode::Custom(RelayMap::from_url(url))
+            }
+            None if self.offline => RelayMode::Disabled,
+            None => RelayMode::Default,
+        })
+    }
+}
+
 /// A node...
 #[derive(Clone)]
 pub struct Node<C: Pairing> {
@@ -34,8 +85,8 @@ pub struct Node<C: Pairing> {
     blobs: BlobsClient,
     /// docs client
     docs: DocsClient,
-    /// the node state
-    state: Arc<Mutex<State<C>>>,
+    /// the state of each universe the node takes part in
+    universes: Arc<Universes<C>>,
 }
 
 impl<C: Pairing> Node<C> {
@@ -46,38 +97,44 @@ impl<C: Pairing> Node<C> {
     pub fn docs(&self) -> DocsClient {
         self.docs.clone()
     }
+
+    /// the node id other nodes and clients dial
+    pub fn node_id(&self) -> NodeId {
+        self.endpoint.node_id()
+    }
+
+    pub fn universes(&self) -> Arc<Universes<C>> {
+        Arc::clone(&self.universes)
+    }
 }
 
-impl<C: Pairing> Node<C> {
-    /// start the node
-    pub async fn build(
-        params: StartNodeParams<C>,
-        rx: flume::Receiver<Announcement>,
-        state: Arc<Mutex<State<C>>>,
-    ) -> Self {
-        println!("Building the node...");
-        let endpoint = Endpoint::builder()
-            .secret_key(params.iroh_secret_key.clone())
-            .discovery_n0()
-            .discovery_local_network()
-            .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, params.bind_port))
-            .bind()
-            .await
-            .unwrap();
-        // build gossip protocol
-        let gossip = Gossip::builder().spawn(endpoint.clone()).await.unwrap();
-        // build the blobs protocol (just in mem for now, not persistent)
-        let blobs = Blobs::memory().build(&endpoint.clone());
-        // build the docs protocol (just in mem for now, not persistent)
-        let docs = Docs::memory().spawn(&blobs, &gossip).await.unwrap();
-        // setup router
-        let router = Router::builder(endpoint.clone())
-            .accept(GOSSIP_ALPN, gossip.clone())
-            .accept(BLOBS_ALPN, blobs.clone())
-            .accept(DOCS_ALPN, docs.clone())
-            .spawn()
-            .await
-            .unwrap();
+/// A node with its protocols set up, which accepts connections once spawned with the RPC
+pub struct NodeBuilder {
+    endpoint: Endpoint,
+    router: RouterBuilder,
+    blobs: BlobsClient,
+    docs: DocsClient,
+}
+
+impl NodeBuilder {
+    /// the blobs of the node, which the RPC serves documents from
+    pub fn blobs(&self) -> BlobsClient {
+        self.blobs.clone()
+    }
+
+    /// the endpoint of the node, which documents are downloaded with
+    pub fn endpoint(&self) -> Endpoint {
+        self.endpoint.clone()
+    }
+
+    /// accept connections for the gossip, blobs, docs and fangorn RPC protocols
+    /// the universes are only known once their docstores are built on the blobs
+    pub async fn spawn<C: Pairing>(
+        self,
+        universes: Arc<Universes<C>>,
+        rpc: RpcProtocol<C>,
+    ) -> Node<C> {
+        let router = self.router.accept(FANGORN_ALPN, rpc).spawn().await.unwrap();
 
         let addr = router.endpoint().node_addr().await;
         println!("> Generated node address: {:?}", addr);
@@ -90,22 +147,62 @@ impl<C: Pairing> Node<C> {
             .open("pubkey.txt")
             .unwrap();
         writeln!(&mut file, "{}", pubkey).expect("Unable to write pubkey to file.");
-        let arc_state_clone = Arc::clone(&state);
 
-        n0_future::task::spawn(async move {
-            while let Ok(announcement) = rx.recv_async().await {
-                let mut state = arc_state_clone.lock().await;
-                state.update(announcement);
+        Node {
+            endpoint: self.endpoint,
+            router,
+            blobs: self.blobs,
+            docs: self.docs,
+            universes,
+        }
+    }
+}
+
+impl<C: Pairing> Node<C> {
+    /// build the node
+    /// the docs and blobs are kept in `data_dir` if given, so they survive restarts
+    pub async fn build(
+        params: StartNodeParams<C>,
+        data_dir: Option<&Path>,
+        network: &NetworkConfig,
+    ) -> Result<NodeBuilder> {
+        println!("Building the node...");
+        if network.offline {
+            println!("> Offline: n0 discovery and relays are off");
+        }
+        let endpoint = network
+            .endpoint_builder()?
+            .secret_key(params.iroh_secret_key.clone())
+            .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, params.bind_port))
+            .bind()
+            .await?;
+        // build gossip protocol
+        let gossip = Gossip::builder().spawn(endpoint.clone()).await?;
+        // build the blobs and docs protocols, in memory unless a data dir is given
+        let (router, blobs, docs) = match data_dir {
+            Some(data_dir) => {
+                println!("> Persisting docs and blobs to {}", data_dir.display());
+                let blobs = Blobs::persistent(data_dir.join("blobs"))
+                    .await?
+                    .build(&endpoint);
+                let docs = Docs::persistent(data_dir.join("docs"))
+                    .spawn(&blobs, &gossip)
+                    .await?;
+                build_router(&endpoint, gossip, blobs, docs)
+            }
+            None => {
+                let blobs = Blobs::memory().build(&endpoint);
+                let docs = Docs::memory().spawn(&blobs, &gossip).await?;
+                build_router(&endpoint, gossip, blobs, docs)
             }
-        });
+        };
 
-        Node {
+        Ok(NodeBuilder {
             endpoint,
             router,
-            blobs: blobs.client().clone(),
-            docs: docs.client().clone(),
-            state,
-        }
+            blobs,
+            docs,
+        })
     }
 
     /// join the gossip topic by connecting to known peers, if any
@@ -126,14 +223,81 @@ impl<C: Pairing> Node<C> {
         println!("> Connection established.");
         Ok(())
     }
+}
 
-    /// Get the node public key (if it exists)
-    pub async fn get_pk(&self) -> Option<PublicKey<C>> {
-        let state = &self.state.lock().await;
-        if let (Some(cfg), sk) = (state.config.clone(), state.sk.clone()) {
-            return Some(sk.get_pk(&cfg.crs));
-        }
+/// An endpoint serving in-memory blobs, which clients publish documents from and download
+/// them to; documents are served until the router is shut down
+pub(crate) async fn client_blobs(network: &NetworkConfig) -> Result<(Router, BlobsClient)> {
+    let endpoint = network.endpoint_builder()?.bind().await?;
+    let blobs = Blobs::memory().build(&endpoint);
+    let client = blobs.client().clone();
+    let router = Router::builder(endpoint)
+        .accept(BLOBS_ALPN, blobs)
+        .spawn()
+        .await?;
+    Ok((router, client))
+}
+
+/// Setup the router with the gossip, blobs and docs protocols
+fn build_router<S: iroh_blobs::store::Store>(
+    endpoint: &Endpoint,
+    gossip: Gossip,
+    blobs: Blobs<S>,
+    docs: Docs<S>,
+) -> (RouterBuilder, BlobsClient, DocsClient) {
+    let blobs_client = blobs.client().clone();
+    let docs_client = docs.client().clone();
+    let router = Router::builder(endpoint.clone())
+        .accept(GOSSIP_ALPN, gossip)
+        .accept(BLOBS_ALPN, blobs)
+        .accept(DOCS_ALPN, docs);
+    (router, blobs_client, docs_client)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn relays_follow_the_network_config() {
+        let online = NetworkConfig::default();
+        assert_eq!(online.relay_mode().unwrap(), RelayMode::Default);
 
-        None
+        let offline = NetworkConfig {
+            offline: true,
+            ..Default::default()
+        };
+        assert_eq!(offline.relay_mode().unwrap(), RelayMode::Disabled);
+
+        // a self-hosted relay is used even offline
+        let url = "https://relay.example.com";
+        let relayed = NetworkConfig {
+            offline: true,
+            relay_url: Some(url.into()),
+            ..Default::default()
+        };
+        let expected = RelayMap::from_url(RelayUrl::from_str(url).unwrap());
+        assert_eq!(relayed.relay_mode().unwrap(), RelayMode::Custom(expected));
+        assert!(relayed.endpoint_builder().is_ok());
+    }
+
+    #[test]
+    fn invalid_relay_urls_are_refused() {
+        let network = NetworkConfig {
+            relay_url: Some("not a url".into()),
+            ..Default::default()
+        };
+        assert!(network.relay_mode().is_err());
+        assert!(network.endpoint_builder().is_err());
+    }
+
+    #[tokio::test]
+    async fn offline_endpoints_bind() {
+        let network = NetworkConfig {
+            offline: true,
+            ..Default::default()
+        };
+        let endpoint = network.endpoint_builder().unwrap().bind().await.unwrap();
+        endpoint.close().await;
     }
 }
@@ -0,0 +1,102 @@
+//! Service readiness
+//!
+//! A node goes through explicit stages while it starts a universe: it syncs the universe doc
+//! with a peer, receives the config, loads the committee history, publishes its own hint and
+//! serves the RPC. Each stage is marked when the doc event (or step) that completes it happens,
+//! so startup waits exactly as long as sync takes instead of for fixed delays.
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// The stages a universe has reached on this node
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Readiness {
+    /// the doc is synced with a peer and its content downloaded (or was created by us)
+    pub connected: bool,
+    /// the config is set
+    pub config_loaded: bool,
+    /// the committee history (epoch changes, hints and retirements) is applied
+    pub hints_synced: bool,
+    /// our hint for the current epoch is published (if we are a member)
+    pub hint_published: bool,
+    /// the RPC server is listening
+    pub rpc_up: bool,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.connected
+            && self.config_loaded
+            && self.hints_synced
+            && self.hint_published
+            && self.rpc_up
+    }
+}
+
+/// The readiness of a universe, shared by the startup tasks, the state and the RPC
+#[derive(Clone, Debug)]
+pub struct ReadinessTracker {
+    tx: Arc<watch::Sender<Readiness>>,
+}
+
+impl Default for ReadinessTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReadinessTracker {
+    pub fn new() -> Self {
+        Self {
+            tx: Arc::new(watch::Sender::new(Readiness::default())),
+        }
+    }
+
+    pub fn get(&self) -> Readiness {
+        *self.tx.borrow()
+    }
+
+    /// Mark the stages set by `mark` as reached (stages are never unmarked)
+    pub fn mark(&self, mark: impl FnOnce(&mut Readiness)) {
+        self.tx.send_if_modified(|readiness| {
+            let before = *readiness;
+            mark(readiness);
+            *readiness != before
+        });
+    }
+
+    /// Wait until the readiness satisfies `reached`
+    pub async fn wait(&self, reached: impl Fn(&Readiness) -> bool) -> Readiness {
+        let mut rx = self.tx.subscribe();
+        // the sender lives as long as this tracker, so the channel is never closed
+        let readiness = rx
+            .wait_for(|readiness| reached(readiness))
+            .await
+            .expect("the sender is held by the tracker");
+        *readiness
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_the_stages_to_be_marked() {
+        let tracker = ReadinessTracker::new();
+        let waiter = tracker.clone();
+        let ready = tokio::spawn(async move { waiter.wait(Readiness::is_ready).await });
+
+        tracker.mark(|r| r.connected = true);
+        tracker.mark(|r| r.config_loaded = true);
+        tracker.mark(|r| {
+            r.hints_synced = true;
+            r.hint_published = true;
+        });
+        assert!(!tracker.get().is_ready());
+        tracker.mark(|r| r.rpc_up = true);
+
+        assert!(ready.await.unwrap().is_ready());
+        // already reached
+        assert!(tracker.wait(|r| r.connected).await.connected);
+    }
+}
@@ -0,0 +1,120 @@
+//! The on-chain committee registry
+//! Operators register their node (node id, STE public key, RPC endpoint and committee index)
+//! in the committee contract, which nodes and clients read to decide who is in the committee
+use crate::backend::BlockchainBackend;
+use anyhow::Result;
+use async_trait::async_trait;
+use codec::{Decode, Encode};
+use std::sync::Arc;
+
+/// A committee member, as stored by the committee contract
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct RegisteredMember {
+    /// the account that registered (and staked for) the node
+    pub operator: [u8; 32],
+    /// the committee index of the node
+    pub index: u32,
+    /// the iroh node id
+    pub node_id: [u8; 32],
+    /// the compressed STE public key (hint), empty if not registered
+    pub ste_public_key: Vec<u8>,
+    /// the RPC endpoint of the node, empty if not registered
+    pub rpc_endpoint: Vec<u8>,
+    /// the stake locked by the operator
+    pub stake: u128,
+}
+
+/// Read and write the committee registry
+#[async_trait]
+pub trait CommitteeRegistry: Send + Sync {
+    /// All registered members
+    async fn members(&self) -> Result<Vec<RegisteredMember>>;
+
+    /// Committee epochs before this one should be retired
+    async fn retire_before(&self) -> Result<u64>;
+
+    /// Register a node as the member at `index`, locking `stake`
+    async fn register_member(
+        &self,
+        index: u32,
+        node_id: [u8; 32],
+        ste_public_key: Vec<u8>,
+        rpc_endpoint: String,
+        stake: u128,
+    ) -> Result<()>;
+}
+
+/// The committee registry contract (`contract/committee`)
+pub struct ContractCommitteeRegistry {
+    contract_address: String,
+    backend: Arc<dyn BlockchainBackend>,
+}
+
+impl ContractCommitteeRegistry {
+    pub fn new(contract_address: String, backend: Arc<dyn BlockchainBackend>) -> Self {
+        Self {
+            contract_address,
+            backend,
+        }
+    }
+}
+
+#[async_trait]
+impl CommitteeRegistry for ContractCommitteeRegistry {
+    async fn members(&self) -> Result<Vec<RegisteredMember>> {
+        let selector = self.backend.selector("members");
+        let contract_addr_bytes = crate::utils::decode_contract_addr(&self.contract_address);
+
+        let mut data = self
+            .backend
+            .query_contract(contract_addr_bytes, selector, Vec::new())
+            .await?;
+        // strip the Result<_, LangError> variant
+        if !data.is_empty() {
+            data.remove(0);
+        }
+
+        Ok(Vec::<RegisteredMember>::decode(&mut &data[..])?)
+    }
+
+    async fn retire_before(&self) -> Result<u64> {
+        let selector = self.backend.selector("retire_before");
+        let contract_addr_bytes = crate::utils::decode_contract_addr(&self.contract_address);
+
+        let mut data = self
+            .backend
+            .query_contract(contract_addr_bytes, selector, Vec::new())
+            .await?;
+        // strip the Result<_, LangError> variant
+        if !data.is_empty() {
+            data.remove(0);
+        }
+
+        Ok(u64::decode(&mut &data[..])?)
+    }
+
+    async fn register_member(
+        &self,
+        index: u32,
+        node_id: [u8; 32],
+        ste_public_key: Vec<u8>,
+        rpc_endpoint: String,
+        stake: u128,
+    ) -> Result<()> {
+        let selector = self.backend.selector("register_member");
+
+        let mut data = Vec::new();
+        data.extend(index.encode());
+        data.extend(node_id.encode());
+        data.extend(ste_public_key.encode());
+        data.extend(rpc_endpoint.into_bytes().encode());
+
+        let contract_addr_bytes = crate::utils::decode_contract_addr(&self.contract_address);
+
+        self.backend
+            .call_contract_with_value(contract_addr_bytes, selector, data, stake)
+            .await?;
+
+        Ok(())
+    }
+}
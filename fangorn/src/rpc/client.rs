@@ -0,0 +1,190 @@
+use crate::rpc::protocol::{IrohRpcClient, RpcMethod};
+use crate::rpc::server::*;
+use crate::universe::UniverseId;
+use async_trait::async_trait;
+use iroh::NodeId;
+use tonic::{Status, transport::Channel};
+
+/// A connection to a node's RPC, over gRPC or over iroh
+#[async_trait]
+pub trait NodeRpc: Send {
+    /// the system keys of a committee epoch of a universe (the current one if `None`)
+    async fn preprocess(
+        &mut self,
+        universe_id: UniverseId,
+        epoch: Option<u64>,
+    ) -> Result<PreprocessResponse, Status>;
+
+    /// a partial decryption, sealed to the requester
+    async fn partdec(&mut self, request: PartDecRequest) -> Result<PartDecResponse, Status>;
+
+    /// the transport key that witnesses for a universe are sealed to
+    async fn identity(&mut self, universe_id: UniverseId) -> Result<IdentityResponse, Status>;
+
+    /// the RPC endpoints of the members of a committee epoch of a universe (the current one if `None`)
+    async fn committee(
+        &mut self,
+        universe_id: UniverseId,
+        epoch: Option<u64>,
+    ) -> Result<CommitteeResponse, Status>;
+
+    /// the stages the node has reached starting a universe
+    async fn readiness(&mut self, universe_id: UniverseId) -> Result<ReadinessResponse, Status>;
+
+    /// have the node download a document registered in a universe from the client serving it
+    async fn publish(
+        &mut self,
+        universe_id: UniverseId,
+        filename: &str,
+        provider: NodeId,
+    ) -> Result<PublishResponse, Status>;
+
+    /// the node's password OPRF evaluated on a blinded input
+    async fn oprf(
+        &mut self,
+        universe_id: UniverseId,
+        blinded: &[u8],
+    ) -> Result<OprfResponse, Status>;
+}
+
+#[async_trait]
+impl NodeRpc for RpcClient<Channel> {
+    async fn preprocess(
+        &mut self,
+        universe_id: UniverseId,
+        epoch: Option<u64>,
+    ) -> Result<PreprocessResponse, Status> {
+        let request = PreprocessRequest {
+            epoch,
+            universe_id: universe_id.to_vec(),
+        };
+        Ok(RpcClient::preprocess(self, request).await?.into_inner())
+    }
+
+    async fn partdec(&mut self, request: PartDecRequest) -> Result<PartDecResponse, Status> {
+        Ok(RpcClient::partdec(self, request).await?.into_inner())
+    }
+
+    async fn identity(&mut self, universe_id: UniverseId) -> Result<IdentityResponse, Status> {
+        let request = IdentityRequest {
+            universe_id: universe_id.to_vec(),
+        };
+        Ok(RpcClient::identity(self, request).await?.into_inner())
+    }
+
+    async fn committee(
+        &mut self,
+        universe_id: UniverseId,
+        epoch: Option<u64>,
+    ) -> Result<CommitteeResponse, Status> {
+        let request = CommitteeRequest {
+            epoch,
+            universe_id: universe_id.to_vec(),
+        };
+        Ok(RpcClient::committee(self, request).await?.into_inner())
+    }
+
+    async fn readiness(&mut self, universe_id: UniverseId) -> Result<ReadinessResponse, Status> {
+        let request = ReadinessRequest {
+            universe_id: universe_id.to_vec(),
+        };
+        Ok(RpcClient::readiness(self, request).await?.into_inner())
+    }
+
+    async fn publish(
+        &mut self,
+        universe_id: UniverseId,
+        filename: &str,
+        provider: NodeId,
+    ) -> Result<PublishResponse, Status> {
+        let request = PublishRequest {
+            filename: filename.to_string(),
+            provider: provider.to_string(),
+            universe_id: universe_id.to_vec(),
+        };
+        Ok(RpcClient::publish(self, request).await?.into_inner())
+    }
+
+    async fn oprf(
+        &mut self,
+        universe_id: UniverseId,
+        blinded: &[u8],
+    ) -> Result<OprfResponse, Status> {
+        let request = OprfRequest {
+            universe_id: universe_id.to_vec(),
+            blinded_hex: hex::encode(blinded),
+        };
+        Ok(RpcClient::oprf(self, request).await?.into_inner())
+    }
+}
+
+#[async_trait]
+impl NodeRpc for IrohRpcClient {
+    async fn preprocess(
+        &mut self,
+        universe_id: UniverseId,
+        epoch: Option<u64>,
+    ) -> Result<PreprocessResponse, Status> {
+        let request = PreprocessRequest {
+            epoch,
+            universe_id: universe_id.to_vec(),
+        };
+        self.call(RpcMethod::Preprocess, request).await
+    }
+
+    async fn partdec(&mut self, request: PartDecRequest) -> Result<PartDecResponse, Status> {
+        self.call(RpcMethod::PartDec, request).await
+    }
+
+    async fn identity(&mut self, universe_id: UniverseId) -> Result<IdentityResponse, Status> {
+        let request = IdentityRequest {
+            universe_id: universe_id.to_vec(),
+        };
+        self.call(RpcMethod::Identity, request).await
+    }
+
+    async fn committee(
+        &mut self,
+        universe_id: UniverseId,
+        epoch: Option<u64>,
+    ) -> Result<CommitteeResponse, Status> {
+        let request = CommitteeRequest {
+            epoch,
+            universe_id: universe_id.to_vec(),
+        };
+        self.call(RpcMethod::Committee, request).await
+    }
+
+    async fn readiness(&mut self, universe_id: UniverseId) -> Result<ReadinessResponse, Status> {
+        let request = ReadinessRequest {
+            universe_id: universe_id.to_vec(),
+        };
+        self.call(RpcMethod::Readiness, request).await
+    }
+
+    async fn publish(
+        &mut self,
+        universe_id: UniverseId,
+        filename: &str,
+        provider: NodeId,
+    ) -> Result<PublishResponse, Status> {
+        let request = PublishRequest {
+            filename: filename.to_string(),
+            provider: provider.to_string(),
+            universe_id: universe_id.to_vec(),
+        };
+        self.call(RpcMethod::Publish, request).await
+    }
+
+    async fn oprf(
+        &mut self,
+        universe_id: UniverseId,
+        blinded: &[u8],
+    ) -> Result<OprfResponse, Status> {
+        let request = OprfRequest {
+            universe_id: universe_id.to_vec(),
+            blinded_hex: hex::encode(blinded),
+        };
+        self.call(RpcMethod::Oprf, request).await
+    }
+}
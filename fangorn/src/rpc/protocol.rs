@@ -0,0 +1,319 @@
+//! The fangorn RPC over iroh
+//!
+//! Nodes accept the fangorn ALPN on their iroh router, so clients can dial them by node id
+//! through the same (hole-punched or relayed) connections that the state sync uses.
+//! Each call is one bidirectional stream: the client sends the SCALE encoded method with the
+//! protobuf encoded request (the messages of `rpc.proto`) and finishes the stream, the node
+//! answers with the protobuf encoded response or the code and message of an error.
+//! A node serves a bounded number of calls of a connection at a time, further streams wait.
+use crate::rpc::server::*;
+use anyhow::Result;
+use ark_ec::pairing::Pairing;
+use codec::{Decode, Encode};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::{Connection, RecvStream, SendStream},
+    protocol::ProtocolHandler,
+};
+use n0_future::boxed::BoxFuture;
+use prost::Message;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tonic::{Code, Request, Status};
+
+/// The ALPN of the fangorn RPC protocol
+pub const FANGORN_ALPN: &[u8] = b"/fangorn/rpc/0";
+
+/// The maximum size of a request or response
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// The maximum number of calls of one connection served at a time
+const MAX_CONCURRENT_CALLS: usize = 16;
+
+/// The answer to a call: the protobuf encoded response or the code and message of an error
+type CallResult = Result<Vec<u8>, (i32, String)>;
+
+/// The methods of the `Rpc` service
+#[derive(Clone, Copy, Debug, Encode, Decode)]
+pub enum RpcMethod {
+    Preprocess,
+    PartDec,
+    Identity,
+    Committee,
+    Readiness,
+    Publish,
+    Oprf,
+}
+
+/// Serves the node RPC on the iroh router
+pub struct RpcProtocol<C: Pairing> {
+    server: Arc<NodeServer<C>>,
+}
+
+impl<C: Pairing> RpcProtocol<C> {
+    pub fn new(server: Arc<NodeServer<C>>) -> Self {
+        Self { server }
+    }
+}
+
+impl<C: Pairing> Clone for RpcProtocol<C> {
+    fn clone(&self) -> Self {
+        Self {
+            server: Arc::clone(&self.server),
+        }
+    }
+}
+
+impl<C: Pairing> std::fmt::Debug for RpcProtocol<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcProtocol").finish()
+    }
+}
+
+impl<C: Pairing> ProtocolHandler for RpcProtocol<C> {
+    fn accept(&self, connection: Connection) -> BoxFuture<Result<()>> {
+        let server = Arc::clone(&self.server);
+        Box::pin(async move {
+            // serve calls until the client closes the connection,
+            // no stream is accepted while the connection has too many calls in flight
+            let calls = Arc::new(Semaphore::new(MAX_CONCURRENT_CALLS));
+            loop {
+                let permit = Arc::clone(&calls).acquire_owned().await?;
+                let Ok((send, recv)) = connection.accept_bi().await else {
+                    break;
+                };
+                let server = Arc::clone(&server);
+                n0_future::task::spawn(async move {
+                    if let Err(e) = serve_call(&server, send, recv).await {
+                        println!("Failed to serve an RPC call: {}", e);
+                    }
+                    drop(permit);
+                });
+            }
+            Ok(())
+        })
+    }
+}
+
+async fn serve_call<C: Pairing>(
+    server: &NodeServer<C>,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) -> Result<()> {
+    let response: CallResult = match recv.read_to_end(MAX_MESSAGE_SIZE).await {
+        Ok(bytes) => match <(RpcMethod, Vec<u8>)>::decode(&mut &bytes[..]) {
+            Ok((method, payload)) => dispatch(server, method, &payload).await,
+            Err(e) => Err(Status::invalid_argument(format!("Unknown RPC call: {}", e))),
+        },
+        // the rest of an oversized request is not read
+        Err(e) => {
+            let _ = recv.stop(0u32.into());
+            Err(Status::resource_exhausted(e.to_string()))
+        }
+    }
+    .map_err(|status| (status.code() as i32, status.message().to_string()));
+    send.write_all(&response.encode()).await?;
+    send.finish()?;
+    Ok(())
+}
+
+async fn dispatch<C: Pairing>(
+    server: &NodeServer<C>,
+    method: RpcMethod,
+    payload: &[u8],
+) -> Result<Vec<u8>, Status> {
+    let invalid = |e: prost::DecodeError| Status::invalid_argument(e.to_string());
+    let response = match method {
+        RpcMethod::Preprocess => {
+            let request = PreprocessRequest::decode(payload).map_err(invalid)?;
+            server
+                .preprocess(Request::new(request))
+                .await?
+                .into_inner()
+                .encode_to_vec()
+        }
+        RpcMethod::PartDec => {
+            let request = PartDecRequest::decode(payload).map_err(invalid)?;
+            server
+                .partdec(Request::new(request))
+                .await?
+                .into_inner()
+                .encode_to_vec()
+        }
+        RpcMethod::Identity => {
+            let request = IdentityRequest::decode(payload).map_err(invalid)?;
+            server
+                .identity(Request::new(request))
+                .await?
+                .into_inner()
+                .encode_to_vec()
+        }
+        RpcMethod::Committee => {
+            let request = CommitteeRequest::decode(payload).map_err(invalid)?;
+            server
+                .committee(Request::new(request))
+                .await?
+                .into_inner()
+                .encode_to_vec()
+        }
+        RpcMethod::Readiness => {
+            let request = ReadinessRequest::decode(payload).map_err(invalid)?;
+            server
+                .readiness(Request::new(request))
+                .await?
+                .into_inner()
+                .encode_to_vec()
+        }
+        RpcMethod::Publish => {
+            let request = PublishRequest::decode(payload).map_err(invalid)?;
+            server
+                .publish(Request::new(request))
+                .await?
+                .into_inner()
+                .encode_to_vec()
+        }
+        RpcMethod::Oprf => {
+            let request = OprfRequest::decode(payload).map_err(invalid)?;
+            server
+                .oprf(Request::new(request))
+                .await?
+                .into_inner()
+                .encode_to_vec()
+        }
+    };
+    Ok(response)
+}
+
+/// A client of the fangorn RPC protocol, connected to a node by its node id
+pub struct IrohRpcClient {
+    connection: Connection,
+}
+
+impl IrohRpcClient {
+    pub async fn connect(endpoint: &Endpoint, node_id: NodeId) -> Result<Self> {
+        let connection = endpoint.connect(node_id, FANGORN_ALPN).await?;
+        Ok(Self { connection })
+    }
+
+    /// Make a call on a new stream of the connection
+    pub async fn call<Req: Message, Resp: Message + Default>(
+        &self,
+        method: RpcMethod,
+        request: Req,
+    ) -> Result<Resp, Status> {
+        let bytes = (method, request.encode_to_vec()).encode();
+        if bytes.len() > MAX_MESSAGE_SIZE {
+            return Err(Status::resource_exhausted("The request is too large"));
+        }
+        let response = self.call_raw(&bytes).await?;
+        Resp::decode(&response[..]).map_err(|e| Status::internal(e.to_string()))
+    }
+
+    /// Send an encoded call and read the answer of the node
+    async fn call_raw(&self, bytes: &[u8]) -> Result<Vec<u8>, Status> {
+        let (mut send, mut recv) = self
+            .connection
+            .open_bi()
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let sent = match send.write_all(bytes).await {
+            Ok(()) => send.finish().map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+
+        // a node refusing a call stops reading it, but still answers why
+        let bytes = recv
+            .read_to_end(MAX_MESSAGE_SIZE)
+            .await
+            .map_err(|e| Status::unavailable(sent.err().unwrap_or_else(|| e.to_string())))?;
+        CallResult::decode(&mut &bytes[..])
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|(code, message)| Status::new(Code::from_i32(code), message))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gadget::GadgetRegistry;
+    use crate::types::*;
+    use crate::universe::{Universes, test::universe};
+    use iroh::{RelayMode, protocol::Router};
+
+    /// A node serving the RPC of one universe and a client connected to it,
+    /// with the node id the universe signs with
+    async fn loopback(config: &Config<E>) -> (Router, IrohRpcClient, NodeId) {
+        let universe = universe(Some(config));
+        let node_id = universe.state.lock().await.node_id;
+        let server = NodeServer {
+            universes: Arc::new(Universes::new(vec![universe])),
+            gadget_registry: Arc::new(tokio::sync::Mutex::new(GadgetRegistry::new())),
+        };
+        let endpoint = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let router = Router::builder(endpoint)
+            .accept(FANGORN_ALPN, RpcProtocol::new(Arc::new(server)))
+            .spawn()
+            .await
+            .unwrap();
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let addr = router.endpoint().node_addr().await.unwrap();
+        client.add_node_addr(addr.clone()).unwrap();
+        let client = IrohRpcClient::connect(&client, addr.node_id).await.unwrap();
+        (router, client, node_id)
+    }
+
+    #[tokio::test]
+    async fn calls_are_answered_over_iroh() {
+        let config = Config::<E>::rand(2);
+        let (router, client, node_id) = loopback(&config).await;
+
+        let request = IdentityRequest {
+            universe_id: config.universe_id().to_vec(),
+        };
+        let response: IdentityResponse = client.call(RpcMethod::Identity, request).await.unwrap();
+        assert_eq!(response.node_id, node_id.to_string());
+
+        // errors keep their code
+        let request = IdentityRequest {
+            universe_id: Config::<E>::rand(2).universe_id().to_vec(),
+        };
+        let status = client
+            .call::<_, IdentityResponse>(RpcMethod::Identity, request)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        router.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_and_oversized_calls_are_refused() {
+        let config = Config::<E>::rand(2);
+        let (router, client, _) = loopback(&config).await;
+
+        // a method of a newer version of the protocol
+        let unknown = (u8::MAX, Vec::<u8>::new()).encode();
+        let status = client.call_raw(&unknown).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let oversized = vec![0u8; MAX_MESSAGE_SIZE + 1];
+        let status = client.call_raw(&oversized).await.unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+
+        // the connection still serves calls
+        let request = IdentityRequest {
+            universe_id: config.universe_id().to_vec(),
+        };
+        let response: Result<IdentityResponse, _> = client.call(RpcMethod::Identity, request).await;
+        assert!(response.is_ok());
+        router.shutdown().await.unwrap();
+    }
+}
@@ -5,14 +5,27 @@ use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use silent_threshold_encryption::{aggregate::SystemPublicKeys, types::Ciphertext};
 
 use crate::{
-    gadget::GadgetRegistry,
+    crypto::{
+        envelope::{Envelope, intent_hash},
+        migration::verify_owner_authorization,
+        transport::{decode_public_key, partdec_context, seal, witness_context},
+    },
+    epoch::EpochError,
+    gadget::{
+        GadgetRegistry,
+        zk_password::{evaluate_oprf, oprf_public_key},
+    },
+    liveness::unix_now,
     storage::*,
     types::*,
+    universe::{Universe, UniverseError, Universes},
 };
 
 use tonic::{Request, Response, Status};
 
-use std::sync::Arc;
+use iroh::NodeId;
+use std::str::FromStr;
+use std::sync::{Arc, atomic::Ordering};
 use tokio::sync::Mutex;
 
 pub mod rpc {
@@ -21,36 +34,57 @@ pub mod rpc {
 
 pub use rpc::rpc_client::RpcClient;
 pub use rpc::rpc_server::{Rpc, RpcServer};
-pub use rpc::{PartDecRequest, PartDecResponse, PreprocessRequest, PreprocessResponse};
+pub use rpc::{
+    CommitteeMember, CommitteeRequest, CommitteeResponse, IdentityRequest, IdentityResponse,
+    OprfRequest, OprfResponse, PartDecRequest, PartDecResponse, PreprocessRequest,
+    PreprocessResponse, PublishRequest, PublishResponse, ReadinessRequest, ReadinessResponse,
+};
 
 pub struct NodeServer<C: Pairing> {
-    pub doc_store: Arc<dyn DocStore>,
-    pub intent_store: Arc<dyn IntentStore>,
-    pub state: Arc<Mutex<State<C>>>,
+    /// the state and stores of each universe
+    pub universes: Arc<Universes<C>>,
     pub gadget_registry: Arc<Mutex<GadgetRegistry>>,
 }
 
+impl<C: Pairing> NodeServer<C> {
+    /// The universe a request names
+    async fn universe(&self, universe_id: &[u8]) -> Result<&Universe<C>, Status> {
+        self.universes.get(universe_id).await.map_err(|e| match e {
+            UniverseError::InvalidId(_) => Status::invalid_argument(e.to_string()),
+            UniverseError::Unknown(_) => Status::not_found(e.to_string()),
+        })
+    }
+}
+
 #[tonic::async_trait]
 impl<C: Pairing> Rpc for NodeServer<C> {
-    /// preprocess with best known hints to get encryption and aggregate keys
-    /// For the hackathon, we can assume this is going to effectively output a static value
+    /// preprocess with the hints of an epoch to get encryption and aggregate keys
     async fn preprocess(
         &self,
-        _request: Request<PreprocessRequest>,
+        request: Request<PreprocessRequest>,
     ) -> Result<Response<PreprocessResponse>, Status> {
         let mut serialized_sys_key: Vec<u8> = vec![];
 
-        let state = self.state.lock().await;
-        if let (Some(config), Some(hints)) = (&state.config, &state.hints) {
+        let universe = self.universe(&request.get_ref().universe_id).await?;
+        let state = universe.state.lock().await;
+        let epoch = request
+            .get_ref()
+            .epoch
+            .unwrap_or_else(|| state.epochs.current());
+        let hints = &state.epochs.get(epoch).map_err(epoch_status)?.hints;
+        if let Some(config) = state.config.as_ref().filter(|_| !hints.is_empty()) {
             let crs = &config.crs;
             let lag_polys = &config.lag_polys;
             // TODO: This shouldn't be hardcoded, send as parameter?
             let k = 1;
 
-            println!("Found {:?} hints", hints.len());
+            println!("Found {:?} hints for epoch {}", hints.len(), epoch);
             println!("Computing the system public keys");
 
-            let system_keys = SystemPublicKeys::<C>::new(hints.clone(), crs, lag_polys, k).unwrap();
+            let hints = hints
+                .to_vec()
+                .map_err(|e| Status::failed_precondition(e.to_string()))?;
+            let system_keys = SystemPublicKeys::<C>::new(hints, crs, lag_polys, k).unwrap();
 
             system_keys
                 .serialize_compressed(&mut serialized_sys_key)
@@ -63,6 +97,7 @@ impl<C: Pairing> Rpc for NodeServer<C> {
 
         Ok(Response::new(PreprocessResponse {
             hex_serialized_sys_key,
+            epoch,
         }))
     }
 
@@ -78,41 +113,310 @@ impl<C: Pairing> Rpc for NodeServer<C> {
         let mut bytes = Vec::new();
 
         let filename = req_ref.filename.clone().into_bytes();
-        let witness = hex::decode(req_ref.witness_hex.clone()).unwrap();
+        let universe = self.universe(&req_ref.universe_id).await?;
+        // the share is only ever returned sealed to the requester
+        let requester_pk = decode_public_key(&req_ref.ephemeral_pubkey_hex)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
 
-        let (cid, intents) = self
+        let (cid, intents) = universe
             .intent_store
             .get_intent(&filename)
             .await
             .expect("Something went wrong when looking for intent.")
             .expect("Intent wasn't found");
 
-        let registry = self.gadget_registry.lock().await;
-        match registry.verify_intents(intents, &witness).await {
+        // the document must have been encrypted under the intents we verify against
+        let expected_intent_hash = intent_hash(&intents);
+
+        // the owner may have the key of an old document released for migration instead
+        let owner_signature = hex::decode(&req_ref.owner_signature_hex)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let migration = !owner_signature.is_empty();
+
+        let verified = if migration {
+            match universe.intent_store.owner(&filename).await {
+                Ok(Some(owner)) => Ok(verify_owner_authorization(
+                    &owner,
+                    &filename,
+                    &cid,
+                    &requester_pk,
+                    &owner_signature,
+                )),
+                Ok(None) => Ok(false),
+                Err(e) => Err(e.to_string()),
+            }
+        } else {
+            // witnesses are sealed to our transport key and bound to us
+            let transport_key = universe.state.lock().await.transport_key.clone();
+            let context = witness_context(&filename, &transport_key.public());
+            let sealed_witness = hex::decode(&req_ref.witness_hex)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            let witness = transport_key
+                .open(&context, &sealed_witness)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+            let registry = self.gadget_registry.lock().await;
+            registry
+                .verify_intents(intents, &witness, &context)
+                .await
+                .map_err(|e| e.to_string())
+        };
+
+        match verified {
             Ok(true) => {
-                println!("Witness verification succeeded! ");
-                if let Some(ciphertext_bytes) = self.doc_store.fetch(&cid).await.unwrap() {
-                    let ciphertext =
-                        Ciphertext::<C>::deserialize_compressed(&ciphertext_bytes[..]).unwrap();
-
-                    let state = self.state.lock().await;
-                    let partial_decryption = state.sk.partial_decryption(&ciphertext);
-
-                    partial_decryption.serialize_compressed(&mut bytes).unwrap();
-                    println!("produced a partial decryption");
-                } else {
-                    println!("data unavailable");
+                println!("Authorization succeeded! ");
+                // only the header is needed to decrypt the key, never the payload
+                match Envelope::read(universe.doc_store.as_ref(), &cid).await {
+                    Ok(Some(envelope)) if envelope.intent_hash != expected_intent_hash => {
+                        println!("The document was not encrypted under the registered intents");
+                    }
+                    Ok(Some(envelope)) => {
+                        let ciphertext =
+                            Ciphertext::<C>::deserialize_compressed(&envelope.kem[..]).unwrap();
+
+                        let state = universe.state.lock().await;
+                        check_decryptable(&state, &envelope, migration)?;
+                        let partial_decryption = state.sk.partial_decryption(&ciphertext);
+
+                        partial_decryption.serialize_compressed(&mut bytes).unwrap();
+                        println!("produced a partial decryption");
+                    }
+                    Ok(None) => println!("data unavailable"),
+                    Err(e) => println!("Invalid document: {}", e),
                 }
             }
             Ok(false) => {
-                println!("Witness verification failed");
+                println!("Authorization failed");
             }
             Err(e) => {
                 println!("An Error occurred: {}", e);
             }
         }
+
+        let sealed = seal(&requester_pk, &partdec_context(&filename), &bytes)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        universe.served.fetch_add(1, Ordering::Relaxed);
         Ok(Response::new(PartDecResponse {
-            hex_serialized_decryption: hex::encode(bytes),
+            hex_serialized_decryption: hex::encode(sealed),
         }))
     }
+
+    /// the transport key that clients seal witnesses to (in a universe) and our OPRF key,
+    /// signed by our node id
+    async fn identity(
+        &self,
+        request: Request<IdentityRequest>,
+    ) -> Result<Response<IdentityResponse>, Status> {
+        let universe = self.universe(&request.get_ref().universe_id).await?;
+        let state = universe.state.lock().await;
+        Ok(Response::new(IdentityResponse {
+            transport_pubkey_hex: state.transport_key.public_hex(),
+            node_id: state.node_id.to_string(),
+            signature_hex: hex::encode(state.identity_signature),
+            oprf_pubkey_hex: hex::encode(oprf_public_key(&state.transport_key)),
+        }))
+    }
+
+    /// the RPC endpoints (and liveness) of the members of an epoch
+    async fn committee(
+        &self,
+        request: Request<CommitteeRequest>,
+    ) -> Result<Response<CommitteeResponse>, Status> {
+        let universe = self.universe(&request.get_ref().universe_id).await?;
+        let state = universe.state.lock().await;
+        let epoch = request
+            .get_ref()
+            .epoch
+            .unwrap_or_else(|| state.epochs.current());
+        let now = unix_now();
+        let committee = state.epochs.get(epoch).map_err(epoch_status)?;
+        let members = committee
+            .committee
+            .iter()
+            .map(|(index, endpoints)| {
+                let health = state.liveness.get(index);
+                CommitteeMember {
+                    index,
+                    rpc_endpoints: endpoints.to_vec(),
+                    live: state.liveness.is_live(index, now),
+                    load: health.map(|health| health.load).unwrap_or_default(),
+                    version: health
+                        .map(|health| health.version.clone())
+                        .unwrap_or_default(),
+                    last_seen: health.map(|health| health.last_seen).unwrap_or_default(),
+                    node_id: committee
+                        .members
+                        .get(index)
+                        .map(|member| member.node_id.to_string())
+                        .unwrap_or_default(),
+                }
+            })
+            .collect();
+        Ok(Response::new(CommitteeResponse { members, epoch }))
+    }
+
+    /// the stages the node has reached starting a universe
+    async fn readiness(
+        &self,
+        request: Request<ReadinessRequest>,
+    ) -> Result<Response<ReadinessResponse>, Status> {
+        let universe = self.universe(&request.get_ref().universe_id).await?;
+        let readiness = universe.state.lock().await.readiness.get();
+        Ok(Response::new(ReadinessResponse {
+            connected: readiness.connected,
+            config_loaded: readiness.config_loaded,
+            hints_synced: readiness.hints_synced,
+            hint_published: readiness.hint_published,
+            rpc_up: readiness.rpc_up,
+            ready: readiness.is_ready(),
+        }))
+    }
+
+    /// download a registered document from the client that published it
+    async fn publish(
+        &self,
+        request: Request<PublishRequest>,
+    ) -> Result<Response<PublishResponse>, Status> {
+        let req_ref = request.get_ref();
+        let universe = self.universe(&req_ref.universe_id).await?;
+        let blob_store = universe
+            .blob_store
+            .as_ref()
+            .ok_or_else(|| Status::unimplemented("documents are not blobs on this node"))?;
+        let provider = NodeId::from_str(&req_ref.provider)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        // only documents registered with their intents are downloaded
+        let (cid, _) = universe
+            .intent_store
+            .get_intent(req_ref.filename.as_bytes())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::not_found(format!("{} is not registered", req_ref.filename)))?;
+
+        match blob_store
+            .download(&cid, provider)
+            .await
+            .map_err(|e| Status::invalid_argument(e.to_string()))?
+        {
+            Some(size) => {
+                println!("> Received document {} ({} bytes)", cid, size);
+                Ok(Response::new(PublishResponse {
+                    cid: cid.to_string(),
+                    size,
+                }))
+            }
+            None => Err(Status::unavailable(format!(
+                "{} could not be downloaded",
+                cid
+            ))),
+        }
+    }
+
+    /// evaluate the password OPRF on a blinded input, so clients derive ZkPassword keys
+    /// that cannot be guessed offline
+    async fn oprf(&self, request: Request<OprfRequest>) -> Result<Response<OprfResponse>, Status> {
+        let req_ref = request.get_ref();
+        let blinded = hex::decode(&req_ref.blinded_hex)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let universe = self.universe(&req_ref.universe_id).await?;
+        let transport_key = universe.state.lock().await.transport_key.clone();
+        let (evaluated, proof) = evaluate_oprf(&transport_key, &blinded)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        Ok(Response::new(OprfResponse {
+            evaluated_hex: hex::encode(evaluated),
+            proof_hex: hex::encode(proof),
+        }))
+    }
+}
+
+/// Whether the node may produce a partial decryption of a document
+/// The shares of one universe never decrypt the ciphertexts of another, owners only get
+/// the keys of documents still to be migrated, and only members of a live epoch decrypt
+/// the ciphertexts created under it
+fn check_decryptable<C: Pairing>(
+    state: &State<C>,
+    envelope: &Envelope,
+    migration: bool,
+) -> Result<(), Status> {
+    if state.universe_id() != Some(envelope.universe_id) {
+        return Err(Status::failed_precondition(format!(
+            "the document belongs to universe {}",
+            hex::encode(envelope.universe_id)
+        )));
+    }
+    if migration && envelope.epoch >= state.epochs.current() {
+        return Err(Status::failed_precondition(format!(
+            "the document is already in epoch {}",
+            envelope.epoch
+        )));
+    }
+    match state.epochs.get(envelope.epoch) {
+        Ok(epoch) if epoch.members.get(state.index).is_some() => Ok(()),
+        Ok(_) => {
+            println!("Not a member of epoch {}", envelope.epoch);
+            Err(Status::failed_precondition(format!(
+                "not a member of epoch {}",
+                envelope.epoch
+            )))
+        }
+        Err(e) => Err(epoch_status(e)),
+    }
+}
+
+/// Unknown epochs may not have started yet, retired ones are never served again
+fn epoch_status(e: EpochError) -> Status {
+    match e {
+        EpochError::UnknownEpoch(_) => Status::not_found(e.to_string()),
+        e => Status::failed_precondition(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::stream::NONCE_PREFIX_LEN;
+    use crate::universe::test::state;
+
+    fn envelope(universe_id: [u8; 32], epoch: u64) -> Envelope {
+        Envelope::new(
+            vec![],
+            1,
+            universe_id,
+            epoch,
+            [0u8; 32],
+            [0u8; NONCE_PREFIX_LEN],
+        )
+    }
+
+    #[test]
+    fn documents_of_other_universes_are_not_decrypted() {
+        let config = Config::<E>::rand(2);
+        let node = state(Some(&config));
+        let universe_id = config.universe_id();
+
+        assert!(check_decryptable(&node, &envelope(universe_id, 0), false).is_ok());
+
+        let other = Config::<E>::rand(2).universe_id();
+        let status = check_decryptable(&node, &envelope(other, 0), false).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        // nor by a node that has not received the config of its universe yet
+        let status = check_decryptable(&state(None), &envelope(universe_id, 0), false).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[test]
+    fn current_and_unknown_epochs_are_refused() {
+        let config = Config::<E>::rand(2);
+        let node = state(Some(&config));
+        let universe_id = config.universe_id();
+
+        // owners only get the keys of documents older than the current (genesis) epoch
+        let status = check_decryptable(&node, &envelope(universe_id, 0), true).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        let status = check_decryptable(&node, &envelope(universe_id, 7), false).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
 }
@@ -12,34 +12,171 @@ use iroh_docs::{
         client::docs::{Doc, ShareMode},
         proto::{Request, Response},
     },
-    store::{FlatQuery, QueryBuilder},
+    store::{FlatQuery, Query, QueryBuilder},
     DocTicket,
 };
 use quic_rpc::transport::flume::FlumeConnector;
 use std::sync::Arc;
-use std::{fs::OpenOptions, io::Write, thread, time::Duration};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 use tokio::sync::Mutex;
-use tonic::transport::Server;
+use tonic::transport::{Server, server::TcpIncoming};
 
 use crate::backend::SubstrateBackend;
-use crate::gadget::{GadgetRegistry, PasswordGadget, Psp22Gadget, Sr25519Gadget};
+use crate::committee::{check_endpoint, iroh_endpoint};
+use crate::crypto::ceremony::{CEREMONY_KEY, CeremonyParams, Contribution};
+use crate::crypto::crs::CONFIG_FILE;
+use crate::crypto::hints::{HINT_KEY, HintAnnouncement, hint_key};
+use crate::crypto::transport::TransportKeypair;
+use crate::epoch::{
+    EPOCH_CHANGE_KEY, EPOCH_RETIREMENT_KEY, EpochChange, EpochRetirement, epoch_change_key,
+    epoch_retirement_key,
+};
+use crate::membership::{AnnouncementSigner, Members, SignedAnnouncement};
+use crate::gadget::{
+    GadgetRegistry, PasswordGadget, Psp22Gadget, Sr25519Gadget, ZkPasswordGadget,
+};
+use crate::liveness::{HEARTBEAT_INTERVAL, HEARTBEAT_KEY, Heartbeat, heartbeat_key};
 use crate::node::*;
+use crate::readiness::{Readiness, ReadinessTracker};
+use crate::registry::{CommitteeRegistry, ContractCommitteeRegistry};
+use crate::rpc::protocol::RpcProtocol;
 use crate::rpc::server::{NodeServer, RpcServer};
-use crate::storage::{contract_store::ContractIntentStore, local_store::LocalDocStore};
+use crate::snapshot::{SNAPSHOT_FILE, Snapshot};
+use crate::storage::{
+    blob_store::IrohDocStore,
+    contract_store::ContractIntentStore,
+    local_store::{LEGACY_DOCS_DIR, LocalDocStore},
+};
 use crate::types::*;
+use crate::universe::{Universe, Universes};
 
 /// Configuration for starting a full node service
 pub struct ServiceConfig {
     pub bind_port: u16,
     pub rpc_port: u16,
-    pub index: usize,
+    /// the address the RPC server binds to
+    pub rpc_host: String,
+    /// the RPC endpoints published for clients [default: http://<rpc host>:<rpc port>]
+    pub rpc_endpoints: Vec<String>,
+    pub bootstrap_peers: Option<Vec<NodeAddr>>,
+    /// where docs, blobs and state snapshots are persisted [default: in memory]
+    pub data_dir: Option<String>,
+    /// how peers are discovered and reached
+    pub network: NetworkConfig,
+    /// the universes the node takes part in, the first one is the default universe
+    pub universes: Vec<UniverseConfig>,
+}
+
+/// Configuration of a universe the node takes part in
+pub struct UniverseConfig {
     pub is_bootstrap: bool,
     pub ticket: Option<String>,
-    pub bootstrap_peers: Option<Vec<NodeAddr>>,
+    /// the config (CRS) created by `fangorn setup`, published by the bootstrap node
+    pub config_path: String,
+    /// the intent registry contract filenames are registered in
     pub contract_addr: String,
+    /// generate the config with a CRS ceremony between this many nodes instead (bootstrap only)
+    pub ceremony_participants: Option<u32>,
+    /// the committee size the ceremony CRS supports [default: the number of participants]
+    pub ceremony_size: Option<usize>,
+    /// the admitted committee members (`<index> <node id>` per line), polled for changes
+    pub members_path: String,
+    /// read the admitted members from the committee registry contract instead
+    pub registry_addr: Option<String>,
+    /// where the ticket and (for the bootstrap node) the config are written for others
+    pub out_dir: String,
+}
+
+/// The file the ticket of the universe doc is kept in, in the universe data dir
+const DOC_TICKET_FILE: &str = "doc.ticket";
+
+/// How often the members source is read for committee changes
+const EPOCH_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Where the committee this node wants is read from
+enum MemberSource {
+    /// a members file
+    File(String),
+    /// the on-chain committee registry
+    Registry(Arc<dyn CommitteeRegistry>),
+}
+
+/// What the bootstrap node publishes to start the network
+enum Genesis<C: Pairing> {
+    /// a config generated (or imported) by `fangorn setup`
+    Config(Config<C>),
+    /// a CRS ceremony, the config is produced by the committee
+    Ceremony(CeremonyParams),
+}
+
+impl MemberSource {
+    async fn new(config: &UniverseConfig) -> Result<Self> {
+        Ok(match &config.registry_addr {
+            Some(registry_addr) => {
+                let backend =
+                    Arc::new(SubstrateBackend::new(crate::WS_URL.to_string(), None).await?);
+                Self::Registry(Arc::new(ContractCommitteeRegistry::new(
+                    registry_addr.to_string(),
+                    backend,
+                )))
+            }
+            None => Self::File(config.members_path.clone()),
+        })
+    }
+
+    /// Read the members, and the epoch to retire before (if any)
+    /// A members file always admits the node itself, so a new network can start from an empty
+    /// file; the registry only admits registered nodes, the node follows the committee otherwise
+    async fn load(&self, signer: &AnnouncementSigner) -> Result<Members> {
+        match self {
+            Self::File(path) => {
+                let mut members = Members::load(path)?;
+                members.admit(signer.index(), signer.node_id())?;
+                Ok(members)
+            }
+            Self::Registry(registry) => {
+                let mut members = Members::from_registry(&registry.members().await?)?;
+                let retire_before = registry.retire_before().await?;
+                if retire_before > 0 {
+                    members.set_retire_before(retire_before);
+                }
+                Ok(members)
+            }
+        }
+    }
 }
 
 impl ServiceConfig {
+    /// The RPC endpoints this node publishes alongside its hint
+    pub fn advertised_rpc_endpoints(&self) -> Vec<String> {
+        if !self.rpc_endpoints.is_empty() {
+            return self.rpc_endpoints.clone();
+        }
+        // a wildcard address is not reachable, assume local clients
+        let host = match self.rpc_host.as_str() {
+            "0.0.0.0" | "::" => "127.0.0.1".to_string(),
+            host if host.contains(':') => format!("[{}]", host),
+            host => host.to_string(),
+        };
+        vec![format!("http://{}:{}", host, self.rpc_port)]
+    }
+
+    /// Parse a peer address given as `<node id>@<ip:port>`
+    pub fn parse_peer(peer: &str) -> Result<NodeAddr> {
+        let (node_id, addr) = peer
+            .split_once('@')
+            .ok_or_else(|| anyhow::anyhow!("Expected <node id>@<ip:port>, found {}", peer))?;
+        let node_id = IrohPublicKey::from_str(node_id)?;
+        let addr: SocketAddr = addr.parse()?;
+        Ok(NodeAddr::from((node_id, None, vec![addr].as_slice())))
+    }
+
     /// Build bootstrap peers from CLI arguments
     pub fn build_bootstrap_peers(
         pubkey: Option<String>,
@@ -61,95 +198,503 @@ impl ServiceConfig {
 
 pub struct ServiceHandle<C: Pairing> {
     pub node: Node<C>,
+    /// the universes, in the order of the config
+    pub universes: Vec<UniverseHandle>,
+}
+
+impl<C: Pairing> ServiceHandle<C> {
+    /// Wait until every universe is ready
+    pub async fn ready(&self) {
+        future::join_all(
+            self.universes
+                .iter()
+                .map(|universe| universe.readiness.wait(Readiness::is_ready)),
+        )
+        .await;
+    }
+}
+
+/// The doc a universe is synced over
+pub struct UniverseHandle {
     pub ticket: String,
     pub doc: Doc<FlumeConnector<Response, Request>>,
+    /// how far the node has come starting the universe
+    pub readiness: ReadinessTracker,
+}
+
+/// A universe whose state is set up, before it is synced
+struct UniverseSetup<C: Pairing> {
+    config: UniverseConfig,
+    signer: AnnouncementSigner,
+    member_source: MemberSource,
+    state: Arc<Mutex<State<C>>>,
+    /// the committee members, the peers documents of the universe are downloaded from
+    providers: Vec<IrohPublicKey>,
+    /// the partial decryptions served in the universe, the load reported in its heartbeats
+    served: Arc<AtomicU64>,
+    /// where the doc ticket and the state snapshot of the universe are persisted
+    data_dir: Option<PathBuf>,
 }
 
 /// Build and start the full Fangorn node service
+/// The node keys of each universe are loaded from its node keystore, so the node keeps its
+/// identity across restarts; a node has one iroh identity, shared by all its universes
 pub async fn build_full_service<C: Pairing>(
     config: ServiceConfig,
-    max_committee_size: usize,
+    keys: Vec<NodeKeys<C>>,
 ) -> Result<ServiceHandle<C>> {
-    // setup channels for state synchronization
-    let (tx, rx) = flume::unbounded();
+    if config.universes.is_empty() || config.universes.len() != keys.len() {
+        anyhow::bail!("Every universe needs its node keys");
+    }
+    check_node_identity(&config.universes, &keys)?;
+
+    // fail early on endpoints that clients could not use
+    let mut rpc_endpoints = config.advertised_rpc_endpoints();
+    for endpoint in &rpc_endpoints {
+        check_endpoint(endpoint)?;
+    }
 
-    // initialize node parameters and state
-    let params = StartNodeParams::<C>::rand(config.bind_port, config.index);
-    let state = State::<C>::empty(params.secret_key.clone());
-    let arc_state = Arc::new(Mutex::new(state));
-    let arc_state_clone = Arc::clone(&arc_state);
+    // initialize node parameters and the state of each universe
+    let mut node_params = None;
+    let mut setups = Vec::new();
+    for (universe, keys) in config.universes.into_iter().zip(keys) {
+        let index = keys.index;
+        let params = StartNodeParams::<C>::new(keys, config.bind_port);
+        // announcements are signed with our node id, and only applied when signed by a member
+        let signer = AnnouncementSigner::new(index as u32, params.iroh_secret_key.clone());
+        let member_source = MemberSource::new(&universe).await?;
+        let members = member_source.load(&signer).await?;
+        println!("> Admitted {} committee members", members.len());
+        if members.get(signer.index()).map(|member| member.node_id) != Some(signer.node_id()) {
+            println!("> Not a member of the committee yet, following it");
+        }
+        let providers = members.iter().map(|(_, member)| member.node_id).collect();
+        let data_dir = config
+            .data_dir
+            .as_ref()
+            .map(|dir| universe_data_dir(Path::new(dir), &signer.node_id(), &params.transport_key));
+        let mut state = State::<C>::empty(
+            &signer,
+            params.secret_key.clone(),
+            params.transport_key.clone(),
+            members,
+        );
+        // the config is only accepted from the node that bootstrapped the universe
+        let bootstrap = bootstrap_node_id(&universe, signer.node_id(), data_dir.as_deref())?;
+        if let Some(node_id) = bootstrap {
+            state = state.with_bootstrap(node_id);
+        }
+        // resume from the snapshot of the universe (then catch up with peers)
+        if let Some(dir) = &data_dir {
+            fs::create_dir_all(dir)?;
+            let replayed = state.resume(Snapshot::open(dir.join(SNAPSHOT_FILE))?);
+            if replayed > 0 {
+                println!("> Resumed from {} journaled announcements", replayed);
+            }
+        }
+        setups.push(UniverseSetup {
+            config: universe,
+            signer,
+            member_source,
+            state: Arc::new(Mutex::new(state)),
+            providers,
+            served: Arc::new(AtomicU64::new(0)),
+            data_dir,
+        });
+        node_params.get_or_insert(params);
+    }
 
-    let mut node = Node::build(params, rx, arc_state).await;
+    let params = node_params.expect("there is at least one universe");
+    let data_dir = config.data_dir.as_deref().map(Path::new);
+    let builder = Node::build(params, data_dir, &config.network).await?;
+
+    // documents are blobs, missing ones are downloaded from the committee members and peers
+    // documents registered before they were blobs are still read from the docs dir,
+    // once moved to its sharded layout
+    let legacy_store = LocalDocStore::new(LEGACY_DOCS_DIR);
+    match legacy_store.migrate_legacy().await {
+        Ok(0) => {}
+        Ok(migrated) => println!("> Migrated {} documents to the sharded layout", migrated),
+        Err(e) => println!("Failed to migrate the legacy documents: {}", e),
+    }
+    let blob_store =
+        IrohDocStore::new(builder.blobs(), builder.endpoint()).with_legacy(legacy_store);
+
+    // filenames are registered per universe, and documents downloaded from its members
+    let peers: Vec<_> = config
+        .bootstrap_peers
+        .iter()
+        .flatten()
+        .map(|peer| peer.node_id)
+        .collect();
+    // initialize backend (todo: add param to config node url instead of hardcoding it)
+    let backend = Arc::new(SubstrateBackend::new(crate::WS_URL.to_string(), None).await?);
+    let universes = Arc::new(Universes::new(
+        setups
+            .iter()
+            .map(|setup| {
+                let doc_store = blob_store.with_own_providers();
+                doc_store.add_providers(
+                    setup
+                        .providers
+                        .iter()
+                        .chain(&peers)
+                        .copied()
+                        .filter(|provider| *provider != setup.signer.node_id()),
+                );
+                Universe {
+                    state: Arc::clone(&setup.state),
+                    served: Arc::clone(&setup.served),
+                    doc_store: Arc::new(doc_store.clone()),
+                    blob_store: Some(doc_store),
+                    intent_store: Arc::new(ContractIntentStore::new(
+                        setup.config.contract_addr.clone(),
+                        backend.clone(),
+                    )),
+                }
+            })
+            .collect(),
+    ));
+
+    // the RPC is served over gRPC and over iroh (dialable by node id)
+    let node_server = Arc::new(build_node_server(Arc::clone(&universes), backend));
+    let mut node = builder
+        .spawn(universes, RpcProtocol::new(Arc::clone(&node_server)))
+        .await;
 
     node.try_connect_peers(config.bootstrap_peers.clone())
         .await
         .unwrap();
 
-    let (doc_stream, ticket) = setup_document_stream(
-        &node,
-        config.is_bootstrap,
-        config.ticket,
-        max_committee_size,
-        &tx,
-    )
-    .await
-    .unwrap();
+    // clients with an iroh endpoint prefer dialing us by node id (this works behind NATs)
+    rpc_endpoints.insert(0, iroh_endpoint(&node.node_id()));
+
+    // universes sync in the background, their readiness tells how far they are
+    let mut handles = Vec::new();
+    for setup in setups {
+        handles.push(
+            start_universe(
+                &node,
+                setup,
+                rpc_endpoints.clone(),
+                config.bootstrap_peers.clone(),
+            )
+            .await?,
+        );
+    }
+
+    spawn_rpc_service(node_server, &config.rpc_host, config.rpc_port).await?;
+    for handle in &handles {
+        handle.readiness.mark(|readiness| readiness.rpc_up = true);
+    }
+
+    // // main service loop
+    // run_service_loop().await
+    Ok(ServiceHandle {
+        node: node.clone(),
+        universes: handles,
+    })
+}
+
+/// Every universe must be joined with the iroh identity the node is reached by,
+/// since members admit and dial each other by node id
+fn check_node_identity<C: Pairing>(
+    universes: &[UniverseConfig],
+    keys: &[NodeKeys<C>],
+) -> Result<()> {
+    let node_id = keys[0].iroh_secret_key.public();
+    for (universe, keys) in universes.iter().zip(keys).skip(1) {
+        let other = keys.iroh_secret_key.public();
+        if other != node_id {
+            anyhow::bail!(
+                "The keystore of the universe in {} has node id {}, but the node runs as {}; \
+                 create it with `fangorn setup --node-identity <keystore dir>`",
+                universe.out_dir,
+                other,
+                node_id
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Where the doc ticket and the state snapshot of a universe are persisted, one dir per keystore
+fn universe_data_dir(
+    data_dir: &Path,
+    node_id: &IrohPublicKey,
+    transport_key: &TransportKeypair,
+) -> PathBuf {
+    let name = format!("{}-{}", node_id, transport_key.public_hex());
+    data_dir.join("universes").join(name)
+}
+
+/// The node id of the node that bootstrapped a universe: this node, or the node that shared
+/// the ticket of its doc
+fn bootstrap_node_id(
+    universe: &UniverseConfig,
+    node_id: IrohPublicKey,
+    data_dir: Option<&Path>,
+) -> Result<Option<IrohPublicKey>> {
+    if universe.is_bootstrap {
+        return Ok(Some(node_id));
+    }
+    let resumed = data_dir
+        .map(|dir| dir.join(DOC_TICKET_FILE))
+        .filter(|path| path.exists());
+    let ticket = match (resumed, &universe.ticket) {
+        (Some(path), _) => fs::read_to_string(path)?.trim().to_string(),
+        (None, Some(ticket)) => ticket.clone(),
+        (None, None) => return Ok(None),
+    };
+    let ticket = DocTicket::from_str(&ticket)?;
+    Ok(ticket.nodes.first().map(|node| node.node_id))
+}
+
+/// Join (or create) the doc of a universe, then sync it in the background
+async fn start_universe<C: Pairing>(
+    node: &Node<C>,
+    setup: UniverseSetup<C>,
+    rpc_endpoints: Vec<String>,
+    bootstrap_peers: Option<Vec<NodeAddr>>,
+) -> Result<UniverseHandle> {
+    let UniverseSetup {
+        config,
+        signer,
+        member_source,
+        state,
+        served,
+        data_dir,
+        ..
+    } = setup;
+
+    // setup channels for state synchronization
+    let (tx, rx) = flume::unbounded();
+    let apply_state = Arc::clone(&state);
+    n0_future::task::spawn(async move {
+        while let Ok(announcement) = rx.recv_async().await {
+            apply_state.lock().await.receive(announcement);
+        }
+    });
+
+    // only the bootstrap node publishes a config (or starts a ceremony), the others receive it
+    let genesis = if !config.is_bootstrap {
+        None
+    } else if let Some(participants) = config.ceremony_participants {
+        // committees can grow up to the size of the CRS
+        let size = config.ceremony_size.unwrap_or(participants as usize);
+        Some(Genesis::Ceremony(CeremonyParams {
+            size: size as u32,
+            participants,
+        }))
+    } else {
+        Some(Genesis::Config(Config::<C>::load(&config.config_path)?))
+    };
+
+    let readiness = state.lock().await.readiness.clone();
+    let (doc_stream, ticket) = match resume_document_stream(node, data_dir.as_deref(), &readiness)
+        .await?
+    {
+        Some(resumed) => resumed,
+        None => setup_document_stream(
+            node,
+            &signer,
+            genesis,
+            config.ticket.clone(),
+            &config.out_dir,
+            &readiness,
+            &tx,
+        )
+        .await
+        .unwrap(),
+    };
+    if let Some(dir) = &data_dir {
+        fs::write(dir.join(DOC_TICKET_FILE), &ticket)?;
+    }
 
     spawn_state_sync_service(
         doc_stream.clone(),
         node.clone(),
         tx.clone(),
-        config.bootstrap_peers,
+        bootstrap_peers,
+        readiness.clone(),
     );
 
-    // wait for initial sync
-    thread::sleep(Duration::from_secs(3));
-
-    // sync: load and distribute config
-    load_and_distribute_config(&node, &doc_stream, &tx)
+    let handle = UniverseHandle {
+        ticket,
+        doc: doc_stream.clone(),
+        readiness,
+    };
+    let node = node.clone();
+    n0_future::task::spawn(async move {
+        if let Err(e) = sync_universe(
+            node,
+            config,
+            signer,
+            member_source,
+            state,
+            doc_stream,
+            rpc_endpoints,
+            served,
+            tx,
+        )
         .await
-        .unwrap();
-    // sync: load previous hints (if not bootstrap)
-    if !config.is_bootstrap {
-        load_previous_hints(&node, &doc_stream, config.index, &tx)
-            .await
-            .unwrap();
+        {
+            eprintln!("Universe sync error: {:?}", e);
+        }
+    });
+
+    Ok(handle)
+}
+
+/// Get the config of a universe, load its committee history, publish our hint and follow
+/// its committee, each step once the readiness stage it depends on is reached
+#[allow(clippy::too_many_arguments)]
+async fn sync_universe<C: Pairing>(
+    node: Node<C>,
+    config: UniverseConfig,
+    signer: AnnouncementSigner,
+    member_source: MemberSource,
+    state: Arc<Mutex<State<C>>>,
+    doc_stream: Doc<FlumeConnector<Response, Request>>,
+    rpc_endpoints: Vec<String>,
+    served: Arc<AtomicU64>,
+    tx: flume::Sender<SignedAnnouncement>,
+) -> Result<()> {
+    let readiness = state.lock().await.readiness.clone();
+
+    // wait for the initial sync, then load and apply the config
+    readiness.wait(|readiness| readiness.connected).await;
+    load_and_distribute_config(&node, &doc_stream, &state).await?;
+
+    // contribute to the ceremony (if any) when it is our turn
+    spawn_ceremony_service(
+        Arc::clone(&state),
+        node.clone(),
+        doc_stream.clone(),
+        signer.clone(),
+        tx.clone(),
+    );
+
+    // hints are computed against the config, which a ceremony only produces once all have contributed
+    let (crs_config, from_ceremony) = wait_for_config(&state).await;
+    if from_ceremony && config.is_bootstrap {
+        crs_config.save(Path::new(&config.out_dir).join(CONFIG_FILE))?;
+        println!("> Saved the ceremony config to disk");
     }
+    println!(
+        "> Joined universe {}",
+        hex::encode(crs_config.universe_id())
+    );
 
-    // wait for everything to synced
-    thread::sleep(Duration::from_secs(1));
+    // sync: load the committee history (if not bootstrap)
+    // after a ceremony, no hints exist before ours and the others arrive through the doc subscription
+    if !config.is_bootstrap && !from_ceremony {
+        load_committee_history(&node, &doc_stream, &state).await?;
+    }
+    readiness.mark(|readiness| readiness.hints_synced = true);
 
-    // publish our own hint
-    publish_node_hint(&node, &doc_stream, config.index, &tx)
-        .await
-        .unwrap();
+    // publish our own hint for the current epoch
+    let (epoch, is_member) = {
+        let state = state.lock().await;
+        let epochs = &state.epochs;
+        let is_member = epochs.current_epoch().members.get(signer.index()).is_some();
+        (epochs.current(), is_member)
+    };
+    if is_member {
+        publish_node_hint(
+            &node,
+            &doc_stream,
+            &state,
+            &signer,
+            epoch,
+            rpc_endpoints.clone(),
+            &tx,
+        )
+        .await?;
+    }
+    readiness.mark(|readiness| readiness.hint_published = true);
 
-    spawn_rpc_service(arc_state_clone, config.rpc_port, &config.contract_addr)
-        .await
-        .unwrap();
+    // tell the others we are alive (while we are a member)
+    spawn_heartbeat_service(
+        Arc::clone(&state),
+        node.clone(),
+        doc_stream.clone(),
+        signer.clone(),
+        served,
+        tx.clone(),
+    );
 
-    // // main service loop
-    // run_service_loop().await
-    Ok(ServiceHandle {
-        node: node.clone(),
-        ticket,
-        doc: doc_stream,
-    })
+    // follow the members source, approving committee changes and retirements
+    spawn_epoch_service(
+        state,
+        node,
+        doc_stream,
+        signer,
+        member_source,
+        rpc_endpoints,
+        tx,
+    );
+    Ok(())
+}
+
+/// Open the doc of the universe kept in the data dir, if the node synced one before
+async fn resume_document_stream<C: Pairing>(
+    node: &Node<C>,
+    data_dir: Option<&Path>,
+    readiness: &ReadinessTracker,
+) -> Result<Option<(Doc<FlumeConnector<Response, Request>>, String)>> {
+    let Some(path) = data_dir
+        .map(|dir| dir.join(DOC_TICKET_FILE))
+        .filter(|path| path.exists())
+    else {
+        return Ok(None);
+    };
+    let ticket = fs::read_to_string(path)?.trim().to_string();
+    let doc_stream = node.docs().import(DocTicket::from_str(&ticket)?).await?;
+    println!("> Resumed the universe doc from disk");
+    // the doc is on disk, peers only bring what we missed
+    readiness.mark(|readiness| readiness.connected = true);
+    Ok(Some((doc_stream, ticket)))
 }
 
 /// Setup the document stream for state synchronization
 async fn setup_document_stream<C: Pairing>(
     node: &Node<C>,
-    is_bootstrap: bool,
+    signer: &AnnouncementSigner,
+    genesis: Option<Genesis<C>>,
     ticket: Option<String>,
-    max_committee_size: usize,
-    tx: &flume::Sender<Announcement>,
+    out_dir: &str,
+    readiness: &ReadinessTracker,
+    tx: &flume::Sender<SignedAnnouncement>,
 ) -> Result<(Doc<FlumeConnector<Response, Request>>, String)> {
-    if is_bootstrap {
-        println!("Initial Startup: Generating new config");
+    if let Some(genesis) = genesis {
+        let (key, genesis_announcement) = match genesis {
+            Genesis::Config(config) => {
+                println!("Initial Startup: Publishing config");
+                // save the config for clients
+                config.save(Path::new(out_dir).join(CONFIG_FILE))?;
+                println!("> Saved config to disk");
+                let mut config_bytes = Vec::new();
+                config.serialize_compressed(&mut config_bytes)?;
+                let announcement = Announcement {
+                    tag: Tag::Config,
+                    data: config_bytes,
+                };
+                (CONFIG_KEY, announcement)
+            }
+            Genesis::Ceremony(params) => {
+                println!(
+                    "Initial Startup: Starting a CRS ceremony with {} participants",
+                    params.participants
+                );
+                let announcement = Announcement {
+                    tag: Tag::CeremonyStart,
+                    data: params.encode(),
+                };
+                (CEREMONY_KEY, announcement)
+            }
+        };
 
-        // Generate config and create document
-        let config_bytes = generate_config(max_committee_size).unwrap();
         let doc = node.docs().create().await.unwrap();
 
         // Create and share ticket
@@ -168,27 +713,25 @@ async fn setup_document_stream<C: Pairing>(
             .create(true)
             .write(true)
             .truncate(true)
-            .open("ticket.txt")
+            .open(Path::new(out_dir).join("ticket.txt"))
             .unwrap();
 
         writeln!(&mut file, "{}", ticket_string).expect("Unable to write ticket to file.");
 
         // Import the document
         let doc_stream = node.docs().import(ticket.clone()).await.unwrap();
+        // we created the doc, there is nothing to sync before we can use it
+        readiness.mark(|readiness| readiness.connected = true);
 
-        // Publish config to document
-        let config_announcement = Announcement {
-            tag: Tag::Config,
-            data: config_bytes,
-        };
-
-        tx.send(config_announcement.clone()).unwrap();
+        // Publish the config (or ceremony) to the document
+        let genesis_announcement = signer.sign(genesis_announcement, GENESIS_EPOCH);
+        tx.send(genesis_announcement.clone()).unwrap();
 
         doc_stream
             .set_bytes(
                 node.docs().authors().default().await?,
-                CONFIG_KEY,
-                config_announcement.encode(),
+                key,
+                genesis_announcement.encode(),
             )
             .await
             .unwrap();
@@ -206,79 +749,186 @@ async fn setup_document_stream<C: Pairing>(
     }
 }
 
-/// Load config from document and distribute to state
+/// Load config (or the ceremony so far) from document and apply it to the state
 async fn load_and_distribute_config<C: Pairing>(
     node: &Node<C>,
     doc_stream: &Doc<FlumeConnector<Response, Request>>,
-    tx: &flume::Sender<Announcement>,
+    state: &Arc<Mutex<State<C>>>,
 ) -> Result<()> {
     let config_query = QueryBuilder::<FlatQuery>::default()
         .key_exact(CONFIG_KEY)
         .limit(1);
+    // the ceremony parameters and every contribution published so far
+    let ceremony_query = QueryBuilder::<FlatQuery>::default().key_prefix(CEREMONY_KEY);
 
-    let cfg_entry = doc_stream.get_many(config_query.build()).await.unwrap();
-    let config = cfg_entry.collect::<Vec<_>>().await;
+    for query in [config_query.build(), ceremony_query.build()] {
+        receive_entries(node, doc_stream, state, query).await?;
+    }
 
-    let hash = config[0].as_ref().unwrap().content_hash();
-    let content = node.blobs().read_to_bytes(hash).await.unwrap();
-    let announcement = Announcement::decode(&mut content.slice(..).to_vec().as_slice()).unwrap();
+    Ok(())
+}
 
-    tx.send(announcement).unwrap();
+/// Apply the announcements of the doc entries matching a query, returning how many there were
+/// Anyone holding the ticket can write to the doc, so bad entries are reported and skipped
+async fn receive_entries<C: Pairing>(
+    node: &Node<C>,
+    doc_stream: &Doc<FlumeConnector<Response, Request>>,
+    state: &Arc<Mutex<State<C>>>,
+    query: Query,
+) -> Result<usize> {
+    let entries = doc_stream.get_many(query).await?.collect::<Vec<_>>().await;
+    let mut count = 0;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                println!("Skipped a doc entry: {}", e);
+                continue;
+            }
+        };
+        let key = String::from_utf8_lossy(entry.key()).to_string();
+        let content = match node.blobs().read_to_bytes(entry.content_hash()).await {
+            Ok(content) => content,
+            Err(e) => {
+                println!("Skipped doc entry {}: {}", key, e);
+                continue;
+            }
+        };
+        match SignedAnnouncement::decode(&mut &content[..]) {
+            Ok(announcement) => {
+                state.lock().await.receive(announcement);
+                count += 1;
+            }
+            Err(e) => println!("Skipped doc entry {}: not an announcement: {}", key, e),
+        }
+    }
+    Ok(count)
+}
 
-    Ok(())
+/// Wait until the config is set, returning it and whether it was produced by a ceremony
+async fn wait_for_config<C: Pairing>(state: &Arc<Mutex<State<C>>>) -> (Config<C>, bool) {
+    let readiness = state.lock().await.readiness.clone();
+    readiness.wait(|readiness| readiness.config_loaded).await;
+    let state = state.lock().await;
+    let config = state.config.clone().expect("the config is loaded");
+    (config, state.ceremony.is_some())
+}
+
+/// Spawn the ceremony contribution background task
+fn spawn_ceremony_service<C: Pairing>(
+    state: Arc<Mutex<State<C>>>,
+    node: Node<C>,
+    doc_stream: Doc<FlumeConnector<Response, Request>>,
+    signer: AnnouncementSigner,
+    tx: flume::Sender<SignedAnnouncement>,
+) {
+    n0_future::task::spawn(async move {
+        if let Err(e) = run_ceremony(state, node, doc_stream, signer, tx).await {
+            eprintln!("Ceremony error: {:?}", e);
+        }
+    });
+}
+
+/// Contribute to the ceremony once every previous contributor has
+/// Returns as soon as the config is set (with or without a ceremony)
+async fn run_ceremony<C: Pairing>(
+    state: Arc<Mutex<State<C>>>,
+    node: Node<C>,
+    doc_stream: Doc<FlumeConnector<Response, Request>>,
+    signer: AnnouncementSigner,
+    tx: flume::Sender<SignedAnnouncement>,
+) -> Result<()> {
+    let index = signer.index();
+    loop {
+        let previous = {
+            let state = state.lock().await;
+            if state.config.is_some() {
+                return Ok(());
+            }
+            state
+                .ceremony
+                .as_ref()
+                .filter(|ceremony| ceremony.next_contributor() == Some(index))
+                .map(|ceremony| ceremony.current())
+        };
+
+        if let Some(previous) = previous {
+            println!("Contributing to the ceremony");
+            let contribution = Contribution::<C>::new(index, &previous)?;
+            let mut bytes = Vec::new();
+            contribution.serialize_compressed(&mut bytes)?;
+            let announcement = signer.sign(
+                Announcement {
+                    tag: Tag::CeremonyContribution,
+                    data: bytes,
+                },
+                GENESIS_EPOCH,
+            );
+
+            tx.send(announcement.clone())?;
+            doc_stream
+                .set_bytes(
+                    node.docs().authors().default().await?,
+                    format!("{}-{}", CEREMONY_KEY, index),
+                    announcement.encode(),
+                )
+                .await?;
+            println!("Published ceremony contribution");
+            return Ok(());
+        }
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
 }
 
-/// Load hints from previous nodes in the network
-async fn load_previous_hints<C: Pairing>(
+/// Load the epoch changes, hints and retirements published so far and apply them to the state
+/// Announcements for epochs that have not started yet are held back until they do
+async fn load_committee_history<C: Pairing>(
     node: &Node<C>,
     doc_stream: &Doc<FlumeConnector<Response, Request>>,
-    our_index: usize,
-    tx: &flume::Sender<Announcement>,
+    state: &Arc<Mutex<State<C>>>,
 ) -> Result<()> {
-    println!("Loading hints from previous nodes...");
+    println!("Loading the committee history...");
 
     let mut count = 0;
-    for i in 1..(our_index as u32) {
-        let hint_query = QueryBuilder::<FlatQuery>::default()
-            .key_exact(i.to_string())
-            .limit(1);
-        let entry_list = doc_stream.get_many(hint_query.build()).await.unwrap();
-        let entry = entry_list.collect::<Vec<_>>().await;
-        let hash = entry[0].as_ref().unwrap().content_hash();
-        let content = node.blobs().read_to_bytes(hash).await.unwrap();
-        let announcement =
-            Announcement::decode(&mut content.slice(..).to_vec().as_slice()).unwrap();
-        tx.send(announcement).unwrap();
-        count += 1;
-    }
-
-    println!("Loaded {} previous hints", count);
+    for prefix in [EPOCH_CHANGE_KEY, HINT_KEY, EPOCH_RETIREMENT_KEY, HEARTBEAT_KEY] {
+        let query = QueryBuilder::<FlatQuery>::default().key_prefix(format!("{}-", prefix));
+        count += receive_entries(node, doc_stream, state, query.build()).await?;
+    }
+
+    println!("Loaded {} committee announcements", count);
     Ok(())
 }
 
-/// Publish this node's hint to the network
+/// Publish this node's hint for an epoch (and where clients reach it) to the network
 async fn publish_node_hint<C: Pairing>(
     node: &Node<C>,
     doc_stream: &Doc<FlumeConnector<Response, Request>>,
-    index: usize,
-    tx: &flume::Sender<Announcement>,
+    state: &Arc<Mutex<State<C>>>,
+    signer: &AnnouncementSigner,
+    epoch: u64,
+    rpc_endpoints: Vec<String>,
+    tx: &flume::Sender<SignedAnnouncement>,
 ) -> Result<()> {
     // publish our own public key, hint, and index
-    let pk = node
-        .get_pk()
+    let pk = state
+        .lock()
         .await
+        .public_key()
         .ok_or_else(|| anyhow::anyhow!("Failed to compute public key"))
         .unwrap();
 
     println!("Computed the hint");
 
-    let mut pk_bytes = Vec::new();
-    pk.serialize_compressed(&mut pk_bytes).unwrap();
+    let hint = HintAnnouncement::new(signer.index(), &pk, rpc_endpoints)?;
 
-    let hint_announcement = Announcement {
-        tag: Tag::Hint,
-        data: pk_bytes,
-    };
+    let hint_announcement = signer.sign(
+        Announcement {
+            tag: Tag::Hint,
+            data: hint.encode(),
+        },
+        epoch,
+    );
 
     // Send to ourselves first
     tx.send(hint_announcement.clone()).unwrap();
@@ -287,13 +937,206 @@ async fn publish_node_hint<C: Pairing>(
     doc_stream
         .set_bytes(
             node.docs().authors().default().await.unwrap(),
-            index.to_string(),
+            hint_key(epoch, signer.index()),
             hint_announcement.encode(),
         )
         .await
         .unwrap();
 
-    println!("Published hint to network");
+    println!("Published hint for epoch {} to network", epoch);
+    Ok(())
+}
+
+/// Spawn the committee epoch background task
+fn spawn_epoch_service<C: Pairing>(
+    state: Arc<Mutex<State<C>>>,
+    node: Node<C>,
+    doc_stream: Doc<FlumeConnector<Response, Request>>,
+    signer: AnnouncementSigner,
+    source: MemberSource,
+    rpc_endpoints: Vec<String>,
+    tx: flume::Sender<SignedAnnouncement>,
+) {
+    n0_future::task::spawn(async move {
+        if let Err(e) =
+            run_epochs(state, node, doc_stream, signer, source, rpc_endpoints, tx).await
+        {
+            eprintln!("Epoch service error: {:?}", e);
+        }
+    });
+}
+
+/// Poll the members source: approve a change when it differs from the current committee,
+/// approve retiring old epochs when it asks to, and publish our hint for each new epoch
+async fn run_epochs<C: Pairing>(
+    state: Arc<Mutex<State<C>>>,
+    node: Node<C>,
+    doc_stream: Doc<FlumeConnector<Response, Request>>,
+    signer: AnnouncementSigner,
+    source: MemberSource,
+    rpc_endpoints: Vec<String>,
+    tx: flume::Sender<SignedAnnouncement>,
+) -> Result<()> {
+    let index = signer.index();
+    let mut hinted = state.lock().await.epochs.current();
+    // what we approved last, so it is only published once
+    let mut approved_change = None;
+    let mut approved_retirement = 0;
+    loop {
+        tokio::time::sleep(EPOCH_POLL_INTERVAL).await;
+
+        let members = match source.load(&signer).await {
+            Ok(members) => members,
+            Err(e) => {
+                println!("Failed to read the committee members: {}", e);
+                continue;
+            }
+        };
+
+        let (epoch, current_members, is_member, retire_before) = {
+            let state = state.lock().await;
+            let epochs = &state.epochs;
+            let current = &epochs.current_epoch().members;
+            (
+                epochs.current(),
+                current.entries(),
+                current.get(index).is_some(),
+                epochs.retire_before(),
+            )
+        };
+
+        // members publish their hint once the epoch they are part of starts
+        if epoch > hinted {
+            hinted = epoch;
+            if is_member {
+                publish_node_hint(
+                    &node,
+                    &doc_stream,
+                    &state,
+                    &signer,
+                    epoch,
+                    rpc_endpoints.clone(),
+                    &tx,
+                )
+                .await?;
+            }
+        }
+        // only the members of the current epoch approve changes and retirements
+        if !is_member {
+            continue;
+        }
+
+        let change = EpochChange {
+            epoch: epoch + 1,
+            members: members.entries(),
+        };
+        if change.members != current_members && approved_change != Some(change.id()) {
+            println!("Approving the committee change to epoch {}", change.epoch);
+            approved_change = Some(change.id());
+            let key = epoch_change_key(change.epoch, index);
+            let announcement = Announcement {
+                tag: Tag::EpochChange,
+                data: change.encode(),
+            };
+            publish(&node, &doc_stream, &signer, epoch, key, announcement, &tx).await?;
+        }
+
+        if let Some(target) = members
+            .retire_before()
+            .filter(|target| *target > retire_before.max(approved_retirement) && *target <= epoch)
+        {
+            println!("Approving the retirement of the epochs before {}", target);
+            approved_retirement = target;
+            let key = epoch_retirement_key(target, index);
+            let announcement = Announcement {
+                tag: Tag::EpochRetirement,
+                data: EpochRetirement {
+                    retire_before: target,
+                }
+                .encode(),
+            };
+            publish(&node, &doc_stream, &signer, epoch, key, announcement, &tx).await?;
+        }
+    }
+}
+
+/// Spawn the heartbeat background task
+fn spawn_heartbeat_service<C: Pairing>(
+    state: Arc<Mutex<State<C>>>,
+    node: Node<C>,
+    doc_stream: Doc<FlumeConnector<Response, Request>>,
+    signer: AnnouncementSigner,
+    served: Arc<AtomicU64>,
+    tx: flume::Sender<SignedAnnouncement>,
+) {
+    n0_future::task::spawn(async move {
+        if let Err(e) = run_heartbeats(state, node, doc_stream, signer, served, tx).await {
+            eprintln!("Heartbeat service error: {:?}", e);
+        }
+    });
+}
+
+/// Publish a heartbeat every interval while we are a member of the current committee,
+/// with the partial decryptions served since the previous one as our load
+async fn run_heartbeats<C: Pairing>(
+    state: Arc<Mutex<State<C>>>,
+    node: Node<C>,
+    doc_stream: Doc<FlumeConnector<Response, Request>>,
+    signer: AnnouncementSigner,
+    served: Arc<AtomicU64>,
+    tx: flume::Sender<SignedAnnouncement>,
+) -> Result<()> {
+    let index = signer.index();
+    let mut last_served = served.load(Ordering::Relaxed);
+    loop {
+        let (epoch, is_member) = {
+            let state = state.lock().await;
+            let epochs = &state.epochs;
+            (
+                epochs.current(),
+                epochs.current_epoch().members.get(index).is_some(),
+            )
+        };
+        let total = served.load(Ordering::Relaxed);
+        let load = u32::try_from(total - last_served).unwrap_or(u32::MAX);
+        last_served = total;
+
+        if is_member {
+            let announcement = Announcement {
+                tag: Tag::Heartbeat,
+                data: Heartbeat::new(load).encode(),
+            };
+            publish(
+                &node,
+                &doc_stream,
+                &signer,
+                epoch,
+                heartbeat_key(index),
+                announcement,
+                &tx,
+            )
+            .await?;
+        }
+
+        tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+    }
+}
+
+/// Sign an announcement, apply it and publish it to the network
+async fn publish<C: Pairing>(
+    node: &Node<C>,
+    doc_stream: &Doc<FlumeConnector<Response, Request>>,
+    signer: &AnnouncementSigner,
+    epoch: u64,
+    key: String,
+    announcement: Announcement,
+    tx: &flume::Sender<SignedAnnouncement>,
+) -> Result<()> {
+    let announcement = signer.sign(announcement, epoch);
+    tx.send(announcement.clone())?;
+    doc_stream
+        .set_bytes(node.docs().authors().default().await?, key, announcement.encode())
+        .await?;
     Ok(())
 }
 
@@ -301,57 +1144,62 @@ async fn publish_node_hint<C: Pairing>(
 fn spawn_state_sync_service<C: Pairing>(
     doc_stream: Doc<FlumeConnector<Response, Request>>,
     node: Node<C>,
-    tx: flume::Sender<Announcement>,
+    tx: flume::Sender<SignedAnnouncement>,
     bootstrap_peers: Option<Vec<NodeAddr>>,
+    readiness: ReadinessTracker,
 ) {
     n0_future::task::spawn(async move {
-        if let Err(e) = run_state_sync(doc_stream, node, tx, bootstrap_peers).await {
+        if let Err(e) = run_state_sync(doc_stream, node, tx, bootstrap_peers, readiness).await {
             eprintln!("State sync error: {:?}", e);
         }
     });
 }
 
 /// Run the state synchronization loop
+/// The doc counts as connected once the content of a sync run with a peer is downloaded
 async fn run_state_sync<C: Pairing>(
     doc_stream: Doc<FlumeConnector<Response, Request>>,
     node: Node<C>,
-    tx: flume::Sender<Announcement>,
+    tx: flume::Sender<SignedAnnouncement>,
     bootstrap_peers: Option<Vec<NodeAddr>>,
+    readiness: ReadinessTracker,
 ) -> Result<()> {
+    // subscribe to changes to the doc first, so no event of the first sync is missed
+    let mut sub = doc_stream.subscribe().await.unwrap();
+    let blobs = node.blobs().clone();
+
     // to sync the doc with peers we need to read the state of the doc and load it
     let peers = bootstrap_peers.unwrap_or_default();
     doc_stream.start_sync(peers).await.unwrap();
 
-    // subscribe to changes to the doc
-    let mut sub = doc_stream.subscribe().await.unwrap();
-    let blobs = node.blobs().clone();
-
     while let Ok(event) = sub.try_next().await {
         if let Some(evt) = event {
             println!("{:?}", evt);
-            if let LiveEvent::InsertRemote { entry, .. } = evt {
-                let msg_body = blobs.read_to_bytes(entry.content_hash()).await;
-                match msg_body {
-                    Ok(msg) => {
-                        let bytes = msg.to_vec();
-                        let announcement = Announcement::decode(&mut &bytes[..]).unwrap();
-                        tx.send(announcement).unwrap();
-                    }
-                    Err(e) => {
-                        println!("{:?}", e);
-                        // may still be syncing so try again (3x)
-                        for _ in 0..3 {
-                            thread::sleep(Duration::from_secs(1));
-                            let message_content = blobs.read_to_bytes(entry.content_hash()).await;
-                            if let Ok(msg) = message_content {
-                                let bytes = msg.to_vec();
-                                let announcement = Announcement::decode(&mut &bytes[..]).unwrap();
-                                tx.send(announcement).unwrap();
-                                break;
+            match evt {
+                LiveEvent::InsertRemote { entry, .. } => {
+                    let msg_body = blobs.read_to_bytes(entry.content_hash()).await;
+                    match msg_body {
+                        Ok(msg) => forward_announcement(entry.key(), &msg, &tx),
+                        Err(e) => {
+                            println!("{:?}", e);
+                            // may still be syncing so try again (3x)
+                            for _ in 0..3 {
+                                tokio::time::sleep(Duration::from_secs(1)).await;
+                                let message_content =
+                                    blobs.read_to_bytes(entry.content_hash()).await;
+                                if let Ok(msg) = message_content {
+                                    forward_announcement(entry.key(), &msg, &tx);
+                                    break;
+                                }
                             }
                         }
                     }
                 }
+                // the entries (and their content) of a sync run with a peer are available
+                LiveEvent::PendingContentReady => {
+                    readiness.mark(|readiness| readiness.connected = true);
+                }
+                _ => {}
             }
         }
     }
@@ -359,44 +1207,57 @@ async fn run_state_sync<C: Pairing>(
     Ok(())
 }
 
-/// Spawn the RPC server
-async fn spawn_rpc_service<C: Pairing>(
-    state: Arc<Mutex<State<C>>>,
-    rpc_port: u16,
-    contract_addr: &str,
-) -> Result<()> {
-    let addr_str = format!("127.0.0.1:{}", rpc_port);
-    let addr = addr_str.parse().unwrap();
-
-    let doc_store = Arc::new(LocalDocStore::new("tmp/docs/"));
-
-    // initialize backend (todo: add param to config node url instead of hardcoding it)
-    let backend = Arc::new(SubstrateBackend::new(crate::WS_URL.to_string(), None).await?);
-
-    let intent_store = Arc::new(ContractIntentStore::new(
-        contract_addr.to_string(),
-        backend.clone(),
-    ));
+/// Pass the announcement of a doc entry on to the state
+/// Anyone holding the ticket can write to the doc, so entries that do not decode are skipped
+fn forward_announcement(key: &[u8], content: &[u8], tx: &flume::Sender<SignedAnnouncement>) {
+    match SignedAnnouncement::decode(&mut &content[..]) {
+        Ok(announcement) => {
+            if tx.send(announcement).is_err() {
+                println!("The state is gone, dropped an announcement");
+            }
+        }
+        Err(e) => println!(
+            "Skipped doc entry {}: not an announcement: {}",
+            String::from_utf8_lossy(key),
+            e
+        ),
+    }
+}
 
+/// Build the RPC server, shared by the gRPC server and the iroh protocol
+fn build_node_server<C: Pairing>(
+    universes: Arc<Universes<C>>,
+    backend: Arc<SubstrateBackend>,
+) -> NodeServer<C> {
     // register gadgets here
     let mut gadget_registry = GadgetRegistry::new();
     gadget_registry.register(PasswordGadget {});
     gadget_registry.register(Psp22Gadget::new(backend.clone()));
     gadget_registry.register(Sr25519Gadget::new(backend.clone()));
+    gadget_registry.register(ZkPasswordGadget::new());
 
     let gadget_registry = Arc::new(Mutex::new(gadget_registry));
 
-    let server = NodeServer::<C> {
-        doc_store,
-        intent_store,
-        state,
+    NodeServer::<C> {
+        universes,
         gadget_registry,
-    };
+    }
+}
+
+/// Spawn the gRPC server
+async fn spawn_rpc_service<C: Pairing>(
+    server: Arc<NodeServer<C>>,
+    rpc_host: &str,
+    rpc_port: u16,
+) -> Result<()> {
+    let addr: SocketAddr = (rpc_host.parse::<core::net::IpAddr>()?, rpc_port).into();
+    // bind before spawning, so the RPC is up (or failed) when this returns
+    let incoming = TcpIncoming::bind(addr)?;
 
     n0_future::task::spawn(async move {
         if let Err(e) = Server::builder()
-            .add_service(RpcServer::new(server))
-            .serve(addr)
+            .add_service(RpcServer::from_arc(server))
+            .serve_with_incoming(incoming)
             .await
         {
             eprintln!("RPC server error: {:?}", e);
@@ -407,24 +1268,92 @@ async fn spawn_rpc_service<C: Pairing>(
     Ok(())
 }
 
-/// Generate the initial config (for bootstrap nodes)
-fn generate_config(size: usize) -> Result<Vec<u8>> {
-    use ark_serialize::CanonicalSerialize;
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::membership::member_entry;
+    use crate::registry::RegisteredMember;
+    use ark_std::rand::rngs::OsRng;
+    use async_trait::async_trait;
+    use iroh::SecretKey as IrohSecretKey;
 
-    let config = Config::<E>::rand(size);
-    let mut bytes = Vec::new();
-    config.serialize_compressed(&mut bytes).unwrap();
+    /// A registry holding a fixed set of members
+    struct Registered(Vec<RegisteredMember>);
 
-    // Save to disk for debugging
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open("config.txt")
-        .unwrap();
+    #[async_trait]
+    impl CommitteeRegistry for Registered {
+        async fn members(&self) -> Result<Vec<RegisteredMember>> {
+            Ok(self.0.clone())
+        }
+        async fn retire_before(&self) -> Result<u64> {
+            Ok(0)
+        }
+        async fn register_member(
+            &self,
+            _: u32,
+            _: [u8; 32],
+            _: Vec<u8>,
+            _: String,
+            _: u128,
+        ) -> Result<()> {
+            anyhow::bail!("the registry is read only")
+        }
+    }
 
-    write!(&mut file, "{}", hex::encode(&bytes)).unwrap();
-    println!("> Saved config to disk");
+    fn registered(index: u32, node_id: &IrohPublicKey) -> RegisteredMember {
+        RegisteredMember {
+            operator: [index as u8; 32],
+            index,
+            node_id: *node_id.as_bytes(),
+            ste_public_key: vec![],
+            rpc_endpoint: vec![],
+            stake: 0,
+        }
+    }
 
-    Ok(bytes)
+    #[tokio::test]
+    async fn only_registered_nodes_are_admitted_from_the_registry() {
+        let signer = AnnouncementSigner::new(1, IrohSecretKey::generate(OsRng));
+        let other = IrohSecretKey::generate(OsRng).public();
+
+        let source = MemberSource::Registry(Arc::new(Registered(vec![registered(0, &other)])));
+        let members = source.load(&signer).await.unwrap();
+        assert_eq!(members.len(), 1);
+        assert!(members.get(1).is_none());
+
+        // once registered, the node is admitted at its index
+        let source = MemberSource::Registry(Arc::new(Registered(vec![
+            registered(0, &other),
+            registered(1, &signer.node_id()),
+        ])));
+        let members = source.load(&signer).await.unwrap();
+        assert_eq!(members.get(1).unwrap().node_id, signer.node_id());
+    }
+
+    #[tokio::test]
+    async fn members_files_admit_the_node_itself() {
+        let signer = AnnouncementSigner::new(1, IrohSecretKey::generate(OsRng));
+        let other = IrohSecretKey::generate(OsRng).public();
+        let path = std::env::temp_dir().join(format!("fangorn-members-{}", rand::random::<u64>()));
+        fs::write(&path, member_entry(0, &other)).unwrap();
+
+        let source = MemberSource::File(path.to_str().unwrap().to_string());
+        let members = source.load(&signer).await.unwrap();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members.get(1).unwrap().node_id, signer.node_id());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn peers_are_parsed_with_their_address() {
+        let node_id = IrohSecretKey::generate(OsRng).public();
+        let peer = ServiceConfig::parse_peer(&format!("{}@10.0.0.2:9933", node_id)).unwrap();
+        assert_eq!(peer.node_id, node_id);
+        let addr: SocketAddr = "10.0.0.2:9933".parse().unwrap();
+        assert_eq!(peer.direct_addresses().collect::<Vec<_>>(), [&addr]);
+
+        assert!(ServiceConfig::parse_peer(&node_id.to_string()).is_err());
+        assert!(ServiceConfig::parse_peer("abc@10.0.0.2:9933").is_err());
+        assert!(ServiceConfig::parse_peer(&format!("{}@10.0.0.2", node_id)).is_err());
+    }
 }
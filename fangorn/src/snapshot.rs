@@ -0,0 +1,182 @@
+//! State snapshots
+//!
+//! The state of a universe is built from the signed announcements a node receives. A snapshot
+//! journals those announcements on disk (appending each new one), so a restarted node replays
+//! them to resume where it stopped, verifying them again, before it catches up with its peers.
+use crate::membership::SignedAnnouncement;
+use codec::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// The file a universe snapshot is journaled to, in the universe data dir
+pub const SNAPSHOT_FILE: &str = "state.snapshot";
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("IO error: {0}")]
+    Io(String),
+}
+
+/// The announcements a universe state was built from, in the order they were received
+#[derive(Debug)]
+pub struct Snapshot {
+    path: PathBuf,
+    announcements: Vec<SignedAnnouncement>,
+    /// hashes of the journaled announcements, as announcements arrive more than once
+    seen: HashSet<[u8; 32]>,
+}
+
+impl Snapshot {
+    /// Open the snapshot at `path`, reading the announcements journaled so far (if any)
+    /// A torn last record (the node stopped mid write) is dropped
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        let path = path.as_ref().to_path_buf();
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(SnapshotError::Io(e.to_string())),
+        };
+
+        let mut snapshot = Self {
+            path,
+            announcements: Vec::new(),
+            seen: HashSet::new(),
+        };
+        let mut input = &bytes[..];
+        while !input.is_empty() {
+            match SignedAnnouncement::decode(&mut input) {
+                Ok(announcement) => {
+                    snapshot.seen.insert(digest(&announcement));
+                    snapshot.announcements.push(announcement);
+                }
+                Err(e) => {
+                    println!("Dropped a torn snapshot record: {}", e);
+                    snapshot.rewrite()?;
+                    break;
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+
+    pub fn announcements(&self) -> &[SignedAnnouncement] {
+        &self.announcements
+    }
+
+    /// Journal an announcement, unless it already is
+    pub fn record(&mut self, announcement: &SignedAnnouncement) -> Result<(), SnapshotError> {
+        if !self.seen.insert(digest(announcement)) {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| SnapshotError::Io(e.to_string()))?;
+        file.write_all(&announcement.encode())
+            .map_err(|e| SnapshotError::Io(e.to_string()))?;
+        self.announcements.push(announcement.clone());
+        Ok(())
+    }
+
+    /// Replace the journal with the announcements read (atomically)
+    fn rewrite(&self) -> Result<(), SnapshotError> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp).map_err(|e| SnapshotError::Io(e.to_string()))?;
+        for announcement in &self.announcements {
+            file.write_all(&announcement.encode())
+                .map_err(|e| SnapshotError::Io(e.to_string()))?;
+        }
+        file.sync_all()
+            .map_err(|e| SnapshotError::Io(e.to_string()))?;
+        fs::rename(&tmp, &self.path).map_err(|e| SnapshotError::Io(e.to_string()))
+    }
+}
+
+fn digest(announcement: &SignedAnnouncement) -> [u8; 32] {
+    Sha256::digest(announcement.encode()).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::membership::{AnnouncementSigner, Members, member_entry};
+    use crate::types::{Announcement, E, NodeKeys, State, Tag};
+    use ark_std::rand::rngs::OsRng;
+    use iroh::SecretKey as IrohSecretKey;
+
+    #[test]
+    fn reopened_snapshots_hold_each_announcement_once() {
+        let dir = std::env::temp_dir().join(format!("fangorn-snapshot-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SNAPSHOT_FILE);
+
+        let signer = AnnouncementSigner::new(0, IrohSecretKey::generate(OsRng));
+        let hint = signer.sign(
+            Announcement {
+                tag: Tag::Hint,
+                data: vec![1, 2, 3],
+            },
+            0,
+        );
+        let retirement = signer.sign(
+            Announcement {
+                tag: Tag::EpochRetirement,
+                data: vec![4],
+            },
+            1,
+        );
+
+        let mut snapshot = Snapshot::open(&path).unwrap();
+        snapshot.record(&hint).unwrap();
+        snapshot.record(&hint).unwrap();
+        snapshot.record(&retirement).unwrap();
+
+        let reopened = Snapshot::open(&path).unwrap();
+        assert_eq!(reopened.announcements().len(), 2);
+        assert_eq!(reopened.announcements()[1].epoch, 1);
+
+        // a torn last record is dropped
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        fs::write(&path, bytes).unwrap();
+        let reopened = Snapshot::open(&path).unwrap();
+        assert_eq!(reopened.announcements().len(), 1);
+        assert_eq!(Snapshot::open(&path).unwrap().announcements().len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_accepted_announcements_are_journaled() {
+        let dir = std::env::temp_dir().join(format!("fangorn-snapshot-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SNAPSHOT_FILE);
+
+        let alice = AnnouncementSigner::new(0, IrohSecretKey::generate(OsRng));
+        let mallory = AnnouncementSigner::new(1, IrohSecretKey::generate(OsRng));
+        let members = Members::parse(&member_entry(0, &alice.node_id())).unwrap();
+        let keys = NodeKeys::<E>::generate(0);
+        let mut state = State::<E>::empty(&alice, keys.secret_key, keys.transport_key, members);
+        state.resume(Snapshot::open(&path).unwrap());
+
+        let announcement = Announcement {
+            tag: Tag::EpochRetirement,
+            data: vec![4],
+        };
+        // forged, and signed for an epoch the node does not know
+        state.receive(mallory.sign(announcement.clone(), 0));
+        state.receive(alice.sign(announcement.clone(), 1 << 40));
+        state.receive(alice.sign(announcement, 0));
+
+        let reopened = Snapshot::open(&path).unwrap();
+        assert_eq!(reopened.announcements().len(), 1);
+        assert_eq!(reopened.announcements()[0].index, 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
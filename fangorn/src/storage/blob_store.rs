@@ -0,0 +1,414 @@
+//! a docstore on top of the iroh-blobs store of a node
+//! documents are blobs, so nodes download the ciphertexts they are asked about from their peers
+//! documents stored before (with sha2-256 CIDs) are read from the local docstore
+//! ranges of documents that are not stored locally are read from the providers, downloading
+//! only the chunks covering them
+use super::integrity::verify;
+use super::local_store::LocalDocStore;
+use super::*;
+use crate::node::BlobsClient;
+use anyhow::{anyhow, bail};
+use bao_tree::{ChunkNum, ChunkRanges, io::BaoContentItem};
+use bytes::Bytes;
+use cid::multihash::Multihash;
+use futures::{SinkExt, StreamExt, channel::mpsc};
+use iroh::{Endpoint, NodeAddr, NodeId};
+use iroh_blobs::{
+    ALPN as BLOBS_ALPN, BlobFormat, Hash,
+    get::fsm::{self, BlobContentNext, ConnectedNext, EndBlobNext},
+    net_protocol::DownloadMode,
+    protocol::{GetRequest, RangeSpecSeq},
+    rpc::client::blobs::{BlobStatus, DownloadOptions, ReadAtLen},
+    util::SetTagOption,
+};
+use std::collections::BTreeSet;
+use std::sync::{Arc, RwLock};
+
+/// The codec for generating CIDs
+const RAW: u64 = 0x55;
+
+/// The multihash code of blake3, the hash iroh-blobs addresses blobs by
+const BLAKE3: u64 = 0x1e;
+
+#[derive(Clone)]
+pub struct IrohDocStore {
+    blobs: BlobsClient,
+    /// the endpoint ranges of missing blobs are downloaded with
+    endpoint: Endpoint,
+    /// the peers missing blobs are downloaded from
+    providers: Arc<RwLock<BTreeSet<NodeId>>>,
+    /// the store of the documents whose CIDs do not address blobs
+    legacy: Option<Arc<LocalDocStore>>,
+}
+
+impl IrohDocStore {
+    pub(crate) fn new(blobs: BlobsClient, endpoint: Endpoint) -> Self {
+        Self {
+            blobs,
+            endpoint,
+            providers: Arc::new(RwLock::new(BTreeSet::new())),
+            legacy: None,
+        }
+    }
+
+    /// Read documents whose CIDs are not blake3 (stored before documents were blobs) from `legacy`
+    pub fn with_legacy(mut self, legacy: LocalDocStore) -> Self {
+        self.legacy = Some(Arc::new(legacy));
+        self
+    }
+
+    /// A store over the same blobs that downloads missing ones from its own providers
+    /// (e.g. only from the members of one universe)
+    pub fn with_own_providers(&self) -> Self {
+        Self {
+            blobs: self.blobs.clone(),
+            endpoint: self.endpoint.clone(),
+            providers: Arc::default(),
+            legacy: self.legacy.clone(),
+        }
+    }
+
+    /// The legacy store, if it holds the document of this CID
+    fn legacy_for(&self, cid: &Cid) -> Option<&LocalDocStore> {
+        if cid.hash().code() == BLAKE3 {
+            return None;
+        }
+        self.legacy.as_deref()
+    }
+
+    /// Download missing blobs from these peers too
+    pub fn add_providers(&self, providers: impl IntoIterator<Item = NodeId>) {
+        self.providers
+            .write()
+            .expect("the providers lock is not poisoned")
+            .extend(providers);
+    }
+
+    /// Download a document from `provider` (or else from the other providers)
+    /// The size of the document, None if it could not be downloaded
+    pub async fn download(&self, cid: &Cid, provider: NodeId) -> Result<Option<u64>> {
+        self.ensure_local(Self::blob_hash(cid)?, Some(provider))
+            .await
+    }
+
+    /// The CID of a blob
+    fn build_cid(hash: Hash) -> Result<Cid> {
+        let hash = Multihash::wrap(BLAKE3, hash.as_bytes())?;
+        Ok(Cid::new_v1(RAW, hash))
+    }
+
+    /// The blob a CID addresses
+    /// the CID digest is the blake3 hash of the blob, so the documents iroh verified against
+    /// the blob hash (when they were added or downloaded) match their CID
+    fn blob_hash(cid: &Cid) -> Result<Hash> {
+        let hash = cid.hash();
+        if hash.code() != BLAKE3 {
+            bail!(
+                "{} is not the CID of a blob (multihash code {:#x})",
+                cid,
+                hash.code()
+            );
+        }
+        let digest: [u8; 32] = hash
+            .digest()
+            .try_into()
+            .map_err(|_| anyhow!("{} has a malformed blake3 digest", cid))?;
+        Ok(Hash::from_bytes(digest))
+    }
+
+    /// The providers, asking `first` before the others
+    fn providers_from(&self, first: Option<NodeId>) -> Vec<NodeId> {
+        first
+            .into_iter()
+            .chain(
+                self.providers
+                    .read()
+                    .expect("the providers lock is not poisoned")
+                    .iter()
+                    .filter(|node_id| Some(**node_id) != first)
+                    .copied()
+                    .collect::<Vec<_>>(),
+            )
+            .collect()
+    }
+
+    /// Download the bytes of a blob in a range from the first provider serving them
+    /// None if no provider served them
+    async fn download_range(&self, hash: Hash, offset: u64, len: u64) -> Result<Option<Data>> {
+        for provider in self.providers_from(None) {
+            match self.get_range(provider, hash, offset, len).await {
+                Ok(range) => return Ok(Some(range)),
+                Err(e) => println!(
+                    "{} did not serve blob {}: {}",
+                    provider.fmt_short(),
+                    hash.fmt_short(),
+                    e
+                ),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get the chunks of a blob covering a range from a provider, which iroh verifies against
+    /// the blob hash as they arrive, and keep the bytes in the range
+    async fn get_range(&self, provider: NodeId, hash: Hash, offset: u64, len: u64) -> Result<Data> {
+        let end = offset.saturating_add(len);
+        let chunks = ChunkRanges::from(ChunkNum::full_chunks(offset)..ChunkNum::chunks(end));
+        let request = GetRequest::new(hash, RangeSpecSeq::from_ranges([chunks]));
+        let connection = self.endpoint.connect(provider, BLOBS_ALPN).await?;
+        let connected = fsm::start(connection, request).next().await?;
+        let ConnectedNext::StartRoot(root) = connected.next().await? else {
+            bail!("the provider did not send the blob");
+        };
+        let (mut content, size) = root.next().next().await?;
+        // ranges past the end of the blob are cut short, like local reads
+        let end = end.min(size);
+        let mut range = Vec::new();
+        let done = loop {
+            match content.next().await {
+                BlobContentNext::More((next, item)) => {
+                    // the leaves arrive in order, covering whole chunks
+                    if let BaoContentItem::Leaf(leaf) = item? {
+                        let leaf_end = leaf.offset + leaf.data.len() as u64;
+                        let (from, to) = (offset.max(leaf.offset), end.min(leaf_end));
+                        if from < to {
+                            let skip = (from - leaf.offset) as usize;
+                            range.extend_from_slice(&leaf.data[skip..skip + (to - from) as usize]);
+                        }
+                    }
+                    content = next;
+                }
+                BlobContentNext::Done(done) => break done,
+            }
+        };
+        if let EndBlobNext::Closing(closing) = done.next() {
+            closing.next().await?;
+        }
+        Ok(range)
+    }
+
+    /// The size of a blob, downloading it from the providers if it is not stored locally
+    /// (asking `first` before them), None if no provider has it
+    async fn ensure_local(&self, hash: Hash, first: Option<NodeId>) -> Result<Option<u64>> {
+        if let BlobStatus::Complete { size } = self.blobs.status(hash).await? {
+            return Ok(Some(size));
+        }
+
+        let nodes: Vec<NodeAddr> = self
+            .providers_from(first)
+            .into_iter()
+            .map(NodeAddr::from)
+            .collect();
+        if nodes.is_empty() {
+            return Ok(None);
+        }
+
+        let download = self
+            .blobs
+            .download_with_opts(
+                hash,
+                DownloadOptions {
+                    format: BlobFormat::Raw,
+                    nodes,
+                    tag: SetTagOption::Auto,
+                    mode: DownloadMode::Queued,
+                },
+            )
+            .await?;
+        match download.finish().await {
+            Ok(outcome) => {
+                println!(
+                    "> Downloaded blob {} ({} bytes)",
+                    hash.fmt_short(),
+                    outcome.downloaded_size
+                );
+            }
+            Err(e) => {
+                println!("No provider served blob {}: {}", hash.fmt_short(), e);
+                return Ok(None);
+            }
+        }
+
+        match self.blobs.status(hash).await? {
+            BlobStatus::Complete { size } => Ok(Some(size)),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl SharedStore<Cid, Data> for IrohDocStore {
+    async fn add(&self, data: &Data) -> Result<Cid> {
+        let outcome = self.blobs.add_bytes(data.clone()).await?;
+        Self::build_cid(outcome.hash)
+    }
+
+    async fn fetch(&self, cid: &Cid) -> Result<Option<Data>> {
+        if let Some(legacy) = self.legacy_for(cid) {
+            return legacy.fetch(cid).await;
+        }
+        let hash = Self::blob_hash(cid)?;
+        if self.ensure_local(hash, None).await?.is_none() {
+            return Ok(None);
+        }
+        let bytes = self.blobs.read_to_bytes(hash).await?;
+        verify(cid, &bytes)?;
+        Ok(Some(bytes.to_vec()))
+    }
+
+    async fn remove(&self, cid: &Cid) -> Result<()> {
+        if let Some(legacy) = self.legacy_for(cid) {
+            return legacy.remove(cid).await;
+        }
+        // blobs are garbage collected once no tag points at them
+        let hash = Self::blob_hash(cid)?;
+        let mut tags = self.blobs.tags().list().await?;
+        while let Some(tag) = tags.next().await {
+            let tag = tag?;
+            if tag.hash == hash {
+                self.blobs.tags().delete(tag.name).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DocStore for IrohDocStore {
+    async fn add_stream(&self, mut chunks: DataStream<'_>) -> Result<Cid> {
+        // the blobs client only takes 'static streams, so the chunks are forwarded through a channel
+        let (mut tx, rx) = mpsc::channel::<std::io::Result<Bytes>>(4);
+        let forward = async move {
+            while let Some(chunk) = chunks.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        if tx.send(Ok(Bytes::from(chunk))).await.is_err() {
+                            // the add failed, its error is returned
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        // abort the add, so no blob is tagged for the partial data
+                        let _ = tx.send(Err(std::io::Error::other(e.to_string()))).await;
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(())
+        };
+        let add = async {
+            let progress = self.blobs.add_stream(rx, SetTagOption::Auto).await?;
+            progress.finish().await
+        };
+        let (forwarded, outcome) = futures::join!(forward, add);
+        forwarded?;
+        Self::build_cid(outcome?.hash)
+    }
+
+    async fn fetch_range(&self, cid: &Cid, offset: u64, len: u64) -> Result<Option<Data>> {
+        if let Some(legacy) = self.legacy_for(cid) {
+            return legacy.fetch_range(cid, offset, len).await;
+        }
+        let hash = Self::blob_hash(cid)?;
+        // only the chunks covering the range are downloaded, so a document can be read
+        // (e.g. streamed) before the whole of it was downloaded
+        let BlobStatus::Complete { size } = self.blobs.status(hash).await? else {
+            return self.download_range(hash, offset, len).await;
+        };
+        if offset >= size {
+            return Ok(Some(Vec::new()));
+        }
+        let len = len.min(size - offset);
+        let bytes = self
+            .blobs
+            .read_at_to_bytes(hash, offset, ReadAtLen::Exact(len))
+            .await?;
+        Ok(Some(bytes.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::node::{NetworkConfig, client_blobs};
+
+    #[test]
+    fn cids_map_to_blob_hashes() {
+        let hash = Hash::new(b"a ciphertext");
+        let cid = IrohDocStore::build_cid(hash).unwrap();
+        assert_eq!(cid.codec(), RAW);
+        assert_eq!(IrohDocStore::blob_hash(&cid).unwrap(), hash);
+
+        // CIDs of other stores do not address blobs
+        let sha256 = Multihash::wrap(0x12, &[0u8; 32]).unwrap();
+        assert!(IrohDocStore::blob_hash(&Cid::new_v1(RAW, sha256)).is_err());
+    }
+
+    #[tokio::test]
+    async fn documents_are_downloaded_from_providers() {
+        let network = NetworkConfig {
+            offline: true,
+            relay_url: None,
+            entry_node: None,
+        };
+        let (router_a, blobs_a) = client_blobs(&network).await.unwrap();
+        let (router_b, blobs_b) = client_blobs(&network).await.unwrap();
+        let store_a = IrohDocStore::new(blobs_a, router_a.endpoint().clone());
+        let store_b = IrohDocStore::new(blobs_b, router_b.endpoint().clone());
+
+        let data = b"a ciphertext".to_vec();
+        let cid = store_a.add(&data).await.unwrap();
+        // without providers, only local blobs are read
+        assert_eq!(store_b.fetch(&cid).await.unwrap(), None);
+
+        let addr_a = router_a.endpoint().node_addr().await.unwrap();
+        router_b.endpoint().add_node_addr(addr_a.clone()).unwrap();
+        store_b.add_providers([addr_a.node_id]);
+        assert_eq!(store_b.fetch(&cid).await.unwrap(), Some(data));
+        assert_eq!(
+            store_b.fetch_range(&cid, 2, 6).await.unwrap(),
+            Some(b"cipher".to_vec())
+        );
+
+        router_a.shutdown().await.unwrap();
+        router_b.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ranges_are_downloaded_without_the_whole_document() {
+        let network = NetworkConfig {
+            offline: true,
+            relay_url: None,
+            entry_node: None,
+        };
+        let (router_a, blobs_a) = client_blobs(&network).await.unwrap();
+        let (router_b, blobs_b) = client_blobs(&network).await.unwrap();
+        let store_a = IrohDocStore::new(blobs_a, router_a.endpoint().clone());
+        let store_b = IrohDocStore::new(blobs_b.clone(), router_b.endpoint().clone());
+
+        // a document of several chunks
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let cid = store_a.add(&data).await.unwrap();
+        let addr_a = router_a.endpoint().node_addr().await.unwrap();
+        router_b.endpoint().add_node_addr(addr_a.clone()).unwrap();
+        store_b.add_providers([addr_a.node_id]);
+
+        // a range across chunks, and one running past the end
+        assert_eq!(
+            store_b.fetch_range(&cid, 1000, 1100).await.unwrap(),
+            Some(data[1000..2100].to_vec())
+        );
+        assert_eq!(
+            store_b.fetch_range(&cid, 4900, 200).await.unwrap(),
+            Some(data[4900..].to_vec())
+        );
+        let hash = IrohDocStore::blob_hash(&cid).unwrap();
+        assert!(!matches!(
+            blobs_b.status(hash).await.unwrap(),
+            BlobStatus::Complete { .. }
+        ));
+
+        router_a.shutdown().await.unwrap();
+        router_b.shutdown().await.unwrap();
+    }
+}
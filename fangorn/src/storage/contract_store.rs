@@ -20,6 +20,55 @@ impl ContractIntentStore {
             backend,
         }
     }
+
+    /// read the registry entry of a filename
+    async fn read(&self, filename: &[u8]) -> Result<Option<Entry>> {
+        use subxt::ext::codec::Decode;
+
+        let selector = self.backend.selector("read");
+
+        let mut data = Vec::new();
+        data.extend(filename.to_vec().encode());
+
+        let contract_addr_bytes: [u8; 32] =
+            crate::utils::decode_contract_addr(&self.contract_address);
+
+        let result = self
+            .backend
+            .query_contract(contract_addr_bytes, selector, data)
+            .await?;
+
+        let mut data = result;
+        if !data.is_empty() {
+            data.remove(0);
+        }
+
+        Ok(<Option<Entry>>::decode(&mut &data[..])?)
+    }
+}
+
+// TODO: use the same struct here and in the contract, exactly
+#[derive(Debug, PartialEq)]
+struct Entry {
+    cid: Vec<u8>,
+    intent: Vec<u8>,
+    /// None for entries registered before owners were recorded
+    owner: Option<[u8; 32]>,
+}
+
+/// Contracts deployed before owners were recorded return entries without the owner field
+impl subxt::ext::codec::Decode for Entry {
+    fn decode<I: subxt::ext::codec::Input>(
+        input: &mut I,
+    ) -> std::result::Result<Self, subxt::ext::codec::Error> {
+        let cid = Vec::<u8>::decode(input)?;
+        let intent = Vec::<u8>::decode(input)?;
+        let owner = match input.remaining_len()? {
+            Some(0) => None,
+            _ => Option::<[u8; 32]>::decode(input)?,
+        };
+        Ok(Self { cid, intent, owner })
+    }
 }
 
 #[async_trait]
@@ -49,34 +98,7 @@ impl IntentStore for ContractIntentStore {
     async fn get_intent(&self, filename: &[u8]) -> Result<Option<(Cid, Vec<Intent>)>> {
         use subxt::ext::codec::Decode;
 
-        let selector = self.backend.selector("read");
-
-        let mut data = Vec::new();
-        data.extend(filename.to_vec().encode());
-
-        let contract_addr_bytes: [u8; 32] =
-            crate::utils::decode_contract_addr(&self.contract_address);
-
-        let result = self
-            .backend
-            .query_contract(contract_addr_bytes, selector, data)
-            .await?;
-
-        // TODO: use the same struct here and in the contract, exactly
-        #[derive(Decode, Debug)]
-        struct Entry {
-            cid: Vec<u8>,
-            intent: Vec<u8>,
-        }
-
-        let mut data = result;
-        if !data.is_empty() {
-            data.remove(0);
-        }
-
-        let decoded = <Option<Entry>>::decode(&mut &data[..])?;
-
-        let result = decoded.map(|entry| {
+        let result = self.read(filename).await?.map(|entry| {
             let intents: Vec<Intent> = Vec::<Intent>::decode(&mut &entry.intent[..]).unwrap();
             (Cid::try_from(entry.cid).expect("Invalid CID"), intents)
         });
@@ -98,4 +120,57 @@ impl IntentStore for ContractIntentStore {
 
         Ok(())
     }
+
+    async fn replace_cid(&self, filename: &[u8], old: &Cid, new: &Cid) -> Result<()> {
+        let selector = self.backend.selector("update_cid");
+
+        let mut data = Vec::new();
+        data.extend(filename.to_vec().encode());
+        data.extend(old.to_bytes().encode());
+        data.extend(new.to_bytes().encode());
+
+        let contract_addr_bytes = crate::utils::decode_contract_addr(&self.contract_address);
+
+        self.backend
+            .call_contract(contract_addr_bytes, selector, data)
+            .await?;
+
+        // rejected updates are reverted by the contract, not the extrinsic, so read it back
+        match self.read(filename).await? {
+            Some(entry) if entry.cid == new.to_bytes() => Ok(()),
+            _ => Err(anyhow::anyhow!(
+                "The entry was not updated (it no longer points at {} or we do not own it)",
+                old
+            )),
+        }
+    }
+
+    async fn owner(&self, filename: &[u8]) -> Result<Option<[u8; 32]>> {
+        Ok(self.read(filename).await?.and_then(|entry| entry.owner))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use subxt::ext::codec::Decode;
+
+    #[test]
+    fn entries_decode_with_and_without_owner() {
+        let owned = (vec![1u8], vec![2u8], Some([3u8; 32])).encode();
+        let legacy = (vec![1u8], vec![2u8]).encode();
+
+        for (bytes, owner) in [(owned, Some([3u8; 32])), (legacy, None)] {
+            // the contract returns an Option<Entry>
+            let response = [vec![1u8], bytes].concat();
+            assert_eq!(
+                <Option<Entry>>::decode(&mut &response[..]).unwrap(),
+                Some(Entry {
+                    cid: vec![1],
+                    intent: vec![2],
+                    owner,
+                })
+            );
+        }
+    }
 }
@@ -0,0 +1,131 @@
+//! content integrity checks
+//! a CID commits to its data through its multihash, so stores check the data they read against it
+use cid::Cid;
+use multihash_codetable::*;
+use multihash_derive::Hasher;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum IntegrityError {
+    #[error("The data of {0} does not match its hash")]
+    HashMismatch(String),
+    #[error("Unsupported multihash code {code:#x} in {cid}")]
+    UnsupportedHash { cid: String, code: u64 },
+    #[error("The digest of {cid} is truncated to {len} bytes")]
+    TruncatedDigest { cid: String, len: usize },
+}
+
+/// Hashes data (in any number of chunks) to check it against a CID
+pub struct CidHasher {
+    cid: Cid,
+    hasher: Box<dyn Hasher + Send>,
+}
+
+impl CidHasher {
+    /// A hasher for the multihash code of the CID
+    pub fn new(cid: &Cid) -> Result<Self, IntegrityError> {
+        let code = cid.hash().code();
+        let hasher: Box<dyn Hasher + Send> = match Code::try_from(code) {
+            Ok(Code::Sha2_256) => Box::new(Sha2_256::default()),
+            Ok(Code::Sha2_512) => Box::new(Sha2_512::default()),
+            Ok(Code::Sha3_224) => Box::new(Sha3_224::default()),
+            Ok(Code::Sha3_256) => Box::new(Sha3_256::default()),
+            Ok(Code::Sha3_384) => Box::new(Sha3_384::default()),
+            Ok(Code::Sha3_512) => Box::new(Sha3_512::default()),
+            Ok(Code::Keccak224) => Box::new(Keccak224::default()),
+            Ok(Code::Keccak256) => Box::new(Keccak256::default()),
+            Ok(Code::Keccak384) => Box::new(Keccak384::default()),
+            Ok(Code::Keccak512) => Box::new(Keccak512::default()),
+            Ok(Code::Blake2b256) => Box::new(Blake2b256::default()),
+            Ok(Code::Blake2b512) => Box::new(Blake2b512::default()),
+            Ok(Code::Blake2s128) => Box::new(Blake2s128::default()),
+            Ok(Code::Blake2s256) => Box::new(Blake2s256::default()),
+            Ok(Code::Blake3_256) => Box::new(Blake3_256::default()),
+            Err(_) => {
+                return Err(IntegrityError::UnsupportedHash {
+                    cid: cid.to_string(),
+                    code,
+                });
+            }
+        };
+        Ok(Self { cid: *cid, hasher })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Check the data hashed so far against the CID
+    /// The CID must carry the full digest, a truncated one would match other data
+    pub fn verify(mut self) -> Result<(), IntegrityError> {
+        let expected = self.cid.hash().digest();
+        let digest = self.hasher.finalize();
+        if expected.len() != digest.len() {
+            return Err(IntegrityError::TruncatedDigest {
+                cid: self.cid.to_string(),
+                len: expected.len(),
+            });
+        }
+        if digest != expected {
+            return Err(IntegrityError::HashMismatch(self.cid.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Check that data hashes to the multihash of a CID
+pub fn verify(cid: &Cid, data: &[u8]) -> Result<(), IntegrityError> {
+    let mut hasher = CidHasher::new(cid)?;
+    hasher.update(data);
+    hasher.verify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn data_is_checked_against_the_cid_hash() {
+        let data = b"a ciphertext".to_vec();
+        for code in [
+            Code::Sha2_256,
+            Code::Sha3_512,
+            Code::Blake2b256,
+            Code::Blake3_256,
+        ] {
+            let cid = Cid::new_v1(0x55, code.digest(&data));
+            assert_eq!(verify(&cid, &data), Ok(()));
+            assert_eq!(
+                verify(&cid, b"another ciphertext"),
+                Err(IntegrityError::HashMismatch(cid.to_string()))
+            );
+
+            // chunked
+            let mut hasher = CidHasher::new(&cid).unwrap();
+            data.chunks(5).for_each(|chunk| hasher.update(chunk));
+            assert_eq!(hasher.verify(), Ok(()));
+        }
+
+        // an empty digest would match any data
+        let sha256 = Code::Sha2_256.digest(&data);
+        let truncated = |len| {
+            let digest = &sha256.digest()[..len];
+            Cid::new_v1(0x55, cid::multihash::Multihash::wrap(0x12, digest).unwrap())
+        };
+        for len in [0, 16] {
+            assert_eq!(
+                verify(&truncated(len), &data),
+                Err(IntegrityError::TruncatedDigest {
+                    cid: truncated(len).to_string(),
+                    len,
+                })
+            );
+        }
+
+        let identity = Cid::new_v1(0x55, cid::multihash::Multihash::wrap(0x00, &data).unwrap());
+        assert!(matches!(
+            verify(&identity, &data),
+            Err(IntegrityError::UnsupportedHash { code: 0x00, .. })
+        ));
+    }
+}
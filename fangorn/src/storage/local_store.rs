@@ -1,15 +1,33 @@
+use super::integrity::{CidHasher, verify};
 use super::*;
+use anyhow::anyhow;
 use async_trait::async_trait;
 use cid::Cid;
+use futures::StreamExt;
 use multihash_codetable::{Code, MultihashDigest};
-use std::fs::OpenOptions;
-use std::io::prelude::*;
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
 /// The codec for generating CIDs
 const RAW: u64 = 0x55;
 
+/// The docs dir of nodes and clients from before documents were iroh blobs
+pub const LEGACY_DOCS_DIR: &str = "tmp/docs/";
+
+/// The size of the reads when checking or migrating a whole document
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Documents are kept as raw bytes at `<docs dir>/<shard>/<cid>`, where the shard is the first
+/// byte of the cid digest (in hex), and are written to a temp file before being moved in place.
+/// Documents of the legacy layout (hex files at `<docs dir>/<cid>`) are migrated when first
+/// read, or all at once with `migrate_legacy`.
+/// Anyone who can write to the docs dir can also reset the timestamps of a file, so documents
+/// are checked against their cid on every read.
 pub struct LocalDocStore {
     pub docs_dir: String,
 }
@@ -21,29 +39,98 @@ impl LocalDocStore {
         }
     }
 
-    /// Ensure the docs directory exists
-    async fn ensure_dir(&self) -> Result<()> {
-        fs::create_dir_all(&self.docs_dir).await?;
-        Ok(())
+    /// Migrate every document of the legacy layout, returning how many were migrated
+    /// documents that cannot be migrated (e.g. not matching their cid) are reported and skipped
+    pub async fn migrate_legacy(&self) -> Result<usize> {
+        let mut entries = match fs::read_dir(&self.docs_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut migrated = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+            // shards and temp files are not named after cids
+            let Some(cid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Cid::try_from(name).ok())
+            else {
+                continue;
+            };
+            match self.migrate(&cid, &entry.path()).await {
+                Ok(()) => migrated += 1,
+                Err(e) => println!("Skipped migrating document {}: {}", cid, e),
+            }
+        }
+        Ok(migrated)
     }
 
-    /// Convert CID to filename for documents
-    fn cid_to_filename(&self, cid: &str) -> PathBuf {
-        PathBuf::from(&self.docs_dir).join(format!("{}", cid))
+    /// The path of a document
+    fn doc_path(&self, cid: &Cid) -> Result<PathBuf> {
+        let shard = cid
+            .hash()
+            .digest()
+            .first()
+            .ok_or_else(|| anyhow!("{} has an empty digest", cid))?;
+        Ok(Path::new(&self.docs_dir)
+            .join(hex::encode([*shard]))
+            .join(cid.to_string()))
     }
 
-    /// Write data to disk as hex-encoded
-    /// TODO: can implify the impl (fs::write) if we make this async
-    fn write_to_disk(&self, data: &Data, filepath: PathBuf) {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(filepath)
-            .unwrap();
+    /// The path of a document in the legacy layout
+    fn legacy_path(&self, cid: &Cid) -> PathBuf {
+        Path::new(&self.docs_dir).join(cid.to_string())
+    }
+
+    /// A new temp file in the docs directory, on the same filesystem as the documents
+    async fn create_tmp(&self) -> Result<TempFile> {
+        TempFile::create(Path::new(&self.docs_dir)).await
+    }
+
+    /// Sync a written temp file and move it in place as the document
+    async fn commit(&self, tmp: TempFile, cid: &Cid) -> Result<()> {
+        tmp.persist(&self.doc_path(cid)?).await
+    }
+
+    /// The path of a stored document, migrating it from the legacy layout if needed
+    async fn locate(&self, cid: &Cid) -> Result<Option<PathBuf>> {
+        let path = self.doc_path(cid)?;
+        if fs::try_exists(&path).await? {
+            return Ok(Some(path));
+        }
+        let legacy = self.legacy_path(cid);
+        if !fs::try_exists(&legacy).await? {
+            return Ok(None);
+        }
+        self.migrate(cid, &legacy).await?;
+        Ok(Some(path))
+    }
+
+    /// Decode a hex-encoded document of the legacy layout into the sharded layout
+    /// documents not matching their cid are left in place
+    async fn migrate(&self, cid: &Cid, legacy: &Path) -> Result<()> {
+        let mut hasher = CidHasher::new(cid)?;
+        let mut legacy_file = fs::File::open(legacy).await?;
+        let mut tmp = self.create_tmp().await?;
+        let mut chunk_hex = vec![0u8; CHUNK_SIZE * 2];
+        loop {
+            let read = read_full(&mut legacy_file, &mut chunk_hex).await?;
+            if read == 0 {
+                break;
+            }
+            let chunk = hex::decode(&chunk_hex[..read])?;
+            hasher.update(&chunk);
+            tmp.file.write_all(&chunk).await?;
+        }
+        hasher.verify()?;
 
-        let hex_enc = hex::encode(data);
-        write!(&mut file, "{}", hex_enc).unwrap();
+        self.commit(tmp, cid).await?;
+        fs::remove_file(legacy).await?;
+        println!("> Migrated document {} to the sharded layout", cid);
+        Ok(())
     }
 
     /// Generate a CID for the given data
@@ -51,53 +138,148 @@ impl LocalDocStore {
         let hash = Code::Sha2_256.digest(data);
         Cid::new_v1(RAW, hash)
     }
+
+    /// Check a whole open document against its cid, reading it in chunks
+    async fn verify_file(&self, cid: &Cid, file: &mut fs::File) -> Result<()> {
+        let mut hasher = CidHasher::new(cid)?;
+        file.seek(SeekFrom::Start(0)).await?;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            let read = read_full(file, &mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&chunk[..read]);
+        }
+        hasher.verify()?;
+        Ok(())
+    }
+}
+
+/// A file written under a temp name and moved in place once complete
+/// it is removed if dropped before, e.g. on errors or when the write is cancelled
+struct TempFile {
+    path: PathBuf,
+    file: fs::File,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// A new temp file in the given directory, on the same filesystem as the files moved there
+    async fn create(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).await?;
+        let path = dir.join(format!(".tmp-{}", rand::random::<u64>()));
+        let file = fs::File::create(&path).await?;
+        Ok(Self {
+            path,
+            file,
+            persisted: false,
+        })
+    }
+
+    /// Sync the file and move it to the given path
+    async fn persist(mut self, path: &Path) -> Result<()> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&self.path, path).await?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.persisted {
+            return;
+        }
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            println!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Read until the buffer is full or the file ends, returning the bytes read
+async fn read_full(file: &mut fs::File, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = file.read(&mut buf[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
 }
 
 #[async_trait]
 impl SharedStore<Cid, Data> for LocalDocStore {
     async fn add(&self, data: &Data) -> Result<Cid> {
-        self.ensure_dir().await?;
-        // build the cid
         let cid = self.build_cid(data);
-        // write to file
-        let filepath = self.cid_to_filename(&cid.to_string());
-        self.write_to_disk(data, filepath);
+        let mut tmp = self.create_tmp().await?;
+        tmp.file.write_all(data).await?;
+        self.commit(tmp, &cid).await?;
         Ok(cid)
     }
 
     async fn fetch(&self, cid: &Cid) -> Result<Option<Data>> {
-        let filepath = self.cid_to_filename(&cid.to_string());
-
-        // Check if file exists
-        if !filepath.exists() {
+        let Some(filepath) = self.locate(cid).await? else {
             return Ok(None);
-        }
+        };
 
-        // Read file
-        let ciphertext_hex = fs::read_to_string(filepath)
-            .await
-            .expect("you must provide a ciphertext.");
-        let ciphertext_bytes = hex::decode(ciphertext_hex.clone()).unwrap();
+        let data = fs::read(filepath).await?;
+        verify(cid, &data)?;
 
-        Ok(Some(ciphertext_bytes))
+        Ok(Some(data))
     }
 
     async fn remove(&self, cid: &Cid) -> Result<()> {
-        let filepath = self.cid_to_filename(&cid.to_string());
-
-        // Check if file exists
-        if filepath.exists() {
-            fs::remove_file(&filepath).await?;
-            // println!("Removed data for CID: {}", &cid.to_string());
-        } else {
-            // println!("No data found for CID: {}", &cid.to_string());
+        for filepath in [self.doc_path(cid)?, self.legacy_path(cid)] {
+            if fs::try_exists(&filepath).await? {
+                fs::remove_file(&filepath).await?;
+            }
         }
 
         Ok(())
     }
 }
 
-impl DocStore for LocalDocStore {}
+#[async_trait]
+impl DocStore for LocalDocStore {
+    async fn add_stream(&self, mut chunks: DataStream<'_>) -> Result<Cid> {
+        // write to a temp file while hashing, then move it into place
+        // the temp file is removed if this fails or is cancelled on the way
+        let mut tmp = self.create_tmp().await?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            tmp.file.write_all(&chunk).await?;
+        }
+
+        let hash = Code::Sha2_256.wrap(&hasher.finalize())?;
+        let cid = Cid::new_v1(RAW, hash);
+        self.commit(tmp, &cid).await?;
+
+        Ok(cid)
+    }
+
+    async fn fetch_range(&self, cid: &Cid, offset: u64, len: u64) -> Result<Option<Data>> {
+        let Some(filepath) = self.locate(cid).await? else {
+            return Ok(None);
+        };
+        // the range is read from the file that was checked, even if it is replaced meanwhile
+        let mut file = fs::File::open(filepath).await?;
+        self.verify_file(cid, &mut file).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut range = Vec::new();
+        file.take(len).read_to_end(&mut range).await?;
+
+        Ok(Some(range))
+    }
+}
 
 // local pt store impl
 
@@ -144,4 +326,217 @@ impl PlaintextStore for LocalPlaintextStore {
 
         Ok(())
     }
+
+    async fn plaintext_reader(&self, message_path: &String) -> Result<PlaintextReader> {
+        let file = fs::File::open(message_path).await?;
+        Ok(Box::new(file))
+    }
+
+    async fn plaintext_writer(&self, filename: &String) -> Result<PlaintextWriter> {
+        let filepath = format!("{}{}", self.pt_dir, filename);
+        let tmp = TempFile::create(Path::new(&self.pt_dir)).await?;
+        Ok(Box::new(PlaintextFile {
+            tmp,
+            path: PathBuf::from(filepath),
+        }))
+    }
+}
+
+/// A plaintext written to a temp file in the plaintext directory, moved in place on commit
+struct PlaintextFile {
+    tmp: TempFile,
+    path: PathBuf,
+}
+
+impl AsyncWrite for PlaintextFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.tmp.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.tmp.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.tmp.file).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl PlaintextSink for PlaintextFile {
+    async fn commit(self: Box<Self>) -> Result<()> {
+        let PlaintextFile { tmp, path } = *self;
+        tmp.persist(&path).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::integrity::IntegrityError;
+    use super::*;
+
+    fn docs_dir() -> String {
+        let dir = std::env::temp_dir().join(format!("fangorn-docs-{}", rand::random::<u64>()));
+        dir.to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn swapped_documents_fail_the_integrity_check() {
+        let dir = docs_dir();
+        let store = LocalDocStore::new(&dir);
+        let cid = store.add(&b"a ciphertext".to_vec()).await.unwrap();
+        assert_eq!(
+            store.fetch_range(&cid, 2, 6).await.unwrap(),
+            Some(b"cipher".to_vec())
+        );
+
+        // swapped under the store that already checked it
+        fs::write(store.doc_path(&cid).unwrap(), b"another one")
+            .await
+            .unwrap();
+        for result in [store.fetch(&cid).await, store.fetch_range(&cid, 0, 4).await] {
+            let e = result.unwrap_err();
+            assert!(matches!(
+                e.downcast_ref::<IntegrityError>(),
+                Some(IntegrityError::HashMismatch(_))
+            ));
+        }
+
+        // swapped for data of the same size, restoring the modification time
+        store.add(&b"a ciphertext".to_vec()).await.unwrap();
+        assert!(store.fetch_range(&cid, 0, 4).await.is_ok());
+        let path = store.doc_path(&cid).unwrap();
+        let modified = fs::metadata(&path).await.unwrap().modified().unwrap();
+        fs::write(&path, b"A ciphertext").await.unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert!(store.fetch_range(&cid, 0, 4).await.is_err());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn legacy_hex_documents_are_migrated() {
+        let dir = docs_dir();
+        let store = LocalDocStore::new(&dir);
+        fs::create_dir_all(&dir).await.unwrap();
+        let data = b"a ciphertext".to_vec();
+        let cid = store.build_cid(&data);
+        fs::write(store.legacy_path(&cid), hex::encode(&data))
+            .await
+            .unwrap();
+
+        // on first read
+        assert_eq!(
+            store.fetch_range(&cid, 2, 6).await.unwrap(),
+            Some(b"cipher".to_vec())
+        );
+        assert!(!fs::try_exists(store.legacy_path(&cid)).await.unwrap());
+        assert_eq!(fs::read(store.doc_path(&cid).unwrap()).await.unwrap(), data);
+
+        // all at once, but never a document not matching its cid
+        let other = b"another ciphertext".to_vec();
+        let other_cid = store.build_cid(&other);
+        fs::write(store.legacy_path(&other_cid), hex::encode(&other))
+            .await
+            .unwrap();
+        assert_eq!(store.migrate_legacy().await.unwrap(), 1);
+        assert_eq!(store.fetch(&other_cid).await.unwrap(), Some(other));
+
+        // a document not matching its cid does not keep the others from migrating
+        fs::write(store.legacy_path(&cid), hex::encode(b"swapped"))
+            .await
+            .unwrap();
+        fs::remove_file(store.doc_path(&cid).unwrap())
+            .await
+            .unwrap();
+        let third = b"a third ciphertext".to_vec();
+        let third_cid = store.build_cid(&third);
+        fs::write(store.legacy_path(&third_cid), hex::encode(&third))
+            .await
+            .unwrap();
+        assert_eq!(store.migrate_legacy().await.unwrap(), 1);
+        assert!(fs::try_exists(store.legacy_path(&cid)).await.unwrap());
+        assert_eq!(store.fetch(&third_cid).await.unwrap(), Some(third));
+
+        // no temp file is left behind
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            assert!(!entry.file_name().to_str().unwrap().starts_with(".tmp-"));
+        }
+
+        // CIDs come from the registry, an empty digest is not a path
+        let empty = Cid::new_v1(RAW, cid::multihash::Multihash::wrap(0x12, &[]).unwrap());
+        assert!(store.fetch(&empty).await.is_err());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    async fn has_temp_files(dir: &str) -> bool {
+        let mut entries = fs::read_dir(dir).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            if entry.file_name().to_str().unwrap().starts_with(".tmp-") {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn failed_and_cancelled_streams_leave_no_temp_file() {
+        let dir = docs_dir();
+        let store = LocalDocStore::new(&dir);
+
+        let failing = futures::stream::iter(vec![Ok(b"a chunk".to_vec()), Err(anyhow!("gone"))]);
+        assert!(store.add_stream(failing.boxed()).await.is_err());
+        assert!(!has_temp_files(&dir).await);
+
+        let stalled =
+            futures::stream::iter(vec![Ok(b"a chunk".to_vec())]).chain(futures::stream::pending());
+        let added = store.add_stream(stalled.boxed());
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), added)
+                .await
+                .is_err()
+        );
+        assert!(!has_temp_files(&dir).await);
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn plaintexts_appear_once_committed() {
+        let dir = format!("{}/", docs_dir());
+        let store = LocalPlaintextStore::new(&dir);
+        let path = format!("{}out.txt", dir);
+
+        let mut writer = store
+            .plaintext_writer(&"out.txt".to_string())
+            .await
+            .unwrap();
+        writer.write_all(b"half of a plaintext").await.unwrap();
+        drop(writer);
+        assert!(!fs::try_exists(&path).await.unwrap());
+        assert!(!has_temp_files(&dir).await);
+
+        let mut writer = store
+            .plaintext_writer(&"out.txt".to_string())
+            .await
+            .unwrap();
+        writer.write_all(b"a plaintext").await.unwrap();
+        assert!(!fs::try_exists(&path).await.unwrap());
+        writer.commit().await.unwrap();
+        assert_eq!(fs::read(&path).await.unwrap(), b"a plaintext");
+        assert!(!has_temp_files(&dir).await);
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
 }
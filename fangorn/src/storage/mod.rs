@@ -4,13 +4,33 @@ use crate::gadget::Intent;
 use anyhow::Result;
 use async_trait::async_trait;
 use cid::Cid;
+use futures::stream::BoxStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 
+pub mod blob_store;
 pub mod contract_store;
+pub mod integrity;
 pub mod local_store;
 
 /// the raw data type for storage
 type Data = Vec<u8>;
 
+/// a stream of data chunks to be written to storage
+pub type DataStream<'a> = BoxStream<'a, Result<Data>>;
+
+/// a boxed reader for streaming plaintext in
+pub type PlaintextReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// a writer for streaming plaintext out, the plaintext only appears in the store once committed
+#[async_trait]
+pub trait PlaintextSink: AsyncWrite + Send + Unpin {
+    /// move the written plaintext in place, it is discarded if the writer is dropped before
+    async fn commit(self: Box<Self>) -> Result<()>;
+}
+
+/// a boxed writer for streaming plaintext out
+pub type PlaintextWriter = Box<dyn PlaintextSink>;
+
 /// The SharedStore manages key-value mappings against some shared storage backend
 #[async_trait]
 pub trait SharedStore<K, V>: Send + Sync {
@@ -26,7 +46,17 @@ pub trait SharedStore<K, V>: Send + Sync {
 
 /// The docstore is a SharedStore where the key is a cid
 /// and the value is the corresponding message
-pub trait DocStore: Send + Sync + SharedStore<Cid, Data> {}
+/// reads are checked against the multihash of the cid, failing with an `IntegrityError`
+#[async_trait]
+pub trait DocStore: Send + Sync + SharedStore<Cid, Data> {
+    /// add data from a stream of chunks and get a content identifier
+    /// implementations should not hold the whole stream in memory
+    async fn add_stream(&self, chunks: DataStream<'_>) -> Result<Cid>;
+
+    /// fetch `len` bytes starting at `offset`
+    /// the range is truncated at the end of the data, None if the cid is unknown
+    async fn fetch_range(&self, cid: &Cid, offset: u64, len: u64) -> Result<Option<Data>>;
+}
 
 /// shared statement storage to associate CID (data) to intent
 // TODO: we need to pass some kind of configurable metadata when registering the intent
@@ -36,12 +66,24 @@ pub trait IntentStore: Send + Sync {
     async fn register_intent(&self, filename: &[u8], cid: &Cid, intents: Vec<Intent>) -> Result<()>;
     async fn get_intent(&self, filename: &[u8]) -> Result<Option<(Cid, Vec<Intent>)>>;
     async fn remove_intent(&self, filename: &[u8]) -> Result<()>;
+
+    /// point the filename at `new` if it still points at `old`
+    async fn replace_cid(&self, filename: &[u8], old: &Cid, new: &Cid) -> Result<()>;
+
+    /// the account that registered the filename, None if it is unknown
+    async fn owner(&self, filename: &[u8]) -> Result<Option<[u8; 32]>>;
 }
 
 #[async_trait]
 pub trait PlaintextStore {
     async fn read_plaintext(&self, message_path: &String) -> Result<Vec<u8>>;
     async fn write_to_pt_store(&self, filename: &String, data: &Vec<u8>) -> Result<()>;
+
+    /// open a plaintext for streaming reads
+    async fn plaintext_reader(&self, message_path: &String) -> Result<PlaintextReader>;
+
+    /// open a plaintext in the store for streaming writes
+    async fn plaintext_writer(&self, filename: &String) -> Result<PlaintextWriter>;
 }
 
 pub struct AppStore<D: DocStore, I: IntentStore, P: PlaintextStore> {
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use iroh::SecretKey as IrohSecretKey;
+use iroh::{PublicKey as IrohPublicKey, SecretKey as IrohSecretKey};
 
 use ark_ec::pairing::Pairing;
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
@@ -10,9 +10,29 @@ use silent_threshold_encryption::{
 };
 
 use codec::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+
+use crate::crypto::ceremony::{Ceremony, CeremonyParams, Contribution};
+use crate::crypto::hints::{HintAnnouncement, HintError};
+use crate::crypto::transport::TransportKeypair;
+use crate::epoch::{EpochChange, EpochRetirement, Epochs};
+use crate::gadget::zk_password::oprf_public_key;
+use crate::liveness::{Heartbeat, Liveness, unix_now};
+use crate::membership::{AnnouncementSigner, Members, SignedAnnouncement};
+use crate::readiness::ReadinessTracker;
+use crate::snapshot::Snapshot;
+use crate::universe::UniverseId;
 
 pub const CONFIG_KEY: &str = "config-key";
 
+/// The epoch of the committee a network starts with
+/// Later epochs start when a majority of the committee approves a change (see `epoch`)
+pub const GENESIS_EPOCH: u64 = 0;
+
+/// The maximum number of announcements held back until their epoch starts
+const MAX_DEFERRED_ANNOUNCEMENTS: usize = 1024;
+
 /// the curve (bls12-381)
 pub type E = ark_bls12_381::Bls12_381;
 /// the g2 group
@@ -22,6 +42,16 @@ pub type G2 = <E as Pairing>::G2;
 pub enum Tag {
     Config,
     Hint,
+    /// the parameters of a CRS ceremony
+    CeremonyStart,
+    /// a contribution to the CRS ceremony
+    CeremonyContribution,
+    /// a member's approval of a committee change
+    EpochChange,
+    /// a member's approval of retiring old epochs
+    EpochRetirement,
+    /// a member's periodic sign of life
+    Heartbeat,
 }
 
 #[derive(Clone, Debug, Encode, Decode)]
@@ -30,21 +60,50 @@ pub struct Announcement {
     pub data: Vec<u8>,
 }
 
+/// The long-lived secrets of a node, persisted in its keystore
+pub struct NodeKeys<C: Pairing> {
+    /// the index of the node in the committee
+    pub index: usize,
+    pub secret_key: SecretKey<C>,
+    pub iroh_secret_key: IrohSecretKey,
+    pub transport_key: TransportKeypair,
+}
+
+impl<C: Pairing> NodeKeys<C> {
+    pub fn generate(index: usize) -> Self {
+        Self {
+            index,
+            secret_key: SecretKey::<C>::new(&mut OsRng, index),
+            iroh_secret_key: IrohSecretKey::generate(OsRng),
+            transport_key: TransportKeypair::generate(),
+        }
+    }
+}
+
 pub struct StartNodeParams<C: Pairing> {
     pub iroh_secret_key: IrohSecretKey,
     pub secret_key: SecretKey<C>,
+    pub transport_key: TransportKeypair,
+    pub index: usize,
     pub bind_port: u16,
 }
 
 /// params to start a new node
 impl<C: Pairing> StartNodeParams<C> {
-    pub fn rand(bind_port: u16, index: usize) -> Self {
+    pub fn new(keys: NodeKeys<C>, bind_port: u16) -> Self {
         Self {
-            iroh_secret_key: IrohSecretKey::generate(OsRng),
-            secret_key: SecretKey::<C>::new(&mut OsRng, index),
+            iroh_secret_key: keys.iroh_secret_key,
+            secret_key: keys.secret_key,
+            transport_key: keys.transport_key,
+            index: keys.index,
             bind_port,
         }
     }
+
+    /// params with fresh keys (nothing survives a restart)
+    pub fn rand(bind_port: u16, index: usize) -> Self {
+        Self::new(NodeKeys::generate(index), bind_port)
+    }
 }
 
 #[derive(Clone, CanonicalDeserialize, CanonicalSerialize)]
@@ -64,45 +123,388 @@ impl<C: Pairing> Config<C> {
             size,
         }
     }
+
+    /// The universe id: the hash of the compressed config
+    pub fn universe_id(&self) -> [u8; 32] {
+        let mut bytes = Vec::new();
+        self.serialize_compressed(&mut bytes)
+            .expect("the config can be serialized");
+        Sha256::digest(&bytes).into()
+    }
 }
 
-#[derive(Clone)]
 pub struct State<C: Pairing> {
     pub config: Option<Config<C>>,
-    pub hints: Option<Vec<PublicKey<C>>>,
+    /// the id of the universe, known once the config is set
+    universe_id: Option<UniverseId>,
+    /// the CRS ceremony in progress, if the config is being generated by the committee
+    pub ceremony: Option<Ceremony<C>>,
+    /// the node id of the node that bootstrapped the universe, the only one a config is
+    /// accepted from
+    bootstrap: Option<IrohPublicKey>,
+    /// the committee (members, hints and endpoints) of each epoch
+    pub epochs: Epochs<C>,
+    /// announcements for epochs that have not started yet, and hints that arrived before the
+    /// config, applied once they can be
+    deferred: VecDeque<SignedAnnouncement>,
+    /// the committee index of this node
+    pub index: u32,
     // TODO: secure vault for key mgmt
     pub sk: SecretKey<C>,
+    /// the key witnesses are sealed to
+    pub transport_key: TransportKeypair,
+    /// the iroh node id of this node, which signed the transport and OPRF keys
+    pub node_id: IrohPublicKey,
+    /// the signature of the transport and OPRF keys, so clients can check they were not swapped
+    pub identity_signature: [u8; 64],
+    /// how far the node has come starting the universe
+    pub readiness: ReadinessTracker,
+    /// the last heartbeat of each committee member
+    pub liveness: Liveness,
+    /// the journal of received announcements, if the state is persisted
+    snapshot: Option<Snapshot>,
 }
 
 impl<C: Pairing> State<C> {
-    pub fn empty(sk: SecretKey<C>) -> Self {
+    pub fn empty(
+        signer: &AnnouncementSigner,
+        sk: SecretKey<C>,
+        transport_key: TransportKeypair,
+        members: Members,
+    ) -> Self {
+        let identity_signature = signer.sign_identity(
+            transport_key.public().as_bytes(),
+            &oprf_public_key(&transport_key),
+        );
         Self {
             config: None,
-            hints: None,
+            universe_id: None,
+            ceremony: None,
+            bootstrap: None,
+            epochs: Epochs::new(GENESIS_EPOCH, members),
+            deferred: VecDeque::new(),
+            index: signer.index(),
             sk,
+            transport_key,
+            node_id: signer.node_id(),
+            identity_signature,
+            readiness: ReadinessTracker::new(),
+            liveness: Liveness::default(),
+            snapshot: None,
         }
     }
 
-    pub fn update(&mut self, announcement: Announcement) {
+    /// Accept the config published by the node with the given node id
+    pub fn with_bootstrap(mut self, node_id: IrohPublicKey) -> Self {
+        self.bootstrap = Some(node_id);
+        self
+    }
+
+    /// The id of the universe, once the config is set
+    pub fn universe_id(&self) -> Option<UniverseId> {
+        self.universe_id
+    }
+
+    /// This node's public key (hint) in the universe, once the config is set
+    pub fn public_key(&self) -> Option<PublicKey<C>> {
+        let config = self.config.as_ref()?;
+        Some(self.sk.get_pk(&config.crs))
+    }
+
+    fn set_config(&mut self, config: Config<C>) {
+        self.universe_id = Some(config.universe_id());
+        self.config = Some(config);
+        self.readiness
+            .mark(|readiness| readiness.config_loaded = true);
+        // validate the hints that arrived before the config
+        self.replay_deferred();
+    }
+
+    /// Replay the announcements of a snapshot, then journal every announcement received to it
+    /// Returns the number of announcements replayed
+    pub fn resume(&mut self, snapshot: Snapshot) -> usize {
+        let announcements = snapshot.announcements().to_vec();
+        for signed in announcements.iter().cloned() {
+            self.apply(signed);
+        }
+        self.snapshot = Some(snapshot);
+        announcements.len()
+    }
+
+    /// Apply a signed announcement; those accepted are journaled (if the state is persisted)
+    pub fn receive(&mut self, signed: SignedAnnouncement) {
+        self.apply(signed);
+    }
+
+    /// Journal an accepted announcement, so it is replayed when the node restarts
+    /// Heartbeats are not journaled, they are stale by the time a node restarts
+    fn journal(&mut self, signed: &SignedAnnouncement) {
+        let Some(snapshot) = &mut self.snapshot else {
+            return;
+        };
+        if matches!(signed.announcement.tag, Tag::Heartbeat) {
+            return;
+        }
+        if let Err(e) = snapshot.record(signed) {
+            println!("Failed to journal an announcement: {}", e);
+        }
+    }
+
+    /// Verify a signed announcement against the members of its epoch, then apply it
+    fn apply(&mut self, signed: SignedAnnouncement) {
+        let current = self.epochs.current();
+        if signed.epoch > current {
+            if !self.try_adopt(&signed) {
+                self.defer(signed);
+            }
+            return;
+        }
+        let members = match self.epochs.get(signed.epoch) {
+            Ok(epoch) => &epoch.members,
+            Err(e) => {
+                println!("Rejected announcement from index {}: {}", signed.index, e);
+                return;
+            }
+        };
+        if let Err(e) = signed.verify(members, signed.epoch) {
+            println!("Rejected announcement from index {}: {}", signed.index, e);
+            return;
+        }
+        // hints are validated against the config, so they wait for it
+        if matches!(signed.announcement.tag, Tag::Hint) && self.config.is_none() {
+            self.defer(signed);
+            return;
+        }
+        self.journal(&signed);
+        self.update(signed.index, signed.epoch, signed.announcement);
+
+        // the announcement may have started the next epoch
+        if self.epochs.current() != current {
+            self.replay_deferred();
+        }
+    }
+
+    /// A node that (re)starts after the committee changed catches up with the current epoch
+    /// once a majority of the committee it trusts approved it
+    fn try_adopt(&mut self, signed: &SignedAnnouncement) -> bool {
+        if !matches!(signed.announcement.tag, Tag::EpochChange) {
+            return false;
+        }
+        // the signer must be a member of the committee we trust
+        if signed
+            .verify(&self.epochs.current_epoch().members, signed.epoch)
+            .is_err()
+        {
+            return false;
+        }
+        let Ok(change) = EpochChange::decode(&mut &signed.announcement.data[..]) else {
+            return false;
+        };
+        let capacity = self.config.as_ref().map(|config| config.size);
+        match self.epochs.adopt(signed.index, change, capacity) {
+            Ok(true) => {
+                self.journal(signed);
+                println!("Joined the committee at epoch {}", self.epochs.current());
+                self.replay_deferred();
+                true
+            }
+            // the approval counts towards adopting the epoch
+            Ok(false) => {
+                self.journal(signed);
+                false
+            }
+            Err(e) => {
+                println!("Rejected epoch change: {}", e);
+                false
+            }
+        }
+    }
+
+    fn defer(&mut self, signed: SignedAnnouncement) {
+        if self.deferred.len() >= MAX_DEFERRED_ANNOUNCEMENTS {
+            self.deferred.pop_front();
+        }
+        self.deferred.push_back(signed);
+    }
+
+    fn replay_deferred(&mut self) {
+        let deferred = std::mem::take(&mut self.deferred);
+        for signed in deferred {
+            self.apply(signed);
+        }
+    }
+
+    /// Apply an announcement from the (verified) member at the signer index
+    fn update(&mut self, signer: u32, epoch: u64, announcement: Announcement) {
         match announcement.tag {
             Tag::Config => {
                 println!("Received Config");
-                let config: Config<C> =
-                    Config::deserialize_compressed(&announcement.data[..]).unwrap();
-                self.config = Some(config.clone());
+                // the config is set once, by the bootstrap node or by a ceremony
+                if self.config.is_some() || self.ceremony.is_some() {
+                    println!("Ignored config: the config is already set or being generated");
+                    return;
+                }
+                if !self.is_bootstrap(signer, epoch) {
+                    println!(
+                        "Rejected config: member {} did not bootstrap the universe",
+                        signer
+                    );
+                    return;
+                }
+                let config = match Config::<C>::deserialize_compressed(&announcement.data[..]) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        println!("Rejected config: {}", e);
+                        return;
+                    }
+                };
+                // never build keys on top of a malformed CRS
+                if let Err(e) = config.verify() {
+                    println!("Rejected config: {}", e);
+                    return;
+                }
+                self.set_config(config);
             }
             Tag::Hint => {
                 println!("Received Hint");
-                let hint: PublicKey<C> =
-                    PublicKey::deserialize_compressed(&announcement.data[..]).unwrap();
-                if let Some(h) = &self.hints {
-                    let mut hints = h.clone();
-                    hints.push(hint);
-                    self.hints = Some(hints.clone());
-                } else {
-                    self.hints = Some(vec![hint]);
+                match self.add_hint(signer, epoch, &announcement.data) {
+                    Ok(true) => println!("> Accepted hint"),
+                    Ok(false) => println!("> Ignored duplicate hint"),
+                    Err(e) => println!("Rejected hint: {}", e),
+                }
+            }
+            Tag::CeremonyStart => {
+                println!("Received Ceremony Start");
+                if self.config.is_some() || self.ceremony.is_some() {
+                    println!("Ignored ceremony start: the config is already set");
+                    return;
+                }
+                match CeremonyParams::decode(&mut &announcement.data[..]) {
+                    Ok(params) => self.ceremony = Some(Ceremony::new(params)),
+                    Err(e) => println!("Rejected ceremony start: {}", e),
+                }
+            }
+            Tag::CeremonyContribution => {
+                println!("Received Ceremony Contribution");
+                let Some(ceremony) = self.ceremony.as_mut() else {
+                    println!("Ignored contribution: no ceremony in progress");
+                    return;
+                };
+                let contribution =
+                    match Contribution::<C>::deserialize_compressed(&announcement.data[..]) {
+                        Ok(contribution) => contribution,
+                        Err(e) => {
+                            println!("Rejected contribution: {}", e);
+                            return;
+                        }
+                    };
+                // members only contribute for themselves
+                if contribution.index != signer {
+                    println!(
+                        "Rejected contribution: index {} was signed by {}",
+                        contribution.index, signer
+                    );
+                    return;
+                }
+                if let Err(e) = ceremony.add(contribution) {
+                    println!("Rejected contribution: {}", e);
+                    return;
+                }
+                // the config is only accepted once the whole transcript verifies
+                if ceremony.is_complete() {
+                    match ceremony.finalize() {
+                        Ok(config) => {
+                            println!("Ceremony complete");
+                            self.set_config(config);
+                        }
+                        Err(e) => println!("Rejected ceremony transcript: {}", e),
+                    }
+                }
+            }
+            Tag::EpochChange => {
+                println!("Received Epoch Change");
+                // changes are approved by the members of the current epoch
+                if epoch != self.epochs.current() {
+                    println!("Ignored epoch change signed in epoch {}", epoch);
+                    return;
+                }
+                let change = match EpochChange::decode(&mut &announcement.data[..]) {
+                    Ok(change) => change,
+                    Err(e) => {
+                        println!("Rejected epoch change: {}", e);
+                        return;
+                    }
+                };
+                let capacity = self.config.as_ref().map(|config| config.size);
+                match self.epochs.approve_change(signer, change, capacity) {
+                    Ok(true) => println!("Epoch {} started", self.epochs.current()),
+                    Ok(false) => println!("> Recorded the approval of member {}", signer),
+                    Err(e) => println!("Rejected epoch change: {}", e),
                 }
             }
+            Tag::EpochRetirement => {
+                println!("Received Epoch Retirement");
+                if epoch != self.epochs.current() {
+                    println!("Ignored epoch retirement signed in epoch {}", epoch);
+                    return;
+                }
+                let retirement = match EpochRetirement::decode(&mut &announcement.data[..]) {
+                    Ok(retirement) => retirement,
+                    Err(e) => {
+                        println!("Rejected epoch retirement: {}", e);
+                        return;
+                    }
+                };
+                match self.epochs.approve_retirement(signer, retirement) {
+                    Ok(true) => {
+                        println!("Retired the epochs before {}", self.epochs.retire_before())
+                    }
+                    Ok(false) => println!("> Recorded the approval of member {}", signer),
+                    Err(e) => println!("Rejected epoch retirement: {}", e),
+                }
+            }
+            Tag::Heartbeat => match Heartbeat::decode(&mut &announcement.data[..]) {
+                Ok(heartbeat) => self.liveness.record(signer, heartbeat, unix_now()),
+                Err(e) => println!("Rejected heartbeat: {}", e),
+            },
+        }
+    }
+
+    /// Whether the member at the signer index is the node that bootstrapped the universe
+    fn is_bootstrap(&self, signer: u32, epoch: u64) -> bool {
+        let Ok(epoch) = self.epochs.get(epoch) else {
+            return false;
+        };
+        let node_id = epoch.members.get(signer).map(|member| member.node_id);
+        self.bootstrap.is_some() && node_id == self.bootstrap
+    }
+
+    /// Validate a hint announcement against the config and add it to the hints of its epoch
+    fn add_hint(&mut self, signer: u32, epoch: u64, data: &[u8]) -> Result<bool, HintError> {
+        let announcement = HintAnnouncement::decode(&mut &data[..])
+            .map_err(|e| HintError::Malformed(e.to_string()))?;
+        // members only publish their own hint
+        if announcement.index != signer {
+            return Err(HintError::SignerMismatch {
+                signer,
+                index: announcement.index,
+            });
+        }
+        let config = self.config.as_ref().ok_or(HintError::MissingConfig)?;
+        let epoch = self.epochs.get_mut(epoch)?;
+        // a hint registered on-chain is the only one accepted for the index
+        let registered = epoch.members.get(signer).and_then(|m| m.hint.as_ref());
+        if registered.is_some_and(|hint| *hint != announcement.hint) {
+            return Err(HintError::RegistryMismatch(signer));
+        }
+        let accepted = epoch.hints.insert(config, &announcement)?;
+        // a node republishes its (unchanged) hint with its current endpoints on restart
+        if let Err(e) = epoch
+            .committee
+            .insert(signer, announcement.rpc_endpoints.clone())
+        {
+            println!("Ignored the endpoints of member {}: {}", signer, e);
         }
+        Ok(accepted)
     }
 }
@@ -0,0 +1,168 @@
+//! Universes
+//!
+//! A node can take part in several independent universes (e.g. staging, production and
+//! partner networks). Each universe has its own config (CRS), committee, hints and system keys,
+//! and is synced over its own doc. A universe is identified by the hash of its config, which
+//! ciphertext headers carry and RPC requests name.
+use crate::storage::{DocStore, IntentStore, blob_store::IrohDocStore};
+use crate::types::State;
+use ark_ec::pairing::Pairing;
+use std::sync::{Arc, atomic::AtomicU64};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+/// The id of a universe: the hash of its config
+pub type UniverseId = [u8; 32];
+
+#[derive(Error, Debug)]
+pub enum UniverseError {
+    #[error("Invalid universe id: expected 32 bytes, found {0}")]
+    InvalidId(usize),
+    #[error("This node is not part of universe {0}")]
+    Unknown(String),
+}
+
+/// A universe the node takes part in
+/// Filenames are only unique within a universe, so each one has its own intent registry
+pub struct Universe<C: Pairing> {
+    pub state: Arc<Mutex<State<C>>>,
+    /// the partial decryptions served in the universe, the load reported in its heartbeats
+    pub served: Arc<AtomicU64>,
+    /// the documents of the universe, missing ones are downloaded from its members
+    pub doc_store: Arc<dyn DocStore>,
+    /// the same documents as blobs (None if documents are not blobs)
+    pub blob_store: Option<IrohDocStore>,
+    /// the filenames and intents registered in the universe
+    pub intent_store: Arc<dyn IntentStore>,
+}
+
+/// The universes a node takes part in, in the order they were started
+/// The first one is the default universe, used by requests that do not name one
+pub struct Universes<C: Pairing> {
+    universes: Vec<Universe<C>>,
+}
+
+impl<C: Pairing> Universes<C> {
+    pub fn new(universes: Vec<Universe<C>>) -> Self {
+        Self { universes }
+    }
+
+    /// The universe named by a request (the default universe if empty)
+    pub async fn get(&self, universe_id: &[u8]) -> Result<&Universe<C>, UniverseError> {
+        if universe_id.is_empty() {
+            return self
+                .universes
+                .first()
+                .ok_or_else(|| UniverseError::Unknown("(default)".into()));
+        }
+        let id: UniverseId = universe_id
+            .try_into()
+            .map_err(|_| UniverseError::InvalidId(universe_id.len()))?;
+        for universe in &self.universes {
+            if universe.state.lock().await.universe_id() == Some(id) {
+                return Ok(universe);
+            }
+        }
+        Err(UniverseError::Unknown(hex::encode(id)))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Universe<C>> {
+        self.universes.iter()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::gadget::Intent;
+    use crate::membership::{AnnouncementSigner, Members, member_entry};
+    use crate::storage::local_store::LocalDocStore;
+    use crate::types::{Announcement, Config, E, GENESIS_EPOCH, NodeKeys, Tag};
+    use anyhow::Result;
+    use ark_serialize::CanonicalSerialize;
+    use async_trait::async_trait;
+    use cid::Cid;
+
+    /// The state of a single member universe, with its config set if given
+    pub(crate) fn state(config: Option<&Config<E>>) -> State<E> {
+        let keys = NodeKeys::<E>::generate(0);
+        let signer = AnnouncementSigner::new(0, keys.iroh_secret_key.clone());
+        let members = Members::parse(&member_entry(0, &signer.node_id())).unwrap();
+        let mut state = State::<E>::empty(&signer, keys.secret_key, keys.transport_key, members)
+            .with_bootstrap(signer.node_id());
+        if let Some(config) = config {
+            let mut data = Vec::new();
+            config.serialize_compressed(&mut data).unwrap();
+            let announcement = Announcement {
+                tag: Tag::Config,
+                data,
+            };
+            state.receive(signer.sign(announcement, GENESIS_EPOCH));
+        }
+        state
+    }
+
+    struct NoIntents;
+
+    #[async_trait]
+    impl IntentStore for NoIntents {
+        async fn register_intent(&self, _: &[u8], _: &Cid, _: Vec<Intent>) -> Result<()> {
+            Ok(())
+        }
+        async fn get_intent(&self, _: &[u8]) -> Result<Option<(Cid, Vec<Intent>)>> {
+            Ok(None)
+        }
+        async fn remove_intent(&self, _: &[u8]) -> Result<()> {
+            Ok(())
+        }
+        async fn replace_cid(&self, _: &[u8], _: &Cid, _: &Cid) -> Result<()> {
+            Ok(())
+        }
+        async fn owner(&self, _: &[u8]) -> Result<Option<[u8; 32]>> {
+            Ok(None)
+        }
+    }
+
+    pub(crate) fn universe(config: Option<&Config<E>>) -> Universe<E> {
+        Universe {
+            state: Arc::new(Mutex::new(state(config))),
+            served: Arc::new(AtomicU64::new(0)),
+            doc_store: Arc::new(LocalDocStore::new("unused")),
+            blob_store: None,
+            intent_store: Arc::new(NoIntents),
+        }
+    }
+
+    #[tokio::test]
+    async fn universes_are_found_by_id() {
+        let staging = Config::<E>::rand(2);
+        let production = Config::<E>::rand(2);
+        let universes = Universes::new(vec![
+            universe(Some(&staging)),
+            universe(Some(&production)),
+            // a universe still waiting for its config
+            universe(None),
+        ]);
+
+        let found = universes.get(&production.universe_id()).await.unwrap();
+        let id = found.state.lock().await.universe_id();
+        assert_eq!(id, Some(production.universe_id()));
+        // requests naming no universe go to the first one
+        let found = universes.get(&[]).await.unwrap();
+        let id = found.state.lock().await.universe_id();
+        assert_eq!(id, Some(staging.universe_id()));
+
+        assert!(matches!(
+            universes.get(&[1u8; 31]).await,
+            Err(UniverseError::InvalidId(31))
+        ));
+        assert!(matches!(
+            universes.get(&[0u8; 32]).await,
+            Err(UniverseError::Unknown(_))
+        ));
+        assert!(matches!(
+            Universes::<E>::new(vec![]).get(&[]).await,
+            Err(UniverseError::Unknown(_))
+        ));
+    }
+}
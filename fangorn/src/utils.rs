@@ -2,6 +2,9 @@ use sp_application_crypto::Ss58Codec;
 use sp_core::crypto::AccountId32;
 use std::fs;
 
+/// The environment variable holding the node keystore password (for non-interactive use)
+pub const KEYSTORE_PASSWORD_ENV: &str = "FANGORN_KEYSTORE_PASSWORD";
+
 // todo: this should probably return a result
 pub fn decode_public_key(address: &str) -> [u8; 32] {
     if let Ok(account_id) = AccountId32::from_ss58check(address) {
@@ -34,3 +37,16 @@ pub fn load_mnemonic(keystore_path: &String) -> String {
     let formatted = seed.trim().trim_matches('"');
     formatted.to_string()
 }
+
+/// read the node keystore password from the environment, or prompt for it
+pub fn read_keystore_password(confirm: bool) -> anyhow::Result<String> {
+    if let Ok(password) = std::env::var(KEYSTORE_PASSWORD_ENV) {
+        return Ok(password);
+    }
+
+    let password = rpassword::prompt_password("Keystore password: ")?;
+    if confirm && rpassword::prompt_password("Confirm password: ")? != password {
+        anyhow::bail!("Passwords do not match");
+    }
+    Ok(password)
+}
@@ -3,9 +3,11 @@ use clap::{Parser, Subcommand};
 use fangorn::{
     crypto::{
         FANGORN,
-        cipher::{handle_decrypt, handle_encrypt},
+        cipher::{handle_decrypt, handle_encrypt, handle_migrate},
+        encrypt::DEFAULT_THRESHOLD,
         keystore::{Keystore, Sr25519Keystore},
     },
+    node::NetworkConfig,
 };
 
 #[derive(Parser, Debug)]
@@ -13,6 +15,15 @@ use fangorn::{
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    /// Turn off n0 discovery and relays: reach nodes on the local network or through `--relay-url`
+    #[arg(long, global = true)]
+    offline: bool,
+    /// A self-hosted relay to use instead of the n0 relays
+    #[arg(long, global = true, default_value = None)]
+    relay_url: Option<String>,
+    /// The node to read the system keys and the committee from (`http(s)://…` or `iroh:<node id>`)
+    #[arg(long, global = true, default_value = None)]
+    entry_node: Option<String>,
 }
 
 /// Define available subcommands
@@ -53,6 +64,9 @@ enum Commands {
         intent: String,
         #[arg(long)]
         contract_addr: String,
+        /// the number of partial decryptions needed to decrypt the document
+        #[arg(long, default_value_t = DEFAULT_THRESHOLD)]
+        threshold: u8,
     },
     /// request to decrypt a message
     /// prepare a witness + send to t-of-n node RPCs
@@ -74,11 +88,38 @@ enum Commands {
         #[arg(long)]
         contract_addr: String,
     },
+    /// re-encrypt a document of an older committee epoch to the current one
+    /// the old committee releases the key under the document's intents (with a witness),
+    /// or to the owner of the document (the keystore account that encrypted it)
+    Migrate {
+        /// the directory of the kzg params
+        #[arg(long)]
+        config_path: String,
+        /// the filename
+        #[arg(long)]
+        filename: String,
+        /// the keystore directory (of the document owner)
+        #[arg(long)]
+        keystore_dir: String,
+        /// A witness that satisfies the intent associated with the CID [default: act as owner]
+        #[arg(long)]
+        witness: Option<String>,
+        #[arg(long)]
+        contract_addr: String,
+        /// the number of partial decryptions needed to decrypt the migrated document
+        #[arg(long, default_value_t = DEFAULT_THRESHOLD)]
+        threshold: u8,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Cli::parse();
+    let network = NetworkConfig {
+        offline: args.offline,
+        relay_url: args.relay_url.clone(),
+        entry_node: args.entry_node.clone(),
+    };
 
     match &args.command {
         Some(Commands::Keygen { keystore_dir }) => {
@@ -112,6 +153,7 @@ async fn main() -> Result<()> {
             keystore_dir,
             intent,
             contract_addr,
+            threshold,
         }) => {
             handle_encrypt(
                 message_path,
@@ -120,6 +162,8 @@ async fn main() -> Result<()> {
                 keystore_dir,
                 intent,
                 contract_addr,
+                *threshold,
+                &network,
             )
             .await;
         }
@@ -130,7 +174,34 @@ async fn main() -> Result<()> {
             pt_filename,
             contract_addr,
         }) => {
-            handle_decrypt(config_path, filename, witness, pt_filename, contract_addr).await;
+            handle_decrypt(
+                config_path,
+                filename,
+                witness,
+                pt_filename,
+                contract_addr,
+                &network,
+            )
+            .await;
+        }
+        Some(Commands::Migrate {
+            config_path,
+            filename,
+            keystore_dir,
+            witness,
+            contract_addr,
+            threshold,
+        }) => {
+            handle_migrate(
+                config_path,
+                filename,
+                keystore_dir,
+                witness.as_ref(),
+                contract_addr,
+                *threshold,
+                &network,
+            )
+            .await;
         }
         None => {
             // do nothing
@@ -143,9 +143,19 @@ cd ..
 NPM_PID=$!
 echo "PID of react app $NPM_PID"
 
+# Create the node keystores once, they are reused across restarts (dev password only!)
+export FANGORN_KEYSTORE_PASSWORD="${FANGORN_KEYSTORE_PASSWORD:-fangorn-dev}"
+for i in 0 1; do
+    if [ ! -f "tmp/nodes/$i/node.keystore" ]; then
+        ./target/debug/fangorn setup --out-dir "tmp/nodes/$i" --index $i
+    fi
+done
+# admit both nodes to the committee
+cat tmp/nodes/0/member.txt tmp/nodes/1/member.txt > tmp/members.txt
+
 # Start the first instance in the background of current terminal
-echo "Starting first instance: ./target/debug/fangorn run --bind-port 9933 --rpc-port 30332 --is-bootstrap --index 0 --contract-addr "$CONTRACT_ADDR""
-./target/debug/fangorn run --bind-port 9933 --rpc-port 30332 --is-bootstrap --index 0 --contract-addr "$CONTRACT_ADDR" &
+echo "Starting first instance: ./target/debug/fangorn run --bind-port 9933 --rpc-port 30332 --is-bootstrap --keystore-dir tmp/nodes/0 --members tmp/members.txt --contract-addr "$CONTRACT_ADDR""
+./target/debug/fangorn run --bind-port 9933 --rpc-port 30332 --is-bootstrap --keystore-dir tmp/nodes/0 --members tmp/members.txt --contract-addr "$CONTRACT_ADDR" &
 FIRST_PID=$!
 echo "PID of first instance: $FIRST_PID"
 
@@ -187,8 +197,8 @@ cleanup_second() {
 
 trap cleanup_second SIGINT
 
-echo 'Starting second instance: ./target/debug/fangorn run --bind-port 9945 --rpc-port 30334 --bootstrap-pubkey $PUBKEY --bootstrap-ip 172.31.149.62:9933 --ticket $TICKET_CONTENT --index 1 --contract-addr "$CONTRACT_ADDR"'
-./target/debug/fangorn run --bind-port 9945 --rpc-port 30334 --bootstrap-pubkey $PUBKEY --bootstrap-ip 172.31.149.62:9933 --ticket $TICKET_CONTENT --index 1 --contract-addr "$CONTRACT_ADDR" &
+echo 'Starting second instance: ./target/debug/fangorn run --bind-port 9945 --rpc-port 30334 --bootstrap-pubkey $PUBKEY --bootstrap-ip 172.31.149.62:9933 --ticket $TICKET_CONTENT --keystore-dir tmp/nodes/1 --members tmp/members.txt --contract-addr "$CONTRACT_ADDR"'
+FANGORN_KEYSTORE_PASSWORD=\"$FANGORN_KEYSTORE_PASSWORD\" ./target/debug/fangorn run --bind-port 9945 --rpc-port 30334 --bootstrap-pubkey $PUBKEY --bootstrap-ip 172.31.149.62:9933 --ticket $TICKET_CONTENT --keystore-dir tmp/nodes/1 --members tmp/members.txt --contract-addr "$CONTRACT_ADDR" &
 SECOND_SERVER_PID=\$!
 echo \"Second server PID: \$SECOND_SERVER_PID\"
 echo \"\$SECOND_SERVER_PID\" > \"$SIGNAL_FILE.second_pid\"